@@ -0,0 +1,62 @@
+//! Benchmarks of the queries behind the cat list and detail pages, the
+//! ones `catdex profile-load` measures under load, run one at a time to
+//! compare changes to query building. Needs a database migrated with
+//! `catdex migrate`, as for the tests:
+//! `TEST_DATABASE_URL=postgres://... cargo bench --bench repository`. The
+//! cats are seeded into its default tenant inside a transaction that is
+//! rolled back, with their images in a temporary directory, so nothing is
+//! left behind.
+use catdex_api::bench_support::Profile;
+use catdex_api::{DbPool, LocalFileStore};
+use criterion::{criterion_group, criterion_main, Criterion};
+use diesel::connection::Connection;
+use diesel::r2d2::{ConnectionManager, CustomizeConnection};
+use diesel::PgConnection;
+use std::env;
+
+/// Cats the default tenant is seeded up to.
+const CATS: usize = 1000;
+
+/// Keeps the only connection of the pool inside a transaction that is
+/// never committed.
+#[derive(Debug)]
+struct Rollback;
+
+impl CustomizeConnection<PgConnection, diesel::r2d2::Error> for Rollback {
+    fn on_acquire(&self, connection: &mut PgConnection) -> Result<(), diesel::r2d2::Error> {
+        connection
+            .begin_test_transaction()
+            .map_err(diesel::r2d2::Error::QueryError)
+    }
+}
+
+fn repository(c: &mut Criterion) {
+    let Ok(database_url) = env::var("TEST_DATABASE_URL") else {
+        eprintln!("TEST_DATABASE_URL is not set, skipping the repository benchmarks");
+        return;
+    };
+    let pool: DbPool = r2d2::Pool::builder()
+        .max_size(1)
+        .connection_customizer(Box::new(Rollback))
+        .build(ConnectionManager::<PgConnection>::new(database_url))
+        .expect("Failed to create DB connection pool.");
+    let images = tempfile::tempdir().expect("Failed to create the image directory");
+    let store = LocalFileStore::new(images.path());
+    let profile = Profile::prepare(&pool, &store, "default", CATS)
+        .expect("Failed to seed the default tenant");
+    let mut connection = pool.get().expect("Failed to get a DB connection");
+
+    c.bench_function("list_cats", |b| {
+        b.iter(|| profile.list_cats(&mut connection).unwrap())
+    });
+    let mut index = 0;
+    c.bench_function("find_cat_by_public_id", |b| {
+        b.iter(|| {
+            index += 1;
+            profile.find_cat(&mut connection, index).unwrap()
+        })
+    });
+}
+
+criterion_group!(benches, repository);
+criterion_main!(benches);
@@ -0,0 +1,47 @@
+use std::env;
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    // Migrations are embedded for `catdex migrate`, rebuild when they change
+    println!("cargo:rerun-if-changed=migrations");
+
+    // The commit being built, reported by `GET /api/version` and
+    // `GET /api/health/details`
+    let git_sha = Command::new("git")
+        .args(["rev-parse", "--short", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|sha| sha.trim().to_string())
+        .unwrap_or_else(|| "unknown".to_string());
+    println!("cargo:rustc-env=CATDEX_GIT_SHA={}", git_sha);
+    for path in [".git/HEAD", ".git/refs/heads"] {
+        if Path::new(path).exists() {
+            println!("cargo:rerun-if-changed={}", path);
+        }
+    }
+
+    // When and with which features it was built, reported by `GET /api/version`.
+    // SOURCE_DATE_EPOCH pins the time for reproducible builds
+    let built_at = env::var("SOURCE_DATE_EPOCH").ok().or_else(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .ok()
+            .map(|elapsed| elapsed.as_secs().to_string())
+    });
+    println!(
+        "cargo:rustc-env=CATDEX_BUILD_EPOCH={}",
+        built_at.unwrap_or_default()
+    );
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(name, _)| Some(name.strip_prefix("CARGO_FEATURE_")?.to_string()))
+        .filter(|feature| feature != "DEFAULT")
+        .map(|feature| feature.to_lowercase().replace('_', "-"))
+        .collect();
+    features.sort();
+    println!("cargo:rustc-env=CATDEX_FEATURES={}", features.join(","));
+}
@@ -0,0 +1,2 @@
+DROP INDEX cats_name_unique_idx;
+ALTER TABLE cats DROP COLUMN allow_duplicate_name;
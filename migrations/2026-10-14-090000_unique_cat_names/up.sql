@@ -0,0 +1,8 @@
+ALTER TABLE cats ADD COLUMN allow_duplicate_name BOOLEAN NOT NULL DEFAULT FALSE;
+
+-- Existing duplicates keep their names, only the oldest cat claims it
+UPDATE cats SET allow_duplicate_name = TRUE
+WHERE id NOT IN (SELECT MIN(id) FROM cats GROUP BY lower(name));
+
+CREATE UNIQUE INDEX cats_name_unique_idx ON cats (lower(name))
+WHERE NOT allow_duplicate_name;
@@ -0,0 +1,2 @@
+DROP INDEX cats_public_id_idx;
+ALTER TABLE cats DROP COLUMN public_id;
@@ -0,0 +1,5 @@
+-- gen_random_uuid() is only built in from Postgres 13
+CREATE EXTENSION IF NOT EXISTS pgcrypto;
+
+ALTER TABLE cats ADD COLUMN public_id UUID NOT NULL DEFAULT gen_random_uuid();
+CREATE UNIQUE INDEX cats_public_id_idx ON cats (public_id);
@@ -0,0 +1,9 @@
+CREATE TABLE outbox (
+    id BIGSERIAL PRIMARY KEY,
+    event VARCHAR NOT NULL,
+    payload JSONB NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    dispatched_at TIMESTAMPTZ
+);
+
+CREATE INDEX outbox_pending_idx ON outbox (id) WHERE dispatched_at IS NULL;
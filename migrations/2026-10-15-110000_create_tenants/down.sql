@@ -0,0 +1,12 @@
+DROP INDEX webhooks_tenant_id_idx;
+DROP INDEX cats_tenant_id_idx;
+
+DROP INDEX cats_name_unique_idx;
+CREATE UNIQUE INDEX cats_name_unique_idx ON cats (lower(name))
+WHERE NOT allow_duplicate_name;
+
+ALTER TABLE outbox DROP COLUMN tenant_id;
+ALTER TABLE webhooks DROP COLUMN tenant_id;
+ALTER TABLE cats DROP COLUMN tenant_id;
+
+DROP TABLE tenants;
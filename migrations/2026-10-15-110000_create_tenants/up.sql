@@ -0,0 +1,29 @@
+CREATE TABLE tenants (
+    id SERIAL PRIMARY KEY,
+    slug VARCHAR NOT NULL UNIQUE,
+    name VARCHAR NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+-- Everything created before tenants existed belongs to the default tenant
+INSERT INTO tenants (slug, name) VALUES ('default', 'Default');
+
+ALTER TABLE cats ADD COLUMN tenant_id INTEGER REFERENCES tenants (id);
+UPDATE cats SET tenant_id = (SELECT id FROM tenants WHERE slug = 'default');
+ALTER TABLE cats ALTER COLUMN tenant_id SET NOT NULL;
+
+ALTER TABLE webhooks ADD COLUMN tenant_id INTEGER REFERENCES tenants (id);
+UPDATE webhooks SET tenant_id = (SELECT id FROM tenants WHERE slug = 'default');
+ALTER TABLE webhooks ALTER COLUMN tenant_id SET NOT NULL;
+
+ALTER TABLE outbox ADD COLUMN tenant_id INTEGER REFERENCES tenants (id);
+UPDATE outbox SET tenant_id = (SELECT id FROM tenants WHERE slug = 'default');
+ALTER TABLE outbox ALTER COLUMN tenant_id SET NOT NULL;
+
+-- Cat names only need to be unique within a tenant
+DROP INDEX cats_name_unique_idx;
+CREATE UNIQUE INDEX cats_name_unique_idx ON cats (tenant_id, lower(name))
+WHERE NOT allow_duplicate_name;
+
+CREATE INDEX cats_tenant_id_idx ON cats (tenant_id);
+CREATE INDEX webhooks_tenant_id_idx ON webhooks (tenant_id);
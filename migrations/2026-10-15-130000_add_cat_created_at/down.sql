@@ -0,0 +1 @@
+ALTER TABLE cats DROP COLUMN created_at;
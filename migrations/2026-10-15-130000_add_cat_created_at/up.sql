@@ -0,0 +1 @@
+ALTER TABLE cats ADD COLUMN created_at TIMESTAMPTZ NOT NULL DEFAULT now();
@@ -0,0 +1,3 @@
+ALTER TABLE cats DROP COLUMN image_size;
+
+DROP TABLE quotas;
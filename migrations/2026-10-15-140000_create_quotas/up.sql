@@ -0,0 +1,7 @@
+CREATE TABLE quotas (
+    tenant_id INTEGER PRIMARY KEY REFERENCES tenants (id) ON DELETE CASCADE,
+    max_cats INTEGER,
+    max_storage_bytes BIGINT
+);
+
+ALTER TABLE cats ADD COLUMN image_size BIGINT NOT NULL DEFAULT 0;
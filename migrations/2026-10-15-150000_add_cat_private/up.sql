@@ -0,0 +1 @@
+ALTER TABLE cats ADD COLUMN private BOOLEAN NOT NULL DEFAULT false;
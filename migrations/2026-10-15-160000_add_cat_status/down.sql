@@ -0,0 +1,5 @@
+DROP INDEX cats_tenant_id_status_idx;
+
+ALTER TABLE cats DROP COLUMN status;
+
+DROP TYPE cat_status;
@@ -0,0 +1,5 @@
+CREATE TYPE cat_status AS ENUM ('available', 'pending', 'adopted');
+
+ALTER TABLE cats ADD COLUMN status cat_status NOT NULL DEFAULT 'available';
+
+CREATE INDEX cats_tenant_id_status_idx ON cats (tenant_id, status);
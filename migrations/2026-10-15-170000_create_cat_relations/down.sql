@@ -0,0 +1,3 @@
+DROP TABLE cat_relations;
+
+DROP TYPE cat_relation_kind;
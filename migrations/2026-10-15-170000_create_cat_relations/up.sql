@@ -0,0 +1,19 @@
+CREATE TYPE cat_relation_kind AS ENUM ('mother', 'father', 'littermate');
+
+-- The related cat is the cat's mother, father or littermate. Littermate
+-- relations are stored once and read in both directions.
+CREATE TABLE cat_relations (
+    id SERIAL PRIMARY KEY,
+    tenant_id INTEGER NOT NULL REFERENCES tenants (id),
+    cat_id INTEGER NOT NULL REFERENCES cats (id) ON DELETE CASCADE,
+    related_cat_id INTEGER NOT NULL REFERENCES cats (id) ON DELETE CASCADE,
+    kind cat_relation_kind NOT NULL,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now(),
+    CHECK (cat_id <> related_cat_id),
+    UNIQUE (cat_id, related_cat_id, kind)
+);
+
+-- A cat has at most one mother and one father
+CREATE UNIQUE INDEX cat_relations_parent_idx ON cat_relations (cat_id, kind)
+    WHERE kind <> 'littermate';
+CREATE INDEX cat_relations_related_cat_id_idx ON cat_relations (related_cat_id);
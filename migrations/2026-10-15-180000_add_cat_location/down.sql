@@ -0,0 +1,8 @@
+DROP INDEX cats_location_idx;
+
+ALTER TABLE cats
+    DROP COLUMN latitude,
+    DROP COLUMN longitude;
+
+DROP EXTENSION earthdistance;
+DROP EXTENSION cube;
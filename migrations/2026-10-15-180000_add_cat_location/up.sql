@@ -0,0 +1,10 @@
+CREATE EXTENSION IF NOT EXISTS cube;
+CREATE EXTENSION IF NOT EXISTS earthdistance;
+
+ALTER TABLE cats
+    ADD COLUMN latitude DOUBLE PRECISION CHECK (latitude BETWEEN -90 AND 90),
+    ADD COLUMN longitude DOUBLE PRECISION CHECK (longitude BETWEEN -180 AND 180),
+    ADD CONSTRAINT cats_location_check CHECK ((latitude IS NULL) = (longitude IS NULL));
+
+CREATE INDEX cats_location_idx ON cats USING gist (ll_to_earth(latitude, longitude))
+    WHERE latitude IS NOT NULL;
@@ -0,0 +1,3 @@
+DROP TABLE medical_records;
+
+DROP TYPE medical_record_type;
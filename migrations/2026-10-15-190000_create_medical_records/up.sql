@@ -0,0 +1,17 @@
+CREATE TYPE medical_record_type AS ENUM ('vaccination', 'checkup', 'treatment', 'other');
+
+-- Attachments are file store keys under the tenant's private directory
+CREATE TABLE medical_records (
+    id SERIAL PRIMARY KEY,
+    tenant_id INTEGER NOT NULL REFERENCES tenants (id),
+    cat_id INTEGER NOT NULL REFERENCES cats (id) ON DELETE CASCADE,
+    record_type medical_record_type NOT NULL,
+    date DATE NOT NULL,
+    -- When a vaccination has to be repeated
+    due_date DATE,
+    notes TEXT NOT NULL DEFAULT '',
+    attachment_path VARCHAR,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX medical_records_cat_id_date_idx ON medical_records (cat_id, date);
@@ -0,0 +1,14 @@
+-- One row per changed field. Values are JSON so any column type fits; a
+-- null old value marks the cat's creation.
+CREATE TABLE cat_history (
+    id BIGSERIAL PRIMARY KEY,
+    tenant_id INTEGER NOT NULL REFERENCES tenants (id),
+    cat_id INTEGER NOT NULL REFERENCES cats (id) ON DELETE CASCADE,
+    field VARCHAR NOT NULL,
+    old_value JSONB,
+    new_value JSONB NOT NULL,
+    actor VARCHAR NOT NULL,
+    changed_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX cat_history_cat_id_id_idx ON cat_history (cat_id, id);
@@ -0,0 +1,16 @@
+-- Images uploaded ahead of creating their cat. The token is the secret the
+-- client uploads with and later creates the cat from.
+CREATE TABLE uploads (
+    token UUID PRIMARY KEY,
+    tenant_id INTEGER NOT NULL REFERENCES tenants (id),
+    private BOOLEAN NOT NULL DEFAULT FALSE,
+    extension VARCHAR NOT NULL,
+    size BIGINT NOT NULL CHECK (size > 0),
+    received BIGINT NOT NULL DEFAULT 0 CHECK (received BETWEEN 0 AND size),
+    -- Set once every byte is received, scanned and stored
+    image_key VARCHAR,
+    created_at TIMESTAMPTZ NOT NULL,
+    expires_at TIMESTAMPTZ NOT NULL
+);
+
+CREATE INDEX uploads_expires_at_idx ON uploads (expires_at);
@@ -0,0 +1 @@
+ALTER TABLE cats DROP COLUMN image_hash;
@@ -0,0 +1,2 @@
+-- Version of the image contents, NULL for cats created before it was recorded
+ALTER TABLE cats ADD COLUMN image_hash VARCHAR;
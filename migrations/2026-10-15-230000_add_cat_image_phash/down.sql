@@ -0,0 +1,3 @@
+DROP INDEX cats_image_phash_idx;
+
+ALTER TABLE cats DROP COLUMN image_phash;
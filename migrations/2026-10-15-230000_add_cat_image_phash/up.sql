@@ -0,0 +1,5 @@
+-- Perceptual hash of the image, NULL when it could not be decoded
+ALTER TABLE cats ADD COLUMN image_phash BIGINT;
+
+CREATE INDEX cats_image_phash_idx ON cats (tenant_id, image_phash)
+    WHERE image_phash IS NOT NULL;
@@ -0,0 +1,3 @@
+DROP INDEX cats_pending_review_idx;
+
+ALTER TABLE cats DROP COLUMN pending_review;
@@ -0,0 +1,6 @@
+-- Submissions awaiting moderation, left out of listings until approved.
+-- Existing cats count as approved
+ALTER TABLE cats ADD COLUMN pending_review BOOLEAN NOT NULL DEFAULT FALSE;
+
+CREATE INDEX cats_pending_review_idx ON cats (tenant_id, created_at)
+    WHERE pending_review;
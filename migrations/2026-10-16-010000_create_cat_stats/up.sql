@@ -0,0 +1,15 @@
+-- Cats added per tenant, day and adoption status, so dashboards do not
+-- aggregate the cats table on every request. Refreshed by the server on a
+-- schedule, see STATS_REFRESH_SECS
+CREATE MATERIALIZED VIEW cat_stats AS
+SELECT tenant_id,
+       (created_at AT TIME ZONE 'UTC')::date AS day,
+       status,
+       count(*) AS cats,
+       sum(image_size)::bigint AS image_bytes
+FROM cats
+WHERE NOT pending_review
+GROUP BY tenant_id, day, status;
+
+-- Unique, so the view can be refreshed concurrently
+CREATE UNIQUE INDEX cat_stats_key ON cat_stats (tenant_id, day, status);
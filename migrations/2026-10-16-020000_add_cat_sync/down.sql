@@ -0,0 +1,4 @@
+DROP TABLE cat_tombstones;
+
+DROP TRIGGER set_updated_at ON cats;
+ALTER TABLE cats DROP COLUMN updated_at;
@@ -0,0 +1,18 @@
+-- When each cat last changed, set by the diesel_set_updated_at trigger, so
+-- clients can fetch only the cats changed since their last sync
+ALTER TABLE cats ADD COLUMN updated_at TIMESTAMPTZ NOT NULL DEFAULT now();
+UPDATE cats SET updated_at = created_at;
+SELECT diesel_manage_updated_at('cats');
+
+CREATE INDEX cats_tenant_id_updated_at_idx ON cats (tenant_id, updated_at);
+
+-- Deleted cats, so syncing clients learn to drop them too
+CREATE TABLE cat_tombstones (
+    id BIGSERIAL PRIMARY KEY,
+    tenant_id INTEGER NOT NULL REFERENCES tenants (id),
+    cat_id INTEGER NOT NULL,
+    public_id UUID NOT NULL,
+    deleted_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX cat_tombstones_tenant_id_deleted_at_idx ON cat_tombstones (tenant_id, deleted_at);
@@ -0,0 +1,23 @@
+-- Every photo of a cat, in gallery order. The primary photo is also the
+-- cat's image_path, which listings and webhooks show.
+CREATE TABLE cat_images (
+    id SERIAL PRIMARY KEY,
+    tenant_id INTEGER NOT NULL REFERENCES tenants (id),
+    cat_id INTEGER NOT NULL REFERENCES cats (id) ON DELETE CASCADE,
+    image_path VARCHAR NOT NULL,
+    image_hash VARCHAR,
+    image_size BIGINT NOT NULL DEFAULT 0,
+    image_phash BIGINT,
+    position INTEGER NOT NULL,
+    is_primary BOOLEAN NOT NULL DEFAULT false,
+    created_at TIMESTAMPTZ NOT NULL DEFAULT now()
+);
+
+CREATE INDEX cat_images_cat_id_position_idx ON cat_images (cat_id, position);
+CREATE UNIQUE INDEX cat_images_primary_idx ON cat_images (cat_id) WHERE is_primary;
+CREATE INDEX cat_images_image_path_idx ON cat_images (image_path);
+
+INSERT INTO cat_images (
+    tenant_id, cat_id, image_path, image_hash, image_size, image_phash, position, is_primary, created_at
+)
+SELECT tenant_id, id, image_path, image_hash, image_size, image_phash, 0, true, created_at FROM cats;
@@ -0,0 +1,3 @@
+ALTER TABLE cat_images
+    DROP COLUMN archived_at,
+    DROP COLUMN original_path;
@@ -0,0 +1,7 @@
+-- When the archival job last handled an image, which it leaves alone from
+-- then on, and the original it kept when it re-encoded the image.
+ALTER TABLE cat_images
+    ADD COLUMN archived_at TIMESTAMPTZ,
+    ADD COLUMN original_path VARCHAR;
+
+CREATE INDEX cat_images_unarchived_idx ON cat_images (created_at) WHERE archived_at IS NULL;
@@ -0,0 +1,2 @@
+ALTER TABLE cat_images DROP COLUMN image_original_format;
+ALTER TABLE cats DROP COLUMN image_original_format;
@@ -0,0 +1,4 @@
+-- The format an image was uploaded in, when it was converted before it was
+-- stored, such as `heic`.
+ALTER TABLE cats ADD COLUMN image_original_format VARCHAR;
+ALTER TABLE cat_images ADD COLUMN image_original_format VARCHAR;
@@ -0,0 +1,4 @@
+-- Until when a dispatcher has claimed an event, so dispatchers running at
+-- once publish each event once, and events a dispatcher stopped on are
+-- claimed again once their lease runs out.
+ALTER TABLE outbox ADD COLUMN claimed_until TIMESTAMPTZ;
@@ -0,0 +1 @@
+ALTER TABLE medical_records DROP COLUMN attachment_size;
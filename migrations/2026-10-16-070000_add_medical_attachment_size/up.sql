@@ -0,0 +1,3 @@
+-- Bytes of the attachment, counted towards the tenant's storage quota.
+-- Attachments stored before are not counted.
+ALTER TABLE medical_records ADD COLUMN attachment_size BIGINT NOT NULL DEFAULT 0;
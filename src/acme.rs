@@ -0,0 +1,735 @@
+//! Certificates from an ACME certificate authority such as Let's Encrypt
+//! (RFC 8555), for `ACME_DOMAIN`.
+//!
+//! The certificate is written to `TLS_CERT_FILE` and `TLS_KEY_FILE`, where
+//! `tls::Certificates` picks it up, and renewed when it has less than 30
+//! days left. Until the first one is issued a short lived self-signed
+//! certificate stands in. Domains are validated with the HTTP-01 challenge,
+//! answered under `/.well-known/acme-challenge/`, which the CA requests on
+//! port 80 of the domain.
+use crate::config::Config;
+use crate::notifications::{Notification, Notifier};
+use crate::tls::Certificates;
+use actix_web::http::header::{ACCEPT, CONTENT_TYPE, LOCATION};
+use actix_web::{web, HttpResponse};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use log::{error, info, warn};
+use openssl::asn1::Asn1Time;
+use openssl::bn::{BigNum, BigNumContext, MsbOption};
+use openssl::ec::{EcGroup, EcKey};
+use openssl::ecdsa::EcdsaSig;
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::sha::sha256;
+use openssl::stack::Stack;
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509Builder, X509NameBuilder, X509ReqBuilder, X509};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+const RENEW_BEFORE_DAYS: u32 = 30;
+const CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+const RETRY_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const POLL_INTERVAL: Duration = Duration::from_secs(2);
+const MAX_POLLS: u32 = 30;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The key authorizations of the HTTP-01 challenges being validated, by
+/// token.
+#[derive(Default)]
+pub struct Challenges(Mutex<HashMap<String, String>>);
+
+impl Challenges {
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<String, String>> {
+        self.0
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+/// Answers the CA's HTTP-01 challenge requests.
+pub async fn challenge_endpoint(
+    challenges: web::Data<Challenges>,
+    path: web::Path<String>,
+) -> HttpResponse {
+    match challenges.lock().get(path.as_str()) {
+        Some(key_authorization) => HttpResponse::Ok()
+            .content_type("application/octet-stream")
+            .body(key_authorization.clone()),
+        None => HttpResponse::NotFound().finish(),
+    }
+}
+
+/// Where and for which domain to obtain certificates.
+pub struct Acme {
+    domain: String,
+    directory_url: String,
+    email: Option<String>,
+    account_key_file: PathBuf,
+}
+
+impl Acme {
+    /// `None` unless `ACME_DOMAIN` is set.
+    pub fn from_config(config: &Config) -> Option<Acme> {
+        Some(Acme {
+            domain: config.acme_domain.clone()?,
+            directory_url: config.acme_directory_url.clone(),
+            email: config.acme_email.clone(),
+            account_key_file: config.acme_account_key_file.clone(),
+        })
+    }
+
+    /// Writes a self-signed certificate for the domain unless there already
+    /// is a certificate, so the TLS listener can start before one is issued.
+    pub fn ensure_certificate(&self, key_file: &Path, cert_file: &Path) -> io::Result<()> {
+        if key_file.exists() && cert_file.exists() {
+            return Ok(());
+        }
+        let (key, certificate) = self_signed(&self.domain).map_err(io::Error::other)?;
+        write_private(key_file, &key)?;
+        write_atomically(cert_file, &certificate)?;
+        info!(
+            "Wrote a self-signed certificate for {} until one is issued",
+            self.domain
+        );
+        Ok(())
+    }
+
+    /// Renews the certificate whenever it is about to expire, for as long as
+    /// the server runs.
+    pub async fn run(
+        self,
+        certificates: Arc<Certificates>,
+        challenges: web::Data<Challenges>,
+        notifier: Option<Arc<Notifier>>,
+    ) {
+        let [key_file, cert_file] = certificates.files();
+        let mut failing = false;
+        loop {
+            let wait = match needs_renewal(&cert_file, &self.domain) {
+                Ok(false) => CHECK_INTERVAL,
+                renew => {
+                    if let Err(e) = renew {
+                        warn!("Failed to read {}: {}", cert_file.display(), e);
+                    }
+                    match self.renew(&key_file, &cert_file, &challenges).await {
+                        Ok(()) => {
+                            certificates.reload();
+                            failing = false;
+                            CHECK_INTERVAL
+                        }
+                        Err(e) => {
+                            error!("Failed to obtain a certificate for {}: {}", self.domain, e);
+                            if let Some(notifier) = notifier.as_ref().filter(|_| !failing) {
+                                notifier.notify(Notification::JobFailed {
+                                    job: "acme_renewal",
+                                    error: &e,
+                                });
+                            }
+                            failing = true;
+                            RETRY_INTERVAL
+                        }
+                    }
+                }
+            };
+            actix_rt::time::sleep(wait).await;
+        }
+    }
+
+    async fn renew(
+        &self,
+        key_file: &Path,
+        cert_file: &Path,
+        challenges: &Challenges,
+    ) -> Result<(), String> {
+        info!("Requesting a certificate for {}", self.domain);
+        let account_key = account_key(&self.account_key_file)
+            .map_err(|e| format!("Account key {}: {}", self.account_key_file.display(), e))?;
+        let mut client = Client::new(&self.directory_url, account_key).await?;
+        client.register(self.email.as_deref()).await?;
+        let (key, chain) = client.issue(&self.domain, challenges).await?;
+        write_private(key_file, &key).map_err(|e| e.to_string())?;
+        write_atomically(cert_file, &chain).map_err(|e| e.to_string())?;
+        info!("Obtained a certificate for {}", self.domain);
+        Ok(())
+    }
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Directory {
+    new_nonce: String,
+    new_account: String,
+    new_order: String,
+}
+
+#[derive(Deserialize)]
+struct Order {
+    status: String,
+    authorizations: Vec<String>,
+    finalize: String,
+    certificate: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Authorization {
+    status: String,
+    challenges: Vec<Challenge>,
+}
+
+#[derive(Deserialize)]
+struct Challenge {
+    #[serde(rename = "type")]
+    kind: String,
+    url: String,
+    token: String,
+    error: Option<Value>,
+}
+
+struct Response {
+    location: Option<String>,
+    body: web::Bytes,
+}
+
+/// An account with the CA, whose requests are signed with its ES256 key.
+struct Client {
+    http: awc::Client,
+    directory: Directory,
+    key: EcKey<Private>,
+    jwk: Value,
+    thumbprint: String,
+    kid: Option<String>,
+    nonce: Option<String>,
+}
+
+impl Client {
+    async fn new(directory_url: &str, key: EcKey<Private>) -> Result<Client, String> {
+        let http = awc::Client::builder().timeout(REQUEST_TIMEOUT).finish();
+        let body = http
+            .get(directory_url)
+            .send()
+            .await
+            .map_err(|e| format!("Failed to reach {}: {}", directory_url, e))?
+            .body()
+            .await
+            .map_err(|e| format!("Failed to read {}: {}", directory_url, e))?;
+        let directory = serde_json::from_slice(&body)
+            .map_err(|e| format!("Invalid ACME directory {}: {}", directory_url, e))?;
+        let (jwk, thumbprint) = jwk(&key).map_err(|e| e.to_string())?;
+        Ok(Client {
+            http,
+            directory,
+            key,
+            jwk,
+            thumbprint,
+            kid: None,
+            nonce: None,
+        })
+    }
+
+    async fn nonce(&mut self) -> Result<String, String> {
+        if let Some(nonce) = self.nonce.take() {
+            return Ok(nonce);
+        }
+        let response = self
+            .http
+            .head(&self.directory.new_nonce)
+            .send()
+            .await
+            .map_err(|e| format!("Failed to get a nonce: {}", e))?;
+        replay_nonce(response.headers()).ok_or_else(|| "No nonce in response".to_string())
+    }
+
+    /// POSTs `payload` signed with the account key, or an empty payload
+    /// when `None` for the POST-as-GET requests that fetch resources.
+    async fn post(&mut self, url: &str, payload: Option<&Value>) -> Result<Response, String> {
+        let payload = match payload {
+            Some(payload) => URL_SAFE_NO_PAD.encode(payload.to_string()),
+            None => String::new(),
+        };
+        // A nonce rejected as stale is retried once with a fresh one
+        for attempt in 0..2 {
+            let nonce = self.nonce().await?;
+            let mut protected = json!({"alg": "ES256", "nonce": nonce, "url": url});
+            match &self.kid {
+                Some(kid) => protected["kid"] = json!(kid),
+                None => protected["jwk"] = self.jwk.clone(),
+            }
+            let protected = URL_SAFE_NO_PAD.encode(protected.to_string());
+            let signature = sign(&self.key, format!("{}.{}", protected, payload).as_bytes())
+                .map_err(|e| e.to_string())?;
+            let body = json!({"protected": protected, "payload": payload, "signature": signature});
+
+            let mut response = self
+                .http
+                .post(url)
+                .insert_header((CONTENT_TYPE, "application/jose+json"))
+                .insert_header((
+                    ACCEPT,
+                    "application/json, application/pem-certificate-chain",
+                ))
+                .send_body(body.to_string())
+                .await
+                .map_err(|e| format!("Failed to reach {}: {}", url, e))?;
+            self.nonce = replay_nonce(response.headers());
+            let location = response
+                .headers()
+                .get(LOCATION)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string);
+            let status = response.status();
+            let body = response
+                .body()
+                .limit(1024 * 1024)
+                .await
+                .map_err(|e| format!("Failed to read response of {}: {}", url, e))?;
+            if status.is_success() {
+                return Ok(Response { location, body });
+            }
+            let problem: Value = serde_json::from_slice(&body).unwrap_or_default();
+            if attempt == 0 && problem["type"] == "urn:ietf:params:acme:error:badNonce" {
+                continue;
+            }
+            return Err(format!(
+                "{} answered {}: {}",
+                url, status, problem["detail"]
+            ));
+        }
+        unreachable!("the second attempt returns")
+    }
+
+    async fn fetch<T: for<'de> Deserialize<'de>>(&mut self, url: &str) -> Result<T, String> {
+        let response = self.post(url, None).await?;
+        serde_json::from_slice(&response.body).map_err(|e| format!("Invalid {}: {}", url, e))
+    }
+
+    /// Fetches `url` until its status is no longer one of `pending`.
+    async fn wait<T: for<'de> Deserialize<'de>>(
+        &mut self,
+        url: &str,
+        pending: &[&str],
+        status: impl Fn(&T) -> &str,
+    ) -> Result<T, String> {
+        for _ in 0..MAX_POLLS {
+            let resource: T = self.fetch(url).await?;
+            if !pending.contains(&status(&resource)) {
+                return Ok(resource);
+            }
+            actix_rt::time::sleep(POLL_INTERVAL).await;
+        }
+        Err(format!("Timed out waiting for {}", url))
+    }
+
+    /// Creates the account, or finds the one of the key when it exists.
+    async fn register(&mut self, email: Option<&str>) -> Result<(), String> {
+        let mut account = json!({"termsOfServiceAgreed": true});
+        if let Some(email) = email {
+            account["contact"] = json!([format!("mailto:{}", email)]);
+        }
+        let url = self.directory.new_account.clone();
+        let response = self.post(&url, Some(&account)).await?;
+        self.kid = Some(response.location.ok_or("No account URL in response")?);
+        Ok(())
+    }
+
+    /// Orders a certificate for `domain`, returning its private key and its
+    /// certificate chain, both PEM encoded.
+    async fn issue(
+        &mut self,
+        domain: &str,
+        challenges: &Challenges,
+    ) -> Result<(Vec<u8>, Vec<u8>), String> {
+        let url = self.directory.new_order.clone();
+        let identifiers = json!({"identifiers": [{"type": "dns", "value": domain}]});
+        let response = self.post(&url, Some(&identifiers)).await?;
+        let order_url = response.location.ok_or("No order URL in response")?;
+        let order: Order =
+            serde_json::from_slice(&response.body).map_err(|e| format!("Invalid order: {}", e))?;
+
+        for authorization in &order.authorizations {
+            self.authorize(authorization, challenges).await?;
+        }
+
+        let key = generate_key().map_err(|e| e.to_string())?;
+        let csr = csr(domain, &key).map_err(|e| e.to_string())?;
+        let finalize = json!({"csr": URL_SAFE_NO_PAD.encode(csr)});
+        self.post(&order.finalize, Some(&finalize)).await?;
+        let order: Order = self
+            .wait(
+                &order_url,
+                &["pending", "ready", "processing"],
+                |order: &Order| &order.status,
+            )
+            .await?;
+        let certificate = match (order.status.as_str(), order.certificate) {
+            ("valid", Some(certificate)) => certificate,
+            (status, _) => return Err(format!("Order ended up {}", status)),
+        };
+        let chain = self.post(&certificate, None).await?.body.to_vec();
+        let key = key.private_key_to_pem_pkcs8().map_err(|e| e.to_string())?;
+        Ok((key, chain))
+    }
+
+    /// Proves control of the domain of an authorization with its HTTP-01
+    /// challenge.
+    async fn authorize(&mut self, url: &str, challenges: &Challenges) -> Result<(), String> {
+        let authorization: Authorization = self.fetch(url).await?;
+        if authorization.status == "valid" {
+            return Ok(());
+        }
+        let challenge = authorization
+            .challenges
+            .into_iter()
+            .find(|challenge| challenge.kind == "http-01")
+            .ok_or("No http-01 challenge offered")?;
+        let key_authorization = format!("{}.{}", challenge.token, self.thumbprint);
+        challenges
+            .lock()
+            .insert(challenge.token.clone(), key_authorization);
+
+        let result = async {
+            self.post(&challenge.url, Some(&json!({}))).await?;
+            self.wait(url, &["pending"], |authorization: &Authorization| {
+                &authorization.status
+            })
+            .await
+        }
+        .await;
+        challenges.lock().remove(&challenge.token);
+
+        let authorization = result?;
+        if authorization.status != "valid" {
+            let error = authorization
+                .challenges
+                .iter()
+                .find_map(|challenge| challenge.error.as_ref())
+                .map(|error| error["detail"].to_string())
+                .unwrap_or_default();
+            return Err(format!("Authorization {}: {}", authorization.status, error));
+        }
+        Ok(())
+    }
+}
+
+fn replay_nonce(headers: &actix_web::http::header::HeaderMap) -> Option<String> {
+    headers
+        .get("Replay-Nonce")
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string)
+}
+
+fn p256() -> Result<EcGroup, ErrorStack> {
+    EcGroup::from_curve_name(Nid::X9_62_PRIME256V1)
+}
+
+/// A key for a certificate.
+fn generate_key() -> Result<PKey<Private>, ErrorStack> {
+    let group = p256()?;
+    PKey::from_ec_key(EcKey::generate(&group)?)
+}
+
+/// The account key, generated on first use.
+fn account_key(path: &Path) -> io::Result<EcKey<Private>> {
+    if let Ok(pem) = fs::read(path) {
+        return EcKey::private_key_from_pem(&pem).map_err(io::Error::other);
+    }
+    let group = p256()?;
+    let key = EcKey::generate(&group)?;
+    write_private(path, &key.private_key_to_pem()?)?;
+    info!("Generated ACME account key {}", path.display());
+    Ok(key)
+}
+
+/// The public JWK of `key` and its RFC 7638 thumbprint.
+fn jwk(key: &EcKey<Private>) -> Result<(Value, String), ErrorStack> {
+    let mut context = BigNumContext::new()?;
+    let mut x = BigNum::new()?;
+    let mut y = BigNum::new()?;
+    key.public_key()
+        .affine_coordinates(key.group(), &mut x, &mut y, &mut context)?;
+    let x = URL_SAFE_NO_PAD.encode(x.to_vec_padded(32)?);
+    let y = URL_SAFE_NO_PAD.encode(y.to_vec_padded(32)?);
+    // The thumbprint hashes the members in lexicographic order, without
+    // whitespace
+    let canonical = format!(r#"{{"crv":"P-256","kty":"EC","x":"{}","y":"{}"}}"#, x, y);
+    let thumbprint = URL_SAFE_NO_PAD.encode(sha256(canonical.as_bytes()));
+    Ok((
+        json!({"crv": "P-256", "kty": "EC", "x": x, "y": y}),
+        thumbprint,
+    ))
+}
+
+/// An ES256 JWS signature, the raw `r` and `s` rather than DER.
+fn sign(key: &EcKey<Private>, data: &[u8]) -> Result<String, ErrorStack> {
+    let signature = EcdsaSig::sign(&sha256(data), key)?;
+    let mut raw = signature.r().to_vec_padded(32)?;
+    raw.extend(signature.s().to_vec_padded(32)?);
+    Ok(URL_SAFE_NO_PAD.encode(raw))
+}
+
+fn csr(domain: &str, key: &PKey<Private>) -> Result<Vec<u8>, ErrorStack> {
+    let mut builder = X509ReqBuilder::new()?;
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, domain)?;
+    builder.set_subject_name(&name.build())?;
+    builder.set_pubkey(key)?;
+    let mut extensions = Stack::new()?;
+    extensions.push(
+        SubjectAlternativeName::new()
+            .dns(domain)
+            .build(&builder.x509v3_context(None))?,
+    )?;
+    builder.add_extensions(&extensions)?;
+    builder.sign(key, MessageDigest::sha256())?;
+    builder.build().to_der()
+}
+
+/// A key and a certificate for `domain` valid for a day, PEM encoded.
+fn self_signed(domain: &str) -> Result<(Vec<u8>, Vec<u8>), ErrorStack> {
+    let key = generate_key()?;
+    let mut name = X509NameBuilder::new()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, domain)?;
+    let name = name.build();
+    let mut serial = BigNum::new()?;
+    serial.rand(128, MsbOption::MAYBE_ZERO, false)?;
+
+    let mut builder = X509Builder::new()?;
+    builder.set_version(2)?;
+    let serial = serial.to_asn1_integer()?;
+    builder.set_serial_number(&serial)?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(&name)?;
+    builder.set_pubkey(&key)?;
+    let not_before = Asn1Time::days_from_now(0)?;
+    let not_after = Asn1Time::days_from_now(1)?;
+    builder.set_not_before(&not_before)?;
+    builder.set_not_after(&not_after)?;
+    let san = SubjectAlternativeName::new()
+        .dns(domain)
+        .build(&builder.x509v3_context(None, None))?;
+    builder.append_extension(san)?;
+    builder.sign(&key, MessageDigest::sha256())?;
+    Ok((key.private_key_to_pem_pkcs8()?, builder.build().to_pem()?))
+}
+
+/// Whether the first certificate in `cert_file` is not for `domain`, e.g.
+/// one configured before ACME was, or expires within `RENEW_BEFORE_DAYS`.
+fn needs_renewal(cert_file: &Path, domain: &str) -> io::Result<bool> {
+    let certificate = X509::from_pem(&fs::read(cert_file)?).map_err(io::Error::other)?;
+    let names_domain = certificate
+        .subject_alt_names()
+        .is_some_and(|names| names.iter().any(|name| name.dnsname() == Some(domain)));
+    let renew_from = Asn1Time::days_from_now(RENEW_BEFORE_DAYS).map_err(io::Error::other)?;
+    Ok(!names_domain || certificate.not_after() <= renew_from)
+}
+
+/// Replaces `path` with `contents` readable by the owner only.
+fn write_private(path: &Path, contents: &[u8]) -> io::Result<()> {
+    write_with_mode(path, contents, 0o600)
+}
+
+/// Replaces `path` with `contents` in one step, so readers never see a
+/// partly written file.
+fn write_atomically(path: &Path, contents: &[u8]) -> io::Result<()> {
+    write_with_mode(path, contents, 0o644)
+}
+
+fn write_with_mode(path: &Path, contents: &[u8], mode: u32) -> io::Result<()> {
+    let mut temp = path.as_os_str().to_owned();
+    temp.push(".tmp");
+    let temp = PathBuf::from(temp);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode)
+        .open(&temp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(temp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use openssl::bn::BigNum;
+
+    #[test]
+    fn test_sign_verifies_with_jwk() {
+        let key = EcKey::generate(&p256().unwrap()).unwrap();
+        let (jwk, thumbprint) = jwk(&key).unwrap();
+        assert_eq!(jwk["kty"], "EC");
+        assert_eq!(URL_SAFE_NO_PAD.decode(&thumbprint).unwrap().len(), 32);
+
+        let signature = URL_SAFE_NO_PAD
+            .decode(sign(&key, b"protected.payload").unwrap())
+            .unwrap();
+        assert_eq!(signature.len(), 64);
+        let signature = EcdsaSig::from_private_components(
+            BigNum::from_slice(&signature[..32]).unwrap(),
+            BigNum::from_slice(&signature[32..]).unwrap(),
+        )
+        .unwrap();
+        assert!(signature
+            .verify(&sha256(b"protected.payload"), &key)
+            .unwrap());
+    }
+
+    #[test]
+    fn test_self_signed_needs_renewal() {
+        let dir = tempfile::tempdir().unwrap();
+        let acme = Acme {
+            domain: "catdex.example".to_string(),
+            directory_url: crate::config::LETS_ENCRYPT_DIRECTORY.to_string(),
+            email: None,
+            account_key_file: dir.path().join("account.pem"),
+        };
+        let key_file = dir.path().join("key.pem");
+        let cert_file = dir.path().join("cert.pem");
+        acme.ensure_certificate(&key_file, &cert_file).unwrap();
+        assert!(Certificates::load(key_file, cert_file.clone()).is_ok());
+        assert!(needs_renewal(&cert_file, "catdex.example").unwrap());
+        assert!(needs_renewal(Path::new("cert.pem"), "catdex.example").unwrap());
+    }
+
+    #[test]
+    fn test_csr_names_domain() {
+        let key = generate_key().unwrap();
+        let csr = openssl::x509::X509Req::from_der(&csr("catdex.example", &key).unwrap()).unwrap();
+        assert!(csr.verify(&key).unwrap());
+        let subject = csr.subject_name().entries().next().unwrap();
+        assert_eq!(subject.data().as_slice(), b"catdex.example");
+    }
+
+    struct MockCa {
+        base_url: String,
+        issued: Mutex<Vec<u8>>,
+    }
+
+    /// A CA that validates challenges by looking them up directly.
+    async fn mock_ca(
+        req: actix_web::HttpRequest,
+        body: web::Bytes,
+        challenges: web::Data<Challenges>,
+        ca: web::Data<MockCa>,
+    ) -> HttpResponse {
+        let url = |path: &str| format!("{}{}", ca.base_url, path);
+        let payload: Value = serde_json::from_slice::<Value>(&body)
+            .ok()
+            .and_then(|jws| URL_SAFE_NO_PAD.decode(jws["payload"].as_str()?).ok())
+            .and_then(|payload| serde_json::from_slice(&payload).ok())
+            .unwrap_or_default();
+        let validated = challenges
+            .lock()
+            .get("token")
+            .is_some_and(|key_authorization| key_authorization.starts_with("token."));
+        let mut res = HttpResponse::Ok();
+        res.insert_header(("Replay-Nonce", "nonce"));
+        match req.path() {
+            "/directory" => res.json(json!({
+                "newNonce": url("/nonce"),
+                "newAccount": url("/account"),
+                "newOrder": url("/order"),
+            })),
+            "/nonce" => res.finish(),
+            "/account" => res.insert_header((LOCATION, url("/account/1"))).finish(),
+            "/order" => res.insert_header((LOCATION, url("/order/1"))).json(json!({
+                "status": "pending",
+                "authorizations": [url("/authorization/1")],
+                "finalize": url("/finalize/1"),
+            })),
+            "/authorization/1" => res.json(json!({
+                "status": if validated { "valid" } else { "pending" },
+                "challenges": [{"type": "http-01", "url": url("/challenge/1"), "token": "token"}],
+            })),
+            "/challenge/1" => res.json(json!({})),
+            "/finalize/1" => {
+                let csr = URL_SAFE_NO_PAD
+                    .decode(payload["csr"].as_str().unwrap())
+                    .unwrap();
+                let csr = openssl::x509::X509Req::from_der(&csr).unwrap();
+                let ca_key = generate_key().unwrap();
+                let mut builder = X509Builder::new().unwrap();
+                builder.set_subject_name(csr.subject_name()).unwrap();
+                builder.set_issuer_name(csr.subject_name()).unwrap();
+                builder.set_pubkey(&csr.public_key().unwrap()).unwrap();
+                let not_before = Asn1Time::days_from_now(0).unwrap();
+                let not_after = Asn1Time::days_from_now(90).unwrap();
+                builder.set_not_before(&not_before).unwrap();
+                builder.set_not_after(&not_after).unwrap();
+                let san = SubjectAlternativeName::new()
+                    .dns("catdex.example")
+                    .build(&builder.x509v3_context(None, None))
+                    .unwrap();
+                builder.append_extension(san).unwrap();
+                builder.sign(&ca_key, MessageDigest::sha256()).unwrap();
+                *ca.issued.lock().unwrap() = builder.build().to_pem().unwrap();
+                res.json(json!({}))
+            }
+            "/order/1" => res.json(json!({
+                "status": "valid",
+                "authorizations": [url("/authorization/1")],
+                "finalize": url("/finalize/1"),
+                "certificate": url("/certificate/1"),
+            })),
+            "/certificate/1" => res
+                .content_type("application/pem-certificate-chain")
+                .body(ca.issued.lock().unwrap().clone()),
+            _ => HttpResponse::NotFound().finish(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_renew_with_http_01() {
+        use actix_web::{App, HttpServer};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let base_url = format!("http://{}", listener.local_addr().unwrap());
+        let challenges = web::Data::new(Challenges::default());
+        let server = {
+            let challenges = challenges.clone();
+            let ca = web::Data::new(MockCa {
+                base_url: base_url.clone(),
+                issued: Mutex::default(),
+            });
+            HttpServer::new(move || {
+                App::new()
+                    .app_data(challenges.clone())
+                    .app_data(ca.clone())
+                    .default_service(web::to(mock_ca))
+            })
+            .listen(listener)
+            .unwrap()
+            .workers(1)
+            .run()
+        };
+        actix_rt::spawn(server);
+
+        let dir = tempfile::tempdir().unwrap();
+        let acme = Acme {
+            domain: "catdex.example".to_string(),
+            directory_url: format!("{}/directory", base_url),
+            email: Some("admin@catdex.example".to_string()),
+            account_key_file: dir.path().join("account.pem"),
+        };
+        let key_file = dir.path().join("key.pem");
+        let cert_file = dir.path().join("cert.pem");
+        acme.renew(&key_file, &cert_file, &challenges)
+            .await
+            .unwrap();
+
+        assert!(challenges.lock().is_empty());
+        assert!(!needs_renewal(&cert_file, "catdex.example").unwrap());
+        assert!(Certificates::load(key_file, cert_file).is_ok());
+    }
+}
@@ -0,0 +1,98 @@
+use crate::auth;
+use crate::clock::Clock;
+use crate::db::DbConn;
+use crate::errors::UserError;
+use crate::history::{self, Requester};
+use crate::models::{CatStatus, Tenant};
+use crate::outbox;
+use crate::repository;
+use crate::signed_urls::UrlSigner;
+use crate::webhooks::CatEvent;
+use actix_web::{web, HttpResponse};
+use diesel::Connection;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, PartialEq)]
+enum Transition {
+    Allowed,
+    /// Only an admin may reopen an adoption, e.g. when it fell through.
+    AdminOnly,
+    Invalid,
+}
+
+fn transition(from: CatStatus, to: CatStatus) -> Transition {
+    match (from, to) {
+        _ if from == to => Transition::Invalid,
+        (CatStatus::Adopted, _) => Transition::AdminOnly,
+        _ => Transition::Allowed,
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CatStatusPath {
+    id: i32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SetCatStatus {
+    pub status: CatStatus,
+}
+
+/// Moves a cat to another adoption status and records a
+/// `cat.status_changed` event.
+pub async fn set_status_endpoint(
+    connection: DbConn,
+    clock: web::Data<dyn Clock>,
+    signer: web::Data<UrlSigner>,
+    tenant: Tenant,
+    requester: Requester,
+    path: web::Path<CatStatusPath>,
+    body: web::Json<SetCatStatus>,
+) -> Result<HttpResponse, UserError> {
+    let cat_id = path.id;
+    let to = body.status;
+    let now = clock.now();
+
+    let cat = connection
+        .run("change cat status", move |connection| {
+            connection.transaction(|connection| {
+                let before = repository::lock_cat(connection, tenant.id, cat_id)?;
+                match transition(before.status, to) {
+                    Transition::Allowed => {}
+                    Transition::AdminOnly => {
+                        if !auth::is_admin(connection, tenant.id, &requester)? {
+                            return Ok(Err(UserError::AdminRequiredError));
+                        }
+                    }
+                    Transition::Invalid => {
+                        return Ok(Err(UserError::InvalidTransitionError(before.status, to)));
+                    }
+                }
+                let cat = repository::set_cat_status(connection, before.id, to)?;
+                let actor = requester.actor(connection, tenant.id)?;
+                history::record(connection, tenant.id, &actor, Some(&before), &cat, now)?;
+                outbox::record(connection, tenant.id, CatEvent::StatusChanged, &cat, now)?;
+                Ok(Ok(cat))
+            })
+        })
+        .await?
+        .inspect_err(|e| warn!("Rejected status change of cat ID {}: {}", cat_id, e))?;
+    Ok(HttpResponse::Ok().json(signer.present(cat, now)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_transitions() {
+        use CatStatus::*;
+        assert_eq!(transition(Available, Pending), Transition::Allowed);
+        assert_eq!(transition(Pending, Available), Transition::Allowed);
+        assert_eq!(transition(Pending, Adopted), Transition::Allowed);
+        assert_eq!(transition(Adopted, Available), Transition::AdminOnly);
+        assert_eq!(transition(Adopted, Pending), Transition::AdminOnly);
+        assert_eq!(transition(Pending, Pending), Transition::Invalid);
+    }
+}
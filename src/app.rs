@@ -0,0 +1,344 @@
+//! The API as an Actix `App`, put together by `CatdexApp::builder()`.
+//!
+//! `catdex serve` builds one from the environment, and projects embedding
+//! catdex or testing against it pass their own config, pool and services:
+//!
+//! ```ignore
+//! let app = CatdexApp::builder().config(config).pool(pool).build();
+//! HttpServer::new(move || app.app()).bind(("127.0.0.1", 8080))?.run().await
+//! ```
+//!
+//! Anything not given is set up from the config, as `catdex serve` does.
+//! Services are given as implementations of the traits exported next to
+//! the builder, e.g. a `FileStore` keeping images elsewhere.
+#[cfg(feature = "tls")]
+use crate::acme::{self, Challenges};
+use crate::assets::StaticAssets;
+use crate::cache::{self, CacheBackend};
+use crate::captcha::{self, CaptchaVerifier};
+use crate::cat_detection::{self, CatDetector};
+use crate::clock::{Clock, SystemClock};
+use crate::concurrency::{self, RouteLimits};
+use crate::config::{Config, Settings};
+use crate::file_store::{FileStore, LocalFileStore};
+use crate::maintenance::{self, Maintenance};
+use crate::notifications::{self, Notifier};
+use crate::payload_log::{self, PayloadLog};
+use crate::reload::LiveSettings;
+use crate::scanner::{self, Scanner};
+use crate::signed_urls::UrlSigner;
+use crate::storage_migration::{self, MigrationTarget};
+use crate::submissions::SubmissionLimiter;
+use crate::{api_config, image_config, schema_guard, security_headers, sessions, telemetry};
+use crate::{client_ip, csrf, deadline, fallback, health, i18n, jsonapi, metrics};
+use crate::{setup_database, DbPool, ACCESS_LOG_FORMAT};
+use actix_web::body::MessageBody;
+use actix_web::cookie::Key;
+use actix_web::dev::{ServiceFactory, ServiceRequest, ServiceResponse};
+use actix_web::middleware::{from_fn, Condition, Logger};
+use actix_web::{web, App, Error};
+use log::warn;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+/// Everything the API's handlers and middleware share. Cheap to clone, so
+/// every worker of an `HttpServer` builds its `App` from the same state.
+#[derive(Clone)]
+pub struct CatdexApp {
+    pool: DbPool,
+    config: web::Data<Config>,
+    settings: web::Data<LiveSettings>,
+    catalog: web::Data<i18n::Catalog>,
+    clock: Arc<dyn Clock>,
+    store: Arc<dyn FileStore>,
+    migration_target: Option<Arc<dyn FileStore>>,
+    scanner: Option<Arc<dyn Scanner>>,
+    detector: Option<Arc<dyn CatDetector>>,
+    notifier: Option<Arc<Notifier>>,
+    captcha: Option<Arc<dyn CaptchaVerifier>>,
+    signer: web::Data<UrlSigner>,
+    payload_log: web::Data<PayloadLog>,
+    maintenance: web::Data<Maintenance>,
+    route_limits: web::Data<RouteLimits>,
+    submission_limiter: web::Data<SubmissionLimiter>,
+    #[cfg(feature = "tls")]
+    challenges: web::Data<Challenges>,
+    static_assets: StaticAssets,
+    session_key: Key,
+    session_ttl: Duration,
+    read_only: bool,
+}
+
+impl CatdexApp {
+    pub fn builder() -> CatdexAppBuilder {
+        CatdexAppBuilder::default()
+    }
+
+    pub fn config(&self) -> &web::Data<Config> {
+        &self.config
+    }
+
+    pub fn pool(&self) -> &DbPool {
+        &self.pool
+    }
+
+    pub(crate) fn file_store(&self) -> &Arc<dyn FileStore> {
+        &self.store
+    }
+
+    pub(crate) fn notifier(&self) -> Option<&Arc<Notifier>> {
+        self.notifier.as_ref()
+    }
+
+    #[cfg(feature = "tls")]
+    pub(crate) fn challenges(&self) -> &web::Data<Challenges> {
+        &self.challenges
+    }
+
+    /// Answers the ACME HTTP-01 challenges of certificates being renewed.
+    #[cfg_attr(not(feature = "tls"), allow(unused_variables))]
+    pub(crate) fn challenge_config(&self, cfg: &mut web::ServiceConfig) {
+        #[cfg(feature = "tls")]
+        cfg.app_data(self.challenges.clone()).route(
+            "/.well-known/acme-challenge/{token}",
+            web::get().to(acme::challenge_endpoint),
+        );
+    }
+
+    /// The API with its middleware, for one worker.
+    pub fn app(
+        &self,
+    ) -> App<
+        impl ServiceFactory<
+            ServiceRequest,
+            Config = (),
+            Response = ServiceResponse<impl MessageBody>,
+            Error = Error,
+            InitError = (),
+        >,
+    > {
+        App::new()
+            .wrap(Condition::new(
+                self.read_only,
+                from_fn(schema_guard::reject_writes),
+            ))
+            .wrap(from_fn(concurrency::limit))
+            .wrap(from_fn(deadline::track_requests))
+            .wrap(from_fn(maintenance::guard))
+            .wrap(from_fn(csrf::protect))
+            .wrap(sessions::middleware(
+                self.session_key.clone(),
+                self.session_ttl,
+            ))
+            .wrap(from_fn(telemetry::trace_requests))
+            .wrap(
+                Logger::new(ACCESS_LOG_FORMAT)
+                    .custom_request_replace("client_ip", client_ip::access_log_value),
+            )
+            .wrap(from_fn(client_ip::resolve_client_ip))
+            .wrap(from_fn(i18n::localize_errors))
+            .wrap(from_fn(jsonapi::json_api))
+            .wrap(from_fn(payload_log::log_payloads))
+            // Outermost, so responses re-rendered by the layers above get them too
+            .wrap(from_fn(security_headers::add_security_headers))
+            .app_data(web::Data::new(self.pool.clone()))
+            .app_data(self.config.clone())
+            .app_data(self.settings.clone())
+            .app_data(self.payload_log.clone())
+            .app_data(self.maintenance.clone())
+            .app_data(self.route_limits.clone())
+            .app_data(self.submission_limiter.clone())
+            .app_data(self.catalog.clone())
+            .app_data(web::Data::from(self.clock.clone()))
+            .app_data(web::Data::from(self.store.clone()))
+            .configure(|cfg| {
+                if let Some(scanner) = &self.scanner {
+                    cfg.app_data(web::Data::from(scanner.clone()));
+                }
+                if let Some(detector) = &self.detector {
+                    cfg.app_data(web::Data::from(detector.clone()));
+                }
+                if let Some(notifier) = &self.notifier {
+                    cfg.app_data(web::Data::from(notifier.clone()));
+                }
+                if let Some(captcha) = &self.captcha {
+                    cfg.app_data(web::Data::from(captcha.clone()));
+                }
+                if let Some(target) = &self.migration_target {
+                    cfg.app_data(web::Data::new(MigrationTarget(target.clone())));
+                }
+            })
+            .app_data(awmp::PartsConfig::default().with_temp_dir("./tmp"))
+            .app_data(self.signer.clone())
+            .configure(|cfg| image_config(cfg, &self.config.image_dir))
+            .configure(api_config)
+            .configure(sessions::pages_config)
+            .configure(|cfg| self.static_assets.configure(cfg))
+            .route("/metrics", web::get().to(metrics::metrics_endpoint))
+            .route("/healthz", web::get().to(health::healthz_endpoint))
+            .configure(|cfg| self.challenge_config(cfg))
+            .default_service(web::to(fallback::not_found_endpoint))
+    }
+}
+
+#[derive(Default)]
+pub struct CatdexAppBuilder {
+    config: Option<Config>,
+    pool: Option<DbPool>,
+    settings: Option<web::Data<LiveSettings>>,
+    clock: Option<Arc<dyn Clock>>,
+    store: Option<Arc<dyn FileStore>>,
+    migration_target: Option<Arc<dyn FileStore>>,
+    scanner: Option<Arc<dyn Scanner>>,
+    captcha: Option<Arc<dyn CaptchaVerifier>>,
+    cache: Option<Arc<dyn CacheBackend>>,
+    signer: Option<UrlSigner>,
+    session_key: Option<Key>,
+    read_only: bool,
+}
+
+impl CatdexAppBuilder {
+    /// Defaults to `Config::from_env()`.
+    pub fn config(mut self, config: Config) -> CatdexAppBuilder {
+        self.config = Some(config);
+        self
+    }
+
+    /// Defaults to a pool connecting to `DATABASE_URL`.
+    pub fn pool(mut self, pool: DbPool) -> CatdexAppBuilder {
+        self.pool = Some(pool);
+        self
+    }
+
+    /// Defaults to the settings of `CONFIG_FILE`, never reloaded.
+    pub(crate) fn settings(mut self, settings: web::Data<LiveSettings>) -> CatdexAppBuilder {
+        self.settings = Some(settings);
+        self
+    }
+
+    /// Defaults to the system clock.
+    pub fn clock(mut self, clock: Arc<dyn Clock>) -> CatdexAppBuilder {
+        self.clock = Some(clock);
+        self
+    }
+
+    /// Where images are stored, by default in `./image`.
+    pub fn file_store(mut self, store: Arc<dyn FileStore>) -> CatdexAppBuilder {
+        self.store = Some(store);
+        self
+    }
+
+    /// Where `POST /api/admin/migrate-storage` moves the stored images to,
+    /// by default the directory `STORAGE_MIGRATION_DIR` names, if any.
+    pub fn migration_target(mut self, target: Arc<dyn FileStore>) -> CatdexAppBuilder {
+        self.migration_target = Some(target);
+        self
+    }
+
+    /// Defaults to the scanner `SCAN_COMMAND` sets up, if any.
+    pub fn scanner(mut self, scanner: Arc<dyn Scanner>) -> CatdexAppBuilder {
+        self.scanner = Some(scanner);
+        self
+    }
+
+    /// Defaults to the verifier `CAPTCHA_PROVIDER` sets up, if any.
+    pub fn captcha_verifier(mut self, verifier: Arc<dyn CaptchaVerifier>) -> CatdexAppBuilder {
+        self.captcha = Some(verifier);
+        self
+    }
+
+    /// Where rate limits are counted, by default the cache `CACHE_URL`
+    /// names or one in memory.
+    pub fn cache_backend(mut self, cache: Arc<dyn CacheBackend>) -> CatdexAppBuilder {
+        self.cache = Some(cache);
+        self
+    }
+
+    /// Defaults to a signer with `IMAGE_SIGNING_KEY`, or a random key.
+    pub fn url_signer(mut self, signer: UrlSigner) -> CatdexAppBuilder {
+        self.signer = Some(signer);
+        self
+    }
+
+    /// Defaults to the key `SESSION_KEY` sets up.
+    pub fn session_key(mut self, key: Key) -> CatdexAppBuilder {
+        self.session_key = Some(key);
+        self
+    }
+
+    /// Rejects every request that may write, see `SCHEMA_OUTDATED_ACTION`.
+    pub fn read_only(mut self, read_only: bool) -> CatdexAppBuilder {
+        self.read_only = read_only;
+        self
+    }
+
+    pub fn build(self) -> CatdexApp {
+        let config = self.config.unwrap_or_else(Config::from_env);
+        let pool = self.pool.unwrap_or_else(setup_database);
+        let settings = self.settings.unwrap_or_else(|| {
+            let settings =
+                Settings::load(config.config_file.as_deref()).unwrap_or_else(|e| panic!("{}", e));
+            web::Data::new(LiveSettings::new(settings, config.config_file.clone()))
+        });
+        let catalog = i18n::Catalog::load(&config.locales_dir).unwrap_or_else(|e| {
+            warn!(
+                "Failed to load locales from {}: {}",
+                config.locales_dir.display(),
+                e
+            );
+            i18n::Catalog::default()
+        });
+        let signer = self.signer.unwrap_or_else(|| {
+            let key = config.image_signing_key.clone().unwrap_or_else(|| {
+                warn!(
+                    "IMAGE_SIGNING_KEY is not set, private image links will not survive a restart"
+                );
+                format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+            });
+            UrlSigner::new(key, config.signed_url_ttl)
+                .with_cdn_base_url(config.image_cdn_base_url.clone())
+        });
+        let clock = self.clock.unwrap_or_else(|| Arc::new(SystemClock));
+        let store = self.store.unwrap_or_else(|| {
+            Arc::new(
+                LocalFileStore::new(&config.image_dir)
+                    .with_date_shards(config.image_date_shards)
+                    .with_clock(clock.clone()),
+            )
+        });
+        let cache = self
+            .cache
+            .unwrap_or_else(|| cache::from_config(&config, clock.clone()));
+        let migration_target = self
+            .migration_target
+            .or_else(|| storage_migration::from_config(&config, clock.clone()));
+        CatdexApp {
+            pool,
+            settings,
+            catalog: web::Data::new(catalog),
+            clock,
+            store,
+            migration_target,
+            scanner: self.scanner.or_else(|| scanner::from_config(&config)),
+            detector: cat_detection::from_config(&config),
+            notifier: notifications::from_config(&config),
+            captcha: self.captcha.or_else(|| captcha::from_config(&config)),
+            signer: web::Data::new(signer),
+            payload_log: web::Data::new(PayloadLog::default()),
+            maintenance: web::Data::new(Maintenance::from_config(&config)),
+            route_limits: web::Data::new(RouteLimits::new(
+                &config.route_concurrency,
+                config.route_queue_timeout,
+            )),
+            submission_limiter: web::Data::new(SubmissionLimiter::new(cache)),
+            #[cfg(feature = "tls")]
+            challenges: web::Data::default(),
+            static_assets: StaticAssets::new(config.static_dir.clone()),
+            session_key: self.session_key.unwrap_or_else(|| sessions::key(&config)),
+            session_ttl: config.session_ttl,
+            read_only: self.read_only,
+            config: web::Data::new(config),
+        }
+    }
+}
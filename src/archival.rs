@@ -0,0 +1,347 @@
+//! Archival of old images to reclaim disk space. Once a gallery image is
+//! `ARCHIVE_AFTER_DAYS` old, a JPEG is re-encoded at `ARCHIVE_JPEG_QUALITY`
+//! and a PNG converted to lossless WebP, and the result stored under a new
+//! key if it is smaller. Every reference then moves to it in one
+//! transaction, and the original is deleted unless its tenant is listed in
+//! `ARCHIVE_KEEP_ORIGINALS`.
+//!
+//! Each image is handled once: those that would not shrink, or cannot be
+//! decoded, are marked as archived as they are. The server archives every
+//! `ARCHIVE_INTERVAL_SECS`, and `catdex archive-images` does so on demand.
+use crate::config::Config;
+use crate::file_store::FileStore;
+use crate::health;
+use crate::notifications::{Notification, Notifier};
+use crate::repository;
+use crate::signed_urls;
+use crate::telemetry;
+use crate::DbPool;
+use chrono::{DateTime, TimeDelta, Utc};
+use diesel::{Connection, PgConnection};
+use log::{error, info, warn};
+use std::error::Error;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+use uuid::Uuid;
+
+const BATCH_SIZE: i64 = 100;
+
+pub struct ArchiveOptions {
+    /// Age of the images to archive.
+    pub older_than: Duration,
+    pub jpeg_quality: u8,
+    /// Slugs of the tenants whose originals are kept.
+    pub keep_originals: Vec<String>,
+    /// Only report what would be archived.
+    pub dry_run: bool,
+}
+
+impl ArchiveOptions {
+    /// The options of the config for images older than `older_than`.
+    pub fn from_config(config: &Config, older_than: Duration) -> ArchiveOptions {
+        ArchiveOptions {
+            older_than,
+            jpeg_quality: config.archive_jpeg_quality,
+            keep_originals: config.archive_keep_originals.clone(),
+            dry_run: false,
+        }
+    }
+}
+
+#[derive(Debug, Default, PartialEq)]
+pub struct ArchiveReport {
+    pub archived: usize,
+    /// Images left as they were, being no smaller re-encoded or not images
+    /// that can be.
+    pub kept: usize,
+    pub reclaimed_bytes: u64,
+}
+
+/// `contents` re-encoded for archival, with the extension of its format.
+/// None when the format of `extension` is not archived.
+#[cfg(feature = "image-processing")]
+fn reencode(
+    contents: &[u8],
+    extension: &str,
+    jpeg_quality: u8,
+) -> Result<Option<(Vec<u8>, &'static str)>, String> {
+    use image::codecs::jpeg::JpegEncoder;
+    use image::codecs::webp::WebPEncoder;
+    use image::{DynamicImage, ImageDecoder, ImageReader};
+    use std::io::Cursor;
+
+    let format = match extension.to_ascii_lowercase().as_str() {
+        "jpg" | "jpeg" => "jpg",
+        "png" => "webp",
+        _ => return Ok(None),
+    };
+    let mut decoder = ImageReader::new(Cursor::new(contents))
+        .with_guessed_format()
+        .map_err(|e| e.to_string())?
+        .into_decoder()
+        .map_err(|e| e.to_string())?;
+    // Applied to the pixels, as the metadata saying how is not carried over
+    let orientation = decoder.orientation().map_err(|e| e.to_string())?;
+    let mut image = DynamicImage::from_decoder(decoder).map_err(|e| e.to_string())?;
+    image.apply_orientation(orientation);
+
+    let mut encoded = Vec::new();
+    match format {
+        "jpg" => DynamicImage::ImageRgb8(image.to_rgb8())
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut encoded, jpeg_quality)),
+        _ => {
+            let image = match image.color().has_alpha() {
+                true => DynamicImage::ImageRgba8(image.to_rgba8()),
+                false => DynamicImage::ImageRgb8(image.to_rgb8()),
+            };
+            image.write_with_encoder(WebPEncoder::new_lossless(&mut encoded))
+        }
+    }
+    .map_err(|e| e.to_string())?;
+    Ok(Some((encoded, format)))
+}
+
+#[cfg(not(feature = "image-processing"))]
+fn reencode(
+    _contents: &[u8],
+    _extension: &str,
+    _jpeg_quality: u8,
+) -> Result<Option<(Vec<u8>, &'static str)>, String> {
+    Err("this build lacks the image-processing feature".to_string())
+}
+
+/// Archives the image `image_id` stored at `path`, returning the bytes it
+/// reclaimed, or None when it is left as it is.
+fn archive_image(
+    connection: &mut PgConnection,
+    store: &dyn FileStore,
+    options: &ArchiveOptions,
+    (image_id, path): (i32, &str),
+    now: DateTime<Utc>,
+) -> Result<Option<u64>, Box<dyn Error + Send + Sync>> {
+    let leave = |connection: &mut PgConnection| {
+        if !options.dry_run {
+            repository::set_image_archived(connection, image_id, now)?;
+        }
+        Ok(None)
+    };
+    let Some(key) = path.strip_prefix("/image/") else {
+        return leave(connection);
+    };
+    let contents = match store.get(key) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            warn!("Not archiving {}, which is not stored", path);
+            return leave(connection);
+        }
+        Err(e) => return Err(format!("Failed to read {}: {}", path, e).into()),
+    };
+    let extension = Path::new(key)
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default();
+    let (archived, archived_extension) = match reencode(&contents, extension, options.jpeg_quality)
+    {
+        Ok(Some(reencoded)) if reencoded.0.len() < contents.len() => reencoded,
+        Ok(_) => return leave(connection),
+        Err(e) => {
+            warn!("Not archiving {}, which failed to re-encode: {}", path, e);
+            return leave(connection);
+        }
+    };
+    let tenant = key.split('/').next().unwrap_or_default();
+    let keep_original = options.keep_originals.iter().any(|slug| slug == tenant);
+    let reclaimed = match keep_original {
+        true => 0,
+        false => (contents.len() - archived.len()) as u64,
+    };
+    if options.dry_run {
+        info!("Would archive {}, reclaiming {} bytes", path, reclaimed);
+        return Ok(Some(reclaimed));
+    }
+
+    // A new key in the same directory, so private and sharded images stay so
+    let dir = key.rsplit_once('/').map_or("", |(dir, _)| dir);
+    let new_key = format!("{}/{}.{}", dir, Uuid::new_v4(), archived_extension);
+    let new_path = format!("/image/{}", new_key);
+    store.put(&new_key, &mut archived.as_slice())?;
+    let moved = connection.transaction(|connection| {
+        repository::archive_image_path(
+            connection,
+            path,
+            &new_path,
+            &signed_urls::image_version(&archived),
+            archived.len() as i64,
+            keep_original.then_some(path),
+            now,
+        )
+    });
+    match moved {
+        Ok(true) => {}
+        // Deleted or replaced since it was listed
+        Ok(false) => {
+            store.delete(&new_key)?;
+            return Ok(None);
+        }
+        Err(e) => {
+            if let Err(e) = store.delete(&new_key) {
+                warn!("Failed to delete the unused {}: {}", new_path, e);
+            }
+            return Err(format!("Failed to archive {}: {}", path, e).into());
+        }
+    }
+    if !keep_original {
+        // Left for gc-images to collect if this fails, as nothing refers to it
+        if let Err(e) = store.delete(key) {
+            warn!("Failed to delete the original {}: {}", path, e);
+        }
+    }
+    info!(
+        "Archived {} as {}, reclaiming {} bytes",
+        path, new_path, reclaimed
+    );
+    Ok(Some(reclaimed))
+}
+
+/// Archives every image older than `options.older_than` at `now`, in
+/// batches.
+pub fn archive_images(
+    connection: &mut PgConnection,
+    store: &dyn FileStore,
+    options: &ArchiveOptions,
+    now: DateTime<Utc>,
+) -> Result<ArchiveReport, Box<dyn Error + Send + Sync>> {
+    let older_than = TimeDelta::from_std(options.older_than)?;
+    let created_before = now - older_than;
+    let mut report = ArchiveReport::default();
+    let mut after_id = 0;
+    loop {
+        let batch =
+            repository::list_unarchived_images(connection, created_before, after_id, BATCH_SIZE)?;
+        let Some(&(last_id, _)) = batch.last() else {
+            break;
+        };
+        for (image_id, path) in &batch {
+            match archive_image(connection, store, options, (*image_id, path), now)? {
+                Some(reclaimed) => {
+                    report.archived += 1;
+                    report.reclaimed_bytes += reclaimed;
+                }
+                None => report.kept += 1,
+            }
+        }
+        after_id = last_id;
+    }
+    Ok(report)
+}
+
+/// Archives the old images every `interval` for as long as the server runs.
+pub async fn run_archiver(
+    pool: DbPool,
+    store: Arc<dyn FileStore>,
+    options: ArchiveOptions,
+    interval: Duration,
+    notifier: Option<Arc<Notifier>>,
+) {
+    let options = Arc::new(options);
+    let mut interval = actix_rt::time::interval(interval);
+    let mut failing = false;
+    loop {
+        interval.tick().await;
+
+        let (pool, store, options) = (pool.clone(), store.clone(), options.clone());
+        let archived = telemetry::block(move || {
+            let mut connection = pool.get().map_err(|e| e.to_string())?;
+            archive_images(&mut connection, store.as_ref(), &options, Utc::now())
+                .map_err(|e| e.to_string())
+        })
+        .await;
+        match archived {
+            Ok(Ok(report)) => {
+                failing = false;
+                health::record_job_run("image_archival", Utc::now());
+                if report.archived > 0 {
+                    info!(
+                        "Archived {} image(s), reclaiming {} bytes",
+                        report.archived, report.reclaimed_bytes
+                    );
+                }
+            }
+            Ok(Err(e)) => {
+                error!("Failed to archive images: {}", e);
+                if let Some(notifier) = notifier.as_ref().filter(|_| !failing) {
+                    notifier.notify(Notification::JobFailed {
+                        job: "image_archival",
+                        error: &e,
+                    });
+                }
+                failing = true;
+            }
+            Err(_) => error!("Blocking Thread Pool Error"),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "image-processing"))]
+mod tests {
+    use super::*;
+    use crate::models::NewCat;
+    use crate::tenants::DEFAULT_TENANT;
+    use crate::test_support::{insert_cat_with, test_cat, test_pool, MemoryFileStore};
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_archive_images() {
+        use image::{ImageFormat, Rgb, RgbImage};
+        use std::io::Cursor;
+
+        let mut jpeg = Vec::new();
+        RgbImage::from_fn(64, 64, |x, y| Rgb([(x * 4) as u8, (y * 4) as u8, 90]))
+            .write_to(&mut Cursor::new(&mut jpeg), ImageFormat::Jpeg)
+            .unwrap();
+        let store = MemoryFileStore::default();
+        let key = format!("{}/{}.jpg", DEFAULT_TENANT, Uuid::new_v4());
+        store.put(&key, &mut jpeg.as_slice()).unwrap();
+
+        let pool = test_pool();
+        let mut connection = pool.get().unwrap();
+        let tenant = repository::find_tenant_by_slug(&mut connection, DEFAULT_TENANT).unwrap();
+        // Older than any other test's cats, so only this one is archived
+        let created_at = Utc.with_ymd_and_hms(2000, 1, 1, 0, 0, 0).unwrap();
+        let new_cat = NewCat {
+            image_path: format!("/image/{}", key),
+            created_at,
+            image_size: jpeg.len() as i64,
+            ..test_cat()
+        };
+        let cat = insert_cat_with(&mut connection, tenant.id, &new_cat);
+
+        let mut options = ArchiveOptions {
+            older_than: Duration::from_secs(7 * 24 * 60 * 60),
+            jpeg_quality: 10,
+            keep_originals: Vec::new(),
+            dry_run: true,
+        };
+        let now = created_at + TimeDelta::days(30);
+        let report = archive_images(&mut connection, &store, &options, now).unwrap();
+        assert_eq!(report.archived, 1);
+        assert!(store.get(&key).is_ok());
+
+        options.dry_run = false;
+        let report = archive_images(&mut connection, &store, &options, now).unwrap();
+        assert_eq!(report.archived, 1);
+        let archived = repository::find_cat(&mut connection, tenant.id, cat.id).unwrap();
+        let archived_key = archived.image_path.strip_prefix("/image/").unwrap();
+        assert_ne!(archived_key, key);
+        assert!(archived_key.ends_with(".jpg"));
+        let contents = store.get(archived_key).unwrap();
+        assert_eq!(report.reclaimed_bytes, (jpeg.len() - contents.len()) as u64);
+        assert!(store.get(&key).is_err());
+
+        // Each image is archived once
+        let report = archive_images(&mut connection, &store, &options, now).unwrap();
+        assert_eq!(report, ArchiveReport::default());
+    }
+}
@@ -0,0 +1,99 @@
+use actix_files::{Files, NamedFile};
+use actix_web::web;
+use std::path::PathBuf;
+
+/// Where the frontend under `/static` and the index page are served from.
+#[derive(Clone)]
+pub enum StaticAssets {
+    /// Read from a directory on every request, handy while editing the frontend.
+    Directory(PathBuf),
+    /// Compiled into the binary with the `embed-static` feature.
+    #[cfg(feature = "embed-static")]
+    Embedded,
+}
+
+impl StaticAssets {
+    /// Uses `dir` when given, otherwise the embedded assets if they were
+    /// compiled in, and `./static` as the last resort.
+    pub fn new(dir: Option<PathBuf>) -> StaticAssets {
+        match dir {
+            Some(dir) => StaticAssets::Directory(dir),
+            #[cfg(feature = "embed-static")]
+            None => StaticAssets::Embedded,
+            #[cfg(not(feature = "embed-static"))]
+            None => StaticAssets::Directory(PathBuf::from("./static")),
+        }
+    }
+
+    pub fn configure(&self, cfg: &mut web::ServiceConfig) {
+        match self {
+            StaticAssets::Directory(dir) => {
+                let index = dir.join("index.html");
+                cfg.service(
+                    Files::new("/static", dir)
+                        .show_files_listing()
+                        .default_handler(web::to(crate::fallback::not_found_endpoint)),
+                )
+                .route(
+                    "/",
+                    web::get().to(move || {
+                        let index = index.clone();
+                        async move { NamedFile::open_async(index).await }
+                    }),
+                );
+            }
+            #[cfg(feature = "embed-static")]
+            StaticAssets::Embedded => {
+                cfg.route("/static/{path:.*}", web::get().to(embedded::asset))
+                    .route("/", web::get().to(embedded::index));
+            }
+        }
+    }
+}
+
+#[cfg(feature = "embed-static")]
+mod embedded {
+    use super::*;
+    use actix_web::http::header::{CacheControl, CacheDirective, ETag, EntityTag, IF_NONE_MATCH};
+    use actix_web::{HttpRequest, HttpResponse};
+    use rust_embed::RustEmbed;
+
+    #[derive(RustEmbed)]
+    #[folder = "static/"]
+    struct Assets;
+
+    pub async fn index(req: HttpRequest) -> HttpResponse {
+        serve(&req, "index.html")
+    }
+
+    pub async fn asset(req: HttpRequest, path: web::Path<String>) -> HttpResponse {
+        serve(&req, &path)
+    }
+
+    fn serve(req: &HttpRequest, path: &str) -> HttpResponse {
+        let Some(file) = Assets::get(path) else {
+            return crate::fallback::not_found_page();
+        };
+        let etag = EntityTag::new_strong(hex::encode(file.metadata.sha256_hash()));
+        let not_modified = req
+            .headers()
+            .get(IF_NONE_MATCH)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(|value| value.split(',').any(|tag| tag.trim() == etag.to_string()));
+
+        let mut response = if not_modified {
+            HttpResponse::NotModified()
+        } else {
+            HttpResponse::Ok()
+        };
+        response
+            .insert_header(ETag(etag))
+            .insert_header(CacheControl(vec![CacheDirective::NoCache]));
+        if not_modified {
+            return response.finish();
+        }
+        response
+            .content_type(file.metadata.mimetype())
+            .body(file.data.into_owned())
+    }
+}
@@ -0,0 +1,126 @@
+use crate::db::DbConn;
+use crate::errors::UserError;
+use crate::history::Requester;
+use crate::models::Tenant;
+use crate::repository;
+use crate::tenants::DEFAULT_TENANT;
+use crate::DbPool;
+use argon2::{Argon2, PasswordHash, PasswordVerifier};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use diesel::{PgConnection, QueryResult};
+use log::warn;
+
+/// Splits an `Authorization: Basic` header into username and password.
+fn basic_credentials(authorization: &str) -> Option<(String, String)> {
+    let (scheme, encoded) = authorization.split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = String::from_utf8(STANDARD.decode(encoded.trim()).ok()?).ok()?;
+    let (username, password) = decoded.split_once(':')?;
+    Some((username.to_string(), password.to_string()))
+}
+
+/// Whether `password` is that of the tenant's admin `username`, as created
+/// by `catdex create-admin`.
+pub fn verify_password(
+    connection: &mut PgConnection,
+    tenant_id: i32,
+    username: &str,
+    password: &str,
+) -> QueryResult<bool> {
+    let Some(password_hash) =
+        repository::find_admin_password_hash(connection, tenant_id, username)?
+    else {
+        return Ok(false);
+    };
+    Ok(PasswordHash::new(&password_hash).is_ok_and(|hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &hash)
+            .is_ok()
+    }))
+}
+
+/// The username of the tenant's admin whose Basic credentials the request's
+/// `Authorization` header carries or, failing that, whose session it is
+/// made in.
+pub fn admin_username(
+    connection: &mut PgConnection,
+    tenant_id: i32,
+    requester: &Requester,
+) -> QueryResult<Option<String>> {
+    if let Some((username, password)) = requester.authorization().and_then(basic_credentials) {
+        let verified = verify_password(connection, tenant_id, &username, &password)?;
+        return Ok(verified.then_some(username));
+    }
+    let Some(admin) = requester
+        .session_admin()
+        .filter(|admin| admin.tenant_id == tenant_id)
+    else {
+        return Ok(None);
+    };
+    // Admins removed since logging in lose their sessions
+    let exists =
+        repository::find_admin_password_hash(connection, tenant_id, &admin.username)?.is_some();
+    Ok(exists.then(|| admin.username.clone()))
+}
+
+/// Whether the request is made by one of the tenant's admins.
+pub fn is_admin(
+    connection: &mut PgConnection,
+    tenant_id: i32,
+    requester: &Requester,
+) -> QueryResult<bool> {
+    admin_username(connection, tenant_id, requester).map(|username| username.is_some())
+}
+
+/// Fails with `AdminRequiredError` unless the request is made by one of the
+/// tenant's admins.
+pub async fn require_admin(
+    pool: &DbPool,
+    tenant_id: i32,
+    requester: Requester,
+) -> Result<(), UserError> {
+    let connection = DbConn::get(pool)?;
+    let admin = connection
+        .run("check admin credentials", move |connection| {
+            is_admin(connection, tenant_id, &requester)
+        })
+        .await?;
+    match admin {
+        true => Ok(()),
+        false => Err(UserError::AdminRequiredError),
+    }
+}
+
+/// Fails with `AdminRequiredError` unless the request is made by an admin
+/// of the default tenant, the instance's operators, for actions affecting
+/// every tenant.
+pub async fn require_operator(
+    pool: &DbPool,
+    tenant: &Tenant,
+    requester: Requester,
+) -> Result<(), UserError> {
+    if tenant.slug != DEFAULT_TENANT {
+        warn!("Refused an operator request to tenant {}", tenant.slug);
+        return Err(UserError::AdminRequiredError);
+    }
+    require_admin(pool, tenant.id, requester).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_basic_credentials() {
+        // "admin:open sesame"
+        assert_eq!(
+            basic_credentials("Basic YWRtaW46b3BlbiBzZXNhbWU="),
+            Some(("admin".to_string(), "open sesame".to_string()))
+        );
+        assert_eq!(basic_credentials("Bearer YWRtaW46b3BlbiBzZXNhbWU="), None);
+        assert_eq!(basic_credentials("Basic not base64!"), None);
+    }
+}
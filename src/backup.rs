@@ -0,0 +1,356 @@
+//! Backups of the whole instance, the data of every tenant along with the
+//! stored images, as a gzipped tarball holding:
+//!
+//! - `manifest.json`, saying when and at which schema version it was taken
+//! - `db/<table>.jsonl`, each table's rows as JSON, one per line
+//! - `image/`, the image directory
+//!
+//! Restoring one replaces all data, and needs the database migrated to the
+//! schema version the backup was taken at, so every table has the same
+//! columns.
+use crate::auth;
+use crate::clock::Clock;
+use crate::config::Config;
+use crate::db::{self, DbConn};
+use crate::errors::UserError;
+use crate::history::Requester;
+use crate::models::Tenant;
+use crate::repository::{self, DATA_TABLES};
+use crate::DbPool;
+use actix_files::NamedFile;
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use diesel::{Connection, PgConnection};
+use diesel_migrations::MigrationHarness;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Seek, Write};
+use std::path::{Component, Path, PathBuf};
+
+pub type BackupResult<T> = Result<T, Box<dyn Error + Send + Sync>>;
+
+/// Version of the archive layout, bumped when it changes.
+const FORMAT: u32 = 1;
+const MANIFEST: &str = "manifest.json";
+const DB_DIR: &str = "db";
+const IMAGES: &str = "image";
+pub const FILE_NAME: &str = "catdex-backup.tar.gz";
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Manifest {
+    pub format: u32,
+    /// The latest migration applied to the database.
+    pub schema_version: String,
+    pub created_at: DateTime<Utc>,
+    pub tables: Vec<String>,
+}
+
+fn schema_version(connection: &mut PgConnection) -> BackupResult<String> {
+    connection
+        .applied_migrations()?
+        .into_iter()
+        .max()
+        .map(|version| version.to_string())
+        .ok_or_else(|| "The database has no migrations applied".into())
+}
+
+fn append_bytes<W: Write>(
+    archive: &mut tar::Builder<W>,
+    path: &str,
+    contents: &[u8],
+) -> BackupResult<()> {
+    let mut header = tar::Header::new_gnu();
+    header.set_size(contents.len() as u64);
+    header.set_mode(0o644);
+    header.set_mtime(Utc::now().timestamp().try_into().unwrap_or_default());
+    archive.append_data(&mut header, path, contents)?;
+    Ok(())
+}
+
+/// Writes the backup to `out` from one snapshot of the database, so the
+/// tables are consistent with each other.
+pub fn write_backup<W: Write>(
+    connection: &mut PgConnection,
+    image_dir: &Path,
+    out: W,
+    now: DateTime<Utc>,
+) -> BackupResult<W> {
+    // Read first, as the migration harness may create its table
+    let schema_version = schema_version(connection)?;
+    connection
+        .build_transaction()
+        .repeatable_read()
+        .read_only()
+        .run(|connection| write_archive(connection, image_dir, out, schema_version, now))
+}
+
+/// Writes the backup with whatever transaction `connection` is in.
+fn write_archive<W: Write>(
+    connection: &mut PgConnection,
+    image_dir: &Path,
+    out: W,
+    schema_version: String,
+    now: DateTime<Utc>,
+) -> BackupResult<W> {
+    let mut archive = tar::Builder::new(GzEncoder::new(out, Compression::default()));
+    let manifest = Manifest {
+        format: FORMAT,
+        schema_version,
+        created_at: now,
+        tables: DATA_TABLES.iter().map(|table| table.to_string()).collect(),
+    };
+    append_bytes(
+        &mut archive,
+        MANIFEST,
+        &serde_json::to_vec_pretty(&manifest)?,
+    )?;
+
+    for table in DATA_TABLES {
+        // Buffered on disk, as the archive needs the size up front
+        let mut dump = BufWriter::new(tempfile::tempfile()?);
+        repository::dump_table(connection, table, &mut dump)?;
+        let mut dump = dump.into_inner().map_err(|e| e.into_error())?;
+        dump.rewind()?;
+        archive.append_file(format!("{}/{}.jsonl", DB_DIR, table), &mut dump)?;
+    }
+    if image_dir.is_dir() {
+        archive.append_dir_all(IMAGES, image_dir)?;
+    }
+    Ok(archive.into_inner()?.finish()?)
+}
+
+fn open_archive(path: &Path) -> BackupResult<tar::Archive<GzDecoder<File>>> {
+    Ok(tar::Archive::new(GzDecoder::new(File::open(path)?)))
+}
+
+/// Checks that the backup with `manifest` can be restored into the
+/// database at `schema_version`.
+fn check_compatible(manifest: &Manifest, schema_version: &str) -> BackupResult<()> {
+    if manifest.format != FORMAT {
+        return Err(format!("Unsupported backup format {}", manifest.format).into());
+    }
+    if manifest.schema_version != schema_version {
+        return Err(format!(
+            "The backup was taken at schema version {}, but the database is at {}; \
+             migrate a database to {} to restore it",
+            manifest.schema_version, schema_version, manifest.schema_version
+        )
+        .into());
+    }
+    if manifest.tables != DATA_TABLES {
+        return Err("The backup does not hold the expected tables".into());
+    }
+    Ok(())
+}
+
+/// The path under the image directory an archive entry is restored to, if
+/// it is a stored image. Paths leaving the directory are refused.
+fn image_path(entry: &Path) -> Option<PathBuf> {
+    let relative = entry.strip_prefix(IMAGES).ok()?;
+    let normal = relative
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)));
+    (normal && relative.components().next().is_some()).then(|| relative.to_path_buf())
+}
+
+/// Replaces all data with the backup at `path`, then restores its images
+/// into `image_dir`, overwriting those with the same names. Returns the
+/// backup's manifest and the number of images restored.
+pub fn restore(
+    connection: &mut PgConnection,
+    image_dir: &Path,
+    path: &Path,
+) -> BackupResult<(Manifest, usize)> {
+    let mut archive = open_archive(path)?;
+    let mut entries = archive.entries()?;
+    let mut first = entries.next().ok_or("The backup is empty")??;
+    if first.path()?.as_ref() != Path::new(MANIFEST) {
+        return Err(format!("The backup does not start with {}", MANIFEST).into());
+    }
+    let mut manifest = String::new();
+    first.read_to_string(&mut manifest)?;
+    let manifest: Manifest = serde_json::from_str(&manifest)?;
+    check_compatible(&manifest, &schema_version(connection)?)?;
+
+    connection.transaction(|connection| {
+        repository::truncate_data_tables(connection)?;
+        for entry in entries {
+            let entry = entry?;
+            let entry_path = entry.path()?.into_owned();
+            let Ok(file) = entry_path.strip_prefix(DB_DIR) else {
+                continue;
+            };
+            let table = file
+                .to_str()
+                .and_then(|file| file.strip_suffix(".jsonl"))
+                .filter(|table| DATA_TABLES.contains(table))
+                .ok_or_else(|| format!("Unexpected file {} in backup", entry_path.display()))?;
+            let rows = repository::load_table(connection, table, &mut BufReader::new(entry))?;
+            info!("Restored {} row(s) of {}", rows, table);
+        }
+        repository::reset_id_sequences(connection)?;
+        BackupResult::Ok(())
+    })?;
+    repository::refresh_cat_stats(connection)?;
+
+    // The images come after the tables, in a second pass so a failed
+    // restore of the tables leaves the image directory alone
+    let mut images = 0;
+    for entry in open_archive(path)?.entries()? {
+        let mut entry = entry?;
+        if entry.header().entry_type() != tar::EntryType::Regular {
+            continue;
+        }
+        let Some(relative) = image_path(&entry.path()?) else {
+            continue;
+        };
+        let target = image_dir.join(relative);
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        entry.unpack(&target)?;
+        images += 1;
+    }
+    Ok((manifest, images))
+}
+
+/// Downloads a backup of the whole instance. Only admins of the default
+/// tenant, the instance's operators, may take one, as it holds the data
+/// of every tenant.
+pub async fn backup_endpoint(
+    req: HttpRequest,
+    pool: web::Data<DbPool>,
+    config: web::Data<Config>,
+    clock: web::Data<dyn Clock>,
+    tenant: Tenant,
+    requester: Requester,
+) -> Result<HttpResponse, UserError> {
+    auth::require_operator(&pool, &tenant, requester).await?;
+    let mut connection = DbConn::get(&pool)?;
+    let now = clock.now();
+    let image_dir = config.image_dir.clone();
+
+    let file = db::block(move || -> BackupResult<File> {
+        let mut file = write_backup(&mut connection, &image_dir, tempfile::tempfile()?, now)?;
+        file.rewind()?;
+        Ok(file)
+    })
+    .await?
+    .map_err(|e| {
+        error!("Failed to take a backup: {}", e);
+        UserError::UnexpectedError
+    })?;
+    info!("Backup taken");
+
+    let file = NamedFile::from_file(file, FILE_NAME)
+        .map_err(|e| {
+            error!("Failed to serve the backup: {}", e);
+            UserError::UnexpectedError
+        })?
+        .set_content_type("application/gzip".parse().expect("Valid MIME type"))
+        .set_content_disposition(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(FILE_NAME.to_string())],
+        });
+    Ok(file.into_response(&req))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::NewCat;
+    use crate::tenants::DEFAULT_TENANT;
+    use crate::test_support::{insert_cat_with, test_cat, test_pool};
+
+    #[test]
+    fn test_image_path() {
+        assert_eq!(
+            image_path(Path::new("image/default/cat.jpg")),
+            Some(PathBuf::from("default/cat.jpg"))
+        );
+        assert_eq!(image_path(Path::new("image/../etc/passwd")), None);
+        assert_eq!(image_path(Path::new("image")), None);
+        assert_eq!(image_path(Path::new("db/cats.jsonl")), None);
+    }
+
+    #[test]
+    fn test_write_archive() {
+        let images = tempfile::tempdir().unwrap();
+        fs::create_dir(images.path().join("default")).unwrap();
+        fs::write(images.path().join("default/cat.jpg"), b"not really a jpeg").unwrap();
+        let pool = test_pool();
+        let mut connection = pool.get().unwrap();
+        let now = Utc::now();
+
+        let version = schema_version(&mut connection).unwrap();
+        let archive = write_archive(
+            &mut connection,
+            images.path(),
+            Vec::new(),
+            version.clone(),
+            now,
+        )
+        .unwrap();
+        let mut archive = tar::Archive::new(GzDecoder::new(archive.as_slice()));
+        let mut paths = Vec::new();
+        let mut manifest = None;
+        for entry in archive.entries().unwrap() {
+            let mut entry = entry.unwrap();
+            let path = entry.path().unwrap().into_owned();
+            if path == Path::new(MANIFEST) {
+                let mut contents = String::new();
+                entry.read_to_string(&mut contents).unwrap();
+                manifest = Some(serde_json::from_str::<Manifest>(&contents).unwrap());
+            }
+            paths.push(path);
+        }
+
+        let manifest = manifest.expect("Backup has a manifest");
+        assert_eq!(manifest.created_at, now);
+        check_compatible(&manifest, &version).unwrap();
+        assert!(check_compatible(&manifest, "20000101000000").is_err());
+        assert_eq!(paths[0], Path::new(MANIFEST));
+        assert!(paths.contains(&PathBuf::from("db/cats.jsonl")));
+        assert!(paths.contains(&PathBuf::from("image/default/cat.jpg")));
+    }
+
+    #[test]
+    fn test_dump_and_load_table() {
+        let pool = test_pool();
+        let mut connection = pool.get().unwrap();
+        let tenant = repository::find_tenant_by_slug(&mut connection, DEFAULT_TENANT).unwrap();
+        let new_cat = NewCat {
+            image_size: 42,
+            latitude: Some(50.45),
+            longitude: Some(30.52),
+            image_phash: Some(-7),
+            ..test_cat()
+        };
+        let cat = insert_cat_with(&mut connection, tenant.id, &new_cat);
+
+        let mut dump = Vec::new();
+        repository::dump_table(&mut connection, "cats", &mut dump).unwrap();
+        let line = String::from_utf8(dump)
+            .unwrap()
+            .lines()
+            .find(|line| line.contains(&new_cat.name))
+            .expect("Cat is dumped")
+            .to_string();
+        repository::delete_cat(&mut connection, cat.id).unwrap();
+        let loaded = repository::load_table(&mut connection, "cats", &mut line.as_bytes()).unwrap();
+
+        assert_eq!(loaded, 1);
+        let restored = repository::find_cat(&mut connection, tenant.id, cat.id).unwrap();
+        assert_eq!(
+            serde_json::to_value(restored).unwrap(),
+            serde_json::to_value(cat).unwrap()
+        );
+        assert!(repository::dump_table(&mut connection, "pg_authid", &mut Vec::new()).is_err());
+    }
+}
@@ -0,0 +1,159 @@
+//! Short-lived state that instances behind a load balancer should share,
+//! such as the counters of the public submission limit. Kept in the
+//! process by default, or in Redis with the `redis` feature when
+//! `CACHE_URL` names a `redis://` or `rediss://` server.
+use crate::clock::Clock;
+use crate::config::Config;
+use chrono::{DateTime, TimeDelta, Utc};
+use futures_util::future::LocalBoxFuture;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+pub trait CacheBackend: Send + Sync {
+    /// Adds one to the counter `key`, which starts at zero and expires
+    /// `ttl` after its first increment. Returns the new count and how long
+    /// the counter has left. Errors when the cache cannot be reached.
+    fn increment<'a>(
+        &'a self,
+        key: &'a str,
+        ttl: Duration,
+    ) -> LocalBoxFuture<'a, Result<(u64, Duration), String>>;
+}
+
+/// Keeps the cache in this process, so every instance counts on its own.
+pub struct MemoryCache {
+    clock: Arc<dyn Clock>,
+    /// When each counter expires, and its count.
+    counters: Mutex<HashMap<String, (DateTime<Utc>, u64)>>,
+}
+
+impl MemoryCache {
+    pub fn new(clock: Arc<dyn Clock>) -> MemoryCache {
+        MemoryCache {
+            clock,
+            counters: Mutex::default(),
+        }
+    }
+}
+
+impl CacheBackend for MemoryCache {
+    fn increment<'a>(
+        &'a self,
+        key: &'a str,
+        ttl: Duration,
+    ) -> LocalBoxFuture<'a, Result<(u64, Duration), String>> {
+        let now = self.clock.now();
+        let mut counters = self
+            .counters
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        counters.retain(|_, (expires, _)| *expires > now);
+        let ttl = TimeDelta::from_std(ttl).unwrap_or(TimeDelta::MAX);
+        let (expires, count) = counters.entry(key.to_string()).or_insert((
+            now.checked_add_signed(ttl)
+                .unwrap_or(DateTime::<Utc>::MAX_UTC),
+            0,
+        ));
+        *count += 1;
+        let left = (*expires - now).to_std().unwrap_or_default();
+        let counted = (*count, left);
+        Box::pin(async move { Ok(counted) })
+    }
+}
+
+#[cfg(feature = "redis")]
+mod redis {
+    use super::CacheBackend;
+    use deadpool_redis::{redis, Pool, Runtime};
+    use futures_util::future::LocalBoxFuture;
+    use std::time::Duration;
+
+    /// Prefixes the keys, so the server may be shared with other apps.
+    const KEY_PREFIX: &str = "catdex:";
+
+    pub struct RedisCache {
+        pool: Pool,
+    }
+
+    impl RedisCache {
+        pub fn connect(url: &str) -> Result<RedisCache, String> {
+            let pool = deadpool_redis::Config::from_url(url)
+                .create_pool(Some(Runtime::Tokio1))
+                .map_err(|e| e.to_string())?;
+            Ok(RedisCache { pool })
+        }
+    }
+
+    impl CacheBackend for RedisCache {
+        fn increment<'a>(
+            &'a self,
+            key: &'a str,
+            ttl: Duration,
+        ) -> LocalBoxFuture<'a, Result<(u64, Duration), String>> {
+            Box::pin(async move {
+                let key = format!("{}{}", KEY_PREFIX, key);
+                let ttl_ms = u64::try_from(ttl.as_millis()).unwrap_or(u64::MAX).max(1);
+                let mut connection = self.pool.get().await.map_err(|e| e.to_string())?;
+                // Created with its expiry first, as INCR keeps the expiry of
+                // a key but gives none to a new one
+                let (count, left_ms): (u64, i64) = redis::pipe()
+                    .atomic()
+                    .cmd("SET")
+                    .arg(&key)
+                    .arg(0)
+                    .arg("NX")
+                    .arg("PX")
+                    .arg(ttl_ms)
+                    .ignore()
+                    .cmd("INCR")
+                    .arg(&key)
+                    .cmd("PTTL")
+                    .arg(&key)
+                    .query_async(&mut connection)
+                    .await
+                    .map_err(|e| e.to_string())?;
+                let left = Duration::from_millis(left_ms.max(0) as u64);
+                Ok((count, left))
+            })
+        }
+    }
+}
+
+/// The cache `CACHE_URL` names, or one in memory on `clock`.
+pub fn from_config(config: &Config, clock: Arc<dyn Clock>) -> Arc<dyn CacheBackend> {
+    #[cfg(feature = "redis")]
+    if let Some(url) = &config.cache_url {
+        let cache = redis::RedisCache::connect(url)
+            .unwrap_or_else(|e| panic!("Failed to set up the cache at CACHE_URL: {}", e));
+        log::info!("Caching in Redis");
+        return Arc::new(cache);
+    }
+    #[cfg(not(feature = "redis"))]
+    if config.cache_url.is_some() {
+        panic!("CACHE_URL is set, but this build lacks the redis feature");
+    }
+    Arc::new(MemoryCache::new(clock))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::ManualClock;
+
+    #[actix_web::test]
+    async fn test_memory_cache_increment() {
+        let clock = Arc::new(ManualClock::new());
+        let cache = MemoryCache::new(clock.clone());
+        let minute = Duration::from_secs(60);
+        assert_eq!(cache.increment("a", minute).await, Ok((1, minute)));
+        clock.advance(TimeDelta::seconds(20));
+        assert_eq!(
+            cache.increment("a", minute).await,
+            Ok((2, Duration::from_secs(40)))
+        );
+        assert_eq!(cache.increment("b", minute).await, Ok((1, minute)));
+        clock.advance(TimeDelta::seconds(40));
+        assert_eq!(cache.increment("a", minute).await, Ok((1, minute)));
+    }
+}
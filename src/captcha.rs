@@ -0,0 +1,109 @@
+//! CAPTCHA checks of the public submissions of `POST /api/submit_cat`,
+//! see `submissions`.
+use crate::config::Config;
+use futures_util::future::LocalBoxFuture;
+use log::info;
+use serde::Deserialize;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Whose CAPTCHA the public submission form shows.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CaptchaProvider {
+    HCaptcha,
+    ReCaptcha,
+}
+
+impl CaptchaProvider {
+    pub fn parse(value: &str) -> Option<CaptchaProvider> {
+        match value {
+            "hcaptcha" => Some(CaptchaProvider::HCaptcha),
+            "recaptcha" => Some(CaptchaProvider::ReCaptcha),
+            _ => None,
+        }
+    }
+
+    fn verify_url(self) -> &'static str {
+        match self {
+            CaptchaProvider::HCaptcha => "https://api.hcaptcha.com/siteverify",
+            CaptchaProvider::ReCaptcha => "https://www.google.com/recaptcha/api/siteverify",
+        }
+    }
+}
+
+/// Checks the CAPTCHA tokens sent along with public submissions.
+pub trait CaptchaVerifier: Send + Sync {
+    /// Whether `token` is a solved CAPTCHA, solved from `client_ip` when
+    /// that is known. Errors when the provider cannot be asked.
+    fn verify<'a>(
+        &'a self,
+        token: &'a str,
+        client_ip: Option<IpAddr>,
+    ) -> LocalBoxFuture<'a, Result<bool, String>>;
+}
+
+/// Asks a provider's `siteverify` endpoint, which hCaptcha and reCAPTCHA
+/// share the protocol of.
+pub struct SiteVerifier {
+    url: String,
+    secret: String,
+}
+
+impl SiteVerifier {
+    pub fn new(url: impl Into<String>, secret: impl Into<String>) -> SiteVerifier {
+        SiteVerifier {
+            url: url.into(),
+            secret: secret.into(),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct SiteVerifyReply {
+    success: bool,
+}
+
+impl CaptchaVerifier for SiteVerifier {
+    fn verify<'a>(
+        &'a self,
+        token: &'a str,
+        client_ip: Option<IpAddr>,
+    ) -> LocalBoxFuture<'a, Result<bool, String>> {
+        Box::pin(async move {
+            let client = awc::Client::builder().timeout(VERIFY_TIMEOUT).finish();
+            let remote_ip = client_ip.map(|ip| ip.to_string()).unwrap_or_default();
+            let mut form = vec![("secret", self.secret.as_str()), ("response", token)];
+            if !remote_ip.is_empty() {
+                form.push(("remoteip", &remote_ip));
+            }
+            let mut response = client
+                .post(&self.url)
+                .send_form(&form)
+                .await
+                .map_err(|e| e.to_string())?;
+            if !response.status().is_success() {
+                return Err(format!("siteverify answered {}", response.status()));
+            }
+            let reply: SiteVerifyReply = response.json().await.map_err(|e| e.to_string())?;
+            Ok(reply.success)
+        })
+    }
+}
+
+pub fn from_config(config: &Config) -> Option<Arc<dyn CaptchaVerifier>> {
+    let provider = config.captcha_provider?;
+    let secret = config
+        .captcha_secret
+        .clone()
+        .unwrap_or_else(|| panic!("CAPTCHA_PROVIDER needs CAPTCHA_SECRET"));
+    let url = config
+        .captcha_verify_url
+        .clone()
+        .unwrap_or_else(|| provider.verify_url().to_string());
+    info!("Verifying public submissions' CAPTCHAs at {}", url);
+    Some(Arc::new(SiteVerifier::new(url, secret)))
+}
@@ -0,0 +1,254 @@
+//! Optional check that a new cat's image shows a cat at all, by an ImageNet
+//! classifier in ONNX format, such as MobileNet or SqueezeNet, run with
+//! tract. It is set up through `CAT_DETECTION_MODEL` in builds with the
+//! `cat-detection` feature.
+//!
+//! Images the classifier is not confident enough about are rejected, or
+//! accepted and flagged for review, as `CAT_DETECTION_ACTION` says.
+use crate::config::Config;
+use crate::metrics::CAT_DETECTIONS;
+#[cfg(feature = "image-processing")]
+use image::DynamicImage;
+use std::sync::Arc;
+
+/// ImageNet classes of domestic cats: tabby, tiger cat, Persian, Siamese
+/// and Egyptian cat.
+#[cfg_attr(not(feature = "cat-detection"), allow(dead_code))]
+const CAT_CLASSES: std::ops::RangeInclusive<usize> = 281..=285;
+
+pub const DEFAULT_MIN_CONFIDENCE: f32 = 0.5;
+
+/// What becomes of an image scoring below the minimum confidence.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DetectionAction {
+    Reject,
+    Flag,
+}
+
+impl DetectionAction {
+    pub fn parse(value: &str) -> Option<DetectionAction> {
+        match value {
+            "reject" => Some(DetectionAction::Reject),
+            "flag" => Some(DetectionAction::Flag),
+            _ => None,
+        }
+    }
+}
+
+/// Scores how likely an image is to show a cat.
+pub trait CatDetector: Send + Sync {
+    /// The confidence, from 0 to 1, that `image` shows a cat.
+    #[cfg(feature = "image-processing")]
+    fn score(&self, image: &DynamicImage) -> Result<f32, String>;
+}
+
+#[derive(Debug, PartialEq)]
+#[cfg_attr(not(feature = "image-processing"), allow(dead_code))]
+pub enum Detection {
+    /// Shows a cat, or could not be decoded to tell.
+    Accepted,
+    /// Accepted to be reviewed, with its score.
+    Flagged(f32),
+    Rejected(f32),
+}
+
+/// Checks the image with `contents` against the configured minimum
+/// confidence. Images in formats that cannot be decoded are accepted, as
+/// there is no telling.
+#[cfg(feature = "image-processing")]
+pub fn detect(
+    detector: &dyn CatDetector,
+    config: &Config,
+    contents: &[u8],
+) -> Result<Detection, String> {
+    let Ok(image) = image::load_from_memory(contents) else {
+        log::warn!("Cannot decode image to detect a cat, accepting it");
+        CAT_DETECTIONS.with_label_values(&["undecodable"]).inc();
+        return Ok(Detection::Accepted);
+    };
+    let score = detector.score(&image)?;
+    let (detection, outcome) = if score >= config.cat_detection_min_confidence {
+        (Detection::Accepted, "cat")
+    } else {
+        match config.cat_detection_action {
+            DetectionAction::Reject => (Detection::Rejected(score), "rejected"),
+            DetectionAction::Flag => (Detection::Flagged(score), "flagged"),
+        }
+    };
+    CAT_DETECTIONS.with_label_values(&[outcome]).inc();
+    Ok(detection)
+}
+
+/// Builds without the `image-processing` feature decode no images, so
+/// accept them all.
+#[cfg(not(feature = "image-processing"))]
+pub fn detect(
+    _detector: &dyn CatDetector,
+    _config: &Config,
+    _contents: &[u8],
+) -> Result<Detection, String> {
+    CAT_DETECTIONS.with_label_values(&["undecodable"]).inc();
+    Ok(Detection::Accepted)
+}
+
+/// Turns the classifier's output into probabilities, unless it gives them
+/// already.
+#[cfg_attr(not(feature = "cat-detection"), allow(dead_code))]
+fn probabilities(output: &[f32]) -> Vec<f32> {
+    let sum: f32 = output.iter().sum();
+    if output.iter().all(|value| (0.0..=1.0).contains(value)) && (sum - 1.0).abs() < 0.01 {
+        return output.to_vec();
+    }
+    let max = output.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let exp: Vec<f32> = output.iter().map(|value| (value - max).exp()).collect();
+    let sum: f32 = exp.iter().sum();
+    exp.into_iter().map(|value| value / sum).collect()
+}
+
+#[cfg(feature = "cat-detection")]
+mod onnx {
+    use super::{probabilities, CatDetector, CAT_CLASSES};
+    use image::imageops::FilterType;
+    use image::DynamicImage;
+    use std::path::Path;
+    use std::sync::Arc;
+    use tract_onnx::prelude::*;
+
+    /// Input side of ImageNet classifiers.
+    const INPUT_SIZE: usize = 224;
+    const MEAN: [f32; 3] = [0.485, 0.456, 0.406];
+    const STD: [f32; 3] = [0.229, 0.224, 0.225];
+
+    /// An ImageNet classifier taking a normalized 1x3x224x224 RGB tensor
+    /// and giving the logits or probabilities of the 1000 classes.
+    pub struct OnnxCatDetector {
+        model: Arc<TypedRunnableModel>,
+    }
+
+    impl OnnxCatDetector {
+        pub fn load(path: &Path) -> TractResult<OnnxCatDetector> {
+            let model = tract_onnx::onnx()
+                .model_for_path(path)?
+                .with_input_fact(0, f32::fact([1, 3, INPUT_SIZE, INPUT_SIZE]).into())?
+                .into_optimized()?
+                .into_runnable()?;
+            Ok(OnnxCatDetector { model })
+        }
+    }
+
+    impl CatDetector for OnnxCatDetector {
+        fn score(&self, image: &DynamicImage) -> Result<f32, String> {
+            let size = INPUT_SIZE as u32;
+            let pixels = image
+                .resize_exact(size, size, FilterType::Triangle)
+                .to_rgb8();
+            let input: Tensor = tract_ndarray::Array4::from_shape_fn(
+                (1, 3, INPUT_SIZE, INPUT_SIZE),
+                |(_, c, y, x)| {
+                    let value = f32::from(pixels.get_pixel(x as u32, y as u32)[c]) / 255.0;
+                    (value - MEAN[c]) / STD[c]
+                },
+            )
+            .into();
+            let output = self
+                .model
+                .run(tvec!(input.into()))
+                .map_err(|e| e.to_string())?;
+            let output: Vec<f32> = output[0]
+                .to_plain_array_view::<f32>()
+                .map_err(|e| e.to_string())?
+                .iter()
+                .copied()
+                .collect();
+            let probabilities = probabilities(&output);
+            probabilities
+                .get(CAT_CLASSES)
+                .map(|cats| cats.iter().sum())
+                .ok_or_else(|| format!("Model gives {} classes, not 1000", output.len()))
+        }
+    }
+}
+
+/// The detector configured through `CAT_DETECTION_MODEL`, if any.
+///
+/// # Panics
+///
+/// When the model cannot be loaded, or the build lacks the `cat-detection`
+/// feature to run it.
+pub fn from_config(config: &Config) -> Option<Arc<dyn CatDetector>> {
+    let path = config.cat_detection_model.as_ref()?;
+    #[cfg(feature = "cat-detection")]
+    {
+        let detector = onnx::OnnxCatDetector::load(path).unwrap_or_else(|e| {
+            panic!(
+                "Failed to load the cat detection model {}: {}",
+                path.display(),
+                e
+            )
+        });
+        log::info!("Detecting cats with {}", path.display());
+        Some(Arc::new(detector))
+    }
+    #[cfg(not(feature = "cat-detection"))]
+    panic!(
+        "CAT_DETECTION_MODEL is set to {}, but this build lacks the cat-detection feature",
+        path.display()
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[cfg(feature = "image-processing")]
+    use image::{ImageFormat, RgbImage};
+    #[cfg(feature = "image-processing")]
+    use std::io::Cursor;
+
+    #[cfg(feature = "image-processing")]
+    struct FixedScore(f32);
+
+    #[cfg(feature = "image-processing")]
+    impl CatDetector for FixedScore {
+        fn score(&self, _: &DynamicImage) -> Result<f32, String> {
+            Ok(self.0)
+        }
+    }
+
+    #[test]
+    #[cfg(feature = "image-processing")]
+    fn test_detect() {
+        let mut png = Vec::new();
+        RgbImage::new(8, 8)
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        let mut config = Config::from_env();
+        config.cat_detection_min_confidence = 0.5;
+        config.cat_detection_action = DetectionAction::Reject;
+
+        assert_eq!(
+            detect(&FixedScore(0.9), &config, &png),
+            Ok(Detection::Accepted)
+        );
+        assert_eq!(
+            detect(&FixedScore(0.1), &config, &png),
+            Ok(Detection::Rejected(0.1))
+        );
+        config.cat_detection_action = DetectionAction::Flag;
+        assert_eq!(
+            detect(&FixedScore(0.1), &config, &png),
+            Ok(Detection::Flagged(0.1))
+        );
+        assert_eq!(
+            detect(&FixedScore(0.1), &config, b"<svg/>"),
+            Ok(Detection::Accepted)
+        );
+    }
+
+    #[test]
+    fn test_probabilities() {
+        assert_eq!(probabilities(&[0.25, 0.75]), vec![0.25, 0.75]);
+        let softmax = probabilities(&[1.0, 1.0, 1.0, 1.0]);
+        assert!(softmax.iter().all(|p| (p - 0.25).abs() < 1e-6));
+    }
+}
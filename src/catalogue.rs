@@ -0,0 +1,320 @@
+//! The cats of a tenant as a printable PDF catalogue, for adoption
+//! booklets: `GET /api/cats/export.pdf`, which takes the `?status=` of
+//! `GET /api/cats`. Each A4 page shows two cats, each with their photo,
+//! name, adoption status, when they were added and where they were found.
+//!
+//! Names are set in Helvetica, which lacks letters outside Latin-1, unless
+//! `PDF_FONT` names a TrueType font that has them.
+use crate::cats::{cat_batch, CatsQuery, STREAM_BATCH_SIZE};
+use crate::clock::Clock;
+use crate::config::Config;
+use crate::db;
+use crate::errors::UserError;
+use crate::file_store::FileStore;
+use crate::models::{Cat, Tenant};
+use crate::DbPool;
+use actix_web::http::header::{ContentDisposition, DispositionParam, DispositionType};
+use actix_web::{web, HttpResponse};
+use log::{error, info, warn};
+
+/// A cat and its photo, as printed.
+#[cfg_attr(not(feature = "pdf"), allow(dead_code))]
+struct Entry {
+    cat: Cat,
+    photo: Option<Photo>,
+}
+
+/// A photo scaled down for print and encoded as JPEG, which PDF embeds as
+/// is.
+#[cfg_attr(not(feature = "pdf"), allow(dead_code))]
+struct Photo {
+    jpeg: Vec<u8>,
+    width: u32,
+    height: u32,
+}
+
+#[cfg(feature = "pdf")]
+mod render {
+    use super::{Entry, Photo};
+    use crate::file_store::FileStore;
+    use crate::models::CatStatus;
+    use chrono::{DateTime, Utc};
+    use printpdf::{
+        BuiltinFont, DictItem, ExternalStream, ExternalXObject, Mm, Op, ParsedFont, PdfDocument,
+        PdfFontHandle, PdfPage, PdfSaveOptions, Point, Pt, Px, TextItem, XObjectTransform,
+    };
+    use std::collections::BTreeMap;
+
+    const PAGE_WIDTH_MM: f32 = 210.0;
+    const PAGE_HEIGHT_MM: f32 = 297.0;
+    const MARGIN_MM: f32 = 15.0;
+    const CATS_PER_PAGE: usize = 2;
+    const PHOTO_HEIGHT_MM: f32 = 95.0;
+    /// Longest side of the embedded photos, about 140 dpi across the page.
+    const PHOTO_MAX_PX: u32 = 1000;
+    const PHOTO_QUALITY: u8 = 80;
+
+    /// The photo of the stored image `key`, scaled down to fit
+    /// `PHOTO_MAX_PX`.
+    pub fn photo(store: &dyn FileStore, key: &str) -> Result<Photo, String> {
+        use image::codecs::jpeg::JpegEncoder;
+        use image::GenericImageView;
+
+        let contents = store.get(key).map_err(|e| e.to_string())?;
+        let mut image = image::load_from_memory(&contents).map_err(|e| e.to_string())?;
+        let (width, height) = image.dimensions();
+        if width.max(height) > PHOTO_MAX_PX {
+            image = image.thumbnail(PHOTO_MAX_PX, PHOTO_MAX_PX);
+        }
+        let image = image.to_rgb8();
+        let mut jpeg = Vec::new();
+        image
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut jpeg, PHOTO_QUALITY))
+            .map_err(|e| e.to_string())?;
+        Ok(Photo {
+            jpeg,
+            width: image.width(),
+            height: image.height(),
+        })
+    }
+
+    fn status_label(status: CatStatus) -> &'static str {
+        match status {
+            CatStatus::Available => "Available for adoption",
+            CatStatus::Pending => "Adoption pending",
+            CatStatus::Adopted => "Adopted",
+        }
+    }
+
+    fn text(ops: &mut Vec<Op>, font: &PdfFontHandle, size: f32, x: f32, y: f32, line: String) {
+        ops.extend([
+            Op::StartTextSection,
+            Op::SetTextCursor {
+                pos: Point::new(Mm(x), Mm(y)),
+            },
+            Op::SetFont {
+                font: font.clone(),
+                size: Pt(size),
+            },
+            Op::ShowText {
+                items: vec![TextItem::Text(line)],
+            },
+            Op::EndTextSection,
+        ]);
+    }
+
+    /// The ops placing `photo` centred in the box of `width` by `height`
+    /// mm whose bottom left corner is at `x`, `y`.
+    fn place_photo(
+        doc: &mut PdfDocument,
+        photo: Photo,
+        (x, y): (f32, f32),
+        (width, height): (f32, f32),
+    ) -> Op {
+        let dict = BTreeMap::from([
+            ("Type".to_string(), DictItem::Name(b"XObject".to_vec())),
+            ("Subtype".to_string(), DictItem::Name(b"Image".to_vec())),
+            ("Width".to_string(), DictItem::Int(photo.width.into())),
+            ("Height".to_string(), DictItem::Int(photo.height.into())),
+            (
+                "ColorSpace".to_string(),
+                DictItem::Name(b"DeviceRGB".to_vec()),
+            ),
+            ("BitsPerComponent".to_string(), DictItem::Int(8)),
+            ("Filter".to_string(), DictItem::Name(b"DCTDecode".to_vec())),
+        ]);
+        let id = doc.add_xobject(&ExternalXObject {
+            stream: ExternalStream {
+                dict,
+                content: photo.jpeg,
+                compress: false,
+            },
+            width: Some(Px(photo.width as usize)),
+            height: Some(Px(photo.height as usize)),
+            dpi: None,
+        });
+        // The dpi at which the photo just fits the box
+        let inch_mm = 25.4;
+        let dpi =
+            (photo.width as f32 / (width / inch_mm)).max(photo.height as f32 / (height / inch_mm));
+        let (printed_width, printed_height) = (
+            photo.width as f32 / dpi * inch_mm,
+            photo.height as f32 / dpi * inch_mm,
+        );
+        Op::UseXobject {
+            id,
+            transform: XObjectTransform {
+                translate_x: Some(Mm(x + (width - printed_width) / 2.0).into_pt()),
+                translate_y: Some(Mm(y + (height - printed_height) / 2.0).into_pt()),
+                dpi: Some(dpi),
+                ..Default::default()
+            },
+        }
+    }
+
+    /// The catalogue of `entries` as a PDF, titled after `tenant`.
+    pub fn catalogue(
+        tenant: &str,
+        entries: Vec<Entry>,
+        font: Option<&[u8]>,
+        now: DateTime<Utc>,
+    ) -> Result<Vec<u8>, String> {
+        let title = format!("Cats of {}", tenant);
+        let mut doc = PdfDocument::new(&title);
+        let font = match font {
+            Some(bytes) => {
+                let parsed = ParsedFont::from_bytes(bytes, 0, &mut Vec::new())
+                    .ok_or("PDF_FONT is not a TrueType font")?;
+                PdfFontHandle::External(doc.add_font(&parsed))
+            }
+            None => PdfFontHandle::Builtin(BuiltinFont::Helvetica),
+        };
+        let page_count = entries.len().div_ceil(CATS_PER_PAGE).max(1);
+        let slot_height = (PAGE_HEIGHT_MM - 2.0 * MARGIN_MM - 10.0) / CATS_PER_PAGE as f32;
+        let content_width = PAGE_WIDTH_MM - 2.0 * MARGIN_MM;
+
+        let mut pages = Vec::with_capacity(page_count);
+        let mut entries = entries.into_iter();
+        for number in 1..=page_count {
+            let mut ops = Vec::new();
+            let top = PAGE_HEIGHT_MM - MARGIN_MM;
+            text(&mut ops, &font, 10.0, MARGIN_MM, top, title.clone());
+            for slot in 0..CATS_PER_PAGE {
+                let Some(Entry { cat, photo }) = entries.next() else {
+                    break;
+                };
+                let slot_top = top - 8.0 - slot as f32 * slot_height;
+                let photo_bottom = slot_top - PHOTO_HEIGHT_MM;
+                if let Some(photo) = photo {
+                    ops.push(place_photo(
+                        &mut doc,
+                        photo,
+                        (MARGIN_MM, photo_bottom),
+                        (content_width, PHOTO_HEIGHT_MM),
+                    ));
+                }
+                let mut y = photo_bottom - 9.0;
+                text(&mut ops, &font, 18.0, MARGIN_MM, y, cat.name);
+                let mut attributes = vec![
+                    status_label(cat.status).to_string(),
+                    format!("Added {}", cat.created_at.format("%-d %B %Y")),
+                ];
+                if let (Some(latitude), Some(longitude)) = (cat.latitude, cat.longitude) {
+                    attributes.push(format!("Found at {:.4}, {:.4}", latitude, longitude));
+                }
+                for attribute in attributes {
+                    y -= 6.0;
+                    text(&mut ops, &font, 11.0, MARGIN_MM, y, attribute);
+                }
+            }
+            let footer = format!(
+                "Page {} of {}, printed {}",
+                number,
+                page_count,
+                now.format("%-d %B %Y")
+            );
+            text(&mut ops, &font, 9.0, MARGIN_MM, MARGIN_MM - 5.0, footer);
+            pages.push(PdfPage::new(Mm(PAGE_WIDTH_MM), Mm(PAGE_HEIGHT_MM), ops));
+        }
+        Ok(doc
+            .with_pages(pages)
+            .save(&PdfSaveOptions::default(), &mut Vec::new()))
+    }
+}
+
+#[cfg(not(feature = "pdf"))]
+mod render {
+    use super::{Entry, Photo};
+    use crate::file_store::FileStore;
+    use chrono::{DateTime, Utc};
+
+    pub fn photo(_store: &dyn FileStore, _key: &str) -> Result<Photo, String> {
+        Err("this build lacks the pdf feature".to_string())
+    }
+
+    pub fn catalogue(
+        _tenant: &str,
+        _entries: Vec<Entry>,
+        _font: Option<&[u8]>,
+        _now: DateTime<Utc>,
+    ) -> Result<Vec<u8>, String> {
+        Err("this build lacks the pdf feature".to_string())
+    }
+}
+
+/// The photos of `cats`, leaving out those that cannot be read.
+fn entries(store: &dyn FileStore, cats: Vec<Cat>) -> Vec<Entry> {
+    cats.into_iter()
+        .map(|cat| {
+            let photo = cat.image_path.strip_prefix("/image/").and_then(|key| {
+                render::photo(store, key)
+                    .inspect_err(|e| warn!("Printing cat ID {} without its photo: {}", cat.id, e))
+                    .ok()
+            });
+            Entry { cat, photo }
+        })
+        .collect()
+}
+
+/// Reads the cats in batches, scaling down each batch's photos before the
+/// next is read, and lays the whole catalogue out once all are in, as a
+/// PDF's cross-reference table needs the offsets of every page.
+pub async fn export_pdf_endpoint(
+    pool: web::Data<DbPool>,
+    config: web::Data<Config>,
+    clock: web::Data<dyn Clock>,
+    store: web::Data<dyn FileStore>,
+    tenant: Tenant,
+    query: web::Query<CatsQuery>,
+) -> Result<HttpResponse, UserError> {
+    if !cfg!(feature = "pdf") {
+        warn!("PDF catalogue requested, but this build lacks the pdf feature");
+        return Err(UserError::NotFoundError);
+    }
+    let status = query.status;
+    let mut printed = Vec::new();
+    let mut after_id = 0;
+    loop {
+        let cats = cat_batch(&pool, tenant.id, status, after_id).await?;
+        let Some(last) = cats.last() else {
+            break;
+        };
+        after_id = last.id;
+        let full = cats.len() as i64 == STREAM_BATCH_SIZE;
+        let store = store.clone();
+        printed.extend(db::block(move || entries(store.get_ref(), cats)).await?);
+        if !full {
+            break;
+        }
+    }
+
+    let count = printed.len();
+    let (slug, font_path, now) = (tenant.slug.clone(), config.pdf_font.clone(), clock.now());
+    let pdf = db::block(move || {
+        let font = font_path
+            .map(|path| {
+                std::fs::read(&path).map_err(|e| format!("PDF_FONT {}: {}", path.display(), e))
+            })
+            .transpose()?;
+        render::catalogue(&slug, printed, font.as_deref(), now)
+    })
+    .await?
+    .map_err(|e| {
+        error!("Failed to lay out the PDF catalogue: {}", e);
+        UserError::UnexpectedError
+    })?;
+    info!(
+        "Exported a PDF catalogue of {} cat(s) of tenant {}",
+        count, tenant.slug
+    );
+    Ok(HttpResponse::Ok()
+        .content_type("application/pdf")
+        .insert_header(ContentDisposition {
+            disposition: DispositionType::Attachment,
+            parameters: vec![DispositionParam::Filename(format!(
+                "catdex-{}.pdf",
+                tenant.slug
+            ))],
+        })
+        .body(pdf))
+}
@@ -0,0 +1,1438 @@
+//! The cats themselves: listing and streaming them, their detail, and
+//! adding them from a multipart upload, an uploaded image or an image URL.
+//! The upload helpers here, e.g. `screen_upload`, are shared with the other
+//! endpoints taking images, such as public submissions and medical records.
+use crate::cat_detection::{self, CatDetector, Detection};
+use crate::clock::Clock;
+use crate::config::Config;
+use crate::db::{self, DbConn};
+use crate::errors::UserError;
+use crate::fields::{self, FieldsQuery};
+use crate::file_store::FileStore;
+use crate::history::{self, Requester};
+use crate::include::{self, IncludeQuery, Included};
+use crate::links::{LinkBuilder, Links};
+use crate::medical::{self, MedicalSummary};
+use crate::models::*;
+use crate::notifications::{Notification, Notifier};
+use crate::quotas::{self, QuotaStatus};
+use crate::reload::LiveSettings;
+use crate::scanner::{self, Scanner};
+use crate::signed_urls::{self, UrlSigner};
+use crate::validated::Validated;
+use crate::webhooks::CatEvent;
+use crate::{auth, gallery, geo, heic, image_download, image_shards, outbox, repository};
+use crate::{similar, sync, uploads, DbPool};
+use actix_web::{web, Error, HttpResponse, HttpResponseBuilder, Result};
+use chrono::{DateTime, Utc};
+use diesel::Connection;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::Seek;
+use std::path::Path;
+use uuid::Uuid;
+use validator::Validate;
+
+#[derive(Deserialize)]
+pub struct CatsQuery {
+    pub status: Option<CatStatus>,
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn cats_endpoint(
+    connection: DbConn,
+    config: web::Data<Config>,
+    signer: web::Data<UrlSigner>,
+    clock: web::Data<dyn Clock>,
+    tenant: Tenant,
+    query: web::Query<CatsQuery>,
+    fields: web::Query<FieldsQuery>,
+    sync_query: web::Query<sync::SyncQuery>,
+) -> Result<HttpResponse, Error> {
+    if sync_query.is_sync() {
+        if query.status.is_some() {
+            warn!("Cannot sync cats of one status");
+            return Err(UserError::ValidationError.into());
+        }
+        let changes =
+            sync::changes(connection, &signer, clock.as_ref(), tenant, &sync_query).await?;
+        return Ok(HttpResponse::Ok().json(changes));
+    }
+    let selection = fields.selection()?;
+    let status = query.status;
+    let cats_data = connection
+        .run("list cats", move |connection| {
+            repository::list_cats(connection, tenant.id, status, 100)
+        })
+        .await?;
+    let now = clock.now();
+    let links = LinkBuilder::new(&config);
+    let cats_data: Vec<_> = cats_data
+        .into_iter()
+        .map(|cat| links.linked_cat(signer.present(cat, now)))
+        .collect();
+    Ok(fields::json(&cats_data, selection.as_ref())?)
+}
+
+/// Cats read per query while streaming the catalog.
+pub const STREAM_BATCH_SIZE: i64 = 500;
+
+/// The next batch of the tenant's cats after `after_id`.
+pub async fn cat_batch(
+    pool: &DbPool,
+    tenant_id: i32,
+    status: Option<CatStatus>,
+    after_id: i32,
+) -> Result<Vec<Cat>, UserError> {
+    DbConn::get(pool)?
+        .run("list cats", move |connection| {
+            repository::list_cats_after(connection, tenant_id, status, after_id, STREAM_BATCH_SIZE)
+        })
+        .await
+}
+
+/// Where streaming the catalog has got to.
+enum StreamState {
+    Send(Vec<Cat>),
+    Fetch { after_id: i32 },
+    Failed(UserError),
+}
+
+/// Every cat of the tenant as newline delimited JSON, read from the
+/// database in batches and sent as each batch is read, so neither side
+/// holds the whole catalog in memory. A connection is only held while a
+/// batch is read, not while a slow client receives it.
+///
+/// The first batch is read before responding, so a failure there gets an
+/// error status. A failure later on can only cut the response short.
+pub async fn cats_stream_endpoint(
+    pool: web::Data<DbPool>,
+    config: web::Data<Config>,
+    signer: web::Data<UrlSigner>,
+    clock: web::Data<dyn Clock>,
+    tenant: Tenant,
+    query: web::Query<CatsQuery>,
+    fields: web::Query<FieldsQuery>,
+) -> Result<HttpResponse, Error> {
+    let selection = fields.selection()?;
+    let status = query.status;
+    let now = clock.now();
+    let first = cat_batch(&pool, tenant.id, status, 0).await?;
+
+    let lines = futures_util::stream::unfold(Some(StreamState::Send(first)), move |state| {
+        let pool = pool.clone();
+        let config = config.clone();
+        let signer = signer.clone();
+        let selection = selection.clone();
+        async move {
+            let cats = match state? {
+                StreamState::Send(cats) => cats,
+                StreamState::Fetch { after_id } => {
+                    match cat_batch(&pool, tenant.id, status, after_id).await {
+                        Ok(cats) => cats,
+                        Err(e) => return Some((Err(Error::from(e)), None)),
+                    }
+                }
+                StreamState::Failed(e) => return Some((Err(Error::from(e)), None)),
+            };
+            let last_id = cats.last()?.id;
+            let next = (cats.len() as i64 == STREAM_BATCH_SIZE)
+                .then_some(StreamState::Fetch { after_id: last_id });
+            let links = LinkBuilder::new(&config);
+            let mut body = Vec::new();
+            for cat in cats {
+                let line = fields::ndjson_line(
+                    &links.linked_cat(signer.present(cat, now)),
+                    selection.as_ref(),
+                );
+                match line {
+                    Ok(line) => body.extend(line),
+                    Err(e) => {
+                        error!("Failed to serialize cat: {}", e);
+                        let next = Some(StreamState::Failed(UserError::UnexpectedError));
+                        return Some((Ok(web::Bytes::from(body)), next));
+                    }
+                }
+            }
+            Some((Ok(web::Bytes::from(body)), next))
+        }
+    });
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(lines))
+}
+
+/// A single cat with a summary of its medical records, and the related
+/// resources asked for with `?include=`.
+#[derive(Serialize)]
+struct CatDetail {
+    #[serde(flatten)]
+    cat: Cat,
+    gallery: Vec<CatImage>,
+    medical: MedicalSummary,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    included: Option<Included>,
+    _links: Links,
+}
+
+impl CatDetail {
+    fn new(
+        signer: &UrlSigner,
+        links: &LinkBuilder,
+        cat: Cat,
+        gallery: Vec<CatImage>,
+        records: &[MedicalRecord],
+        related: Option<include::Related>,
+        now: DateTime<Utc>,
+    ) -> CatDetail {
+        let gallery = gallery::present(signer, gallery, cat.private, now);
+        let cat = signer.present(cat, now);
+        CatDetail {
+            _links: links.cat(&cat),
+            cat,
+            gallery,
+            medical: medical::summarize(records, now.date_naive()),
+            included: related.map(|related| Included::new(signer, related, records, now)),
+        }
+    }
+}
+
+#[derive(Deserialize, Validate)]
+pub struct CatEndpointPath {
+    #[validate(range(min = 1, max = 150))]
+    id: i32,
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn cat_endpoint(
+    connection: DbConn,
+    config: web::Data<Config>,
+    signer: web::Data<UrlSigner>,
+    clock: web::Data<dyn Clock>,
+    tenant: Tenant,
+    cat_id: Validated<web::Path<CatEndpointPath>>,
+    fields: web::Query<FieldsQuery>,
+    include: web::Query<IncludeQuery>,
+) -> Result<HttpResponse, UserError> {
+    let mut selection = fields.selection()?;
+    let includes = include.includes()?;
+    if let Some(selection) = selection.as_mut().filter(|_| includes.any()) {
+        selection.insert("included".to_string());
+    }
+
+    let query_id = cat_id.id;
+
+    let (cat_data, gallery, records, related) = connection
+        .run(&format!("find cat {}", query_id), move |connection| {
+            let cat = repository::find_cat(connection, tenant.id, query_id)?;
+            let gallery = repository::list_cat_gallery(connection, tenant.id, cat.id)?;
+            let records = repository::list_medical_records(connection, tenant.id, cat.id)?;
+            let related = match includes.any() {
+                true => Some(include::load(connection, tenant.id, cat.id, includes)?),
+                false => None,
+            };
+            Ok((cat, gallery, records, related))
+        })
+        .await?;
+    let links = LinkBuilder::new(&config);
+    let detail = CatDetail::new(
+        &signer,
+        &links,
+        cat_data,
+        gallery,
+        &records,
+        related,
+        clock.now(),
+    );
+    fields::json(&detail, selection.as_ref())
+}
+
+#[derive(Deserialize)]
+pub struct CatByPublicIdPath {
+    public_id: Uuid,
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn cat_by_public_id_endpoint(
+    connection: DbConn,
+    config: web::Data<Config>,
+    signer: web::Data<UrlSigner>,
+    clock: web::Data<dyn Clock>,
+    tenant: Tenant,
+    path: web::Path<CatByPublicIdPath>,
+    fields: web::Query<FieldsQuery>,
+    include: web::Query<IncludeQuery>,
+) -> Result<HttpResponse, UserError> {
+    let mut selection = fields.selection()?;
+    let includes = include.includes()?;
+    if let Some(selection) = selection.as_mut().filter(|_| includes.any()) {
+        selection.insert("included".to_string());
+    }
+    let query_public_id = path.public_id;
+
+    let (cat_data, gallery, records, related) = connection
+        .run("find cat by public ID", move |connection| {
+            let cat = repository::find_cat_by_public_id(connection, tenant.id, query_public_id)?;
+            let gallery = repository::list_cat_gallery(connection, tenant.id, cat.id)?;
+            let records = repository::list_medical_records(connection, tenant.id, cat.id)?;
+            let related = match includes.any() {
+                true => Some(include::load(connection, tenant.id, cat.id, includes)?),
+                false => None,
+            };
+            Ok((cat, gallery, records, related))
+        })
+        .await?;
+    let links = LinkBuilder::new(&config);
+    let detail = CatDetail::new(
+        &signer,
+        &links,
+        cat_data,
+        gallery,
+        &records,
+        related,
+        clock.now(),
+    );
+    fields::json(&detail, selection.as_ref())
+}
+
+/// Checks an upload with the configured scanner, if any, rejecting it when
+/// infected.
+pub async fn screen_upload(
+    scanner: Option<web::Data<dyn Scanner>>,
+    config: &Config,
+    file: awmp::File,
+) -> Result<awmp::File, UserError> {
+    let Some(scanner) = scanner else {
+        return Ok(file);
+    };
+    let quarantine_dir = config.quarantine_dir.clone();
+    db::block(move || scanner::screen_upload(scanner.get_ref(), file, &quarantine_dir))
+        .await?
+        .map_err(|e| {
+            error!("Failed to scan upload: {}", e);
+            UserError::UnexpectedError
+        })?
+        .map_err(|signature| {
+            warn!("Rejected infected upload: {}", signature);
+            UserError::InfectedUploadError
+        })
+}
+
+/// The store directory for a tenant's uploads, or its private
+/// subdirectory.
+pub fn upload_dir(tenant_slug: &str, private: bool) -> String {
+    match private {
+        true => format!("{}/{}", tenant_slug, signed_urls::PRIVATE_DIR),
+        false => tenant_slug.to_string(),
+    }
+}
+
+/// Stores an uploaded file in `dir` under a freshly generated name, so
+/// uploads never overwrite each other regardless of the client's filename,
+/// noting the format it was converted from, if any. Returns the key of the
+/// stored file.
+pub fn persist_upload(
+    store: &dyn FileStore,
+    file: awmp::File,
+    dir: &str,
+    original_format: Option<&str>,
+) -> Option<String> {
+    let extension = Path::new(file.sanitized_file_name())
+        .extension()
+        .map(|ext| heic::stored_extension(&ext.to_string_lossy().to_lowercase(), original_format));
+    let key = store.new_key(dir, extension.as_deref());
+    // The temporary file is positioned after the bytes written by the parser
+    let mut contents = file.into_inner();
+    contents.rewind().ok()?;
+    store.put(&key, &mut contents).ok().map(|_| key)
+}
+
+/// What is learned from a new cat's stored image.
+pub struct StoredImage {
+    /// None when the image cannot be read, leaving its URL unversioned.
+    pub version: Option<String>,
+    /// None when the image cannot be decoded either.
+    pub phash: Option<i64>,
+    /// The cat detector's score, when it flagged the image for moderation.
+    pub flagged: Option<f32>,
+}
+
+/// Hashes a new cat's stored image and checks that it shows a cat with
+/// the configured detector, if any.
+pub async fn inspect_stored_image(
+    store: &web::Data<dyn FileStore>,
+    detector: Option<web::Data<dyn CatDetector>>,
+    config: &web::Data<Config>,
+    key: &str,
+) -> Result<StoredImage, UserError> {
+    let (store, config, key) = (store.clone(), config.clone(), key.to_string());
+    db::block(move || {
+        let contents = match store.get(&key) {
+            Ok(contents) => contents,
+            Err(e) => {
+                warn!("Failed to read image {} to hash it: {}", key, e);
+                return Ok(StoredImage {
+                    version: None,
+                    phash: None,
+                    flagged: None,
+                });
+            }
+        };
+        let detection = match &detector {
+            Some(detector) => cat_detection::detect(detector.get_ref(), &config, &contents)
+                .map_err(|e| {
+                    error!("Failed to detect a cat in {}: {}", key, e);
+                    UserError::UnexpectedError
+                })?,
+            None => Detection::Accepted,
+        };
+        let flagged = match detection {
+            Detection::Accepted => None,
+            Detection::Flagged(score) => Some(score),
+            Detection::Rejected(score) => {
+                warn!("Rejected image {} scoring {:.2} as a cat", key, score);
+                return Err(UserError::NotACatError);
+            }
+        };
+        Ok(StoredImage {
+            version: Some(signed_urls::image_version(&contents)),
+            phash: similar::phash(&contents),
+            flagged,
+        })
+    })
+    .await?
+}
+
+/// Logs why a new cat whose image the detector doubts shows a cat went to
+/// the moderation queue.
+pub fn flag_for_review(cat: &Cat, flagged: Option<f32>) {
+    if let Some(score) = flagged {
+        warn!(
+            "Cat ID: {} held for moderation, its image scores {:.2} as a cat",
+            cat.id, score
+        );
+    }
+}
+
+/// A new cat's response, warning of cats that look like it through the
+/// `SIMILAR_HEADER`.
+async fn created_response(
+    pool: &DbPool,
+    tenant_id: i32,
+    cat_id: i32,
+    image_phash: Option<i64>,
+) -> HttpResponseBuilder {
+    let similar = similar::similar_cat_ids(pool, tenant_id, cat_id, image_phash).await;
+    let mut response = HttpResponse::Created();
+    if !similar.is_empty() {
+        let ids: Vec<String> = similar.iter().map(i32::to_string).collect();
+        response.insert_header((similar::SIMILAR_HEADER, ids.join(", ")));
+    }
+    response
+}
+
+/// Removes an uploaded image that will not be referenced by any cat.
+pub fn discard_upload(store: &dyn FileStore, key: &str) {
+    if let Err(e) = store.delete(key) {
+        warn!("Failed to remove discarded upload {}: {}", key, e);
+    }
+}
+
+/// What became of a new cat inside the insert transaction.
+pub enum AddCatOutcome {
+    /// With the tenant's quota when this cat brought it near its limit.
+    Created(Cat, Option<QuotaStatus>),
+    NameConflict(Cat),
+    QuotaExceeded(QuotaStatus),
+}
+
+/// Inserts a new cat along with its history and `cat.created` event, unless
+/// the tenant's quota or a name conflict stands in the way.
+///
+/// With `moderate` set, cats submitted without admin credentials await
+/// moderation, and their `cat.created` event waits for their approval.
+pub async fn insert_new_cat(
+    pool: &DbPool,
+    tenant_id: i32,
+    requester: Requester,
+    mut new_cat: NewCat,
+    allow_duplicate: bool,
+    moderate: bool,
+) -> Result<AddCatOutcome, UserError> {
+    let mut connection = DbConn::get(pool)?;
+    db::block(move || {
+        connection.transaction(|connection| {
+            let quota = repository::lock_quota(connection, tenant_id)?;
+            let mut was_near_limit = false;
+            if let Some(quota) = quota {
+                let status = quotas::status(connection, tenant_id, quota)?;
+                if !status.allows(new_cat.image_size) {
+                    return Ok(AddCatOutcome::QuotaExceeded(status));
+                }
+                was_near_limit = status.near_limit();
+            }
+            if moderate && !new_cat.pending_review {
+                new_cat.pending_review = !auth::is_admin(connection, tenant_id, &requester)?;
+            }
+            match repository::insert_cat(connection, tenant_id, &new_cat, allow_duplicate)? {
+                Some(cat) => {
+                    let actor = requester.actor(connection, tenant_id)?;
+                    history::record(connection, tenant_id, &actor, None, &cat, cat.created_at)?;
+                    if !cat.pending_review {
+                        outbox::record(
+                            connection,
+                            tenant_id,
+                            CatEvent::Created,
+                            &cat,
+                            cat.created_at,
+                        )?;
+                    }
+                    let near_limit = match quota {
+                        Some(quota) if !was_near_limit => {
+                            Some(quotas::status(connection, tenant_id, quota)?)
+                                .filter(QuotaStatus::near_limit)
+                        }
+                        _ => None,
+                    };
+                    Ok(AddCatOutcome::Created(cat, near_limit))
+                }
+                None => repository::find_cat_by_unique_name(connection, tenant_id, &new_cat.name)
+                    .map(AddCatOutcome::NameConflict),
+            }
+        })
+    })
+    .await?
+    .map_err(|e| {
+        error!("Failed to insert cat: {}", e);
+        UserError::ValidationError
+    })
+}
+
+/// The created cat, or the error for a rejected one. The image is removed
+/// along with a rejected cat when `discard` is set, i.e. it was stored for
+/// this cat alone.
+///
+/// Admins are notified of a created cat awaiting moderation, and of the
+/// tenant's quota when the cat brought it near its limit.
+pub fn created_cat(
+    outcome: AddCatOutcome,
+    store: &dyn FileStore,
+    notifier: Option<&Notifier>,
+    tenant: &Tenant,
+    image_key: &str,
+    discard: bool,
+) -> Result<Cat, UserError> {
+    let error = match outcome {
+        AddCatOutcome::Created(cat, near_limit) => {
+            if let Some(notifier) = notifier {
+                if cat.pending_review {
+                    notifier.notify(Notification::PendingModeration { tenant, cat: &cat });
+                }
+                if let Some(status) = &near_limit {
+                    notifier.notify(Notification::QuotaNearLimit { tenant, status });
+                }
+            }
+            return Ok(cat);
+        }
+        AddCatOutcome::NameConflict(existing) => {
+            warn!("Cat name conflicts with cat ID: {}", existing.id);
+            UserError::NameConflictError(Box::new(existing))
+        }
+        AddCatOutcome::QuotaExceeded(status) => {
+            warn!("Tenant {} is over its upload quota", tenant.slug);
+            UserError::QuotaExceededError(status)
+        }
+    };
+    if discard {
+        discard_upload(store, image_key);
+    }
+    Err(error)
+}
+
+pub fn upload_size(file: &awmp::File) -> Result<i64, UserError> {
+    file.as_ref()
+        .as_file()
+        .metadata()
+        .map(|metadata| metadata.len() as i64)
+        .map_err(|e| {
+            error!("Failed to read upload size: {}", e);
+            UserError::UnexpectedError
+        })
+}
+
+#[derive(Deserialize)]
+pub struct AddCatQuery {
+    #[serde(default)]
+    allow_duplicates: bool,
+}
+
+#[allow(clippy::too_many_arguments)]
+pub async fn add_cat_endpoint(
+    pool: web::Data<DbPool>,
+    config: web::Data<Config>,
+    settings: web::Data<LiveSettings>,
+    clock: web::Data<dyn Clock>,
+    store: web::Data<dyn FileStore>,
+    scanner: Option<web::Data<dyn Scanner>>,
+    detector: Option<web::Data<dyn CatDetector>>,
+    notifier: Option<web::Data<Notifier>>,
+    tenant: Tenant,
+    requester: Requester,
+    query: web::Query<AddCatQuery>,
+    mut parts: awmp::Parts,
+) -> Result<HttpResponse, Error> {
+    let file = parts.files.take("image").pop().ok_or_else(|| {
+        error!("Error in getting image file");
+        UserError::ValidationError
+    })?;
+    let file = screen_upload(scanner, &config, file).await?;
+    let (file, original_format) = heic::convert_upload(&config, file).await?;
+
+    let image_size = upload_size(&file)?;
+    let text_fields: HashMap<_, _> = parts.texts.as_pairs().into_iter().collect();
+    let private = text_fields
+        .get("private")
+        .is_some_and(|value| matches!(*value, "true" | "on" | "1"));
+    let location = geo::parse_location(&text_fields)?;
+
+    let dir = upload_dir(&tenant.slug, private);
+    let image_key =
+        persist_upload(store.get_ref(), file, &dir, original_format).ok_or_else(|| {
+            error!("Error in getting image path");
+            UserError::ValidationError
+        })?;
+
+    let image = inspect_stored_image(&store, detector, &config, &image_key)
+        .await
+        .inspect_err(|_| discard_upload(store.get_ref(), &image_key))?;
+    let new_cat = NewCat {
+        name: text_fields
+            .get("name")
+            .ok_or_else(|| {
+                error!("Error in getting name field");
+                UserError::ValidationError
+            })?
+            .trim()
+            .to_string(),
+        image_path: format!("/image/{}", image_key),
+        created_at: clock.now(),
+        image_size,
+        private,
+        latitude: location.map(|(latitude, _)| latitude),
+        longitude: location.map(|(_, longitude)| longitude),
+        image_hash: image.version,
+        image_phash: image.phash,
+        pending_review: image.flagged.is_some(),
+        image_original_format: original_format.map(str::to_string),
+    };
+
+    if let Err(errors) = new_cat.validate() {
+        warn!("New cat validation failed");
+        discard_upload(store.get_ref(), &image_key);
+        return Err(UserError::FieldValidationError(errors).into());
+    }
+
+    let settings = settings.load();
+    let allow_duplicate = !settings.unique_cat_names || query.allow_duplicates;
+    let outcome = insert_new_cat(
+        &pool,
+        tenant.id,
+        requester,
+        new_cat,
+        allow_duplicate,
+        settings.moderate_submissions,
+    )
+    .await?;
+    let cat = created_cat(
+        outcome,
+        store.get_ref(),
+        notifier.as_ref().map(|notifier| notifier.get_ref()),
+        &tenant,
+        &image_key,
+        true,
+    )?;
+    flag_for_review(&cat, image.flagged);
+    Ok(created_response(&pool, tenant.id, cat.id, image.phash)
+        .await
+        .finish())
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UploadedImage {
+    pub image_key: String,
+}
+
+/// Stores an image on its own, for `POST /api/cats` to reference by key.
+/// Images no cat ends up referencing are left to `gc-images`.
+pub async fn upload_image_endpoint(
+    config: web::Data<Config>,
+    store: web::Data<dyn FileStore>,
+    scanner: Option<web::Data<dyn Scanner>>,
+    tenant: Tenant,
+    mut parts: awmp::Parts,
+) -> Result<HttpResponse, UserError> {
+    let file = parts.files.take("image").pop().ok_or_else(|| {
+        error!("Error in getting image file");
+        UserError::ValidationError
+    })?;
+    let file = screen_upload(scanner, &config, file).await?;
+    let (file, original_format) = heic::convert_upload(&config, file).await?;
+    let private = parts
+        .texts
+        .as_hash_map()
+        .get("private")
+        .is_some_and(|value| matches!(*value, "true" | "on" | "1"));
+    let dir = upload_dir(&tenant.slug, private);
+    let image_key =
+        persist_upload(store.get_ref(), file, &dir, original_format).ok_or_else(|| {
+            error!("Error in getting image path");
+            UserError::UnexpectedError
+        })?;
+    Ok(HttpResponse::Created().json(UploadedImage { image_key }))
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct CreateCat {
+    pub name: String,
+    /// Downloaded by the server.
+    pub image_url: Option<String>,
+    /// Key of an image stored through `POST /api/images`.
+    pub image_key: Option<String>,
+    /// Token of a completed upload started with `POST /api/uploads`.
+    pub upload_token: Option<Uuid>,
+    #[serde(default)]
+    pub private: bool,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+}
+
+/// Whether `image_key` names an upload directly in the tenant's public or,
+/// for private cats, private directory.
+pub fn is_tenant_upload(image_key: &str, tenant_slug: &str, private: bool) -> bool {
+    image_key
+        .strip_prefix(&upload_dir(tenant_slug, private))
+        .and_then(|name| name.strip_prefix('/'))
+        .map(image_shards::strip_shard)
+        .is_some_and(|name| !name.is_empty() && !name.contains('/') && !name.starts_with('.'))
+}
+
+/// Creates a cat from JSON, with its image downloaded from `image_url`,
+/// already stored under `image_key` or received through `upload_token`.
+#[allow(clippy::too_many_arguments)]
+pub async fn create_cat_endpoint(
+    pool: web::Data<DbPool>,
+    config: web::Data<Config>,
+    settings: web::Data<LiveSettings>,
+    clock: web::Data<dyn Clock>,
+    store: web::Data<dyn FileStore>,
+    scanner: Option<web::Data<dyn Scanner>>,
+    detector: Option<web::Data<dyn CatDetector>>,
+    notifier: Option<web::Data<Notifier>>,
+    signer: web::Data<UrlSigner>,
+    tenant: Tenant,
+    requester: Requester,
+    query: web::Query<AddCatQuery>,
+    body: web::Json<CreateCat>,
+) -> Result<HttpResponse, UserError> {
+    let body = body.into_inner();
+    if body.latitude.is_some() != body.longitude.is_some() {
+        warn!("Location is missing a coordinate");
+        return Err(UserError::ValidationError);
+    }
+
+    let sources = (&body.image_url, &body.image_key, body.upload_token);
+    let (image_key, image_size, original_format, downloaded) = match sources {
+        (Some(url), None, None) => {
+            let file = image_download::download(&config, url).await?;
+            let file = screen_upload(scanner, &config, file).await?;
+            let (file, original_format) = heic::convert_upload(&config, file).await?;
+            let image_size = upload_size(&file)?;
+            let dir = upload_dir(&tenant.slug, body.private);
+            let image_key = persist_upload(store.get_ref(), file, &dir, original_format)
+                .ok_or_else(|| {
+                    error!("Error in getting image path");
+                    UserError::UnexpectedError
+                })?;
+            (image_key, image_size, original_format, true)
+        }
+        (None, Some(image_key), None) => {
+            if !is_tenant_upload(image_key, &tenant.slug, body.private) {
+                warn!("Rejected image key outside the tenant's uploads");
+                return Err(UserError::ValidationError);
+            }
+            let image_size = store.size(image_key).map_err(|e| {
+                warn!("Referenced image {} is not stored: {}", image_key, e);
+                UserError::ValidationError
+            })?;
+            let original_format = heic::original_format(image_key);
+            (image_key.clone(), image_size as i64, original_format, false)
+        }
+        (None, None, Some(token)) => {
+            let image_key =
+                uploads::completed_upload(&pool, tenant.id, token, body.private, clock.now())
+                    .await?;
+            // Not the size of the upload, which may have been converted
+            let image_size = store.size(&image_key).map_err(|e| {
+                error!("Completed upload {} is not stored: {}", image_key, e);
+                UserError::UnexpectedError
+            })?;
+            let original_format = heic::original_format(&image_key);
+            (image_key, image_size as i64, original_format, false)
+        }
+        _ => {
+            warn!("New cat needs exactly one of image_url, image_key and upload_token");
+            return Err(UserError::ValidationError);
+        }
+    };
+
+    let image = inspect_stored_image(&store, detector, &config, &image_key)
+        .await
+        .inspect_err(|_| {
+            if downloaded {
+                discard_upload(store.get_ref(), &image_key);
+            }
+        })?;
+    let new_cat = NewCat {
+        name: body.name.trim().to_string(),
+        image_path: format!("/image/{}", image_key),
+        created_at: clock.now(),
+        image_size,
+        private: body.private,
+        latitude: body.latitude,
+        longitude: body.longitude,
+        image_hash: image.version,
+        image_phash: image.phash,
+        pending_review: image.flagged.is_some(),
+        image_original_format: original_format.map(str::to_string),
+    };
+    if let Err(errors) = new_cat.validate() {
+        warn!("New cat validation failed");
+        if downloaded {
+            discard_upload(store.get_ref(), &image_key);
+        }
+        return Err(UserError::FieldValidationError(errors));
+    }
+
+    let settings = settings.load();
+    let allow_duplicate = !settings.unique_cat_names || query.allow_duplicates;
+    let outcome = insert_new_cat(
+        &pool,
+        tenant.id,
+        requester,
+        new_cat,
+        allow_duplicate,
+        settings.moderate_submissions,
+    )
+    .await?;
+    let cat = created_cat(
+        outcome,
+        store.get_ref(),
+        notifier.as_ref().map(|notifier| notifier.get_ref()),
+        &tenant,
+        &image_key,
+        downloaded,
+    )?;
+    if let Some(token) = body.upload_token {
+        uploads::finish_upload(&pool, token).await;
+    }
+    flag_for_review(&cat, image.flagged);
+    Ok(created_response(&pool, tenant.id, cat.id, image.phash)
+        .await
+        .json(signer.present(cat, clock.now())))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scanner::CommandScanner;
+    use crate::test_support::{
+        image_key_of, insert_cat, multipart_body, test_app, test_app_with, test_app_with_store,
+        test_pool, MemoryFileStore,
+    };
+    use actix_web::http::header::{CONTENT_RANGE, CONTENT_TYPE};
+    use actix_web::http::{Method, StatusCode};
+    use actix_web::{test, App, HttpServer};
+    use std::sync::Arc;
+
+    #[actix_web::test]
+    async fn test_cats_endpoint_get() {
+        let app = test_app().await;
+        let req = test::TestRequest::get().uri("/api/cats").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+    }
+
+    #[actix_web::test]
+    async fn test_sparse_fieldsets() {
+        let pool = test_pool();
+        let public_id = {
+            let mut connection = pool.get().unwrap();
+            let tenant = repository::find_tenant_by_slug(&mut connection, "default").unwrap();
+            let cat_id = insert_cat(&mut connection, tenant.id).id;
+            repository::find_cat(&mut connection, tenant.id, cat_id)
+                .unwrap()
+                .public_id
+        };
+        let app = test_app_with(
+            pool,
+            Arc::new(MemoryFileStore::default()),
+            Config::from_env(),
+            None,
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/api/cats?fields=id,name")
+            .to_request();
+        let cats: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        assert!(!cats.is_empty());
+        for cat in &cats {
+            let mut keys: Vec<_> = cat.as_object().unwrap().keys().collect();
+            keys.sort();
+            assert_eq!(keys, ["id", "name"]);
+        }
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/api/cat/uuid/{}?fields=medical", public_id))
+            .to_request();
+        let cat: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(cat.as_object().unwrap().len(), 1);
+        assert!(cat["medical"].is_object());
+
+        let req = test::TestRequest::get()
+            .uri("/api/cats?fields=")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_cats_stream() {
+        let pool = test_pool();
+        {
+            let mut connection = pool.get().unwrap();
+            let tenant = repository::find_tenant_by_slug(&mut connection, "default").unwrap();
+            insert_cat(&mut connection, tenant.id);
+            insert_cat(&mut connection, tenant.id);
+        }
+        let app = test_app_with(
+            pool,
+            Arc::new(MemoryFileStore::default()),
+            Config::from_env(),
+            None,
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/api/cats/stream?fields=id,name")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+        assert_eq!(
+            resp.headers().get(CONTENT_TYPE).unwrap(),
+            "application/x-ndjson"
+        );
+        let body = test::read_body(resp).await;
+        let body = std::str::from_utf8(&body).unwrap();
+        assert!(body.ends_with('\n'));
+        let ids: Vec<i64> = body
+            .lines()
+            .map(|line| {
+                let cat: serde_json::Value = serde_json::from_str(line).unwrap();
+                assert_eq!(cat.as_object().unwrap().len(), 2);
+                cat["id"].as_i64().unwrap()
+            })
+            .collect();
+        assert!(ids.len() >= 2);
+        assert!(ids.windows(2).all(|pair| pair[0] < pair[1]));
+    }
+
+    #[actix_web::test]
+    async fn test_include_related_resources() {
+        let pool = test_pool();
+        let (public_id, mother) = {
+            let mut connection = pool.get().unwrap();
+            let tenant = repository::find_tenant_by_slug(&mut connection, "default").unwrap();
+            let [kitten, mother] = [(); 2].map(|_| insert_cat(&mut connection, tenant.id).id);
+            let relation = NewCatRelation {
+                tenant_id: tenant.id,
+                cat_id: kitten,
+                related_cat_id: mother,
+                kind: CatRelationKind::Mother,
+            };
+            repository::insert_cat_relation(&mut connection, &relation).unwrap();
+            let kitten = repository::find_cat(&mut connection, tenant.id, kitten).unwrap();
+            (kitten.public_id, mother)
+        };
+        let app = test_app_with(
+            pool,
+            Arc::new(MemoryFileStore::default()),
+            Config::from_env(),
+            None,
+        )
+        .await;
+
+        let uri = format!("/api/cat/uuid/{}", public_id);
+        let req = test::TestRequest::get().uri(&uri).to_request();
+        let cat: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert!(cat.get("included").is_none());
+
+        let req = test::TestRequest::get()
+            .uri(&format!("{}?include=relations,records&fields=name", uri))
+            .to_request();
+        let cat: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let included = &cat["included"];
+        assert_eq!(cat.as_object().unwrap().len(), 2);
+        assert_eq!(included["relations"][0]["related_cat_id"], mother);
+        assert_eq!(included["cats"][0]["id"], mother);
+        assert_eq!(included["records"], serde_json::json!([]));
+        assert!(included.get("history").is_none());
+
+        let req = test::TestRequest::get()
+            .uri(&format!("{}?include=owner", uri))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[actix_web::test]
+    async fn test_add_and_get_cat() {
+        let store = Arc::new(MemoryFileStore::default());
+        let app = test_app_with_store(store.clone()).await;
+        let name = format!("Test cat {}", Uuid::new_v4());
+        let (content_type, body) = multipart_body(&[("name", &name)], b"not really a jpeg");
+        let req = test::TestRequest::post()
+            .uri("/api/add_cat")
+            .insert_header((CONTENT_TYPE, content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let req = test::TestRequest::get().uri("/api/cats").to_request();
+        let cats: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        let cat = cats
+            .into_iter()
+            .find(|cat| cat["name"] == name.as_str())
+            .expect("Added cat is listed");
+        let image_key = image_key_of(cat["image_path"].as_str().unwrap());
+        assert!(image_key.starts_with("default/"));
+        assert_eq!(store.get(image_key).unwrap(), b"not really a jpeg");
+        assert_eq!(cat["private"], false);
+        assert_eq!(cat["created_at"], "2026-01-01T00:00:00Z");
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/api/cat/uuid/{}",
+                cat["public_id"].as_str().unwrap()
+            ))
+            .to_request();
+        let mut fetched: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let medical = fetched.as_object_mut().unwrap().remove("medical").unwrap();
+        let gallery = fetched.as_object_mut().unwrap().remove("gallery").unwrap();
+        assert_eq!(fetched, cat);
+        assert_eq!(gallery[0]["image_path"], cat["image_path"]);
+        assert_eq!(
+            medical,
+            serde_json::json!({"last_checkup": null, "vaccinations_due": []})
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_private_cat_image_is_signed() {
+        let store = Arc::new(MemoryFileStore::default());
+        let app = test_app_with_store(store.clone()).await;
+        let name = format!("Private cat {}", Uuid::new_v4());
+        let fields = [("name", name.as_str()), ("private", "true")];
+        let (content_type, body) = multipart_body(&fields, b"secret jpeg");
+        let req = test::TestRequest::post()
+            .uri("/api/add_cat")
+            .insert_header((CONTENT_TYPE, content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let req = test::TestRequest::get().uri("/api/cats").to_request();
+        let cats: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        let cat = cats
+            .into_iter()
+            .find(|cat| cat["name"] == name.as_str())
+            .expect("Added cat is listed");
+        assert_eq!(cat["private"], true);
+        let url = cat["image_path"].as_str().unwrap();
+        assert!(url.starts_with("/signed-image/default/private/"));
+
+        let req = test::TestRequest::get().uri(url).to_request();
+        let image = test::call_and_read_body(&app, req).await;
+        assert_eq!(image, "secret jpeg");
+
+        let tampered = url.replace("signature=", "signature=00");
+        let req = test::TestRequest::get().uri(&tampered).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[actix_web::test]
+    async fn test_create_cat_from_uploaded_image() {
+        let store = Arc::new(MemoryFileStore::default());
+        let app = test_app_with_store(store.clone()).await;
+        let (content_type, body) = multipart_body(&[], b"jpeg");
+        let req = test::TestRequest::post()
+            .uri("/api/images")
+            .insert_header((CONTENT_TYPE, content_type))
+            .set_payload(body)
+            .to_request();
+        let uploaded: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let image_key = uploaded["image_key"].as_str().unwrap();
+
+        let name = format!("JSON cat {}", Uuid::new_v4());
+        let req = test::TestRequest::post()
+            .uri("/api/cats")
+            .set_json(serde_json::json!({"name": name, "image_key": image_key}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let cat: Cat = test::read_body_json(resp).await;
+        assert_eq!(cat.name, name);
+        assert_eq!(
+            cat.image_path,
+            format!(
+                "/image/{}?v={}",
+                image_key,
+                signed_urls::image_version(&store.get(image_key).unwrap())
+            )
+        );
+
+        for body in [
+            serde_json::json!({"name": "Stray", "image_key": "acme/cat.jpg"}),
+            serde_json::json!({"name": "Stray", "image_key": "default/../acme/cat.jpg"}),
+            serde_json::json!({"name": "Stray", "image_key": "default/missing.jpg"}),
+            serde_json::json!({"name": "Stray"}),
+            serde_json::json!({"name": "Stray", "image_key": image_key, "image_url": "https://example.com/cat.jpg"}),
+        ] {
+            let req = test::TestRequest::post()
+                .uri("/api/cats")
+                .set_json(&body)
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", body);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_create_cat_from_chunked_upload() {
+        let staging = tempfile::tempdir().unwrap();
+        let mut config = Config::from_env();
+        config.upload_staging_dir = staging.path().to_path_buf();
+        let store = Arc::new(MemoryFileStore::default());
+        let app = test_app_with(test_pool(), store.clone(), config, None).await;
+
+        let req = test::TestRequest::post()
+            .uri("/api/uploads")
+            .set_json(serde_json::json!({"size": 10, "content_type": "image/png"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let upload: serde_json::Value = test::read_body_json(resp).await;
+        let upload_url = upload["upload_url"].as_str().unwrap().to_string();
+        let token = upload["token"].as_str().unwrap().to_string();
+
+        let chunk = |range: &str, bytes: &'static [u8]| {
+            test::TestRequest::put()
+                .uri(&upload_url)
+                .insert_header((CONTENT_RANGE, range.to_string()))
+                .set_payload(bytes)
+                .to_request()
+        };
+        let resp = test::call_service(&app, chunk("bytes 0-5/10", b"png pa")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        // A chunk past the received bytes is refused with where to resume
+        let resp = test::call_service(&app, chunk("bytes 8-9/10", b"ts")).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        let error: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(error["received"], 6);
+
+        let req = test::TestRequest::get().uri(&upload_url).to_request();
+        let status: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(status["received"], 6);
+        assert_eq!(status["complete"], false);
+
+        let resp = test::call_service(&app, chunk("bytes 6-9/10", b"rts!")).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let status: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(status["complete"], true);
+        assert!(!staging.path().join(&token).exists());
+
+        let name = format!("Uploaded cat {}", Uuid::new_v4());
+        let req = test::TestRequest::post()
+            .uri("/api/cats")
+            .set_json(serde_json::json!({"name": name, "upload_token": token}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let cat: Cat = test::read_body_json(resp).await;
+        let image_key = image_key_of(&cat.image_path);
+        assert!(image_key.ends_with(".png"));
+        assert_eq!(store.get(image_key).unwrap(), b"png parts!");
+
+        // The upload is used up by the cat
+        let req = test::TestRequest::get().uri(&upload_url).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn test_create_cat_from_tus_upload() {
+        use actix_http::error::PayloadError;
+        use actix_http::{BoxedPayloadStream, Payload};
+
+        let staging = tempfile::tempdir().unwrap();
+        let mut config = Config::from_env();
+        config.upload_staging_dir = staging.path().to_path_buf();
+        let store = Arc::new(MemoryFileStore::default());
+        let app = test_app_with(test_pool(), store.clone(), config, None).await;
+
+        let req = test::TestRequest::default()
+            .method(Method::OPTIONS)
+            .uri("/api/tus")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(resp.headers().get("tus-version").unwrap(), "1.0.0");
+
+        let req = test::TestRequest::post()
+            .uri("/api/tus")
+            .insert_header(("Upload-Length", "8"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::PRECONDITION_FAILED);
+
+        let req = test::TestRequest::post()
+            .uri("/api/tus")
+            .insert_header(("Tus-Resumable", "1.0.0"))
+            .insert_header(("Upload-Length", "8"))
+            // filetype image/gif
+            .insert_header(("Upload-Metadata", "filetype aW1hZ2UvZ2lm"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        assert_eq!(resp.headers().get("tus-resumable").unwrap(), "1.0.0");
+        let location = resp
+            .headers()
+            .get("location")
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+        let token = location.rsplit('/').next().unwrap().to_string();
+
+        let patch = |offset: &str, bytes: &'static [u8]| {
+            test::TestRequest::patch()
+                .uri(&location)
+                .insert_header(("Tus-Resumable", "1.0.0"))
+                .insert_header((CONTENT_TYPE, "application/offset+octet-stream"))
+                .insert_header(("Upload-Offset", offset.to_string()))
+                .set_payload(bytes)
+                .to_request()
+        };
+        let resp = test::call_service(&app, patch("0", b"gif ")).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(resp.headers().get("upload-offset").unwrap(), "4");
+        let resp = test::call_service(&app, patch("0", b"gif ")).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        let req = test::TestRequest::default()
+            .method(Method::HEAD)
+            .uri(&location)
+            .insert_header(("Tus-Resumable", "1.0.0"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get("upload-offset").unwrap(), "4");
+        assert_eq!(resp.headers().get("upload-length").unwrap(), "8");
+
+        // A body cut short keeps the bytes that arrived
+        let mut req = patch("4", b"");
+        let cut_short = futures_util::stream::iter([
+            Ok(web::Bytes::from_static(b"da")),
+            Err(PayloadError::Incomplete(None)),
+        ]);
+        *req.payload() = Payload::from(Box::pin(cut_short) as BoxedPayloadStream);
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let req = test::TestRequest::default()
+            .method(Method::HEAD)
+            .uri(&location)
+            .insert_header(("Tus-Resumable", "1.0.0"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.headers().get("upload-offset").unwrap(), "6");
+
+        let resp = test::call_service(&app, patch("6", b"ta")).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(resp.headers().get("upload-offset").unwrap(), "8");
+
+        let req = test::TestRequest::post()
+            .uri("/api/cats")
+            .set_json(serde_json::json!({
+                "name": format!("Resumed cat {}", Uuid::new_v4()),
+                "upload_token": token,
+            }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let cat: Cat = test::read_body_json(resp).await;
+        let image_key = image_key_of(&cat.image_path);
+        assert!(image_key.ends_with(".gif"));
+        assert_eq!(store.get(image_key).unwrap(), b"gif data");
+    }
+
+    #[actix_web::test]
+    async fn test_create_cat_from_image_url() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let image_url = format!("http://{}/cat.png", listener.local_addr().unwrap());
+        let server = HttpServer::new(|| {
+            App::new().route(
+                "/cat.png",
+                web::get()
+                    .to(|| async { HttpResponse::Ok().content_type("image/png").body("png") }),
+            )
+        })
+        .listen(listener)
+        .unwrap()
+        .workers(1)
+        .run();
+        actix_rt::spawn(server);
+
+        let store = Arc::new(MemoryFileStore::default());
+        let body = serde_json::json!({
+            "name": format!("Downloaded cat {}", Uuid::new_v4()),
+            "image_url": image_url,
+        });
+        // Loopback addresses are refused by default
+        let app = test_app_with(test_pool(), store.clone(), Config::from_env(), None).await;
+        let req = test::TestRequest::post()
+            .uri("/api/cats")
+            .set_json(&body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(store.is_empty());
+
+        let mut config = Config::from_env();
+        config.image_download_allow_private = true;
+        let app = test_app_with(test_pool(), store.clone(), config, None).await;
+        let req = test::TestRequest::post()
+            .uri("/api/cats")
+            .set_json(&body)
+            .to_request();
+        let cat: Cat = test::call_and_read_body_json(&app, req).await;
+        let image_key = image_key_of(&cat.image_path);
+        assert!(image_key.ends_with(".png"));
+        assert_eq!(store.get(image_key).unwrap(), b"png");
+    }
+
+    #[actix_web::test]
+    async fn test_add_cat_rejects_invalid_name() {
+        let store = Arc::new(MemoryFileStore::default());
+        let app = test_app_with_store(store.clone()).await;
+        let (content_type, body) = multipart_body(&[("name", "")], b"not really a jpeg");
+        let req = test::TestRequest::post()
+            .uri("/api/add_cat")
+            .insert_header((CONTENT_TYPE, content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(store.is_empty());
+    }
+
+    #[cfg(not(feature = "heic"))]
+    #[actix_web::test]
+    async fn test_add_cat_refuses_heic_without_the_feature() {
+        let store = Arc::new(MemoryFileStore::default());
+        let app = test_app_with_store(store.clone()).await;
+        let heic = b"\0\0\0\x18ftypheic\0\0\0\0mif1heic\0\0\0\x08meta";
+        let (content_type, body) = multipart_body(&[("name", "iPhone cat")], heic);
+        let req = test::TestRequest::post()
+            .uri("/api/add_cat")
+            .insert_header((CONTENT_TYPE, content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        assert!(store.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_add_cat_quarantines_infected_upload() {
+        let store = Arc::new(MemoryFileStore::default());
+        let mut config = Config::from_env();
+        config.quarantine_dir =
+            std::env::temp_dir().join(format!("catdex-quarantine-{}", Uuid::new_v4()));
+        // Exits with 1, the scan command's "infected" status
+        let scanner: Arc<dyn Scanner> = Arc::new(CommandScanner::new("false").unwrap());
+        let quarantine_dir = config.quarantine_dir.clone();
+        let app = test_app_with(test_pool(), store.clone(), config, Some(scanner)).await;
+
+        let (content_type, body) = multipart_body(&[("name", "Infected cat")], b"EICAR");
+        let req = test::TestRequest::post()
+            .uri("/api/add_cat")
+            .insert_header((CONTENT_TYPE, content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        assert!(store.is_empty());
+
+        let quarantined = std::fs::read_dir(&quarantine_dir).unwrap().count();
+        assert_eq!(quarantined, 1);
+        std::fs::remove_dir_all(quarantine_dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_add_cat_over_quota() {
+        let pool = test_pool();
+        {
+            let mut connection = pool.get().unwrap();
+            let tenant = repository::find_tenant_by_slug(&mut connection, "default").unwrap();
+            let (used_cats, _) = repository::quota_usage(&mut connection, tenant.id).unwrap();
+            let quota = Quota {
+                max_cats: Some(used_cats as i32),
+                max_storage_bytes: None,
+            };
+            repository::set_quota(&mut connection, tenant.id, quota).unwrap();
+        }
+        let store = Arc::new(MemoryFileStore::default());
+        let app = test_app_with(pool, store.clone(), Config::from_env(), None).await;
+
+        let (content_type, body) = multipart_body(&[("name", "One cat too many")], b"jpeg");
+        let req = test::TestRequest::post()
+            .uri("/api/add_cat")
+            .insert_header((CONTENT_TYPE, content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["quota"]["remaining_cats"], 0);
+        assert!(store.is_empty());
+
+        let req = test::TestRequest::get().uri("/api/quota").to_request();
+        let quota: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(quota["remaining_cats"], 0);
+        assert!(quota["max_storage_bytes"].is_null());
+    }
+
+    #[actix_web::test]
+    async fn test_get_unknown_cat() {
+        let app = test_app().await;
+        let req = test::TestRequest::get()
+            .uri(&format!("/api/cat/uuid/{}", Uuid::new_v4()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+}
@@ -0,0 +1,758 @@
+use crate::archival::{self, ArchiveOptions};
+use crate::backup;
+use crate::clock::{Clock, SystemClock};
+use crate::config::Config;
+use crate::file_store::{FileStore, LocalFileStore};
+use crate::image_shards;
+use crate::image_variants;
+use crate::models::{NewAdmin, Quota};
+use crate::profile;
+use crate::repository;
+use crate::secrets;
+use crate::seed;
+use crate::setup_database;
+use crate::similar;
+use crate::tenants::DEFAULT_TENANT;
+use crate::uploads;
+use argon2::password_hash::SaltString;
+use argon2::{Argon2, PasswordHasher};
+use chrono::{DateTime, Utc};
+use clap::{Parser, Subcommand, ValueEnum};
+use diesel::{Connection, PgConnection, QueryResult};
+use diesel_migrations::{embed_migrations, EmbeddedMigrations, MigrationHarness};
+use std::collections::{BTreeSet, HashSet};
+use std::error::Error;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use uuid::Uuid;
+
+pub const MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations");
+
+pub type CliResult = Result<(), Box<dyn Error + Send + Sync>>;
+
+const MIN_ADMIN_PASSWORD_LENGTH: usize = 8;
+
+#[derive(Parser)]
+#[command(name = "catdex", about = "Catdex API server and admin tools")]
+pub struct Cli {
+    /// Defaults to `serve`
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the HTTP server
+    Serve,
+    /// Apply pending database migrations
+    Migrate,
+    /// Create an admin account, reading the password from
+    /// CATDEX_ADMIN_PASSWORD or stdin
+    CreateAdmin {
+        #[arg(long)]
+        username: String,
+        #[arg(long, default_value = DEFAULT_TENANT)]
+        tenant: String,
+    },
+    /// Delete expired uploads, the uploaded images that no cat refers to and
+    /// their converted variants
+    GcImages {
+        /// Only list the images that would be deleted
+        #[arg(long)]
+        dry_run: bool,
+        /// Leave images younger than this alone, so uploads still being
+        /// processed are not collected
+        #[arg(long, default_value_t = 3600)]
+        min_age_secs: u64,
+    },
+    /// Re-encode the images older than ARCHIVE_AFTER_DAYS smaller, as the
+    /// server does every ARCHIVE_INTERVAL_SECS
+    ArchiveImages {
+        /// Archive images older than this instead
+        #[arg(long)]
+        older_than_days: Option<u64>,
+        /// Only list the images that would be archived
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Move the images stored before IMAGE_DATE_SHARDS into a directory for
+    /// the day they were stored. Links to moved public images redirect
+    ShardImages {
+        /// Only list the images that would be moved
+        #[arg(long)]
+        dry_run: bool,
+    },
+    /// Recompute the perceptual hashes of every cat's image and gallery
+    /// photo in batches, then rebuild the indexes of the cats table without locking it and
+    /// refresh the statistics
+    Reindex {
+        /// Cats or gallery photos rehashed per transaction
+        #[arg(long, default_value_t = 500)]
+        batch_size: i64,
+    },
+    /// Insert sample cats with placeholder images, for demos and local testing
+    Seed {
+        #[arg(long, default_value_t = 20)]
+        count: usize,
+        #[arg(long, default_value = DEFAULT_TENANT)]
+        tenant: String,
+    },
+    /// Set a tenant's upload quota, leaving out a limit lifts it
+    SetQuota {
+        #[arg(long, default_value = DEFAULT_TENANT)]
+        tenant: String,
+        #[arg(long)]
+        max_cats: Option<i32>,
+        #[arg(long)]
+        max_storage_bytes: Option<i64>,
+    },
+    /// Seed cats and measure the list and detail queries under concurrent
+    /// load, printing p50/p95/p99 latencies
+    ProfileLoad {
+        /// Cats the tenant is seeded up to
+        #[arg(long, default_value_t = 1000)]
+        cats: usize,
+        /// Connections querying at once, at most the pool size
+        #[arg(long, default_value_t = 8)]
+        concurrency: usize,
+        /// Requests per query
+        #[arg(long, default_value_t = 2000)]
+        requests: usize,
+        /// Fail when a query's p95 latency exceeds this
+        #[arg(long)]
+        max_p95_ms: Option<u64>,
+        #[arg(long, default_value = DEFAULT_TENANT)]
+        tenant: String,
+    },
+    /// Write a tenant's cats to stdout
+    Export {
+        #[arg(long, value_enum, default_value_t = ExportFormat::Csv)]
+        format: ExportFormat,
+        #[arg(long, default_value = DEFAULT_TENANT)]
+        tenant: String,
+    },
+    /// Write a backup of every tenant's data and the stored images, from
+    /// one consistent snapshot of the database
+    Backup {
+        #[arg(long, default_value = backup::FILE_NAME)]
+        out: PathBuf,
+    },
+    /// Replace all data with a backup, and restore its images. The
+    /// database must be migrated to the schema version of the backup
+    Restore {
+        #[arg(long = "in")]
+        input: PathBuf,
+        /// Confirm that all data is to be replaced
+        #[arg(long)]
+        yes: bool,
+    },
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ExportFormat {
+    Csv,
+    Json,
+}
+
+/// Runs an administrative command, anything but `serve`.
+pub fn run(command: Command) -> CliResult {
+    let pool = setup_database();
+    let mut connection = pool.get()?;
+    let config = Config::from_env();
+    match command {
+        Command::Serve => unreachable!("serve is handled by main"),
+        Command::Migrate => migrate(&mut connection),
+        Command::CreateAdmin { username, tenant } => {
+            create_admin(&mut connection, &tenant, &username)
+        }
+        Command::GcImages {
+            dry_run,
+            min_age_secs,
+        } => gc_images(
+            &mut connection,
+            &config.image_dir,
+            &config.image_variant_dir,
+            &config.upload_staging_dir,
+            Duration::from_secs(min_age_secs),
+            dry_run,
+        ),
+        Command::ArchiveImages {
+            older_than_days,
+            dry_run,
+        } => archive_images(&mut connection, older_than_days, dry_run),
+        Command::ShardImages { dry_run } => {
+            shard_images(&mut connection, &config.image_dir, dry_run)
+        }
+        Command::Reindex { batch_size } => {
+            reindex(&mut connection, &image_store(&config), batch_size)
+        }
+        Command::Seed { count, tenant } => {
+            let tenant = repository::find_tenant_by_slug(&mut connection, &tenant)
+                .map_err(|e| format!("Tenant {}: {}", tenant, e))?;
+            seed::seed(
+                &mut connection,
+                &image_store(&config),
+                &SystemClock,
+                &tenant,
+                count,
+            )?;
+            println!("Created {} sample cat(s) in {}", count, tenant.slug);
+            Ok(())
+        }
+        Command::SetQuota {
+            tenant,
+            max_cats,
+            max_storage_bytes,
+        } => {
+            let tenant = repository::find_tenant_by_slug(&mut connection, &tenant)
+                .map_err(|e| format!("Tenant {}: {}", tenant, e))?;
+            let quota = Quota {
+                max_cats,
+                max_storage_bytes,
+            };
+            repository::set_quota(&mut connection, tenant.id, quota)?;
+            println!("Updated the quota of {}", tenant.slug);
+            Ok(())
+        }
+        Command::ProfileLoad {
+            cats,
+            concurrency,
+            requests,
+            max_p95_ms,
+            tenant,
+        } => {
+            let tenant = repository::find_tenant_by_slug(&mut connection, &tenant)
+                .map_err(|e| format!("Tenant {}: {}", tenant, e))?;
+            drop(connection);
+            profile::run(
+                &pool,
+                &image_store(&config),
+                tenant,
+                cats,
+                concurrency,
+                requests,
+                max_p95_ms.map(Duration::from_millis),
+            )
+        }
+        Command::Export { format, tenant } => export(&mut connection, &tenant, format),
+        Command::Backup { out } => {
+            let file = fs::File::create(&out)?;
+            let written =
+                backup::write_backup(&mut connection, &config.image_dir, file, SystemClock.now())
+                    .and_then(|file| Ok(file.sync_all()?));
+            if let Err(e) = written {
+                fs::remove_file(&out)?;
+                return Err(e);
+            }
+            println!("Wrote backup to {}", out.display());
+            Ok(())
+        }
+        Command::Restore { input, yes } => {
+            if !yes {
+                return Err("Restoring replaces all data, pass --yes to go ahead".into());
+            }
+            let (manifest, images) = backup::restore(&mut connection, &config.image_dir, &input)?;
+            println!(
+                "Restored the backup taken at {} with {} image(s)",
+                manifest.created_at, images
+            );
+            Ok(())
+        }
+    }
+}
+
+/// The image store, sharded by date like the server's.
+fn image_store(config: &Config) -> LocalFileStore {
+    LocalFileStore::new(&config.image_dir).with_date_shards(config.image_date_shards)
+}
+
+fn migrate(connection: &mut PgConnection) -> CliResult {
+    let applied = connection.run_pending_migrations(MIGRATIONS)?;
+    for version in &applied {
+        println!("Applied migration {}", version);
+    }
+    if applied.is_empty() {
+        println!("Database is up to date");
+    }
+    Ok(())
+}
+
+fn read_admin_password() -> io::Result<String> {
+    if let Some(password) = secrets::get("CATDEX_ADMIN_PASSWORD") {
+        return Ok(password);
+    }
+    eprint!("Password: ");
+    io::stderr().flush()?;
+    let mut password = String::new();
+    io::stdin().lock().read_line(&mut password)?;
+    Ok(password.trim_end_matches(['\r', '\n']).to_string())
+}
+
+fn create_admin(connection: &mut PgConnection, tenant_slug: &str, username: &str) -> CliResult {
+    let tenant = repository::find_tenant_by_slug(connection, tenant_slug)
+        .map_err(|e| format!("Tenant {}: {}", tenant_slug, e))?;
+    let password = read_admin_password()?;
+    if password.chars().count() < MIN_ADMIN_PASSWORD_LENGTH {
+        return Err(format!(
+            "Password must be at least {} characters",
+            MIN_ADMIN_PASSWORD_LENGTH
+        )
+        .into());
+    }
+    // A v4 UUID is 16 bytes from the OS random number generator
+    let salt = SaltString::encode_b64(Uuid::new_v4().as_bytes()).map_err(|e| e.to_string())?;
+    let password_hash = Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map_err(|e| e.to_string())?
+        .to_string();
+
+    let new_admin = NewAdmin {
+        tenant_id: tenant.id,
+        username: username.to_string(),
+        password_hash,
+    };
+    match repository::insert_admin(connection, &new_admin)? {
+        Some(id) => {
+            println!("Created admin {} (ID: {}) in {}", username, id, tenant.slug);
+            Ok(())
+        }
+        None => Err(format!("Admin {} already exists in {}", username, tenant.slug).into()),
+    }
+}
+
+/// Lists the files under `dir`, descending into the per tenant directories.
+fn list_files(dir: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            files.extend(list_files(&path)?);
+        } else {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+/// Maps a file under the image directory `dir` to the path stored on its
+/// cat, e.g. `./image/acme/x.jpg` to `/image/acme/x.jpg`.
+fn stored_image_path(dir: &Path, file: &Path) -> Option<String> {
+    let key = file.strip_prefix(dir).ok()?.to_str()?;
+    Some(format!("/image/{}", key))
+}
+
+fn gc_images(
+    connection: &mut PgConnection,
+    dir: &Path,
+    variant_dir: &Path,
+    staging_dir: &Path,
+    min_age: Duration,
+    dry_run: bool,
+) -> CliResult {
+    // Completed uploads keep their images until they expire
+    if !dry_run {
+        let purged = uploads::purge_expired(connection, staging_dir, SystemClock.now())?;
+        println!("{} expired upload(s) deleted", purged);
+    }
+    let referenced: HashSet<String> = repository::list_image_paths(connection)?
+        .into_iter()
+        .collect();
+
+    let collected = collect_unreferenced(
+        list_files(dir)?,
+        |file| stored_image_path(dir, file),
+        &referenced,
+        min_age,
+        dry_run,
+    )?;
+    println!(
+        "{} unreferenced image(s){}",
+        collected,
+        if dry_run { " found" } else { " deleted" }
+    );
+    // Converted variants go along with their originals
+    let variants = match fs::exists(variant_dir)? {
+        true => list_files(variant_dir)?,
+        false => Vec::new(),
+    };
+    let collected = collect_unreferenced(
+        variants,
+        |file| {
+            let name = file.strip_prefix(variant_dir).ok()?.to_str()?;
+            Some(format!("/image/{}", image_variants::original_key(name)?))
+        },
+        &referenced,
+        min_age,
+        dry_run,
+    )?;
+    println!(
+        "{} image variant(s) of unreferenced images{}",
+        collected,
+        if dry_run { " found" } else { " deleted" }
+    );
+    Ok(())
+}
+
+/// Deletes, or with `dry_run` lists, the `files` at least `min_age` old
+/// whose stored path, as `stored_path` maps them to, is not `referenced`.
+/// Returns how many there were.
+fn collect_unreferenced(
+    files: Vec<PathBuf>,
+    stored_path: impl Fn(&Path) -> Option<String>,
+    referenced: &HashSet<String>,
+    min_age: Duration,
+    dry_run: bool,
+) -> io::Result<usize> {
+    let now = SystemTime::now();
+    let mut collected = 0;
+    for file in files {
+        if stored_path(&file).is_some_and(|path| referenced.contains(&path)) {
+            continue;
+        }
+        let age = fs::metadata(&file)?
+            .modified()
+            .ok()
+            .and_then(|modified| now.duration_since(modified).ok())
+            .unwrap_or_default();
+        if age < min_age {
+            continue;
+        }
+
+        if dry_run {
+            println!("Would delete {}", file.display());
+        } else {
+            fs::remove_file(&file)?;
+            println!("Deleted {}", file.display());
+        }
+        collected += 1;
+    }
+    Ok(collected)
+}
+
+fn archive_images(
+    connection: &mut PgConnection,
+    older_than_days: Option<u64>,
+    dry_run: bool,
+) -> CliResult {
+    let config = Config::from_env();
+    let older_than = older_than_days
+        .map(|days| Duration::from_secs(days * 24 * 60 * 60))
+        .or(config.archive_after)
+        .ok_or("Pass --older-than-days or set ARCHIVE_AFTER_DAYS")?;
+    let options = ArchiveOptions {
+        dry_run,
+        ..ArchiveOptions::from_config(&config, older_than)
+    };
+    let report = archival::archive_images(
+        connection,
+        &image_store(&config),
+        &options,
+        SystemClock.now(),
+    )?;
+    println!(
+        "{} image(s){}, reclaiming {} bytes, {} left as they were",
+        report.archived,
+        if dry_run { " to archive" } else { " archived" },
+        report.reclaimed_bytes,
+        report.kept
+    );
+    Ok(())
+}
+
+/// Moves each referenced image stored flat in its directory into the date
+/// shard of when it was stored, then points its references at it.
+fn shard_images(connection: &mut PgConnection, dir: &Path, dry_run: bool) -> CliResult {
+    let paths: BTreeSet<String> = repository::list_image_paths(connection)?
+        .into_iter()
+        .collect();
+
+    let mut moved = 0;
+    for path in paths {
+        let Some(key) = path.strip_prefix("/image/") else {
+            continue;
+        };
+        let file = dir.join(key);
+        let Ok(metadata) = fs::metadata(&file) else {
+            eprintln!("Skipping {}, which is not stored", path);
+            continue;
+        };
+        let stored_at: DateTime<Utc> = metadata.modified()?.into();
+        let Some(new_key) = image_shards::sharded(key, stored_at) else {
+            continue;
+        };
+        let new_path = format!("/image/{}", new_key);
+
+        if dry_run {
+            println!("Would move {} to {}", path, new_path);
+        } else {
+            let target = dir.join(&new_key);
+            if let Some(parent) = target.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::rename(&file, &target)?;
+            let renamed = connection.transaction(|connection| {
+                repository::rename_image_path(connection, &path, &new_path)
+            });
+            if let Err(e) = renamed {
+                fs::rename(&target, &file)?;
+                return Err(format!("Failed to move {}: {}", path, e).into());
+            }
+            println!("Moved {} to {}", path, new_path);
+        }
+        moved += 1;
+    }
+    println!(
+        "{} image(s){}",
+        moved,
+        if dry_run { " to move" } else { " moved" }
+    );
+    Ok(())
+}
+
+fn reindex(connection: &mut PgConnection, store: &dyn FileStore, batch_size: i64) -> CliResult {
+    if batch_size < 1 {
+        return Err("Batch size must be at least 1".into());
+    }
+    let (cats, photos) = rehash_images(connection, store, batch_size)?;
+    // Not in a transaction, which REINDEX CONCURRENTLY refuses to run in
+    println!("Rebuilding the indexes of the cats table");
+    repository::reindex_cats(connection)?;
+    println!("Refreshing the cat statistics");
+    repository::refresh_cat_stats(connection)?;
+    println!("Reindexed {} cat(s) and {} gallery photo(s)", cats, photos);
+    Ok(())
+}
+
+/// Recomputes the perceptual hashes of the cats' images, then of the other
+/// photos of their galleries. Returns how many cats and photos were
+/// checked.
+fn rehash_images(
+    connection: &mut PgConnection,
+    store: &dyn FileStore,
+    batch_size: i64,
+) -> Result<(usize, usize), Box<dyn Error + Send + Sync>> {
+    let cats = rehash_batches(
+        connection,
+        store,
+        batch_size,
+        "cat",
+        repository::list_cat_paths_after,
+        repository::set_cat_phash,
+    )?;
+    let photos = rehash_batches(
+        connection,
+        store,
+        batch_size,
+        "gallery photo",
+        repository::list_gallery_photos_after,
+        repository::set_cat_image_phash,
+    )?;
+    Ok((cats, photos))
+}
+
+/// Lists the ids and paths of up to `limit` images after `after_id`.
+type ListImagesAfter = fn(&mut PgConnection, i32, i64) -> QueryResult<Vec<(i32, String)>>;
+
+/// Recomputes the perceptual hashes of the images `list` walks,
+/// `batch_size` at a time, storing each batch with `set` in a short
+/// transaction of its own, so the table is never locked for long. Returns
+/// how many images were checked.
+fn rehash_batches(
+    connection: &mut PgConnection,
+    store: &dyn FileStore,
+    batch_size: i64,
+    kind: &str,
+    list: ListImagesAfter,
+    set: fn(&mut PgConnection, i32, Option<i64>) -> QueryResult<bool>,
+) -> Result<usize, Box<dyn Error + Send + Sync>> {
+    let (mut checked, mut changed, mut after_id) = (0, 0, 0);
+    loop {
+        let batch = list(connection, after_id, batch_size)?;
+        let Some(&(last_id, _)) = batch.last() else {
+            break;
+        };
+        let hashes: Vec<(i32, Option<i64>)> = batch
+            .iter()
+            .filter_map(|(row_id, path)| {
+                let key = path.strip_prefix("/image/")?;
+                match store.get(key) {
+                    Ok(contents) => Some((*row_id, similar::phash(&contents))),
+                    Err(e) => {
+                        eprintln!("Skipping {} ID {}, its image {}: {}", kind, row_id, path, e);
+                        None
+                    }
+                }
+            })
+            .collect();
+        changed += connection.transaction(|connection| {
+            let mut changed = 0;
+            for (row_id, phash) in &hashes {
+                if set(connection, *row_id, *phash)? {
+                    changed += 1;
+                }
+            }
+            Ok::<_, diesel::result::Error>(changed)
+        })?;
+        checked += batch.len();
+        after_id = last_id;
+        println!("Rehashed {} {}(s), {} changed", checked, kind, changed);
+    }
+    Ok(checked)
+}
+
+fn export(connection: &mut PgConnection, tenant_slug: &str, format: ExportFormat) -> CliResult {
+    let tenant = repository::find_tenant_by_slug(connection, tenant_slug)
+        .map_err(|e| format!("Tenant {}: {}", tenant_slug, e))?;
+    let cats = repository::list_cats(connection, tenant.id, None, i64::MAX)?;
+
+    let stdout = io::stdout().lock();
+    match format {
+        ExportFormat::Csv => {
+            let mut writer = csv::Writer::from_writer(stdout);
+            for cat in &cats {
+                writer.serialize(cat)?;
+            }
+            writer.flush()?;
+        }
+        ExportFormat::Json => {
+            serde_json::to_writer_pretty(stdout, &cats)?;
+            println!();
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stored_image_path() {
+        assert_eq!(
+            stored_image_path(Path::new("./image"), Path::new("./image/acme/cat.jpg")),
+            Some("/image/acme/cat.jpg".to_string())
+        );
+        assert_eq!(
+            stored_image_path(
+                Path::new("/srv/images"),
+                Path::new("/srv/images/acme/cat.jpg")
+            ),
+            Some("/image/acme/cat.jpg".to_string())
+        );
+    }
+
+    #[test]
+    fn test_gc_images_deletes_unreferenced_variants() {
+        use crate::models::NewCat;
+        use crate::test_support::{insert_cat_with, test_cat, test_pool};
+
+        let (images, variants, staging) = (
+            tempfile::tempdir().unwrap(),
+            tempfile::tempdir().unwrap(),
+            tempfile::tempdir().unwrap(),
+        );
+        let write = |path: PathBuf| {
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(&path, b"image").unwrap();
+            path
+        };
+        let kept = write(images.path().join("default/kept.jpg"));
+        let kept_variant = write(variants.path().join("default/kept.jpg.webp"));
+        let gone = write(images.path().join("default/gone.jpg"));
+        let gone_variant = write(variants.path().join("default/gone.jpg.webp"));
+
+        let pool = test_pool();
+        let mut connection = pool.get().unwrap();
+        let tenant = repository::find_tenant_by_slug(&mut connection, DEFAULT_TENANT).unwrap();
+        let new_cat = NewCat {
+            image_path: "/image/default/kept.jpg".to_string(),
+            ..test_cat()
+        };
+        insert_cat_with(&mut connection, tenant.id, &new_cat);
+
+        gc_images(
+            &mut connection,
+            images.path(),
+            variants.path(),
+            staging.path(),
+            Duration::ZERO,
+            false,
+        )
+        .unwrap();
+        assert!(kept.exists());
+        assert!(kept_variant.exists());
+        assert!(!gone.exists());
+        assert!(!gone_variant.exists());
+    }
+
+    #[test]
+    #[cfg(feature = "image-processing")]
+    fn test_rehash_images() {
+        use crate::models::{NewCat, NewCatImage};
+        use crate::schema::cat_images;
+        use crate::test_support::{insert_cat_with, test_cat, test_pool, MemoryFileStore};
+        use diesel::prelude::*;
+        use image::{ImageFormat, Rgb, RgbImage};
+        use std::io::Cursor;
+
+        let mut png = Vec::new();
+        RgbImage::from_fn(64, 64, |x, y| Rgb([(x * 4) as u8, (y * 4) as u8, 90]))
+            .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+            .unwrap();
+        let store = MemoryFileStore::default();
+        store
+            .put("default/rehash.png", &mut png.as_slice())
+            .unwrap();
+
+        let pool = test_pool();
+        let mut connection = pool.get().unwrap();
+        let tenant = repository::find_tenant_by_slug(&mut connection, DEFAULT_TENANT).unwrap();
+        let new_cat = NewCat {
+            image_path: "/image/default/rehash.png".to_string(),
+            image_size: png.len() as i64,
+            image_phash: Some(0),
+            ..test_cat()
+        };
+        let cat = insert_cat_with(&mut connection, tenant.id, &new_cat);
+        // The cat's image is mirrored as the primary photo of its gallery
+        let primary_id: i32 = cat_images::table
+            .filter(cat_images::cat_id.eq(cat.id))
+            .filter(cat_images::is_primary)
+            .select(cat_images::id)
+            .first(&mut connection)
+            .unwrap();
+        let new_image = NewCatImage {
+            tenant_id: tenant.id,
+            cat_id: cat.id,
+            image_path: new_cat.image_path.clone(),
+            image_hash: None,
+            image_size: png.len() as i64,
+            image_phash: Some(0),
+            position: 1,
+            is_primary: false,
+            created_at: Utc::now(),
+            image_original_format: None,
+        };
+        let photo_id = repository::insert_cat_image(&mut connection, &new_image)
+            .unwrap()
+            .id;
+
+        let (cats, photos) = rehash_images(&mut connection, &store, 1).unwrap();
+        assert!(cats >= 1);
+        assert!(photos >= 1);
+        assert_eq!(
+            repository::find_cat_phash(&mut connection, tenant.id, cat.id).unwrap(),
+            similar::phash(&png)
+        );
+        for image_id in [primary_id, photo_id] {
+            let phash: Option<i64> = cat_images::table
+                .find(image_id)
+                .select(cat_images::image_phash)
+                .first(&mut connection)
+                .unwrap();
+            assert_eq!(phash, similar::phash(&png));
+        }
+    }
+}
@@ -0,0 +1,900 @@
+//! A typed async client for the API, in builds with the `client` feature,
+//! so integrators need not hand-write its HTTP calls. It sends and receives
+//! the same structs the handlers do, so the two cannot drift apart:
+//!
+//! ```ignore
+//! let client = Client::new("https://catdex.example.com").tenant("acme");
+//! let image = client.upload_image("tom.jpg", bytes, false).await?;
+//! let created = client
+//!     .create_cat(&CreateCat {
+//!         name: "Tom".to_string(),
+//!         image_key: Some(image.image_key),
+//!         ..CreateCat::default()
+//!     })
+//!     .await?;
+//! let mut cats = client.cat_stream(None).await?;
+//! while let Some(cat) = cats.next().await {
+//!     println!("{}", cat?.name);
+//! }
+//! ```
+//!
+//! It covers the API's public routes: cats, the `add_cat` and `submit_cat`
+//! forms, records, relations, family, look-alikes and history, uploads
+//! including the tus protocol, the PDF catalogue, statistics, the version,
+//! webhooks and moderation. The other operational endpoints under
+//! `/api/admin` and `/api/health` are left to operators.
+pub use crate::adoption::SetCatStatus;
+pub use crate::family::{AddRelation, Family};
+pub use crate::gallery::{AddCatImage, ReorderCatImages};
+pub use crate::geo::{Location, NearbyCat};
+pub use crate::history::HistoryPage;
+pub use crate::models::{
+    Cat, CatChange, CatImage, CatRelation, CatRelationKind, CatStatus, CatTombstone, MedicalRecord,
+    MedicalRecordForm, MedicalRecordType, Webhook, WebhookDelivery,
+};
+pub use crate::quotas::QuotaStatus;
+pub use crate::similar::SimilarCat;
+pub use crate::stats::{DayCount, Summary};
+pub use crate::sync::CatChanges;
+pub use crate::uploads::{CreateUpload, UploadStatus};
+pub use crate::version::{Backends, Build, RuntimeInfo};
+pub use crate::webhooks::RegisterWebhook;
+pub use crate::{CreateCat, UploadedImage};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use derive_more::Display;
+use reqwest::header::{CONTENT_RANGE, CONTENT_TYPE, LOCATION};
+use reqwest::multipart::{Form, Part};
+use reqwest::{Method, RequestBuilder, Response, StatusCode};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::str::FromStr;
+use uuid::Uuid;
+
+const TUS_RESUMABLE: &str = "Tus-Resumable";
+const TUS_MAX_SIZE: &str = "Tus-Max-Size";
+const UPLOAD_LENGTH: &str = "Upload-Length";
+const UPLOAD_OFFSET: &str = "Upload-Offset";
+const UPLOAD_METADATA: &str = "Upload-Metadata";
+const OFFSET_CONTENT_TYPE: &str = "application/offset+octet-stream";
+
+#[derive(Display, Debug)]
+pub enum ClientError {
+    #[display(fmt = "Request failed: {}", _0)]
+    Http(reqwest::Error),
+    /// An error response, with the `msg` of its body.
+    #[display(fmt = "{}: {}", status, message)]
+    Api {
+        status: StatusCode,
+        message: String,
+        /// The whole body, with e.g. the field errors of a 422.
+        body: serde_json::Value,
+    },
+    #[display(fmt = "Invalid response: {}", _0)]
+    InvalidResponse(String),
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(e: reqwest::Error) -> ClientError {
+        ClientError::Http(e)
+    }
+}
+
+/// A new cat with the ids of cats whose images look near identical, which
+/// may be duplicates.
+#[derive(Debug)]
+pub struct CreatedCat {
+    pub cat: Cat,
+    pub similar_cat_ids: Vec<i32>,
+}
+
+/// The fields of the `add_cat` and `submit_cat` forms besides the image.
+#[derive(Default)]
+pub struct CatForm {
+    pub name: String,
+    /// Ignored by `submit_cat`, as submitted cats are public.
+    pub private: bool,
+    pub location: Option<Location>,
+}
+
+#[derive(Clone)]
+pub struct Client {
+    http: reqwest::Client,
+    base_url: String,
+    tenant: Option<String>,
+    credentials: Option<(String, String)>,
+}
+
+impl Client {
+    /// A client of the API at `base_url`, e.g. `https://catdex.example.com`.
+    pub fn new(base_url: impl Into<String>) -> Client {
+        Client {
+            http: reqwest::Client::new(),
+            base_url: base_url.into().trim_end_matches('/').to_string(),
+            tenant: None,
+            credentials: None,
+        }
+    }
+
+    /// Sends requests with `http`, e.g. to set timeouts or proxies.
+    pub fn with_http_client(mut self, http: reqwest::Client) -> Client {
+        self.http = http;
+        self
+    }
+
+    /// Sends requests to the tenant `slug` rather than the default one.
+    pub fn tenant(mut self, slug: impl Into<String>) -> Client {
+        self.tenant = Some(slug.into());
+        self
+    }
+
+    /// Authenticates as one of the tenant's admins, as needed to moderate.
+    pub fn basic_auth(
+        mut self,
+        username: impl Into<String>,
+        password: impl Into<String>,
+    ) -> Client {
+        self.credentials = Some((username.into(), password.into()));
+        self
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        let mut request = self
+            .http
+            .request(method, format!("{}{}", self.base_url, path));
+        if let Some(tenant) = &self.tenant {
+            request = request.header(crate::tenants::TENANT_HEADER, tenant);
+        }
+        if let Some((username, password)) = &self.credentials {
+            request = request.basic_auth(username, Some(password));
+        }
+        request
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str) -> Result<T, ClientError> {
+        json(send(self.request(Method::GET, path)).await?).await
+    }
+
+    async fn send_json<T: DeserializeOwned>(
+        &self,
+        method: Method,
+        path: &str,
+        body: &impl Serialize,
+    ) -> Result<T, ClientError> {
+        json(send(self.request(method, path).json(body)).await?).await
+    }
+
+    async fn delete(&self, path: &str) -> Result<(), ClientError> {
+        send(self.request(Method::DELETE, path)).await.map(drop)
+    }
+
+    /// Up to 100 of the tenant's cats, with the given status if any. See
+    /// `cat_stream` for all of them.
+    pub async fn cats(&self, status: Option<CatStatus>) -> Result<Vec<Cat>, ClientError> {
+        let request = self
+            .request(Method::GET, "/api/cats")
+            .query(&[("status", status.map(CatStatus::as_str))]);
+        json(send(request).await?).await
+    }
+
+    /// Every cat of the tenant, with the given status if any, read as the
+    /// server streams them.
+    pub async fn cat_stream(&self, status: Option<CatStatus>) -> Result<CatStream, ClientError> {
+        let request = self
+            .request(Method::GET, "/api/cats/stream")
+            .query(&[("status", status.map(CatStatus::as_str))]);
+        Ok(CatStream {
+            response: send(request).await?,
+            buffer: Vec::new(),
+            cats: VecDeque::new(),
+        })
+    }
+
+    /// The cats changed and deleted since `since`, with the token for the
+    /// next `sync`.
+    pub async fn changes_since(&self, since: DateTime<Utc>) -> Result<CatChanges, ClientError> {
+        let request = self
+            .request(Method::GET, "/api/cats")
+            .query(&[("modified_since", since.to_rfc3339())]);
+        json(send(request).await?).await
+    }
+
+    /// The cats changed and deleted since the sync that returned `token`.
+    pub async fn sync(&self, token: &str) -> Result<CatChanges, ClientError> {
+        let request = self
+            .request(Method::GET, "/api/cats")
+            .query(&[("sync_token", token)]);
+        json(send(request).await?).await
+    }
+
+    pub async fn cat(&self, id: i32) -> Result<Cat, ClientError> {
+        self.get(&format!("/api/cat/{}", id)).await
+    }
+
+    pub async fn cat_by_public_id(&self, public_id: Uuid) -> Result<Cat, ClientError> {
+        self.get(&format!("/api/cat/uuid/{}", public_id)).await
+    }
+
+    /// Creates a cat, failing with a 409 when the tenant has one by the
+    /// same name and does not allow duplicates.
+    pub async fn create_cat(&self, cat: &CreateCat) -> Result<CreatedCat, ClientError> {
+        self.post_cat(cat, false).await
+    }
+
+    /// Creates a cat even when the tenant has one by the same name.
+    pub async fn create_duplicate_cat(&self, cat: &CreateCat) -> Result<CreatedCat, ClientError> {
+        self.post_cat(cat, true).await
+    }
+
+    async fn post_cat(
+        &self,
+        cat: &CreateCat,
+        allow_duplicates: bool,
+    ) -> Result<CreatedCat, ClientError> {
+        let request = self
+            .request(Method::POST, "/api/cats")
+            .query(&[("allow_duplicates", allow_duplicates)])
+            .json(cat);
+        let response = send(request).await?;
+        let similar_cat_ids = similar_cat_ids(&response);
+        Ok(CreatedCat {
+            cat: json(response).await?,
+            similar_cat_ids,
+        })
+    }
+
+    /// Creates a cat through the `add_cat` form, sending its image along. The
+    /// form's response carries no cat, so this returns the ids of cats whose
+    /// images look near identical. Unless `allow_duplicates`, fails with a
+    /// 409 like `create_cat`.
+    pub async fn add_cat(
+        &self,
+        form: &CatForm,
+        file_name: &str,
+        image: Vec<u8>,
+        allow_duplicates: bool,
+    ) -> Result<Vec<i32>, ClientError> {
+        let request = self
+            .request(Method::POST, "/api/add_cat")
+            .query(&[("allow_duplicates", allow_duplicates)])
+            .multipart(cat_form(form, file_name, image));
+        Ok(similar_cat_ids(&send(request).await?))
+    }
+
+    /// Submits a cat for moderation through the public `submit_cat` form,
+    /// with the token of a solved CAPTCHA. Fails with a 404 when the server
+    /// takes no public submissions.
+    pub async fn submit_cat(
+        &self,
+        form: &CatForm,
+        file_name: &str,
+        image: Vec<u8>,
+        captcha_token: &str,
+    ) -> Result<(), ClientError> {
+        let multipart =
+            cat_form(form, file_name, image).text("captcha_token", captcha_token.to_string());
+        let request = self
+            .request(Method::POST, "/api/submit_cat")
+            .multipart(multipart);
+        send(request).await.map(drop)
+    }
+
+    /// Up to 100 cats within `radius_km` of `location`, or the server's
+    /// default radius, nearest first.
+    pub async fn nearby(
+        &self,
+        location: Location,
+        radius_km: Option<f64>,
+    ) -> Result<Vec<NearbyCat>, ClientError> {
+        let request = self
+            .request(Method::GET, "/api/cats/nearby")
+            .query(&[("lat", location.latitude), ("lon", location.longitude)])
+            .query(&[("radius_km", radius_km)]);
+        json(send(request).await?).await
+    }
+
+    /// The catalogue of the tenant's cats, with the given status if any, as
+    /// a PDF. Fails with a 404 when the server is built without `pdf`.
+    pub async fn export_pdf(&self, status: Option<CatStatus>) -> Result<Vec<u8>, ClientError> {
+        let request = self
+            .request(Method::GET, "/api/cats/export.pdf")
+            .query(&[("status", status.map(CatStatus::as_str))]);
+        Ok(send(request).await?.bytes().await?.to_vec())
+    }
+
+    /// Stores an image for `create_cat` to reference by its key.
+    pub async fn upload_image(
+        &self,
+        file_name: &str,
+        image: Vec<u8>,
+        private: bool,
+    ) -> Result<UploadedImage, ClientError> {
+        let form = Form::new()
+            .text("private", private.to_string())
+            .part("image", Part::bytes(image).file_name(file_name.to_string()));
+        json(send(self.request(Method::POST, "/api/images").multipart(form)).await?).await
+    }
+
+    /// Starts a resumable upload, for images too large to send at once.
+    pub async fn create_upload(&self, upload: &CreateUpload) -> Result<UploadStatus, ClientError> {
+        self.send_json(Method::POST, "/api/uploads", upload).await
+    }
+
+    pub async fn upload_status(&self, token: Uuid) -> Result<UploadStatus, ClientError> {
+        self.get(&format!("/api/uploads/{}", token)).await
+    }
+
+    /// Sends the bytes of `upload` from `offset` on. A chunk not starting at
+    /// `received` is refused with a 409.
+    pub async fn upload_chunk(
+        &self,
+        upload: &UploadStatus,
+        offset: i64,
+        chunk: Vec<u8>,
+    ) -> Result<UploadStatus, ClientError> {
+        let last = offset + chunk.len() as i64 - 1;
+        let range = format!("bytes {}-{}/{}", offset, last, upload.size);
+        let request = self
+            .request(Method::PUT, &upload.upload_url)
+            .header(CONTENT_RANGE, range)
+            .body(chunk);
+        json(send(request).await?).await
+    }
+
+    /// The largest upload the tus endpoint takes, in bytes.
+    pub async fn tus_max_size(&self) -> Result<i64, ClientError> {
+        let response = send(self.tus_request(Method::OPTIONS, "/api/tus")).await?;
+        header_value(&response, TUS_MAX_SIZE)
+    }
+
+    /// Creates a tus upload of `size` bytes, returning its token for
+    /// `CreateCat::upload_token` once all are sent.
+    pub async fn tus_create(
+        &self,
+        size: i64,
+        content_type: &str,
+        private: bool,
+    ) -> Result<Uuid, ClientError> {
+        let metadata = format!(
+            "filetype {},private {}",
+            STANDARD.encode(content_type),
+            STANDARD.encode(private.to_string())
+        );
+        let request = self
+            .tus_request(Method::POST, "/api/tus")
+            .header(UPLOAD_LENGTH, size)
+            .header(UPLOAD_METADATA, metadata);
+        let location: String = header_value(&send(request).await?, LOCATION.as_str())?;
+        location
+            .rsplit('/')
+            .next()
+            .and_then(|token| token.parse().ok())
+            .ok_or_else(|| ClientError::InvalidResponse(format!("Invalid Location {}", location)))
+    }
+
+    /// The offset to resume the tus upload from.
+    pub async fn tus_offset(&self, token: Uuid) -> Result<i64, ClientError> {
+        let path = format!("/api/tus/{}", token);
+        let response = send(self.tus_request(Method::HEAD, &path)).await?;
+        header_value(&response, UPLOAD_OFFSET)
+    }
+
+    /// Appends `chunk` to the tus upload at `offset`, which must be its
+    /// current offset, returning the new one.
+    pub async fn tus_append(
+        &self,
+        token: Uuid,
+        offset: i64,
+        chunk: Vec<u8>,
+    ) -> Result<i64, ClientError> {
+        let request = self
+            .tus_request(Method::PATCH, &format!("/api/tus/{}", token))
+            .header(CONTENT_TYPE, OFFSET_CONTENT_TYPE)
+            .header(UPLOAD_OFFSET, offset)
+            .body(chunk);
+        header_value(&send(request).await?, UPLOAD_OFFSET)
+    }
+
+    /// A request with the `Tus-Resumable` version the server speaks.
+    fn tus_request(&self, method: Method, path: &str) -> RequestBuilder {
+        self.request(method, path)
+            .header(TUS_RESUMABLE, crate::tus::TUS_VERSION)
+    }
+
+    pub async fn set_status(&self, id: i32, status: CatStatus) -> Result<Cat, ClientError> {
+        let path = format!("/api/cat/{}/status", id);
+        self.send_json(Method::POST, &path, &SetCatStatus { status })
+            .await
+    }
+
+    pub async fn set_location(&self, id: i32, location: Location) -> Result<Cat, ClientError> {
+        let path = format!("/api/cat/{}/location", id);
+        self.send_json(Method::PUT, &path, &location).await
+    }
+
+    pub async fn clear_location(&self, id: i32) -> Result<Cat, ClientError> {
+        let path = format!("/api/cat/{}/location", id);
+        json(send(self.request(Method::DELETE, &path)).await?).await
+    }
+
+    /// A page of the cat's changes, oldest first, after the change `after`.
+    pub async fn history(&self, id: i32, after: Option<i64>) -> Result<HistoryPage, ClientError> {
+        let request = self
+            .request(Method::GET, &format!("/api/cat/{}/history", id))
+            .query(&[("after", after)]);
+        json(send(request).await?).await
+    }
+
+    /// Every change to the cat, following the history's pages.
+    pub async fn full_history(&self, id: i32) -> Result<Vec<CatChange>, ClientError> {
+        let mut changes = Vec::new();
+        let mut after = None;
+        loop {
+            let page = self.history(id, after).await?;
+            changes.extend(page.changes);
+            match page.next_after {
+                Some(next) => after = Some(next),
+                None => return Ok(changes),
+            }
+        }
+    }
+
+    pub async fn add_relation(
+        &self,
+        id: i32,
+        relation: &AddRelation,
+    ) -> Result<CatRelation, ClientError> {
+        let path = format!("/api/cat/{}/relations", id);
+        self.send_json(Method::POST, &path, relation).await
+    }
+
+    pub async fn delete_relation(&self, id: i32, relation_id: i32) -> Result<(), ClientError> {
+        self.delete(&format!("/api/cat/{}/relations/{}", id, relation_id))
+            .await
+    }
+
+    /// The cat's relatives up to `depth` relations away, which the server
+    /// caps.
+    pub async fn family(&self, id: i32, depth: Option<u32>) -> Result<Family, ClientError> {
+        let request = self
+            .request(Method::GET, &format!("/api/cat/{}/family", id))
+            .query(&[("depth", depth)]);
+        json(send(request).await?).await
+    }
+
+    /// The cats whose images look like the cat's, most alike first, their
+    /// perceptual hashes differing in at most `max_distance` bits.
+    pub async fn similar(
+        &self,
+        id: i32,
+        max_distance: Option<i32>,
+    ) -> Result<Vec<SimilarCat>, ClientError> {
+        let request = self
+            .request(Method::GET, &format!("/api/cat/{}/similar", id))
+            .query(&[("max_distance", max_distance)]);
+        json(send(request).await?).await
+    }
+
+    /// The photos of the cat's gallery, in order.
+    pub async fn gallery(&self, id: i32) -> Result<Vec<CatImage>, ClientError> {
+        self.get(&format!("/api/cat/{}/images", id)).await
+    }
+
+    /// Adds an image stored with `upload_image` to the cat's gallery.
+    pub async fn add_image(&self, id: i32, image: &AddCatImage) -> Result<CatImage, ClientError> {
+        let path = format!("/api/cat/{}/images", id);
+        self.send_json(Method::POST, &path, image).await
+    }
+
+    /// Puts the gallery in the order of `image_ids`, which lists each of its
+    /// photos once.
+    pub async fn reorder_images(
+        &self,
+        id: i32,
+        image_ids: Vec<i32>,
+    ) -> Result<Vec<CatImage>, ClientError> {
+        let path = format!("/api/cat/{}/images", id);
+        self.send_json(Method::PUT, &path, &ReorderCatImages { image_ids })
+            .await
+    }
+
+    /// Makes the photo the cat's image.
+    pub async fn set_primary_image(&self, id: i32, image_id: i32) -> Result<Cat, ClientError> {
+        let path = format!("/api/cat/{}/images/{}/primary", id, image_id);
+        json(send(self.request(Method::POST, &path)).await?).await
+    }
+
+    pub async fn delete_image(&self, id: i32, image_id: i32) -> Result<(), ClientError> {
+        self.delete(&format!("/api/cat/{}/images/{}", id, image_id))
+            .await
+    }
+
+    pub async fn records(&self, id: i32) -> Result<Vec<MedicalRecord>, ClientError> {
+        self.get(&format!("/api/cat/{}/records", id)).await
+    }
+
+    pub async fn record(&self, id: i32, record_id: i32) -> Result<MedicalRecord, ClientError> {
+        self.get(&format!("/api/cat/{}/records/{}", id, record_id))
+            .await
+    }
+
+    /// Adds a record to the cat, with an attachment given as its file name
+    /// and contents.
+    pub async fn add_record(
+        &self,
+        id: i32,
+        form: &MedicalRecordForm,
+        attachment: Option<(&str, Vec<u8>)>,
+    ) -> Result<MedicalRecord, ClientError> {
+        let request = self
+            .request(Method::POST, &format!("/api/cat/{}/records", id))
+            .multipart(record_form(form, attachment));
+        json(send(request).await?).await
+    }
+
+    /// Replaces the record's fields, and its attachment when given one.
+    pub async fn update_record(
+        &self,
+        id: i32,
+        record_id: i32,
+        form: &MedicalRecordForm,
+        attachment: Option<(&str, Vec<u8>)>,
+    ) -> Result<MedicalRecord, ClientError> {
+        let path = format!("/api/cat/{}/records/{}", id, record_id);
+        let request = self
+            .request(Method::PUT, &path)
+            .multipart(record_form(form, attachment));
+        json(send(request).await?).await
+    }
+
+    pub async fn delete_record(&self, id: i32, record_id: i32) -> Result<(), ClientError> {
+        self.delete(&format!("/api/cat/{}/records/{}", id, record_id))
+            .await
+    }
+
+    pub async fn quota(&self) -> Result<QuotaStatus, ClientError> {
+        self.get("/api/quota").await
+    }
+
+    /// The cats added over the last `days` days, by status and by day.
+    pub async fn stats_summary(&self, days: Option<u32>) -> Result<Summary, ClientError> {
+        let request = self
+            .request(Method::GET, "/api/stats/summary")
+            .query(&[("days", days)]);
+        json(send(request).await?).await
+    }
+
+    /// The server's build and the backends it runs on.
+    pub async fn version(&self) -> Result<RuntimeInfo, ClientError> {
+        self.get("/api/version").await
+    }
+
+    /// Registers a webhook. The returned webhook carries the secret its
+    /// deliveries are signed with, which is not handed out again. Like the
+    /// other webhook calls, needs `basic_auth`.
+    pub async fn register_webhook(
+        &self,
+        webhook: &RegisterWebhook,
+    ) -> Result<Webhook, ClientError> {
+        self.send_json(Method::POST, "/api/webhooks", webhook).await
+    }
+
+    pub async fn webhooks(&self) -> Result<Vec<Webhook>, ClientError> {
+        self.get("/api/webhooks").await
+    }
+
+    pub async fn delete_webhook(&self, id: i32) -> Result<(), ClientError> {
+        self.delete(&format!("/api/webhooks/{}", id)).await
+    }
+
+    pub async fn webhook_deliveries(&self, id: i32) -> Result<Vec<WebhookDelivery>, ClientError> {
+        self.get(&format!("/api/webhooks/{}/deliveries", id)).await
+    }
+
+    /// Cats awaiting moderation, oldest first. Needs `basic_auth`.
+    pub async fn pending_cats(&self) -> Result<Vec<Cat>, ClientError> {
+        self.get("/api/admin/moderation").await
+    }
+
+    pub async fn approve_cat(&self, id: i32) -> Result<Cat, ClientError> {
+        let path = format!("/api/admin/moderation/{}/approve", id);
+        json(send(self.request(Method::POST, &path)).await?).await
+    }
+
+    pub async fn reject_cat(&self, id: i32) -> Result<(), ClientError> {
+        let path = format!("/api/admin/moderation/{}/reject", id);
+        send(self.request(Method::POST, &path)).await.map(drop)
+    }
+}
+
+/// The tenant's cats as `Client::cat_stream` receives them.
+pub struct CatStream {
+    response: Response,
+    /// Received bytes of a line not yet complete.
+    buffer: Vec<u8>,
+    cats: VecDeque<Cat>,
+}
+
+impl CatStream {
+    /// The next cat, or none once all are read.
+    pub async fn next(&mut self) -> Option<Result<Cat, ClientError>> {
+        while self.cats.is_empty() {
+            match self.response.chunk().await {
+                Ok(Some(chunk)) => self.buffer.extend_from_slice(&chunk),
+                Ok(None) if self.buffer.is_empty() => return None,
+                Ok(None) => {
+                    let message = "Stream ends within a line".to_string();
+                    return Some(Err(ClientError::InvalidResponse(message)));
+                }
+                Err(e) => return Some(Err(e.into())),
+            }
+            while let Some(end) = self.buffer.iter().position(|byte| *byte == b'\n') {
+                let line: Vec<u8> = self.buffer.drain(..=end).collect();
+                match serde_json::from_slice(&line) {
+                    Ok(cat) => self.cats.push_back(cat),
+                    Err(e) => return Some(Err(ClientError::InvalidResponse(e.to_string()))),
+                }
+            }
+        }
+        self.cats.pop_front().map(Ok)
+    }
+}
+
+/// The multipart form `add_record` and `update_record` send.
+fn record_form(form: &MedicalRecordForm, attachment: Option<(&str, Vec<u8>)>) -> Form {
+    let mut multipart = Form::new()
+        .text("record_type", form.record_type.as_str())
+        .text("date", form.date.to_string())
+        .text("notes", form.notes.clone());
+    if let Some(due_date) = form.due_date {
+        multipart = multipart.text("due_date", due_date.to_string());
+    }
+    if let Some((file_name, contents)) = attachment {
+        let part = Part::bytes(contents).file_name(file_name.to_string());
+        multipart = multipart.part("attachment", part);
+    }
+    multipart
+}
+
+/// The multipart form `add_cat` and `submit_cat` send.
+fn cat_form(form: &CatForm, file_name: &str, image: Vec<u8>) -> Form {
+    let mut multipart = Form::new()
+        .text("name", form.name.clone())
+        .text("private", form.private.to_string());
+    if let Some(location) = form.location {
+        multipart = multipart
+            .text("latitude", location.latitude.to_string())
+            .text("longitude", location.longitude.to_string());
+    }
+    multipart.part("image", Part::bytes(image).file_name(file_name.to_string()))
+}
+
+/// The ids of the `SIMILAR_HEADER` of a new cat's response.
+fn similar_cat_ids(response: &Response) -> Vec<i32> {
+    response
+        .headers()
+        .get(crate::similar::SIMILAR_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(|ids| {
+            ids.split(',')
+                .filter_map(|id| id.trim().parse().ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+/// The `name` header of `response`, parsed.
+fn header_value<T: FromStr>(response: &Response, name: &str) -> Result<T, ClientError> {
+    response
+        .headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| ClientError::InvalidResponse(format!("Missing or invalid {}", name)))
+}
+
+/// Sends `request`, turning an error status into `ClientError::Api`.
+async fn send(request: RequestBuilder) -> Result<Response, ClientError> {
+    let response = request.send().await?;
+    let status = response.status();
+    if status.is_success() {
+        return Ok(response);
+    }
+    let body: serde_json::Value = response.json().await.unwrap_or_default();
+    let message = body["msg"]
+        .as_str()
+        .or(status.canonical_reason())
+        .unwrap_or_default()
+        .to_string();
+    Err(ClientError::Api {
+        status,
+        message,
+        body,
+    })
+}
+
+async fn json<T: DeserializeOwned>(response: Response) -> Result<T, ClientError> {
+    let bytes = response.bytes().await?;
+    serde_json::from_slice(&bytes).map_err(|e| ClientError::InvalidResponse(e.to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{test_app_builder, test_pool, FakeCaptcha, MemoryFileStore};
+    use actix_web::HttpServer;
+    use chrono::NaiveDate;
+    use std::sync::Arc;
+
+    /// Serves a test app on a free port, returning a client of it.
+    fn serve() -> Client {
+        let app = test_app_builder(test_pool(), Arc::new(MemoryFileStore::default()))
+            .captcha_verifier(Arc::new(FakeCaptcha))
+            .build();
+        let server = HttpServer::new(move || app.app())
+            .workers(1)
+            .bind(("127.0.0.1", 0))
+            .unwrap();
+        let addr = server.addrs()[0];
+        actix_rt::spawn(server.run());
+        Client::new(format!("http://{}/", addr))
+    }
+
+    #[actix_web::test]
+    async fn test_client() {
+        let client = serve();
+        let image = client
+            .upload_image("tom.jpg", b"jpeg".to_vec(), false)
+            .await
+            .unwrap();
+        let name = format!("Client cat {}", Uuid::new_v4());
+        let created = client
+            .create_cat(&CreateCat {
+                name: name.clone(),
+                image_key: Some(image.image_key),
+                ..CreateCat::default()
+            })
+            .await
+            .unwrap();
+        let cat = created.cat;
+        assert_eq!(cat.name, name);
+
+        let found = client.cat_by_public_id(cat.public_id).await.unwrap();
+        assert_eq!(found.id, cat.id);
+        let cats = client.cats(Some(CatStatus::Available)).await.unwrap();
+        assert!(cats.iter().any(|listed| listed.id == cat.id));
+        let mut stream = client.cat_stream(None).await.unwrap();
+        let mut streamed = Vec::new();
+        while let Some(listed) = stream.next().await {
+            streamed.push(listed.unwrap().id);
+        }
+        assert!(streamed.contains(&cat.id));
+
+        let cat = client.set_status(cat.id, CatStatus::Pending).await.unwrap();
+        assert_eq!(cat.status, CatStatus::Pending);
+        let changes = client.full_history(cat.id).await.unwrap();
+        assert!(changes.iter().any(|change| change.field == "status"));
+        let photo = client
+            .upload_image("side.jpg", b"side".to_vec(), false)
+            .await
+            .unwrap();
+        let photo = client
+            .add_image(
+                cat.id,
+                &AddCatImage {
+                    image_key: photo.image_key,
+                    primary: false,
+                },
+            )
+            .await
+            .unwrap();
+        let first = client.gallery(cat.id).await.unwrap()[0].id;
+        let gallery = client
+            .reorder_images(cat.id, vec![photo.id, first])
+            .await
+            .unwrap();
+        assert_eq!(gallery[0].id, photo.id);
+        let with_photo = client.set_primary_image(cat.id, photo.id).await.unwrap();
+        assert_eq!(with_photo.image_path, photo.image_path);
+        client.delete_image(cat.id, first).await.unwrap();
+        assert_eq!(client.gallery(cat.id).await.unwrap().len(), 1);
+
+        let synced = client.changes_since(cat.updated_at).await.unwrap();
+        assert!(synced.cats.iter().any(|changed| changed.id == cat.id));
+        client.sync(&synced.sync_token).await.unwrap();
+
+        let mut form = MedicalRecordForm {
+            record_type: MedicalRecordType::Vaccination,
+            date: NaiveDate::from_ymd_opt(2024, 3, 1).unwrap(),
+            due_date: None,
+            notes: "Rabies".to_string(),
+        };
+        let record = client.add_record(cat.id, &form, None).await.unwrap();
+        form.notes = "Rabies, booster due".to_string();
+        let record = client
+            .update_record(cat.id, record.id, &form, None)
+            .await
+            .unwrap();
+        assert_eq!(record.notes, form.notes);
+        assert_eq!(client.records(cat.id).await.unwrap().len(), 1);
+        client.delete_record(cat.id, record.id).await.unwrap();
+
+        let family = client.family(cat.id, None).await.unwrap();
+        assert_eq!(family.root, cat.id);
+        assert!(family.cats.iter().any(|relative| relative.id == cat.id));
+        client.similar(cat.id, Some(4)).await.unwrap();
+        let location = Location {
+            latitude: 51.5,
+            longitude: -0.12,
+        };
+        client.set_location(cat.id, location).await.unwrap();
+        let nearby = client.nearby(location, Some(1.0)).await.unwrap();
+        assert!(nearby.iter().any(|near| near.cat.id == cat.id));
+
+        let form = CatForm {
+            name: format!("Form cat {}", Uuid::new_v4()),
+            private: false,
+            location: Some(location),
+        };
+        client
+            .add_cat(&form, "form.jpg", b"form".to_vec(), false)
+            .await
+            .unwrap();
+        let cats = client.cats(None).await.unwrap();
+        assert!(cats.iter().any(|listed| listed.name == form.name));
+        let submission = CatForm {
+            name: format!("Submitted cat {}", Uuid::new_v4()),
+            ..CatForm::default()
+        };
+        client
+            .submit_cat(&submission, "stray.jpg", b"stray".to_vec(), "solved")
+            .await
+            .unwrap();
+
+        let tus_image = b"tus image".to_vec();
+        assert!(client.tus_max_size().await.unwrap() >= tus_image.len() as i64);
+        let token = client
+            .tus_create(tus_image.len() as i64, "image/jpeg", false)
+            .await
+            .unwrap();
+        assert_eq!(client.tus_offset(token).await.unwrap(), 0);
+        let offset = client
+            .tus_append(token, 0, tus_image.clone())
+            .await
+            .unwrap();
+        assert_eq!(offset, tus_image.len() as i64);
+        client
+            .create_cat(&CreateCat {
+                name: format!("Tus cat {}", Uuid::new_v4()),
+                upload_token: Some(token),
+                ..CreateCat::default()
+            })
+            .await
+            .unwrap();
+
+        let summary = client.stats_summary(None).await.unwrap();
+        assert_eq!(summary.by_status.len(), 3);
+        let version = client.version().await.unwrap();
+        assert_eq!(version.build.version, env!("CARGO_PKG_VERSION"));
+        if cfg!(feature = "pdf") {
+            let pdf = client.export_pdf(None).await.unwrap();
+            assert!(pdf.starts_with(b"%PDF"));
+        }
+
+        let without_image = CreateCat {
+            name,
+            ..CreateCat::default()
+        };
+        match client.create_cat(&without_image).await {
+            Err(ClientError::Api { status, body, .. }) => {
+                assert_eq!(status, StatusCode::BAD_REQUEST);
+                assert!(body["msg"].is_string());
+            }
+            other => panic!("Expected an error response, got {:?}", other),
+        }
+    }
+}
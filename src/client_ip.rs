@@ -0,0 +1,169 @@
+use crate::config::Config;
+use crate::errors::UserError;
+use actix_web::body::MessageBody;
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderMap, FORWARDED, X_FORWARDED_FOR};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, FromRequest, HttpMessage, HttpRequest};
+use log::error;
+use std::future::{ready, Ready};
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+/// The address of the client that sent the request, as opposed to the
+/// reverse proxy it came through.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ClientIp(pub IpAddr);
+
+/// Parses one address out of a forwarding header, accepting an optional
+/// port and the quoted, bracketed IPv6 form of `Forwarded`.
+fn parse_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim().trim_matches('"');
+    node.parse()
+        .ok()
+        .or_else(|| node.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| node.strip_prefix('[')?.strip_suffix(']')?.parse().ok())
+}
+
+/// The addresses a request was forwarded for, client first. `Forwarded`
+/// wins over `X-Forwarded-For` when both are present. `None` stands for an
+/// address that could not be parsed, such as an obfuscated identifier.
+fn forwarded_chain(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let forwarded: Vec<&str> = headers
+        .get_all(FORWARDED)
+        .filter_map(|value| value.to_str().ok())
+        .collect();
+    if !forwarded.is_empty() {
+        return forwarded
+            .iter()
+            .flat_map(|value| value.split(','))
+            .filter_map(|element| {
+                element.split(';').find_map(|pair| {
+                    let (name, value) = pair.split_once('=')?;
+                    name.trim()
+                        .eq_ignore_ascii_case("for")
+                        .then(|| parse_node(value))
+                })
+            })
+            .collect();
+    }
+    headers
+        .get_all(X_FORWARDED_FOR)
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(parse_node)
+        .collect()
+}
+
+/// Works out the client address of a request that reached us from `peer`.
+///
+/// Forwarding headers are only believed when `peer` is a trusted proxy.
+/// The chain is then walked from the nearest hop outwards and the first
+/// address that is not a trusted proxy is the client, so a client cannot
+/// spoof its address by sending the headers itself.
+fn resolve(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpAddr]) -> IpAddr {
+    if !trusted_proxies.contains(&peer) {
+        return peer;
+    }
+    let mut client = peer;
+    for hop in forwarded_chain(headers).into_iter().rev() {
+        match hop {
+            Some(ip) => {
+                client = ip;
+                if !trusted_proxies.contains(&ip) {
+                    break;
+                }
+            }
+            None => break,
+        }
+    }
+    client
+}
+
+/// Middleware storing the `ClientIp` of every request, for handlers and
+/// the access log.
+pub async fn resolve_client_ip(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let trusted_proxies = req
+        .app_data::<web::Data<Config>>()
+        .map(|config| config.trusted_proxies.as_slice())
+        .unwrap_or_default();
+    // Requests over a Unix socket have no peer address, they come from a
+    // proxy on this host
+    let peer = req
+        .peer_addr()
+        .map_or(IpAddr::V4(Ipv4Addr::LOCALHOST), |peer| peer.ip());
+    let client_ip = resolve(peer, req.headers(), trusted_proxies);
+    req.extensions_mut().insert(ClientIp(client_ip));
+    next.call(req).await
+}
+
+/// The client address for the `%{client_ip}xi` access log field.
+pub fn access_log_value(req: &ServiceRequest) -> String {
+    match req.extensions().get::<ClientIp>() {
+        Some(ClientIp(ip)) => ip.to_string(),
+        None => "-".to_string(),
+    }
+}
+
+impl FromRequest for ClientIp {
+    type Error = UserError;
+    type Future = Ready<Result<ClientIp, UserError>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(req.extensions().get::<ClientIp>().copied().ok_or_else(|| {
+            error!("Client IP was not resolved for this request");
+            UserError::UnexpectedError
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::HeaderValue;
+
+    fn ip(value: &str) -> IpAddr {
+        value.parse().unwrap()
+    }
+
+    fn headers(name: &str, value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name.parse().unwrap(), HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    #[test]
+    fn test_untrusted_peer_is_the_client() {
+        let headers = headers("x-forwarded-for", "1.2.3.4");
+        assert_eq!(resolve(ip("10.0.0.1"), &headers, &[]), ip("10.0.0.1"));
+    }
+
+    #[test]
+    fn test_x_forwarded_for_skips_trusted_hops() {
+        let trusted = [ip("10.0.0.1"), ip("10.0.0.2")];
+        let headers = headers("x-forwarded-for", "6.6.6.6, 1.2.3.4, 10.0.0.2");
+        assert_eq!(resolve(ip("10.0.0.1"), &headers, &trusted), ip("1.2.3.4"));
+    }
+
+    #[test]
+    fn test_forwarded_header() {
+        let trusted = [ip("10.0.0.1")];
+        let headers = headers(
+            "forwarded",
+            r#"for=1.2.3.4;proto=https, for="[2001:db8::1]:4711""#,
+        );
+        assert_eq!(
+            resolve(ip("10.0.0.1"), &headers, &trusted),
+            ip("2001:db8::1")
+        );
+    }
+
+    #[test]
+    fn test_unparsable_hop_stops_the_walk() {
+        let trusted = [ip("10.0.0.1")];
+        let headers = headers("forwarded", "for=1.2.3.4, for=_hidden");
+        assert_eq!(resolve(ip("10.0.0.1"), &headers, &trusted), ip("10.0.0.1"));
+    }
+}
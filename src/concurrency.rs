@@ -0,0 +1,234 @@
+//! Limits on how many requests to expensive routes run at once, so uploads
+//! and exports cannot take up the whole blocking thread pool and stall
+//! reads. Requests over a route's limit queue for up to
+//! `ROUTE_QUEUE_TIMEOUT_MS`, then get a 503 with a `Retry-After`.
+//!
+//! Limits are set with `ROUTE_CONCURRENCY`, as comma separated
+//! `[METHOD ]<route pattern>=<limit>`, e.g. `POST /api/cats=8`. Without a
+//! method the limit applies to every method of the route. A permit is held
+//! until the response body is sent, so streamed exports count throughout.
+use crate::errors::UserError;
+use actix_web::body::{BodySize, BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::web::Bytes;
+use actix_web::{web, Error};
+use log::warn;
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+pub const DEFAULT_ROUTE_CONCURRENCY: &str = "POST /api/add_cat=8, POST /api/cats=8, \
+     POST /api/submit_cat=8, POST /api/images=8, PUT /api/uploads/{token}=8, PATCH /api/tus/{token}=8, \
+     GET /api/cats/stream=4, GET /api/cats/export.pdf=1, GET /api/admin/backup=1";
+/// Seconds a rejected client is told to wait, about as long as an upload
+/// takes to free its permit.
+const RETRY_AFTER_SECS: u64 = 5;
+
+/// The limit of one route, or one method of it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RouteLimit {
+    pub method: Option<Method>,
+    pub pattern: String,
+    pub limit: usize,
+}
+
+impl RouteLimit {
+    fn parse(value: &str) -> Result<RouteLimit, String> {
+        let invalid = || {
+            format!(
+                "Invalid route limit {:?}, expected [METHOD ]route=limit",
+                value
+            )
+        };
+        let (route, limit) = value.rsplit_once('=').ok_or_else(invalid)?;
+        let limit = limit
+            .trim()
+            .parse()
+            .ok()
+            .filter(|limit| *limit > 0)
+            .ok_or_else(invalid)?;
+        let (method, pattern) = match route.trim().split_once(' ') {
+            Some((method, pattern)) => (
+                Some(Method::from_bytes(method.as_bytes()).map_err(|_| invalid())?),
+                pattern.trim(),
+            ),
+            None => (None, route.trim()),
+        };
+        if !pattern.starts_with('/') {
+            return Err(invalid());
+        }
+        Ok(RouteLimit {
+            method,
+            pattern: pattern.to_string(),
+            limit,
+        })
+    }
+
+    /// Parses a comma separated list of limits.
+    pub fn parse_all(value: &str) -> Result<Vec<RouteLimit>, String> {
+        value
+            .split(',')
+            .map(str::trim)
+            .filter(|limit| !limit.is_empty())
+            .map(RouteLimit::parse)
+            .collect()
+    }
+}
+
+/// The permits of each limited route, shared by every worker.
+pub struct RouteLimits {
+    semaphores: HashMap<(Option<Method>, String), Arc<Semaphore>>,
+    queue_timeout: Duration,
+}
+
+impl RouteLimits {
+    pub fn new(limits: &[RouteLimit], queue_timeout: Duration) -> RouteLimits {
+        let semaphores = limits
+            .iter()
+            .map(|limit| {
+                let key = (limit.method.clone(), limit.pattern.clone());
+                (key, Arc::new(Semaphore::new(limit.limit)))
+            })
+            .collect();
+        RouteLimits {
+            semaphores,
+            queue_timeout,
+        }
+    }
+
+    /// The semaphore limiting `method` requests to the route `pattern`, if
+    /// any. A limit on the method wins over one on the whole route.
+    fn semaphore(&self, method: &Method, pattern: &str) -> Option<Arc<Semaphore>> {
+        self.semaphores
+            .get(&(Some(method.clone()), pattern.to_string()))
+            .or_else(|| self.semaphores.get(&(None, pattern.to_string())))
+            .cloned()
+    }
+}
+
+/// A response body holding a permit until it is sent.
+struct PermittedBody {
+    body: BoxBody,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl MessageBody for PermittedBody {
+    type Error = Box<dyn std::error::Error>;
+
+    fn size(&self) -> BodySize {
+        self.body.size()
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        Pin::new(&mut self.get_mut().body).poll_next(cx)
+    }
+}
+
+/// Middleware holding requests to limited routes until a permit is free.
+pub async fn limit(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse, Error> {
+    let semaphore = req
+        .app_data::<web::Data<RouteLimits>>()
+        .zip(req.match_pattern())
+        .and_then(|(limits, pattern)| {
+            let semaphore = limits.semaphore(req.method(), &pattern)?;
+            Some((semaphore, limits.queue_timeout, pattern))
+        });
+    let Some((semaphore, queue_timeout, pattern)) = semaphore else {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_boxed_body);
+    };
+
+    let permit = match actix_rt::time::timeout(queue_timeout, semaphore.acquire_owned()).await {
+        Ok(Ok(permit)) => permit,
+        // Only closed semaphores fail, which these never are
+        Ok(Err(_)) | Err(_) => {
+            warn!("Rejected {} {}, the route is busy", req.method(), pattern);
+            return Ok(req
+                .error_response(UserError::BusyError(RETRY_AFTER_SECS))
+                .map_into_boxed_body());
+        }
+    };
+    let res = next.call(req).await?.map_into_boxed_body();
+    Ok(res.map_body(|_, body| {
+        BoxBody::new(PermittedBody {
+            body,
+            _permit: permit,
+        })
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::RETRY_AFTER;
+    use actix_web::http::StatusCode;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_service, init_service, read_body, TestRequest};
+    use actix_web::{App, HttpResponse};
+
+    #[test]
+    fn test_parse_all() {
+        let limits = RouteLimit::parse_all("POST /api/cats=8, /api/cat/{id}/similar = 2,").unwrap();
+        assert_eq!(
+            limits,
+            vec![
+                RouteLimit {
+                    method: Some(Method::POST),
+                    pattern: "/api/cats".to_string(),
+                    limit: 8,
+                },
+                RouteLimit {
+                    method: None,
+                    pattern: "/api/cat/{id}/similar".to_string(),
+                    limit: 2,
+                },
+            ]
+        );
+        assert!(RouteLimit::parse_all(DEFAULT_ROUTE_CONCURRENCY).is_ok());
+        assert!(RouteLimit::parse_all("/api/cats=0").is_err());
+        assert!(RouteLimit::parse_all("POST api/cats=1").is_err());
+        assert!(RouteLimit::parse_all("/api/cats").is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_limit() {
+        let limits = RouteLimit::parse_all("GET /slow=1").unwrap();
+        let limits = web::Data::new(RouteLimits::new(&limits, Duration::from_millis(50)));
+        let app = init_service(
+            App::new()
+                .app_data(limits.clone())
+                .wrap(from_fn(limit))
+                .route(
+                    "/slow",
+                    web::get().to(|| async { HttpResponse::Ok().body("done") }),
+                )
+                .route("/slow", web::post().to(HttpResponse::Ok)),
+        )
+        .await;
+
+        // A response not yet sent holds the only permit
+        let held = call_service(&app, TestRequest::get().uri("/slow").to_request()).await;
+        let resp = call_service(&app, TestRequest::get().uri("/slow").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers().get(RETRY_AFTER).unwrap(), "5");
+        let resp = call_service(&app, TestRequest::post().uri("/slow").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        assert_eq!(read_body(held).await, "done");
+        let resp = call_service(&app, TestRequest::get().uri("/slow").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+    }
+}
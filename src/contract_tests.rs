@@ -0,0 +1,341 @@
+//! Contract tests: every endpoint's JSON must read back as the model types
+//! the client shares with the handlers, and those must write back every
+//! field as it was sent. A renamed, retyped or dropped field of e.g. `Cat`
+//! fails here rather than in integrators' code.
+use crate::config::{Config, Settings};
+use crate::history::HistoryPage;
+use crate::models::{
+    Cat, CatChange, CatImage, CatRelation, CatTombstone, MedicalRecord, NewCat, NewWebhookDelivery,
+    Webhook, WebhookDelivery,
+};
+use crate::quotas::QuotaStatus;
+use crate::reload::LiveSettings;
+use crate::repository;
+use crate::sync::CatChanges;
+use crate::test_support::{
+    insert_cat_with, insert_test_admin, multipart_body, multipart_form, test_app_builder, test_cat,
+    test_pool, MemoryFileStore, ADMIN_AUTHORIZATION,
+};
+use crate::uploads::UploadStatus;
+use crate::{DbPool, UploadedImage};
+use actix_http::Request;
+use actix_web::body::MessageBody;
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::http::header::{AUTHORIZATION, CONTENT_TYPE};
+use actix_web::{test, web, Error};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::any::type_name;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Reads `json` as a `T`, checking that writing it back gives each of its
+/// fields present and unchanged. Members starting with `_`, such as
+/// `_links`, are hypermedia rather than part of the types.
+fn assert_contract<T: DeserializeOwned + Serialize>(json: &Value) -> T {
+    let item: T = serde_json::from_value(json.clone())
+        .unwrap_or_else(|e| panic!("{} does not read as {}: {}", json, type_name::<T>(), e));
+    let written = serde_json::to_value(&item).unwrap();
+    for (field, value) in written.as_object().expect("Models write objects") {
+        if field.starts_with('_') {
+            continue;
+        }
+        assert_eq!(
+            json.get(field),
+            Some(value),
+            "{}.{} differs in {}",
+            type_name::<T>(),
+            field,
+            json
+        );
+    }
+    item
+}
+
+/// `assert_contract` for each item of a non-empty array.
+fn assert_contract_each<T: DeserializeOwned + Serialize>(json: &Value) -> Vec<T> {
+    let items = json.as_array().expect("A JSON array");
+    assert!(!items.is_empty(), "Nothing to check the contract of");
+    items.iter().map(assert_contract).collect()
+}
+
+/// An app rejecting duplicate names, so name conflicts can be checked.
+async fn test_app(
+    pool: DbPool,
+    config: Config,
+) -> impl Service<Request, Response = ServiceResponse<impl MessageBody>, Error = Error> {
+    let mut settings = Settings::load(None).unwrap();
+    settings.unique_cat_names = true;
+    let app = test_app_builder(pool, Arc::new(MemoryFileStore::default()))
+        .config(config)
+        .settings(web::Data::new(LiveSettings::new(settings, None)))
+        .build();
+    test::init_service(app.app()).await
+}
+
+/// Stores an image through the API, returning its key.
+async fn upload_image(
+    app: &impl Service<Request, Response = ServiceResponse<impl MessageBody>, Error = Error>,
+) -> String {
+    let (content_type, body) = multipart_body(&[], b"jpeg");
+    let req = test::TestRequest::post()
+        .uri("/api/images")
+        .insert_header((CONTENT_TYPE, content_type))
+        .set_payload(body)
+        .to_request();
+    let image: UploadedImage = assert_contract(&test::call_and_read_body_json(app, req).await);
+    image.image_key
+}
+
+/// Creates a cat through the API, as integrators do.
+async fn create_cat(
+    app: &impl Service<Request, Response = ServiceResponse<impl MessageBody>, Error = Error>,
+) -> Cat {
+    let req = test::TestRequest::post()
+        .uri("/api/cats")
+        .set_json(json!({
+            "name": format!("Contract cat {}", Uuid::new_v4()),
+            "image_key": upload_image(app).await,
+            "latitude": 50.45,
+            "longitude": 30.52,
+        }))
+        .to_request();
+    assert_contract(&test::call_and_read_body_json(app, req).await)
+}
+
+#[actix_web::test]
+async fn test_cat_contracts() {
+    let app = test_app(test_pool(), Config::from_env()).await;
+    let cat = create_cat(&app).await;
+
+    let req = test::TestRequest::get().uri("/api/cats").to_request();
+    let cats: Vec<Cat> = assert_contract_each(&test::call_and_read_body_json(&app, req).await);
+    assert!(cats.iter().any(|listed| listed.id == cat.id));
+
+    let req = test::TestRequest::get()
+        .uri("/api/cats/stream")
+        .to_request();
+    let body = test::call_and_read_body(&app, req).await;
+    let lines = std::str::from_utf8(&body).unwrap().lines();
+    let streamed: Vec<Cat> = lines
+        .map(|line| assert_contract(&serde_json::from_str(line).unwrap()))
+        .collect();
+    assert!(streamed.iter().any(|listed| listed.id == cat.id));
+
+    let uri = format!("/api/cat/uuid/{}", cat.public_id);
+    let req = test::TestRequest::get().uri(&uri).to_request();
+    let found: Cat = assert_contract(&test::call_and_read_body_json(&app, req).await);
+    assert_eq!(found.id, cat.id);
+
+    let req = test::TestRequest::post()
+        .uri(&format!("/api/cat/{}/status", cat.id))
+        .set_json(json!({"status": "pending"}))
+        .to_request();
+    assert_contract::<Cat>(&test::call_and_read_body_json(&app, req).await);
+    let req = test::TestRequest::delete()
+        .uri(&format!("/api/cat/{}/location", cat.id))
+        .to_request();
+    assert_contract::<Cat>(&test::call_and_read_body_json(&app, req).await);
+
+    let req = test::TestRequest::get()
+        .uri(&format!("/api/cat/{}/history", cat.id))
+        .to_request();
+    let page: Value = test::call_and_read_body_json(&app, req).await;
+    assert_contract::<HistoryPage>(&page);
+    assert_contract_each::<CatChange>(&page["changes"]);
+
+    let related = create_cat(&app).await;
+    let req = test::TestRequest::post()
+        .uri(&format!("/api/cat/{}/relations", cat.id))
+        .set_json(json!({"related_cat_id": related.id, "kind": "littermate"}))
+        .to_request();
+    assert_contract::<CatRelation>(&test::call_and_read_body_json(&app, req).await);
+
+    let images = format!("/api/cat/{}/images", cat.id);
+    let req = test::TestRequest::post()
+        .uri(&images)
+        .set_json(json!({"image_key": upload_image(&app).await}))
+        .to_request();
+    let photo: CatImage = assert_contract(&test::call_and_read_body_json(&app, req).await);
+    let req = test::TestRequest::get().uri(&images).to_request();
+    let gallery: Vec<CatImage> =
+        assert_contract_each(&test::call_and_read_body_json(&app, req).await);
+    let req = test::TestRequest::put()
+        .uri(&images)
+        .set_json(json!({"image_ids": [photo.id, gallery[0].id]}))
+        .to_request();
+    assert_contract_each::<CatImage>(&test::call_and_read_body_json(&app, req).await);
+    let req = test::TestRequest::post()
+        .uri(&format!("{}/{}/primary", images, photo.id))
+        .to_request();
+    assert_contract::<Cat>(&test::call_and_read_body_json(&app, req).await);
+
+    let req = test::TestRequest::get().uri("/api/quota").to_request();
+    assert_contract::<QuotaStatus>(&test::call_and_read_body_json(&app, req).await);
+
+    // Name conflicts carry the existing cat
+    let req = test::TestRequest::post()
+        .uri("/api/cats")
+        .set_json(json!({"name": cat.name, "image_key": upload_image(&app).await}))
+        .to_request();
+    let conflict: Value = test::call_and_read_body_json(&app, req).await;
+    assert_contract::<Cat>(&conflict["conflict"]);
+}
+
+#[actix_web::test]
+async fn test_sync_contracts() {
+    let pool = test_pool();
+    let app = test_app(pool.clone(), Config::from_env()).await;
+    let cat = create_cat(&app).await;
+    let deleted = create_cat(&app).await;
+    repository::delete_cat(&mut pool.get().unwrap(), deleted.id).unwrap();
+
+    let since = cat
+        .updated_at
+        .to_rfc3339_opts(chrono::SecondsFormat::Micros, true);
+    let req = test::TestRequest::get()
+        .uri(&format!("/api/cats?modified_since={}", since))
+        .to_request();
+    let changes: Value = test::call_and_read_body_json(&app, req).await;
+    assert_contract::<CatChanges>(&changes);
+    let cats: Vec<Cat> = assert_contract_each(&changes["cats"]);
+    assert!(cats.iter().any(|changed| changed.id == cat.id));
+    let tombstones: Vec<CatTombstone> = assert_contract_each(&changes["deleted"]);
+    assert!(tombstones
+        .iter()
+        .any(|tombstone| tombstone.id == deleted.id));
+}
+
+#[actix_web::test]
+async fn test_record_contracts() {
+    let app = test_app(test_pool(), Config::from_env()).await;
+    let cat = create_cat(&app).await;
+    let fields = [
+        ("record_type", "vaccination"),
+        ("date", "2025-06-01"),
+        ("due_date", "2026-06-01"),
+        ("notes", "Rabies"),
+    ];
+
+    let (content_type, body) = multipart_form(&fields, &[]);
+    let req = test::TestRequest::post()
+        .uri(&format!("/api/cat/{}/records", cat.id))
+        .insert_header((CONTENT_TYPE, content_type))
+        .set_payload(body)
+        .to_request();
+    let record: MedicalRecord = assert_contract(&test::call_and_read_body_json(&app, req).await);
+
+    let (content_type, body) = multipart_form(&fields, &[("attachment", "rabies.pdf", b"%PDF")]);
+    let uri = format!("/api/cat/{}/records/{}", cat.id, record.id);
+    let req = test::TestRequest::put()
+        .uri(&uri)
+        .insert_header((CONTENT_TYPE, content_type))
+        .set_payload(body)
+        .to_request();
+    assert_contract::<MedicalRecord>(&test::call_and_read_body_json(&app, req).await);
+
+    let req = test::TestRequest::get().uri(&uri).to_request();
+    assert_contract::<MedicalRecord>(&test::call_and_read_body_json(&app, req).await);
+    let req = test::TestRequest::get()
+        .uri(&format!("/api/cat/{}/records", cat.id))
+        .to_request();
+    assert_contract_each::<MedicalRecord>(&test::call_and_read_body_json(&app, req).await);
+}
+
+#[actix_web::test]
+async fn test_upload_contracts() {
+    let staging = tempfile::tempdir().unwrap();
+    let mut config = Config::from_env();
+    config.upload_staging_dir = staging.path().to_path_buf();
+    let app = test_app(test_pool(), config).await;
+    let req = test::TestRequest::post()
+        .uri("/api/uploads")
+        .set_json(json!({"size": 4, "content_type": "image/jpeg"}))
+        .to_request();
+    let upload: UploadStatus = assert_contract(&test::call_and_read_body_json(&app, req).await);
+
+    let req = test::TestRequest::get()
+        .uri(&upload.upload_url)
+        .to_request();
+    assert_contract::<UploadStatus>(&test::call_and_read_body_json(&app, req).await);
+    let req = test::TestRequest::put()
+        .uri(&upload.upload_url)
+        .set_payload(&b"jpeg"[..])
+        .to_request();
+    let upload: UploadStatus = assert_contract(&test::call_and_read_body_json(&app, req).await);
+    assert!(upload.complete);
+}
+
+#[actix_web::test]
+async fn test_webhook_contracts() {
+    let pool = test_pool();
+    {
+        let mut connection = pool.get().unwrap();
+        let tenant = repository::find_tenant_by_slug(&mut connection, "default").unwrap();
+        insert_test_admin(&mut connection, tenant.id);
+    }
+    let app = test_app(pool.clone(), Config::from_env()).await;
+    let req = test::TestRequest::post()
+        .uri("/api/webhooks")
+        .insert_header((AUTHORIZATION, ADMIN_AUTHORIZATION))
+        .set_json(json!({"url": "https://93.184.216.34/hook"}))
+        .to_request();
+    let webhook: Webhook = assert_contract(&test::call_and_read_body_json(&app, req).await);
+    assert!(!webhook.secret.is_empty());
+
+    let req = test::TestRequest::get()
+        .uri("/api/webhooks")
+        .insert_header((AUTHORIZATION, ADMIN_AUTHORIZATION))
+        .to_request();
+    assert_contract_each::<Webhook>(&test::call_and_read_body_json(&app, req).await);
+
+    // Deliveries are only made by the dispatcher
+    let delivery = NewWebhookDelivery {
+        webhook_id: webhook.id,
+        event: "cat.created".to_string(),
+        payload: json!({"id": 1}),
+        attempt: 1,
+        status_code: Some(500),
+        error: None,
+        delivered: false,
+    };
+    repository::insert_webhook_delivery(&mut pool.get().unwrap(), &delivery).unwrap();
+    let req = test::TestRequest::get()
+        .uri(&format!("/api/webhooks/{}/deliveries", webhook.id))
+        .insert_header((AUTHORIZATION, ADMIN_AUTHORIZATION))
+        .to_request();
+    assert_contract_each::<WebhookDelivery>(&test::call_and_read_body_json(&app, req).await);
+}
+
+#[actix_web::test]
+async fn test_moderation_contracts() {
+    let pool = test_pool();
+    let cat_id = {
+        let mut connection = pool.get().unwrap();
+        let tenant = repository::find_tenant_by_slug(&mut connection, "default").unwrap();
+        insert_test_admin(&mut connection, tenant.id);
+        let new_cat = NewCat {
+            image_path: "/image/default/pending.jpg".to_string(),
+            image_size: 4,
+            pending_review: true,
+            ..test_cat()
+        };
+        insert_cat_with(&mut connection, tenant.id, &new_cat).id
+    };
+    let app = test_app(pool, Config::from_env()).await;
+
+    let req = test::TestRequest::get()
+        .uri("/api/admin/moderation")
+        .insert_header((AUTHORIZATION, ADMIN_AUTHORIZATION))
+        .to_request();
+    let queue: Vec<Cat> = assert_contract_each(&test::call_and_read_body_json(&app, req).await);
+    assert!(queue.iter().any(|cat| cat.id == cat_id));
+
+    let req = test::TestRequest::post()
+        .uri(&format!("/api/admin/moderation/{}/approve", cat_id))
+        .insert_header((AUTHORIZATION, ADMIN_AUTHORIZATION))
+        .to_request();
+    let cat: Cat = assert_contract(&test::call_and_read_body_json(&app, req).await);
+    assert!(!cat.pending_review);
+}
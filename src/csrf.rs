@@ -0,0 +1,95 @@
+//! CSRF protection for state-changing requests a browser sends along with
+//! the session cookie. They must carry the session's CSRF token, in the
+//! `X-CSRF-Token` header, the `csrf_token` query parameter or, for
+//! url-encoded forms, the `csrf_token` field.
+//!
+//! Requests with an `Authorization` header authenticate by it rather than
+//! the cookie, and requests without the cookie have no session to abuse,
+//! so both pass unchecked.
+use crate::errors::UserError;
+use crate::sessions;
+use actix_session::SessionExt;
+use actix_web::body::MessageBody;
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::error::PayloadError;
+use actix_web::http::header::{ContentType, AUTHORIZATION, CONTENT_TYPE};
+use actix_web::middleware::Next;
+use actix_web::mime::Mime;
+use actix_web::{web, Error, HttpRequest};
+use futures_util::stream;
+use log::warn;
+use std::collections::HashMap;
+
+/// The CSRF token in the request's header or query string, if any.
+pub fn request_token(req: &HttpRequest) -> Option<String> {
+    let header = req
+        .headers()
+        .get(sessions::CSRF_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    header.or_else(|| {
+        web::Query::<HashMap<String, String>>::from_query(req.query_string())
+            .ok()
+            .and_then(|query| query.into_inner().remove(sessions::CSRF_FIELD))
+    })
+}
+
+/// The CSRF token in a url-encoded form body, if any.
+fn form_token(body: &[u8]) -> Option<String> {
+    let body = std::str::from_utf8(body).ok()?;
+    web::Query::<HashMap<String, String>>::from_query(body)
+        .ok()?
+        .into_inner()
+        .remove(sessions::CSRF_FIELD)
+}
+
+fn is_form(req: &ServiceRequest) -> bool {
+    req.headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<Mime>().ok())
+        .is_some_and(|mime| mime.essence_str() == ContentType::form_url_encoded().essence_str())
+}
+
+/// Middleware rejecting state-changing requests with the session cookie but
+/// without its CSRF token. Must be wrapped inside the session middleware.
+pub async fn protect(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse, Error> {
+    let exempt = req.method().is_safe()
+        || req.headers().contains_key(AUTHORIZATION)
+        || req.cookie(sessions::SESSION_COOKIE).is_none();
+    if exempt {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_boxed_body);
+    }
+
+    let mut token = request_token(req.request());
+    if token.is_none() && is_form(&req) {
+        // Read the form for its token, and put it back for the handler
+        let body = req.extract::<web::Bytes>().await?;
+        token = form_token(&body);
+        let restored = stream::once(async move { Ok::<_, PayloadError>(body) });
+        req.set_payload(Payload::Stream {
+            payload: Box::pin(restored),
+        });
+    }
+    if !sessions::verify_csrf(&req.get_session(), token.as_deref()) {
+        warn!(
+            "Rejected {} {} without a valid CSRF token",
+            req.method(),
+            req.path()
+        );
+        // Rendered here rather than returned, so outer middleware such as
+        // error localization still sees the response
+        return Ok(req
+            .error_response(UserError::CsrfError)
+            .map_into_boxed_body());
+    }
+    next.call(req)
+        .await
+        .map(ServiceResponse::map_into_boxed_body)
+}
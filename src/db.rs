@@ -0,0 +1,172 @@
+//! Database access for handlers. `DbConn` extracts a pooled connection,
+//! and `DbConn::run` runs queries with it on the blocking thread pool, so
+//! failures are mapped to `UserError`s and logged the same way everywhere.
+//!
+//! The extractor holds its connection for the whole request. Handlers that
+//! receive uploads or call out to other services first take one later with
+//! `DbConn::get`, so slow clients do not tie up the pool.
+//!
+//! Work is given up once the request it serves is gone, see `deadline`.
+use crate::deadline::{self, RequestScope};
+use crate::errors::UserError;
+use crate::telemetry;
+use crate::DbPool;
+use actix_web::dev::Payload;
+use actix_web::{web, FromRequest, HttpRequest};
+use diesel::r2d2::{ConnectionManager, PooledConnection};
+use diesel::{PgConnection, QueryResult, RunQueryDsl};
+use futures_util::future::{ready, Ready};
+use log::{error, warn};
+use std::ops::{Deref, DerefMut};
+use std::time::Duration;
+
+pub struct DbConn(PooledConnection<ConnectionManager<PgConnection>>);
+
+impl DbConn {
+    /// Takes a connection from `pool`.
+    pub fn get(pool: &DbPool) -> Result<DbConn, UserError> {
+        pool.get().map(DbConn).map_err(|e| {
+            error!("Failed to get DB connection from pool: {}", e);
+            UserError::DBPoolGetError
+        })
+    }
+
+    /// Runs `query` with the connection on the blocking thread pool. A
+    /// failing query is logged as failing to `action`, e.g. "list cats",
+    /// and answered as `query_error` does. Postgres cancels the statements
+    /// still running when the request's deadline passes.
+    pub async fn run<T, F>(mut self, action: &str, query: F) -> Result<T, UserError>
+    where
+        F: FnOnce(&mut PgConnection) -> QueryResult<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        block_within(move |left| match left {
+            Some(left) => with_statement_timeout(&mut self, left, query),
+            None => query(&mut self),
+        })
+        .await?
+        .map_err(|e| query_error(e, action))
+    }
+}
+
+/// Runs `query` with Postgres cancelling any statement that runs longer
+/// than `timeout`.
+fn with_statement_timeout<T>(
+    connection: &mut PgConnection,
+    timeout: Duration,
+    query: impl FnOnce(&mut PgConnection) -> QueryResult<T>,
+) -> QueryResult<T> {
+    diesel::sql_query(format!(
+        "SET statement_timeout = {}",
+        timeout.as_millis().max(1)
+    ))
+    .execute(connection)?;
+    let result = query(connection);
+    if let Err(e) = diesel::sql_query("RESET statement_timeout").execute(connection) {
+        warn!("Failed to reset the statement timeout: {}", e);
+    }
+    result
+}
+
+impl Deref for DbConn {
+    type Target = PgConnection;
+
+    fn deref(&self) -> &PgConnection {
+        &self.0
+    }
+}
+
+impl DerefMut for DbConn {
+    fn deref_mut(&mut self) -> &mut PgConnection {
+        &mut self.0
+    }
+}
+
+impl FromRequest for DbConn {
+    type Error = UserError;
+    type Future = Ready<Result<DbConn, UserError>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(match req.app_data::<web::Data<DbPool>>() {
+            Some(pool) => DbConn::get(pool),
+            None => {
+                error!("No DbPool configured");
+                Err(UserError::UnexpectedError)
+            }
+        })
+    }
+}
+
+/// Logs a failure to `action` and maps it to `NotFoundError` when a row it
+/// looked up is missing, `DeadlineExceededError` when it ran out of time,
+/// or `UnexpectedError` otherwise.
+pub fn query_error(e: diesel::result::Error, action: &str) -> UserError {
+    match e {
+        diesel::result::Error::NotFound => {
+            warn!("Failed to {}: not found", action);
+            UserError::NotFoundError
+        }
+        diesel::result::Error::DatabaseError(_, info)
+            if info.message().contains("statement timeout") =>
+        {
+            warn!("Failed to {}: the request ran out of time", action);
+            deadline::cancelled("deadline")
+        }
+        e => {
+            error!("Failed to {}: {}", action, e);
+            UserError::UnexpectedError
+        }
+    }
+}
+
+/// Runs `run` on the blocking thread pool like `telemetry::block`,
+/// answering a failure to run it with `UnexpectedError`. Skips it when the
+/// request it serves is gone by the time a thread is free.
+pub async fn block<F, R>(run: F) -> Result<R, UserError>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    block_within(|_| run()).await
+}
+
+/// Like `block`, giving `run` the time left until the request's deadline.
+async fn block_within<F, R>(run: F) -> Result<R, UserError>
+where
+    F: FnOnce(Option<Duration>) -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let scope = RequestScope::current();
+    telemetry::block(move || {
+        let left = match scope {
+            Some(scope) => scope.remaining()?,
+            None => None,
+        };
+        Ok(run(left))
+    })
+    .await
+    .map_err(|_| {
+        error!("Blocking Thread Pool Error");
+        UserError::UnexpectedError
+    })?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::repository;
+    use crate::test_support::test_pool;
+    use actix_web::test::TestRequest;
+
+    #[actix_web::test]
+    async fn test_db_conn() {
+        let (req, mut payload) = TestRequest::get()
+            .app_data(web::Data::new(test_pool()))
+            .to_http_parts();
+        let connection = DbConn::from_request(&req, &mut payload).await.unwrap();
+        connection.run("ping", repository::ping).await.unwrap();
+
+        let (req, mut payload) = TestRequest::get().to_http_parts();
+        assert!(DbConn::from_request(&req, &mut payload).await.is_err());
+    }
+}
@@ -0,0 +1,185 @@
+//! How long the work a request starts on the blocking thread pool may
+//! run. A task a request queued is dropped unrun, returning its database
+//! connection to the pool, once the client has disconnected or the request
+//! is older than `REQUEST_DEADLINE_MS`. Queries already running are
+//! cancelled by Postgres when the deadline passes, through
+//! `statement_timeout`. Abandoned work is counted in
+//! `catdex_cancelled_db_tasks_total`.
+use crate::config::Config;
+use crate::errors::UserError;
+use crate::metrics::CANCELLED_DB_TASKS;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, Error};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+tokio::task_local! {
+    static CURRENT: RequestScope;
+}
+
+/// What the blocking tasks of a request know of it.
+#[derive(Clone, Debug)]
+pub struct RequestScope {
+    deadline: Option<Instant>,
+    /// Set once the request is dropped unanswered.
+    gone: Arc<AtomicBool>,
+}
+
+impl RequestScope {
+    /// The scope of the request being handled, if any.
+    pub fn current() -> Option<RequestScope> {
+        CURRENT.try_with(RequestScope::clone).ok()
+    }
+
+    /// The time left until the deadline, if there is one. Once the client
+    /// has gone or the deadline has passed, counts the task as cancelled
+    /// and errors instead.
+    pub fn remaining(&self) -> Result<Option<Duration>, UserError> {
+        if self.gone.load(Ordering::Relaxed) {
+            return Err(cancelled("disconnected"));
+        }
+        match self.deadline {
+            None => Ok(None),
+            Some(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                Some(left) if !left.is_zero() => Ok(Some(left)),
+                _ => Err(cancelled("deadline")),
+            },
+        }
+    }
+}
+
+/// Counts work abandoned for `reason`, `disconnected` or `deadline`, and
+/// answers it with `DeadlineExceededError`.
+pub fn cancelled(reason: &str) -> UserError {
+    CANCELLED_DB_TASKS.with_label_values(&[reason]).inc();
+    UserError::DeadlineExceededError
+}
+
+/// Marks a request gone when dropped before it is answered, as the server
+/// does with the requests of clients that disconnect.
+struct Disconnect {
+    gone: Arc<AtomicBool>,
+    answered: bool,
+}
+
+impl Drop for Disconnect {
+    fn drop(&mut self) {
+        if !self.answered {
+            self.gone.store(true, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Middleware giving each request a scope, whose deadline starts as the
+/// request arrives.
+pub async fn track_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let deadline = req
+        .app_data::<web::Data<Config>>()
+        .and_then(|config| config.request_deadline)
+        .map(|after| Instant::now() + after);
+    let scope = RequestScope {
+        deadline,
+        gone: Arc::default(),
+    };
+    let mut disconnect = Disconnect {
+        gone: scope.gone.clone(),
+        answered: false,
+    };
+    let res = CURRENT.scope(scope, next.call(req)).await;
+    disconnect.answered = true;
+    res
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::db::{self, DbConn};
+    use crate::test_support::test_pool;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{App, HttpResponse};
+    use diesel::RunQueryDsl;
+    use std::sync::Mutex;
+
+    fn scope(deadline: Option<Duration>) -> RequestScope {
+        RequestScope {
+            deadline: deadline.map(|after| Instant::now() + after),
+            gone: Arc::default(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_disconnected_request() {
+        let seen = web::Data::new(Mutex::new(None::<RequestScope>));
+        let app = init_service(
+            App::new()
+                .app_data(seen.clone())
+                .wrap(from_fn(track_requests))
+                .route(
+                    "/slow",
+                    web::get().to(|seen: web::Data<Mutex<Option<RequestScope>>>| async move {
+                        *seen.lock().unwrap() = RequestScope::current();
+                        std::future::pending::<HttpResponse>().await
+                    }),
+                ),
+        )
+        .await;
+        let req = TestRequest::get().uri("/slow").to_request();
+        let call = call_service(&app, req);
+        assert!(actix_rt::time::timeout(Duration::from_millis(50), call)
+            .await
+            .is_err());
+
+        let scope = seen.lock().unwrap().take().unwrap();
+        let before = CANCELLED_DB_TASKS
+            .with_label_values(&["disconnected"])
+            .get();
+        let ran = Arc::new(AtomicBool::new(false));
+        let result = CURRENT
+            .scope(scope, {
+                let ran = ran.clone();
+                db::block(move || ran.store(true, Ordering::Relaxed))
+            })
+            .await;
+        assert!(matches!(result, Err(UserError::DeadlineExceededError)));
+        assert!(!ran.load(Ordering::Relaxed));
+        assert!(
+            CANCELLED_DB_TASKS
+                .with_label_values(&["disconnected"])
+                .get()
+                > before
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_deadline() {
+        let pool = test_pool();
+        let ping = CURRENT.scope(scope(Some(Duration::from_secs(10))), {
+            let connection = DbConn::get(&pool).unwrap();
+            connection.run("ping", crate::repository::ping)
+        });
+        assert!(ping.await.is_ok());
+
+        let started = Instant::now();
+        let sleep = CURRENT.scope(scope(Some(Duration::from_millis(100))), {
+            let connection = DbConn::get(&pool).unwrap();
+            connection.run("sleep", |c| {
+                diesel::sql_query("SELECT pg_sleep(5)").execute(c)
+            })
+        });
+        assert!(matches!(sleep.await, Err(UserError::DeadlineExceededError)));
+        assert!(started.elapsed() < Duration::from_secs(5));
+
+        let expired = CURRENT.scope(scope(Some(Duration::ZERO)), db::block(|| ()));
+        assert!(matches!(
+            expired.await,
+            Err(UserError::DeadlineExceededError)
+        ));
+    }
+}
@@ -1,32 +1,211 @@
+use crate::i18n::Translator;
+use crate::models::{Cat, CatStatus};
+use crate::quotas::QuotaStatus;
 use actix_web::body::BoxBody;
+use actix_web::http::header::RETRY_AFTER;
 use actix_web::http::StatusCode;
-use actix_web::{error, HttpResponse};
+use actix_web::{error, HttpResponse, ResponseError};
 use derive_more::Display;
-use serde_json::json;
+use serde_json::{json, Map, Value};
+use validator::ValidationErrors;
 
+#[allow(clippy::enum_variant_names)]
 #[derive(Display, Debug)]
 pub enum UserError {
     #[display(fmt = "Invalid input parameter")]
     ValidationError,
+    #[display(fmt = "Validation failed")]
+    FieldValidationError(ValidationErrors),
+    #[display(fmt = "Cat name already exists")]
+    NameConflictError(Box<Cat>),
     #[display(fmt = "Internal server error")]
     DBPoolGetError,
     #[display(fmt = "Not found")]
     NotFoundError,
+    #[display(fmt = "Method not allowed")]
+    MethodNotAllowedError,
+    #[display(fmt = "Upload rejected by the malware scan")]
+    InfectedUploadError,
+    #[display(fmt = "Image does not appear to show a cat")]
+    NotACatError,
+    #[display(fmt = "Upload quota exceeded")]
+    QuotaExceededError(QuotaStatus),
+    #[display(fmt = "Invalid or expired link")]
+    InvalidSignatureError,
+    #[display(fmt = "Only an admin can do this")]
+    AdminRequiredError,
+    #[display(fmt = "Missing or invalid CSRF token")]
+    CsrfError,
+    #[display(fmt = "Status change not allowed")]
+    InvalidTransitionError(CatStatus, CatStatus),
+    #[display(fmt = "Relation conflicts with the family tree")]
+    RelationConflictError,
+    #[display(fmt = "Image could not be downloaded")]
+    ImageDownloadError,
+    #[display(fmt = "Chunk does not start where the upload left off")]
+    UploadOffsetError(i64),
+    #[display(fmt = "Unsupported content type")]
+    UnsupportedMediaTypeError,
+    #[display(fmt = "Configuration could not be reloaded")]
+    ConfigReloadError(String),
+    #[display(fmt = "The server is read-only for now, try again later")]
+    ReadOnlyError,
+    #[display(fmt = "Down for maintenance, try again later")]
+    MaintenanceError(u64),
+    #[display(fmt = "Too busy, try again shortly")]
+    BusyError(u64),
+    #[display(fmt = "CAPTCHA was not solved")]
+    CaptchaError,
+    #[display(fmt = "Too many submissions, try again later")]
+    RateLimitedError(u64),
+    #[display(fmt = "The request took too long")]
+    DeadlineExceededError,
+    #[display(fmt = "Turn maintenance mode on first")]
+    MaintenanceRequiredError,
     #[display(fmt = "Internal server error")]
     UnexpectedError,
 }
 
+impl UserError {
+    /// Catalog key of the top level message.
+    fn message_key(&self) -> &'static str {
+        match self {
+            UserError::ValidationError => "error.validation",
+            UserError::FieldValidationError(_) => "error.field_validation",
+            UserError::NameConflictError(_) => "error.name_conflict",
+            UserError::DBPoolGetError => "error.internal",
+            UserError::NotFoundError => "error.not_found",
+            UserError::MethodNotAllowedError => "error.method_not_allowed",
+            UserError::InfectedUploadError => "error.infected_upload",
+            UserError::NotACatError => "error.not_a_cat",
+            UserError::QuotaExceededError(_) => "error.quota_exceeded",
+            UserError::InvalidSignatureError => "error.invalid_signature",
+            UserError::AdminRequiredError => "error.admin_required",
+            UserError::CsrfError => "error.csrf",
+            UserError::InvalidTransitionError(_, _) => "error.invalid_transition",
+            UserError::RelationConflictError => "error.relation_conflict",
+            UserError::ImageDownloadError => "error.image_download",
+            UserError::UploadOffsetError(_) => "error.upload_offset",
+            UserError::UnsupportedMediaTypeError => "error.unsupported_media_type",
+            UserError::ConfigReloadError(_) => "error.config_reload",
+            UserError::ReadOnlyError => "error.read_only",
+            UserError::MaintenanceError(_) => "error.maintenance",
+            UserError::BusyError(_) => "error.busy",
+            UserError::CaptchaError => "error.captcha",
+            UserError::RateLimitedError(_) => "error.rate_limited",
+            UserError::DeadlineExceededError => "error.deadline_exceeded",
+            UserError::MaintenanceRequiredError => "error.maintenance_required",
+            UserError::UnexpectedError => "error.internal",
+        }
+    }
+
+    pub fn localized_response(&self, translator: &Translator) -> HttpResponse<BoxBody> {
+        let msg = translator.text(self.message_key(), &self.to_string(), &[]);
+        let body = match self {
+            UserError::FieldValidationError(errors) => {
+                json!({"msg": msg, "errors": field_errors_json(errors, translator)})
+            }
+            UserError::NameConflictError(cat) => {
+                json!({"msg": msg, "conflict": cat})
+            }
+            UserError::QuotaExceededError(status) => {
+                json!({"msg": msg, "quota": status})
+            }
+            UserError::InvalidTransitionError(from, to) => {
+                json!({"msg": msg, "from": from, "to": to})
+            }
+            UserError::UploadOffsetError(received) => {
+                json!({"msg": msg, "received": received})
+            }
+            UserError::ConfigReloadError(reason) => {
+                json!({"msg": msg, "reason": reason})
+            }
+            UserError::MaintenanceError(retry_after)
+            | UserError::BusyError(retry_after)
+            | UserError::RateLimitedError(retry_after) => {
+                json!({"msg": msg, "retry_after": retry_after})
+            }
+            _ => json!({"msg": msg}),
+        };
+        let mut response = HttpResponse::build(self.status_code());
+        if let UserError::MaintenanceError(retry_after)
+        | UserError::BusyError(retry_after)
+        | UserError::RateLimitedError(retry_after) = self
+        {
+            response.insert_header((RETRY_AFTER, retry_after.to_string()));
+        }
+        response.json(body)
+    }
+}
+
+/// Renders validator errors as `{"field": [{"code": ..., "message": ...}]}`.
+///
+/// Messages are looked up as `validation.<code>`, with the validator params
+/// (e.g. `{min}` and `{max}`) available as placeholders.
+fn field_errors_json(errors: &ValidationErrors, translator: &Translator) -> Value {
+    let fields = errors
+        .field_errors()
+        .into_iter()
+        .map(|(field, errors)| {
+            let details = errors
+                .iter()
+                .map(|e| {
+                    let args = e
+                        .params
+                        .iter()
+                        .map(|(name, value)| {
+                            let value = match value {
+                                Value::String(s) => s.clone(),
+                                other => other.to_string(),
+                            };
+                            (name.as_ref(), value)
+                        })
+                        .collect::<Vec<_>>();
+                    let fallback = e.message.as_deref().unwrap_or(&e.code);
+                    let message =
+                        translator.text(&format!("validation.{}", e.code), fallback, &args);
+                    json!({"code": e.code, "message": message})
+                })
+                .collect();
+            (field.to_string(), Value::Array(details))
+        })
+        .collect::<Map<_, _>>();
+    Value::Object(fields)
+}
+
 impl error::ResponseError for UserError {
     fn status_code(&self) -> StatusCode {
-        match *self {
+        match self {
             UserError::ValidationError => StatusCode::BAD_REQUEST,
+            UserError::FieldValidationError(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            UserError::NameConflictError(_) => StatusCode::CONFLICT,
             UserError::DBPoolGetError => StatusCode::INTERNAL_SERVER_ERROR,
             UserError::NotFoundError => StatusCode::NOT_FOUND,
+            UserError::MethodNotAllowedError => StatusCode::METHOD_NOT_ALLOWED,
+            UserError::InfectedUploadError => StatusCode::UNPROCESSABLE_ENTITY,
+            UserError::NotACatError => StatusCode::UNPROCESSABLE_ENTITY,
+            UserError::QuotaExceededError(_) => StatusCode::FORBIDDEN,
+            UserError::InvalidSignatureError => StatusCode::FORBIDDEN,
+            UserError::AdminRequiredError => StatusCode::FORBIDDEN,
+            UserError::CsrfError => StatusCode::FORBIDDEN,
+            UserError::InvalidTransitionError(_, _) => StatusCode::CONFLICT,
+            UserError::RelationConflictError => StatusCode::CONFLICT,
+            UserError::ImageDownloadError => StatusCode::UNPROCESSABLE_ENTITY,
+            UserError::UploadOffsetError(_) => StatusCode::CONFLICT,
+            UserError::UnsupportedMediaTypeError => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            UserError::ConfigReloadError(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            UserError::ReadOnlyError => StatusCode::SERVICE_UNAVAILABLE,
+            UserError::MaintenanceError(_) => StatusCode::SERVICE_UNAVAILABLE,
+            UserError::BusyError(_) => StatusCode::SERVICE_UNAVAILABLE,
+            UserError::CaptchaError => StatusCode::FORBIDDEN,
+            UserError::RateLimitedError(_) => StatusCode::TOO_MANY_REQUESTS,
+            UserError::DeadlineExceededError => StatusCode::SERVICE_UNAVAILABLE,
+            UserError::MaintenanceRequiredError => StatusCode::CONFLICT,
             UserError::UnexpectedError => StatusCode::INTERNAL_SERVER_ERROR,
         }
     }
 
     fn error_response(&self) -> HttpResponse<BoxBody> {
-        HttpResponse::build(self.status_code()).json(json!({"msg": self.to_string()}))
+        self.localized_response(&Translator::fallback())
     }
 }
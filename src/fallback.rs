@@ -0,0 +1,51 @@
+//! Responses for requests no route matches: the structured JSON error under
+//! `/api`, and a page for browsers everywhere else.
+use crate::errors::UserError;
+use crate::sessions;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::ALLOW;
+use actix_web::http::StatusCode;
+use actix_web::middleware::Next;
+use actix_web::{Error, HttpRequest, HttpResponse};
+use log::info;
+
+/// Default service of the `/api` scope.
+pub async fn api_not_found_endpoint(req: HttpRequest) -> Result<HttpResponse, UserError> {
+    info!("No API route for {} {}", req.method(), req.path());
+    Err(UserError::NotFoundError)
+}
+
+/// Middleware giving the bare 405 actix answers a resource's other methods
+/// with the structured error format, keeping its `Allow` header.
+pub async fn api_method_not_allowed(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse, Error> {
+    let res = next.call(req).await?;
+    if res.status() != StatusCode::METHOD_NOT_ALLOWED || res.response().error().is_some() {
+        return Ok(res.map_into_boxed_body());
+    }
+    let allow = res.headers().get(ALLOW).cloned();
+    let (req, _) = res.into_parts();
+    let mut res = ServiceResponse::from_err(UserError::MethodNotAllowedError, req);
+    if let Some(allow) = allow {
+        res.headers_mut().insert(ALLOW, allow);
+    }
+    Ok(res)
+}
+
+/// The page shown for paths outside the API that lead nowhere.
+pub fn not_found_page() -> HttpResponse {
+    sessions::page(
+        StatusCode::NOT_FOUND,
+        "Page not found",
+        r#"        <p>There is no cat here.</p>
+        <p><a href="/">Back to all cats</a></p>"#,
+    )
+}
+
+/// Default service of the app.
+pub async fn not_found_endpoint() -> HttpResponse {
+    not_found_page()
+}
@@ -0,0 +1,278 @@
+use crate::clock::Clock;
+use crate::db::DbConn;
+use crate::errors::UserError;
+use crate::models::{Cat, CatRelation, CatRelationKind, NewCatRelation, Tenant};
+use crate::reload::LiveSettings;
+use crate::repository;
+use crate::signed_urls::UrlSigner;
+use actix_web::{web, HttpResponse};
+use diesel::{Connection, PgConnection, QueryResult};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+
+pub const DEFAULT_FAMILY_DEPTH: u32 = 2;
+
+/// The cats reached by walking the relation graph, and the relations
+/// walked along the way.
+#[derive(Debug, Default)]
+struct Walk {
+    cats: HashSet<i32>,
+    relations: BTreeMap<i32, CatRelation>,
+}
+
+/// Walks the relation graph breadth first from `root`, at most `depth`
+/// relations away, loading the relations of each generation with `load`.
+/// With `ancestors_only` set only mother and father relations are followed
+/// upwards, otherwise every relation is followed in both directions.
+///
+/// Cats already reached are not expanded again, so the walk ends even when
+/// the relations form a cycle, as littermates of littermates do.
+fn walk(
+    root: i32,
+    depth: u32,
+    ancestors_only: bool,
+    mut load: impl FnMut(&[i32]) -> QueryResult<Vec<CatRelation>>,
+) -> QueryResult<Walk> {
+    let mut walk = Walk::default();
+    walk.cats.insert(root);
+    let mut frontier = vec![root];
+    for _ in 0..depth {
+        if frontier.is_empty() {
+            break;
+        }
+        let mut next = Vec::new();
+        for relation in load(&frontier)? {
+            let neighbor = if frontier.contains(&relation.cat_id) {
+                if ancestors_only && !relation.kind.is_parent() {
+                    continue;
+                }
+                relation.related_cat_id
+            } else if !ancestors_only {
+                relation.cat_id
+            } else {
+                continue;
+            };
+            if walk.cats.insert(neighbor) {
+                next.push(neighbor);
+            }
+            walk.relations.insert(relation.id, relation);
+        }
+        frontier = next;
+    }
+    Ok(walk)
+}
+
+/// Whether making `parent` a parent of `cat` would make a cat its own
+/// ancestor.
+fn creates_cycle(
+    connection: &mut PgConnection,
+    tenant_id: i32,
+    cat: i32,
+    parent: i32,
+) -> QueryResult<bool> {
+    let ancestors = walk(parent, u32::MAX, true, |ids| {
+        repository::list_cat_relations(connection, tenant_id, ids)
+    })?;
+    Ok(ancestors.cats.contains(&cat))
+}
+
+#[derive(Deserialize)]
+pub struct CatPath {
+    id: i32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AddRelation {
+    pub related_cat_id: i32,
+    pub kind: CatRelationKind,
+}
+
+/// Records that `related_cat_id` is the cat's mother, father or littermate.
+pub async fn add_relation_endpoint(
+    connection: DbConn,
+    tenant: Tenant,
+    path: web::Path<CatPath>,
+    body: web::Json<AddRelation>,
+) -> Result<HttpResponse, UserError> {
+    if path.id == body.related_cat_id {
+        warn!("Rejected relation of cat ID {} to itself", path.id);
+        return Err(UserError::ValidationError);
+    }
+    // Littermates are stored once, lower id first
+    let (cat_id, related_cat_id) = match body.kind {
+        CatRelationKind::Littermate if body.related_cat_id < path.id => {
+            (body.related_cat_id, path.id)
+        }
+        _ => (path.id, body.related_cat_id),
+    };
+    let new_relation = NewCatRelation {
+        tenant_id: tenant.id,
+        cat_id,
+        related_cat_id,
+        kind: body.kind,
+    };
+
+    let relation = connection
+        .run("add relation", move |connection| {
+            connection.transaction(|connection| {
+                let found =
+                    repository::list_cats_by_ids(connection, tenant.id, &[cat_id, related_cat_id])?;
+                if found.len() != 2 {
+                    return Err(diesel::result::Error::NotFound);
+                }
+                if new_relation.kind.is_parent()
+                    && creates_cycle(connection, tenant.id, cat_id, related_cat_id)?
+                {
+                    warn!(
+                        "Relation of cat ID {} to {} would create a cycle",
+                        cat_id, related_cat_id
+                    );
+                    return Ok(None);
+                }
+                repository::insert_cat_relation(connection, &new_relation)
+            })
+        })
+        .await?
+        .ok_or(UserError::RelationConflictError)?;
+    Ok(HttpResponse::Created().json(relation))
+}
+
+#[derive(Deserialize)]
+pub struct RelationPath {
+    id: i32,
+    relation_id: i32,
+}
+
+pub async fn delete_relation_endpoint(
+    connection: DbConn,
+    tenant: Tenant,
+    path: web::Path<RelationPath>,
+) -> Result<HttpResponse, UserError> {
+    let (cat_id, relation_id) = (path.id, path.relation_id);
+    let deleted = connection
+        .run("delete relation", move |connection| {
+            repository::delete_cat_relation(connection, tenant.id, cat_id, relation_id)
+        })
+        .await?;
+    match deleted {
+        0 => Err(UserError::NotFoundError),
+        _ => Ok(HttpResponse::NoContent().finish()),
+    }
+}
+
+#[derive(Deserialize)]
+pub struct FamilyQuery {
+    depth: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Family {
+    pub root: i32,
+    pub depth: u32,
+    pub cats: Vec<Cat>,
+    pub relations: Vec<CatRelation>,
+}
+
+/// The cat's relatives up to `depth` relations away, capped by
+/// `FAMILY_MAX_DEPTH`.
+pub async fn family_endpoint(
+    connection: DbConn,
+    settings: web::Data<LiveSettings>,
+    clock: web::Data<dyn Clock>,
+    signer: web::Data<UrlSigner>,
+    tenant: Tenant,
+    path: web::Path<CatPath>,
+    query: web::Query<FamilyQuery>,
+) -> Result<HttpResponse, UserError> {
+    let root = path.id;
+    let depth = query
+        .depth
+        .unwrap_or(DEFAULT_FAMILY_DEPTH)
+        .min(settings.load().family_max_depth);
+
+    let (cats, relations) = connection
+        .run("load family", move |connection| {
+            repository::find_cat(connection, tenant.id, root)?;
+            let walk = walk(root, depth, false, |ids| {
+                repository::list_cat_relations(connection, tenant.id, ids)
+            })?;
+            let ids: Vec<i32> = walk.cats.into_iter().collect();
+            let mut cats = repository::list_cats_by_ids(connection, tenant.id, &ids)?;
+            cats.sort_by_key(|cat| cat.id);
+            Ok((cats, walk.relations.into_values().collect()))
+        })
+        .await?;
+
+    let now = clock.now();
+    Ok(HttpResponse::Ok().json(Family {
+        root,
+        depth,
+        cats: cats
+            .into_iter()
+            .map(|cat| signer.present(cat, now))
+            .collect(),
+        relations,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn relation(id: i32, cat_id: i32, related_cat_id: i32, kind: CatRelationKind) -> CatRelation {
+        CatRelation {
+            id,
+            cat_id,
+            related_cat_id,
+            kind,
+        }
+    }
+
+    /// Loads from an in memory list of relations.
+    fn walk_over(relations: &[CatRelation], root: i32, depth: u32, ancestors_only: bool) -> Walk {
+        walk(root, depth, ancestors_only, |ids| {
+            Ok(relations
+                .iter()
+                .filter(|r| ids.contains(&r.cat_id) || ids.contains(&r.related_cat_id))
+                .cloned()
+                .collect())
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_walk_depth_and_direction() {
+        use CatRelationKind::*;
+        // 1's mother is 2, whose mother is 3; 1 and 4 are littermates
+        let relations = [
+            relation(1, 1, 2, Mother),
+            relation(2, 2, 3, Mother),
+            relation(3, 1, 4, Littermate),
+        ];
+        let family = walk_over(&relations, 1, 1, false);
+        assert_eq!(family.cats, HashSet::from([1, 2, 4]));
+        assert_eq!(family.relations.len(), 2);
+
+        let family = walk_over(&relations, 3, 2, false);
+        assert_eq!(family.cats, HashSet::from([3, 2, 1]));
+
+        let ancestors = walk_over(&relations, 1, u32::MAX, true);
+        assert_eq!(ancestors.cats, HashSet::from([1, 2, 3]));
+        let ancestors = walk_over(&relations, 3, u32::MAX, true);
+        assert_eq!(ancestors.cats, HashSet::from([3]));
+    }
+
+    #[test]
+    fn test_walk_ends_on_cycles() {
+        use CatRelationKind::*;
+        let relations = [
+            relation(1, 1, 2, Littermate),
+            relation(2, 2, 3, Littermate),
+            relation(3, 1, 3, Littermate),
+        ];
+        let family = walk_over(&relations, 1, u32::MAX, false);
+        assert_eq!(family.cats, HashSet::from([1, 2, 3]));
+        assert_eq!(family.relations.len(), 3);
+    }
+}
@@ -0,0 +1,122 @@
+use crate::errors::UserError;
+use actix_web::HttpResponse;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashSet;
+
+/// The `fields` query parameter, a comma separated list of the top level
+/// response fields a client wants, e.g. `?fields=name,image_path`. Every
+/// field is sent when it is left out.
+#[derive(Deserialize)]
+pub struct FieldsQuery {
+    fields: Option<String>,
+}
+
+impl FieldsQuery {
+    /// The requested field names, `None` for all of them.
+    pub fn selection(&self) -> Result<Option<HashSet<String>>, UserError> {
+        let Some(fields) = &self.fields else {
+            return Ok(None);
+        };
+        let names: HashSet<String> = fields
+            .split(',')
+            .map(|name| name.trim().to_string())
+            .collect();
+        let valid = |name: &String| {
+            !name.is_empty()
+                && name
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+        };
+        if !names.iter().all(valid) {
+            warn!("Invalid fields parameter: {}", fields);
+            return Err(UserError::ValidationError);
+        }
+        Ok(Some(names))
+    }
+}
+
+/// Keeps only the selected fields of an object, or of each object in an
+/// array. Names the response does not have are ignored.
+fn retain(value: &mut Value, selection: &HashSet<String>) {
+    match value {
+        Value::Object(object) => object.retain(|name, _| selection.contains(name)),
+        Value::Array(items) => items.iter_mut().for_each(|item| retain(item, selection)),
+        _ => {}
+    }
+}
+
+/// Responds with `body` serialized to JSON, cut down to the `selection` of
+/// `FieldsQuery::selection`.
+pub fn json<T: Serialize>(
+    body: &T,
+    selection: Option<&HashSet<String>>,
+) -> Result<HttpResponse, UserError> {
+    let Some(selection) = selection else {
+        return Ok(HttpResponse::Ok().json(body));
+    };
+    let mut value = serde_json::to_value(body).map_err(|e| {
+        error!("Failed to serialize response: {}", e);
+        UserError::UnexpectedError
+    })?;
+    retain(&mut value, selection);
+    Ok(HttpResponse::Ok().json(value))
+}
+
+/// `item` as one line of newline delimited JSON, cut down to the
+/// `selection` like `json`.
+pub fn ndjson_line<T: Serialize>(
+    item: &T,
+    selection: Option<&HashSet<String>>,
+) -> serde_json::Result<Vec<u8>> {
+    let mut line = match selection {
+        Some(selection) => {
+            let mut value = serde_json::to_value(item)?;
+            retain(&mut value, selection);
+            serde_json::to_vec(&value)?
+        }
+        None => serde_json::to_vec(item)?,
+    };
+    line.push(b'\n');
+    Ok(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn query(fields: &str) -> FieldsQuery {
+        FieldsQuery {
+            fields: Some(fields.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_selection() {
+        let selection = query("name, image_path").selection().unwrap().unwrap();
+        assert_eq!(
+            selection,
+            HashSet::from(["name".to_string(), "image_path".to_string()])
+        );
+        assert_eq!(FieldsQuery { fields: None }.selection().unwrap(), None);
+        assert!(query("").selection().is_err());
+        assert!(query("name,,id").selection().is_err());
+        assert!(query("name;drop").selection().is_err());
+    }
+
+    #[test]
+    fn test_retain() {
+        let selection = query("id,name,nope").selection().unwrap().unwrap();
+        let mut cats = json!([
+            {"id": 1, "name": "Whiskers", "private": false},
+            {"id": 2, "name": "Tom", "medical": {"last_checkup": null}},
+        ]);
+        retain(&mut cats, &selection);
+        assert_eq!(
+            cats,
+            json!([{"id": 1, "name": "Whiskers"}, {"id": 2, "name": "Tom"}])
+        );
+    }
+}
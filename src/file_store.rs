@@ -0,0 +1,136 @@
+use crate::clock::{Clock, SystemClock};
+use crate::image_shards;
+use crate::telemetry;
+use opentelemetry::KeyValue;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::PathBuf;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Storage for uploaded images, addressed by keys such as `acme/<uuid>.jpg`
+/// relative to the `/image` URL prefix.
+pub trait FileStore: Send + Sync {
+    fn put(&self, key: &str, contents: &mut dyn Read) -> io::Result<()>;
+    fn get(&self, key: &str) -> io::Result<Vec<u8>>;
+    /// Size in bytes of the file stored under `key`.
+    fn size(&self, key: &str) -> io::Result<u64>;
+    fn delete(&self, key: &str) -> io::Result<()>;
+
+    /// What kind of store this is, e.g. `local`, as reported by
+    /// `GET /api/version`.
+    fn kind(&self) -> &'static str {
+        "custom"
+    }
+
+    /// A fresh key for a file with `extension` stored in `dir`, e.g.
+    /// `acme/<uuid>.jpg`.
+    fn new_key(&self, dir: &str, extension: Option<&str>) -> String {
+        format!("{}/{}", dir, file_name(extension))
+    }
+}
+
+/// A freshly generated file name, so files never overwrite each other.
+fn file_name(extension: Option<&str>) -> String {
+    match extension {
+        Some(ext) => format!("{}.{}", Uuid::new_v4(), ext),
+        None => Uuid::new_v4().to_string(),
+    }
+}
+
+/// Runs the storage operation `name` on `key` in a span of its own.
+fn traced<T>(name: &'static str, key: &str, run: impl FnOnce() -> io::Result<T>) -> io::Result<T> {
+    let attributes = vec![KeyValue::new("storage.key", key.to_string())];
+    telemetry::in_span(name, attributes, || {
+        run().inspect_err(telemetry::record_error)
+    })
+}
+
+/// Keeps images in a directory on the local filesystem.
+pub struct LocalFileStore {
+    root: PathBuf,
+    date_shards: bool,
+    clock: Arc<dyn Clock>,
+}
+
+impl LocalFileStore {
+    pub fn new(root: impl Into<PathBuf>) -> LocalFileStore {
+        LocalFileStore {
+            root: root.into(),
+            date_shards: false,
+            clock: Arc::new(SystemClock),
+        }
+    }
+
+    /// Stores new files in a subdirectory per day, see `image_shards`.
+    pub fn with_date_shards(mut self, date_shards: bool) -> LocalFileStore {
+        self.date_shards = date_shards;
+        self
+    }
+
+    /// The clock new files are sharded by, the system clock by default.
+    pub fn with_clock(mut self, clock: Arc<dyn Clock>) -> LocalFileStore {
+        self.clock = clock;
+        self
+    }
+}
+
+impl FileStore for LocalFileStore {
+    fn put(&self, key: &str, contents: &mut dyn Read) -> io::Result<()> {
+        traced("storage put", key, || {
+            let path = self.root.join(key);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            io::copy(contents, &mut File::create(path)?).map(|_| ())
+        })
+    }
+
+    fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        traced("storage get", key, || fs::read(self.root.join(key)))
+    }
+
+    fn size(&self, key: &str) -> io::Result<u64> {
+        traced("storage size", key, || {
+            fs::metadata(self.root.join(key)).map(|metadata| metadata.len())
+        })
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        traced("storage delete", key, || {
+            fs::remove_file(self.root.join(key))
+        })
+    }
+
+    fn kind(&self) -> &'static str {
+        "local"
+    }
+
+    fn new_key(&self, dir: &str, extension: Option<&str>) -> String {
+        match self.date_shards {
+            true => image_shards::shard_key(dir, &file_name(extension), self.clock.now()),
+            false => format!("{}/{}", dir, file_name(extension)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::FixedClock;
+
+    #[test]
+    fn test_new_key_shards_by_clock() {
+        let store = LocalFileStore::new("./image")
+            .with_date_shards(true)
+            .with_clock(Arc::new(FixedClock::new()));
+        let key = store.new_key("acme", Some("jpg"));
+        assert!(key.starts_with("acme/2026/01/01/"), "{}", key);
+        assert!(key.ends_with(".jpg"));
+
+        let flat = LocalFileStore::new("./image").with_clock(Arc::new(FixedClock::new()));
+        assert!(!image_shards::is_sharded(
+            &flat.new_key("acme", Some("jpg"))
+        ));
+    }
+}
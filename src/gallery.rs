@@ -0,0 +1,256 @@
+//! A cat's photo gallery under `/api/cat/{id}/images`. Every cat has one
+//! photo from its creation, the primary one, which is also its
+//! `image_path`. More are added from images stored through
+//! `POST /api/images`, and any of them can be made the primary one.
+use crate::cat_detection::CatDetector;
+use crate::cats::{inspect_stored_image, is_tenant_upload};
+use crate::clock::Clock;
+use crate::config::Config;
+use crate::db::DbConn;
+use crate::errors::UserError;
+use crate::file_store::FileStore;
+use crate::heic;
+use crate::history::{self, Requester};
+use crate::models::{Cat, CatImage, NewCatImage, Tenant};
+use crate::quotas;
+use crate::repository;
+use crate::signed_urls::UrlSigner;
+use crate::DbPool;
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use diesel::{Connection, PgConnection, QueryResult};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+#[derive(Deserialize)]
+pub struct CatPath {
+    id: i32,
+}
+
+#[derive(Deserialize)]
+pub struct CatImagePath {
+    id: i32,
+    image_id: i32,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct AddCatImage {
+    /// Key of an image stored through `POST /api/images`.
+    pub image_key: String,
+    /// Makes the photo the cat's image.
+    #[serde(default)]
+    pub primary: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ReorderCatImages {
+    /// Every photo of the gallery, in the new order.
+    pub image_ids: Vec<i32>,
+}
+
+/// Makes the photo with `image_id` the cat's image, recording the change
+/// in the cat's history. `before` is the cat, locked.
+fn make_primary(
+    connection: &mut PgConnection,
+    tenant_id: i32,
+    requester: &Requester,
+    before: &Cat,
+    image_id: i32,
+    now: DateTime<Utc>,
+) -> QueryResult<Cat> {
+    let cat = repository::set_primary_cat_image(connection, before.id, image_id)?;
+    let actor = requester.actor(connection, tenant_id)?;
+    history::record(connection, tenant_id, &actor, Some(before), &cat, now)?;
+    Ok(cat)
+}
+
+/// The cat's gallery as shown to clients.
+pub fn present(
+    signer: &UrlSigner,
+    gallery: Vec<CatImage>,
+    private: bool,
+    now: DateTime<Utc>,
+) -> Vec<CatImage> {
+    gallery
+        .into_iter()
+        .map(|image| signer.present_image(image, private, now))
+        .collect()
+}
+
+pub async fn gallery_endpoint(
+    connection: DbConn,
+    clock: web::Data<dyn Clock>,
+    signer: web::Data<UrlSigner>,
+    tenant: Tenant,
+    path: web::Path<CatPath>,
+) -> Result<HttpResponse, UserError> {
+    let cat_id = path.id;
+    let (cat, gallery) = connection
+        .run("list cat photos", move |connection| {
+            let cat = repository::find_cat(connection, tenant.id, cat_id)?;
+            let gallery = repository::list_cat_gallery(connection, tenant.id, cat_id)?;
+            Ok((cat, gallery))
+        })
+        .await?;
+    Ok(HttpResponse::Ok().json(present(&signer, gallery, cat.private, clock.now())))
+}
+
+/// Adds a photo to the end of the cat's gallery. Photos the cat detector
+/// doubts are refused, as they cannot be held for moderation on their own.
+#[allow(clippy::too_many_arguments)]
+pub async fn add_image_endpoint(
+    pool: web::Data<DbPool>,
+    config: web::Data<Config>,
+    clock: web::Data<dyn Clock>,
+    store: web::Data<dyn FileStore>,
+    detector: Option<web::Data<dyn CatDetector>>,
+    signer: web::Data<UrlSigner>,
+    tenant: Tenant,
+    requester: Requester,
+    path: web::Path<CatPath>,
+    body: web::Json<AddCatImage>,
+) -> Result<HttpResponse, UserError> {
+    let (tenant_id, cat_id) = (tenant.id, path.id);
+    let AddCatImage { image_key, primary } = body.into_inner();
+    let cat = DbConn::get(&pool)?
+        .run(&format!("find cat {}", cat_id), move |connection| {
+            repository::find_cat(connection, tenant_id, cat_id)
+        })
+        .await?;
+    if !is_tenant_upload(&image_key, &tenant.slug, cat.private) {
+        warn!("Rejected image key outside the tenant's uploads");
+        return Err(UserError::ValidationError);
+    }
+    let image_size = store.size(&image_key).map_err(|e| {
+        warn!("Referenced image {} is not stored: {}", image_key, e);
+        UserError::ValidationError
+    })? as i64;
+    let image = inspect_stored_image(&store, detector, &config, &image_key).await?;
+    if let Some(score) = image.flagged {
+        warn!(
+            "Rejected photo {} of cat ID {} scoring {:.2} as a cat",
+            image_key, cat_id, score
+        );
+        return Err(UserError::NotACatError);
+    }
+
+    let now = clock.now();
+    let mut new_image = NewCatImage {
+        tenant_id,
+        cat_id,
+        image_path: format!("/image/{}", image_key),
+        image_hash: image.version,
+        image_size,
+        image_phash: image.phash,
+        position: 0,
+        is_primary: false,
+        created_at: now,
+        image_original_format: heic::original_format(&image_key).map(str::to_string),
+    };
+    let (added, cat) = DbConn::get(&pool)?
+        .run("add cat photo", move |connection| {
+            connection.transaction(|connection| {
+                let before = repository::lock_cat(connection, tenant_id, cat_id)?;
+                if let Some(quota) = repository::lock_quota(connection, tenant_id)? {
+                    let status = quotas::status(connection, tenant_id, quota)?;
+                    if !status.allows_photo(image_size) {
+                        return Ok(Err(UserError::QuotaExceededError(status)));
+                    }
+                }
+                new_image.position = repository::next_gallery_position(connection, cat_id)?;
+                let mut added = repository::insert_cat_image(connection, &new_image)?;
+                if primary {
+                    make_primary(connection, tenant_id, &requester, &before, added.id, now)?;
+                    added.is_primary = true;
+                }
+                Ok(Ok((added, before)))
+            })
+        })
+        .await?
+        .inspect_err(|e| warn!("Refused photo of cat ID {}: {}", cat_id, e))?;
+    Ok(HttpResponse::Created().json(signer.present_image(added, cat.private, now)))
+}
+
+/// Puts the photos of the cat's gallery in the given order, which has to
+/// list each of them once.
+pub async fn reorder_images_endpoint(
+    connection: DbConn,
+    clock: web::Data<dyn Clock>,
+    signer: web::Data<UrlSigner>,
+    tenant: Tenant,
+    path: web::Path<CatPath>,
+    body: web::Json<ReorderCatImages>,
+) -> Result<HttpResponse, UserError> {
+    let cat_id = path.id;
+    let image_ids = body.into_inner().image_ids;
+    let (cat, gallery) = connection
+        .run("reorder cat photos", move |connection| {
+            connection.transaction(|connection| {
+                let cat = repository::lock_cat(connection, tenant.id, cat_id)?;
+                let gallery = repository::list_cat_gallery(connection, tenant.id, cat_id)?;
+                let listed: HashSet<i32> = image_ids.iter().copied().collect();
+                if listed.len() != image_ids.len()
+                    || listed != gallery.iter().map(|image| image.id).collect()
+                {
+                    return Ok(Err(UserError::ValidationError));
+                }
+                repository::set_gallery_order(connection, cat_id, &image_ids)?;
+                let gallery = repository::list_cat_gallery(connection, tenant.id, cat_id)?;
+                Ok(Ok((cat, gallery)))
+            })
+        })
+        .await?
+        .inspect_err(|_| warn!("Photo order of cat ID {} does not list its photos", cat_id))?;
+    Ok(HttpResponse::Ok().json(present(&signer, gallery, cat.private, clock.now())))
+}
+
+/// Makes the photo the cat's image, answering with the cat.
+pub async fn set_primary_image_endpoint(
+    connection: DbConn,
+    clock: web::Data<dyn Clock>,
+    signer: web::Data<UrlSigner>,
+    tenant: Tenant,
+    requester: Requester,
+    path: web::Path<CatImagePath>,
+) -> Result<HttpResponse, UserError> {
+    let (cat_id, image_id) = (path.id, path.image_id);
+    let now = clock.now();
+    let cat = connection
+        .run("set primary cat photo", move |connection| {
+            connection.transaction(|connection| {
+                let before = repository::lock_cat(connection, tenant.id, cat_id)?;
+                let image = repository::find_cat_image(connection, tenant.id, cat_id, image_id)?;
+                if image.is_primary {
+                    return Ok(before);
+                }
+                make_primary(connection, tenant.id, &requester, &before, image.id, now)
+            })
+        })
+        .await?;
+    Ok(HttpResponse::Ok().json(signer.present(cat, now)))
+}
+
+/// Removes a photo from the cat's gallery, leaving the stored image to
+/// `gc-images`. The primary photo has to be replaced first.
+pub async fn delete_image_endpoint(
+    connection: DbConn,
+    tenant: Tenant,
+    path: web::Path<CatImagePath>,
+) -> Result<HttpResponse, UserError> {
+    let (cat_id, image_id) = (path.id, path.image_id);
+    connection
+        .run("delete cat photo", move |connection| {
+            connection.transaction(|connection| {
+                let image = repository::find_cat_image(connection, tenant.id, cat_id, image_id)?;
+                if image.is_primary {
+                    return Ok(Err(UserError::ValidationError));
+                }
+                repository::delete_cat_image(connection, image.id)?;
+                Ok(Ok(()))
+            })
+        })
+        .await?
+        .inspect_err(|_| warn!("Refused to delete the primary photo of cat ID {}", cat_id))?;
+    Ok(HttpResponse::NoContent().finish())
+}
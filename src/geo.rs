@@ -0,0 +1,190 @@
+use crate::clock::Clock;
+use crate::config::Config;
+use crate::db::DbConn;
+use crate::errors::UserError;
+use crate::fields::{self, FieldsQuery};
+use crate::history::{self, Requester};
+use crate::links::{LinkBuilder, Links};
+use crate::models::{Cat, Tenant};
+use crate::repository;
+use crate::signed_urls::UrlSigner;
+use crate::validated::Validated;
+use actix_web::{web, HttpResponse};
+use diesel::Connection;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use validator::Validate;
+
+pub const DEFAULT_NEARBY_RADIUS_KM: f64 = 10.0;
+
+/// Half the Earth's circumference, beyond which every point is in range.
+const MAX_NEARBY_RADIUS_KM: f64 = 20_038.0;
+
+const NEARBY_LIMIT: i64 = 100;
+
+#[derive(Serialize, Deserialize, Validate, Clone, Copy)]
+pub struct Location {
+    #[validate(range(min = -90.0, max = 90.0))]
+    pub latitude: f64,
+    #[validate(range(min = -180.0, max = 180.0))]
+    pub longitude: f64,
+}
+
+/// Reads the optional `latitude` and `longitude` fields of a new cat form,
+/// which must be given together.
+pub fn parse_location(fields: &HashMap<&str, &str>) -> Result<Option<(f64, f64)>, UserError> {
+    let parse = |field: &str| {
+        fields
+            .get(field)
+            .map(|value| value.trim().parse::<f64>())
+            .transpose()
+            .map_err(|_| {
+                warn!("Invalid {} field", field);
+                UserError::ValidationError
+            })
+    };
+    match (parse("latitude")?, parse("longitude")?) {
+        (Some(latitude), Some(longitude)) => Ok(Some((latitude, longitude))),
+        (None, None) => Ok(None),
+        _ => {
+            warn!("Location is missing a coordinate");
+            Err(UserError::ValidationError)
+        }
+    }
+}
+
+#[derive(Deserialize, Validate)]
+pub struct NearbyQuery {
+    #[validate(range(min = -90.0, max = 90.0))]
+    lat: f64,
+    #[validate(range(min = -180.0, max = 180.0))]
+    lon: f64,
+    #[validate(range(min = 0.0, max = "MAX_NEARBY_RADIUS_KM"))]
+    radius_km: Option<f64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct NearbyCat {
+    #[serde(flatten)]
+    pub cat: Cat,
+    pub distance_km: f64,
+    #[serde(skip_deserializing)]
+    _links: Links,
+}
+
+/// The tenant's cats within `radius_km` of the given point, nearest first.
+pub async fn nearby_endpoint(
+    connection: DbConn,
+    config: web::Data<Config>,
+    clock: web::Data<dyn Clock>,
+    signer: web::Data<UrlSigner>,
+    tenant: Tenant,
+    query: Validated<web::Query<NearbyQuery>>,
+    fields: web::Query<FieldsQuery>,
+) -> Result<HttpResponse, UserError> {
+    let selection = fields.selection()?;
+    let (lat, lon) = (query.lat, query.lon);
+    let radius_m = query.radius_km.unwrap_or(DEFAULT_NEARBY_RADIUS_KM) * 1000.0;
+
+    let cats = connection
+        .run("search nearby cats", move |connection| {
+            repository::list_cats_nearby(connection, tenant.id, lat, lon, radius_m, NEARBY_LIMIT)
+        })
+        .await?;
+
+    let now = clock.now();
+    let links = LinkBuilder::new(&config);
+    let cats: Vec<NearbyCat> = cats
+        .into_iter()
+        .map(|(cat, distance_m)| {
+            let cat = signer.present(cat, now);
+            NearbyCat {
+                _links: links.cat(&cat),
+                cat,
+                distance_km: distance_m / 1000.0,
+            }
+        })
+        .collect();
+    fields::json(&cats, selection.as_ref())
+}
+
+#[derive(Deserialize)]
+pub struct CatPath {
+    id: i32,
+}
+
+/// Sets the cat's location, or clears it when no body is given.
+async fn update_location(
+    connection: DbConn,
+    clock: web::Data<dyn Clock>,
+    signer: web::Data<UrlSigner>,
+    tenant: Tenant,
+    requester: Requester,
+    cat_id: i32,
+    location: Option<Location>,
+) -> Result<HttpResponse, UserError> {
+    let location = location.map(|location| (location.latitude, location.longitude));
+    let now = clock.now();
+
+    let cat = connection
+        .run("set cat location", move |connection| {
+            connection.transaction(|connection| {
+                let before = repository::lock_cat(connection, tenant.id, cat_id)?;
+                let cat = repository::set_cat_location(connection, tenant.id, cat_id, location)?;
+                let actor = requester.actor(connection, tenant.id)?;
+                history::record(connection, tenant.id, &actor, Some(&before), &cat, now)?;
+                Ok(cat)
+            })
+        })
+        .await?;
+    Ok(HttpResponse::Ok().json(signer.present(cat, now)))
+}
+
+pub async fn set_location_endpoint(
+    connection: DbConn,
+    clock: web::Data<dyn Clock>,
+    signer: web::Data<UrlSigner>,
+    tenant: Tenant,
+    requester: Requester,
+    path: web::Path<CatPath>,
+    body: Validated<web::Json<Location>>,
+) -> Result<HttpResponse, UserError> {
+    update_location(
+        connection,
+        clock,
+        signer,
+        tenant,
+        requester,
+        path.id,
+        Some(body.into_inner().into_inner()),
+    )
+    .await
+}
+
+pub async fn clear_location_endpoint(
+    connection: DbConn,
+    clock: web::Data<dyn Clock>,
+    signer: web::Data<UrlSigner>,
+    tenant: Tenant,
+    requester: Requester,
+    path: web::Path<CatPath>,
+) -> Result<HttpResponse, UserError> {
+    update_location(connection, clock, signer, tenant, requester, path.id, None).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_location() {
+        let fields = HashMap::from([("latitude", "50.45"), ("longitude", " 30.52")]);
+        assert_eq!(parse_location(&fields).unwrap(), Some((50.45, 30.52)));
+        assert_eq!(parse_location(&HashMap::new()).unwrap(), None);
+        let fields = HashMap::from([("latitude", "50.45")]);
+        assert!(parse_location(&fields).is_err());
+        let fields = HashMap::from([("latitude", "north"), ("longitude", "30.52")]);
+        assert!(parse_location(&fields).is_err());
+    }
+}
@@ -0,0 +1,153 @@
+use crate::auth;
+use crate::db::DbConn;
+use crate::errors::UserError;
+use crate::file_store::FileStore;
+use crate::history::Requester;
+use crate::models::Tenant;
+use crate::repository;
+use crate::version::{self, Build};
+use actix_web::http::header::CACHE_CONTROL;
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use log::{error, warn};
+use serde::Serialize;
+use std::collections::BTreeMap;
+use std::io;
+use std::sync::{LazyLock, Mutex};
+use std::time::Instant;
+use uuid::Uuid;
+
+/// When each background job last completed a run, by job name.
+static JOB_RUNS: LazyLock<Mutex<BTreeMap<&'static str, DateTime<Utc>>>> =
+    LazyLock::new(Default::default);
+
+/// Records that the background `job` completed a run `at`.
+pub fn record_job_run(job: &'static str, at: DateTime<Utc>) {
+    JOB_RUNS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .insert(job, at);
+}
+
+fn job_runs() -> BTreeMap<&'static str, DateTime<Utc>> {
+    JOB_RUNS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+        .clone()
+}
+
+/// The outcome of checking one dependency.
+#[derive(Serialize, Debug)]
+struct Check {
+    ok: bool,
+    latency_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+impl Check {
+    /// Times `run`, keeping its error message when it fails.
+    fn run<E: ToString>(run: impl FnOnce() -> Result<(), E>) -> Check {
+        let start = Instant::now();
+        let result = run();
+        Check {
+            ok: result.is_ok(),
+            latency_ms: start.elapsed().as_secs_f64() * 1000.0,
+            error: result.err().map(|e| e.to_string()),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct HealthDetails {
+    ok: bool,
+    build: Build,
+    checks: BTreeMap<&'static str, Check>,
+    last_job_runs: BTreeMap<&'static str, DateTime<Utc>>,
+}
+
+/// Writes, reads back and deletes a small file, so a full or read-only
+/// image store fails the check.
+fn check_storage(store: &dyn FileStore) -> io::Result<()> {
+    let key = format!(".health/{}", Uuid::new_v4());
+    let contents = key.as_bytes();
+    store.put(&key, &mut &contents[..])?;
+    let read = store.get(&key);
+    store.delete(&key)?;
+    if read? != contents {
+        return Err(io::Error::other("read back different contents"));
+    }
+    Ok(())
+}
+
+/// How the server and its dependencies are doing, for admins: the latency
+/// of a database round trip and of a storage write, when background jobs
+/// last ran, and which build is running. Responds with 503 when a
+/// dependency check fails.
+pub async fn health_details_endpoint(
+    connection: DbConn,
+    store: web::Data<dyn FileStore>,
+    tenant: Tenant,
+    requester: Requester,
+) -> Result<HttpResponse, UserError> {
+    let checks = connection
+        .run("check admin credentials", move |connection| {
+            if !auth::is_admin(connection, tenant.id, &requester)? {
+                return Ok(Err(UserError::AdminRequiredError));
+            }
+            let database = Check::run(|| repository::ping(connection));
+            let storage = Check::run(|| check_storage(store.get_ref()));
+            Ok(Ok(BTreeMap::from([
+                ("database", database),
+                ("storage", storage),
+            ])))
+        })
+        .await?
+        .inspect_err(|_| warn!("Rejected health details request without admin credentials"))?;
+
+    let details = HealthDetails {
+        ok: checks.values().all(|check| check.ok),
+        build: version::build(),
+        checks,
+        last_job_runs: job_runs(),
+    };
+    for (name, check) in &details.checks {
+        if let Some(e) = &check.error {
+            error!("Health check {} failed: {}", name, e);
+        }
+    }
+    let mut res = match details.ok {
+        true => HttpResponse::Ok(),
+        false => HttpResponse::ServiceUnavailable(),
+    };
+    Ok(res.insert_header((CACHE_CONTROL, "no-store")).json(details))
+}
+
+/// Answers as long as the server accepts requests, for load balancers and
+/// orchestrators. Dependencies are left out, see `health_details_endpoint`.
+pub async fn healthz_endpoint() -> HttpResponse {
+    HttpResponse::Ok()
+        .insert_header((CACHE_CONTROL, "no-store"))
+        .body("ok")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_store::LocalFileStore;
+
+    #[test]
+    fn test_check_storage() {
+        let dir = tempfile::tempdir().unwrap();
+        assert!(check_storage(&LocalFileStore::new(dir.path())).is_ok());
+        assert!(check_storage(&LocalFileStore::new(dir.path().join("missing/\0"))).is_err());
+    }
+
+    #[test]
+    fn test_check_run() {
+        let check = Check::run(|| Err("down"));
+        assert!(!check.ok);
+        assert_eq!(check.error.as_deref(), Some("down"));
+        assert!(Check::run(|| Ok::<(), String>(())).ok);
+    }
+}
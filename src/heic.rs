@@ -0,0 +1,258 @@
+//! HEIC photos, which iPhones take by default but browsers cannot show.
+//! Uploads in HEIC, or any other HEIF, are converted before they are
+//! stored, to JPEG or with `HEIC_CONVERT_TO=webp` to lossless WebP. The
+//! format they were uploaded in is kept in the stored key, see
+//! `stored_extension`, and recorded by the cat or gallery photo created
+//! from it. Decoding takes libheif,
+//! 1.17 or newer, through the `heic` feature. Without it such uploads are
+//! refused as unsupported.
+use crate::config::Config;
+use crate::db;
+use crate::errors::UserError;
+use log::{error, info, warn};
+use std::io::{Read, Seek, Write};
+use tempfile::NamedTempFile;
+
+/// What HEIC uploads are converted to.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HeicTarget {
+    Jpeg,
+    Webp,
+}
+
+impl HeicTarget {
+    pub fn parse(value: &str) -> Option<HeicTarget> {
+        match value {
+            "jpeg" => Some(HeicTarget::Jpeg),
+            "webp" => Some(HeicTarget::Webp),
+            _ => None,
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            HeicTarget::Jpeg => "jpg",
+            HeicTarget::Webp => "webp",
+        }
+    }
+}
+
+/// The extension an upload converted from `original_format` is stored
+/// under, which keeps that format ahead of its own, as in `heic.jpg`, for
+/// whatever is later created from the stored key to find with
+/// `original_format`.
+pub fn stored_extension(extension: &str, original_format: Option<&str>) -> String {
+    match original_format {
+        Some(format) => format!("{}.{}", format, extension),
+        None => extension.to_string(),
+    }
+}
+
+/// The format the image stored under `key` was uploaded in, when it was
+/// converted from HEIF before it was stored.
+pub fn original_format(key: &str) -> Option<&'static str> {
+    let name = key.rsplit('/').next()?;
+    // From the end: the extension, the format and the rest of the name
+    let mut parts = name.rsplit('.').skip(1);
+    let format = parts.next()?;
+    parts.next()?;
+    match format {
+        "heic" => Some("heic"),
+        "heif" => Some("heif"),
+        _ => None,
+    }
+}
+
+/// The format of a HEIF file starting with `head`, `heic` when its images
+/// are HEVC coded as iPhones' are and `heif` for other codecs, or None for
+/// other files. AVIF, which is HEIF with AV1 images, is not counted.
+pub fn heif_format(head: &[u8]) -> Option<&'static str> {
+    // A HEIF file opens with its `ftyp` box: size, type, major brand,
+    // minor version and compatible brands
+    let size = u32::from_be_bytes(head.get(..4)?.try_into().ok()?) as usize;
+    if head.get(4..8)? != b"ftyp" || size < 16 {
+        return None;
+    }
+    let major = head.get(8..12)?;
+    let compatible = head.get(16..size.min(head.len()))?.chunks_exact(4);
+    let brands = || std::iter::once(major).chain(compatible.clone());
+    if brands().any(|brand| {
+        matches!(
+            brand,
+            b"heic" | b"heix" | b"heim" | b"heis" | b"hevc" | b"hevx"
+        )
+    }) {
+        Some("heic")
+    } else if brands().any(|brand| matches!(brand, b"avif" | b"avis")) {
+        None
+    } else if brands().any(|brand| matches!(brand, b"mif1" | b"msf1")) {
+        Some("heif")
+    } else {
+        None
+    }
+}
+
+#[cfg(feature = "heic")]
+mod convert {
+    use super::HeicTarget;
+    use image::codecs::jpeg::JpegEncoder;
+    use image::codecs::webp::WebPEncoder;
+    use image::RgbImage;
+    use libheif_rs::{ColorSpace, HeifContext, LibHeif, RgbChroma};
+
+    /// Quality the converted JPEG images are encoded at.
+    const JPEG_QUALITY: u8 = 90;
+
+    /// The primary image of a HEIF file, rotated and cropped as the file
+    /// says.
+    fn decode(contents: &[u8]) -> Result<RgbImage, String> {
+        let context = HeifContext::read_from_bytes(contents).map_err(|e| e.to_string())?;
+        let handle = context.primary_image_handle().map_err(|e| e.to_string())?;
+        let image = LibHeif::new()
+            .decode(&handle, ColorSpace::Rgb(RgbChroma::Rgb), None)
+            .map_err(|e| e.to_string())?;
+        let plane = image
+            .planes()
+            .interleaved
+            .ok_or("libheif decoded no RGB plane")?;
+        let (width, height) = (plane.width, plane.height);
+        let row = width as usize * 3;
+        let mut pixels = Vec::with_capacity(row * height as usize);
+        for line in plane.data.chunks(plane.stride).take(height as usize) {
+            pixels.extend_from_slice(line.get(..row).ok_or("libheif decoded a short row")?);
+        }
+        RgbImage::from_raw(width, height, pixels)
+            .ok_or_else(|| "libheif decoded a truncated image".to_string())
+    }
+
+    /// `contents` decoded and encoded as `target`.
+    pub fn convert(contents: &[u8], target: HeicTarget) -> Result<Vec<u8>, String> {
+        let image = decode(contents)?;
+        let mut converted = Vec::new();
+        match target {
+            HeicTarget::Jpeg => image
+                .write_with_encoder(JpegEncoder::new_with_quality(&mut converted, JPEG_QUALITY)),
+            HeicTarget::Webp => image.write_with_encoder(WebPEncoder::new_lossless(&mut converted)),
+        }
+        .map_err(|e| e.to_string())?;
+        Ok(converted)
+    }
+}
+
+#[cfg(not(feature = "heic"))]
+mod convert {
+    use super::HeicTarget;
+
+    pub fn convert(_contents: &[u8], _target: HeicTarget) -> Result<Vec<u8>, String> {
+        Err("this build lacks the heic feature".to_string())
+    }
+}
+
+/// Converts an upload in HEIF to `HEIC_CONVERT_TO`, returning the file to
+/// store in its place and the format it was uploaded in. Other uploads are
+/// returned as they are.
+pub async fn convert_upload(
+    config: &Config,
+    file: awmp::File,
+) -> Result<(awmp::File, Option<&'static str>), UserError> {
+    let target = config.heic_convert_to;
+    db::block(move || {
+        let mut contents = file.as_ref().as_file();
+        let mut head = Vec::new();
+        let read = contents
+            .rewind()
+            .and_then(|_| contents.take(64).read_to_end(&mut head));
+        if let Err(e) = read {
+            error!("Failed to read upload to check its format: {}", e);
+            return Err(UserError::UnexpectedError);
+        }
+        let Some(format) = heif_format(&head) else {
+            return Ok((file, None));
+        };
+        if !cfg!(feature = "heic") {
+            warn!(
+                "Rejected {} upload, this build lacks the heic feature",
+                format
+            );
+            return Err(UserError::UnsupportedMediaTypeError);
+        }
+        let mut original = Vec::new();
+        if let Err(e) = contents
+            .rewind()
+            .and_then(|_| contents.read_to_end(&mut original))
+        {
+            error!("Failed to read {} upload: {}", format, e);
+            return Err(UserError::UnexpectedError);
+        }
+        let converted = convert::convert(&original, target).map_err(|e| {
+            warn!("Rejected {} upload that cannot be decoded: {}", format, e);
+            UserError::UnsupportedMediaTypeError
+        })?;
+        let stored = NamedTempFile::new()
+            .and_then(|mut stored| stored.write_all(&converted).map(|_| stored))
+            .map_err(|e| {
+                error!("Failed to buffer converted {} upload: {}", format, e);
+                UserError::UnexpectedError
+            })?;
+        info!(
+            "Converted {} upload of {} bytes to {} bytes of {:?}",
+            format,
+            original.len(),
+            converted.len(),
+            target
+        );
+        Ok((
+            awmp::File::new_with_file_name(stored, format!("upload.{}", target.extension())),
+            Some(format),
+        ))
+    })
+    .await?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The start of a file with an `ftyp` box of the given brands.
+    fn ftyp(major: &[u8; 4], compatible: &[&[u8; 4]]) -> Vec<u8> {
+        let size = 16 + 4 * compatible.len() as u32;
+        let mut head = size.to_be_bytes().to_vec();
+        head.extend_from_slice(b"ftyp");
+        head.extend_from_slice(major);
+        head.extend_from_slice(&[0; 4]);
+        for brand in compatible {
+            head.extend_from_slice(*brand);
+        }
+        head.extend_from_slice(b"\0\0\0\x08meta");
+        head
+    }
+
+    #[test]
+    fn test_heif_format() {
+        assert_eq!(
+            heif_format(&ftyp(b"heic", &[b"mif1", b"heic"])),
+            Some("heic")
+        );
+        assert_eq!(heif_format(&ftyp(b"mif1", &[b"heic"])), Some("heic"));
+        assert_eq!(heif_format(&ftyp(b"mif1", &[b"mif1"])), Some("heif"));
+        assert_eq!(heif_format(&ftyp(b"avif", &[b"mif1", b"miaf"])), None);
+        assert_eq!(heif_format(&ftyp(b"isom", &[b"mp41"])), None);
+        assert_eq!(heif_format(b"\xff\xd8\xff\xe0\0\x10JFIF"), None);
+        assert_eq!(heif_format(b"ftyp"), None);
+    }
+
+    #[test]
+    fn test_original_format() {
+        assert_eq!(stored_extension("jpg", Some("heic")), "heic.jpg");
+        assert_eq!(stored_extension("jpg", None), "jpg");
+        assert_eq!(
+            original_format("acme/2026/01/01/cat.heic.jpg"),
+            Some("heic")
+        );
+        assert_eq!(original_format("acme/cat.heif.webp"), Some("heif"));
+        assert_eq!(original_format("acme/cat.jpg"), None);
+        assert_eq!(original_format("acme/heic.jpg"), None);
+        assert_eq!(original_format("acme/cat"), None);
+    }
+}
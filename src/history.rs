@@ -0,0 +1,266 @@
+use crate::auth;
+use crate::client_ip::ClientIp;
+use crate::config::Config;
+use crate::csrf;
+use crate::db::DbConn;
+use crate::errors::UserError;
+use crate::links::{LinkBuilder, Links};
+use crate::models::{Cat, CatChange, NewCatChange, Tenant};
+use crate::repository;
+use crate::sessions::{self, SessionAdmin};
+use actix_session::SessionExt;
+use actix_web::dev::Payload;
+use actix_web::http::header::AUTHORIZATION;
+use actix_web::{web, FromRequest, HttpMessage, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use diesel::{PgConnection, QueryResult};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::convert::Infallible;
+use std::future::{ready, Ready};
+use std::net::IpAddr;
+
+/// The fields of a cat whose changes are kept, as named in its JSON form.
+const TRACKED_FIELDS: &[&str] = &[
+    "name",
+    "image_path",
+    "private",
+    "status",
+    "latitude",
+    "longitude",
+    "pending_review",
+];
+
+pub const DEFAULT_HISTORY_LIMIT: i64 = 50;
+pub const MAX_HISTORY_LIMIT: i64 = 200;
+
+/// Who sent a request, to be resolved into the actor of the changes it
+/// makes.
+pub struct Requester {
+    authorization: Option<String>,
+    /// Only set when the request carries the session's CSRF token.
+    session_admin: Option<SessionAdmin>,
+    client_ip: Option<IpAddr>,
+}
+
+impl Requester {
+    pub fn authorization(&self) -> Option<&str> {
+        self.authorization.as_deref()
+    }
+
+    pub fn session_admin(&self) -> Option<&SessionAdmin> {
+        self.session_admin.as_ref()
+    }
+
+    /// `admin:<username>` when the request carries an admin's credentials,
+    /// otherwise `ip:<client address>`.
+    pub fn actor(&self, connection: &mut PgConnection, tenant_id: i32) -> QueryResult<String> {
+        if let Some(username) = auth::admin_username(connection, tenant_id, self)? {
+            return Ok(format!("admin:{}", username));
+        }
+        Ok(match self.client_ip {
+            Some(ip) => format!("ip:{}", ip),
+            None => "anonymous".to_string(),
+        })
+    }
+}
+
+impl FromRequest for Requester {
+    type Error = Infallible;
+    type Future = Ready<Result<Requester, Infallible>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        let session = req.get_session();
+        let session_admin = sessions::admin(&session)
+            .filter(|_| sessions::verify_csrf(&session, csrf::request_token(req).as_deref()));
+        ready(Ok(Requester {
+            authorization: req
+                .headers()
+                .get(AUTHORIZATION)
+                .and_then(|value| value.to_str().ok())
+                .map(str::to_string),
+            session_admin,
+            client_ip: req.extensions().get::<ClientIp>().map(|ClientIp(ip)| *ip),
+        }))
+    }
+}
+
+/// The tracked fields that differ between `before` and `after`, with their
+/// old and new values. Every field that is set counts as changed when there
+/// is no `before`, i.e. the cat was just created.
+fn changed_fields(before: Option<&Cat>, after: &Cat) -> Vec<(&'static str, Option<Value>, Value)> {
+    let to_value = |cat: &Cat| serde_json::to_value(cat).expect("Cats serialize to JSON");
+    let before = before.map(to_value);
+    let after = to_value(after);
+    TRACKED_FIELDS
+        .iter()
+        .filter_map(|field| {
+            let old = before.as_ref().map(|before| before[field].clone());
+            let new = after[field].clone();
+            match &old {
+                Some(old) if *old == new => None,
+                None if new.is_null() => None,
+                _ => Some((*field, old, new)),
+            }
+        })
+        .collect()
+}
+
+/// Stores the changes from `before` to `after` in the cat's history, with
+/// `before` being `None` for a new cat.
+///
+/// Call this inside the transaction that changes the cat, like
+/// `outbox::record`.
+pub fn record(
+    connection: &mut PgConnection,
+    tenant_id: i32,
+    actor: &str,
+    before: Option<&Cat>,
+    after: &Cat,
+    changed_at: DateTime<Utc>,
+) -> QueryResult<()> {
+    let changes: Vec<NewCatChange> = changed_fields(before, after)
+        .into_iter()
+        .map(|(field, old_value, new_value)| NewCatChange {
+            tenant_id,
+            cat_id: after.id,
+            field: field.to_string(),
+            old_value,
+            new_value,
+            actor: actor.to_string(),
+            changed_at,
+        })
+        .collect();
+    if !changes.is_empty() {
+        repository::insert_cat_changes(connection, &changes)?;
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct CatPath {
+    id: i32,
+}
+
+#[derive(Deserialize)]
+pub struct HistoryQuery {
+    /// Id of the last change of the previous page.
+    after: Option<i64>,
+    limit: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct HistoryPage {
+    pub changes: Vec<CatChange>,
+    /// The `after` value for the next page, if there may be one.
+    pub next_after: Option<i64>,
+    /// `self`, `cat` and, with `next_after`, `next`.
+    #[serde(skip_deserializing)]
+    _links: Links,
+}
+
+/// The cat's changes, oldest first, a page at a time.
+pub async fn history_endpoint(
+    connection: DbConn,
+    config: web::Data<Config>,
+    tenant: Tenant,
+    path: web::Path<CatPath>,
+    query: web::Query<HistoryQuery>,
+) -> Result<HttpResponse, UserError> {
+    let cat_id = path.id;
+    let after = query.after;
+    let limit = query
+        .limit
+        .unwrap_or(DEFAULT_HISTORY_LIMIT)
+        .clamp(1, MAX_HISTORY_LIMIT);
+
+    let changes = connection
+        .run("load cat history", move |connection| {
+            repository::find_cat(connection, tenant.id, cat_id)?;
+            repository::list_cat_changes(connection, tenant.id, cat_id, after, limit)
+        })
+        .await?;
+
+    let next_after = match changes.len() as i64 {
+        len if len == limit => changes.last().map(|change| change.id),
+        _ => None,
+    };
+    let links = LinkBuilder::new(&config);
+    let path = format!("/api/cat/{}/history", cat_id);
+    let page = |after: Option<i64>| match after {
+        Some(after) => links.link(&format!("{}?after={}&limit={}", path, after, limit)),
+        None => links.link(&format!("{}?limit={}", path, limit)),
+    };
+    let mut _links = Links::from([
+        ("self", page(after)),
+        ("cat", links.link(&format!("/api/cat/{}", cat_id))),
+    ]);
+    if next_after.is_some() {
+        _links.insert("next", page(next_after));
+    }
+    Ok(HttpResponse::Ok().json(HistoryPage {
+        changes,
+        next_after,
+        _links,
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::CatStatus;
+    use serde_json::json;
+    use uuid::Uuid;
+
+    fn cat() -> Cat {
+        Cat {
+            id: 1,
+            public_id: Uuid::nil(),
+            name: "Whiskers".to_string(),
+            image_path: "/image/default/cat.jpg".to_string(),
+            created_at: Utc::now(),
+            private: false,
+            status: CatStatus::Available,
+            latitude: None,
+            longitude: None,
+            image_hash: None,
+            pending_review: false,
+            updated_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_changed_fields_of_new_cat() {
+        let fields: Vec<_> = changed_fields(None, &cat())
+            .into_iter()
+            .map(|(field, old, _)| (field, old))
+            .collect();
+        assert_eq!(
+            fields,
+            [
+                ("name", None),
+                ("image_path", None),
+                ("private", None),
+                ("status", None),
+                ("pending_review", None)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_changed_fields() {
+        let before = cat();
+        let mut after = cat();
+        assert!(changed_fields(Some(&before), &after).is_empty());
+
+        after.status = CatStatus::Pending;
+        after.latitude = Some(50.45);
+        assert_eq!(
+            changed_fields(Some(&before), &after),
+            [
+                ("status", Some(json!("available")), json!("pending")),
+                ("latitude", Some(Value::Null), json!(50.45)),
+            ]
+        );
+    }
+}
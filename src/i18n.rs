@@ -0,0 +1,189 @@
+use crate::errors::UserError;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderValue, ACCEPT_LANGUAGE, CONTENT_LANGUAGE, VARY};
+use actix_web::middleware::Next;
+use actix_web::{web, Error};
+use log::{info, warn};
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Translated messages per locale, loaded from `<locale>.json` files that map
+/// message keys to text. Adding a locale only takes dropping in another file.
+#[derive(Default)]
+pub struct Catalog {
+    locales: HashMap<String, HashMap<String, String>>,
+}
+
+impl Catalog {
+    pub fn load(dir: &Path) -> io::Result<Catalog> {
+        let mut locales = HashMap::new();
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != "json") {
+                continue;
+            }
+            let Some(locale) = path.file_stem().map(|s| s.to_string_lossy().to_lowercase()) else {
+                continue;
+            };
+            let messages = fs::read_to_string(&path).and_then(|content| {
+                serde_json::from_str(&content)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+            });
+            match messages {
+                Ok(messages) => {
+                    locales.insert(locale, messages);
+                }
+                Err(e) => warn!("Skipping locale file {}: {}", path.display(), e),
+            }
+        }
+        info!("Loaded {} locale(s) from {}", locales.len(), dir.display());
+        Ok(Catalog { locales })
+    }
+
+    /// Picks the best available locale for an `Accept-Language` header.
+    pub fn negotiate(&self, accept_language: &str) -> Option<&str> {
+        parse_accept_language(accept_language)
+            .into_iter()
+            .find_map(|tag| {
+                let primary = tag.split('-').next().unwrap_or(&tag);
+                self.locales
+                    .get_key_value(tag.as_str())
+                    .or_else(|| self.locales.get_key_value(primary))
+            })
+            .map(|(locale, _)| locale.as_str())
+    }
+
+    pub fn translator(&self, locale: &str) -> Translator<'_> {
+        Translator {
+            messages: self.locales.get(locale),
+        }
+    }
+}
+
+/// Looks up messages in a single locale, falling back to the built-in English text.
+pub struct Translator<'a> {
+    messages: Option<&'a HashMap<String, String>>,
+}
+
+impl Translator<'_> {
+    pub fn fallback() -> Translator<'static> {
+        Translator { messages: None }
+    }
+
+    /// Returns the message for `key` with `{name}` placeholders filled from `args`.
+    pub fn text(&self, key: &str, fallback: &str, args: &[(&str, String)]) -> String {
+        let template = self
+            .messages
+            .and_then(|messages| messages.get(key))
+            .map_or(fallback, String::as_str);
+        args.iter()
+            .fold(template.to_string(), |text, (name, value)| {
+                text.replace(&format!("{{{}}}", name), value)
+            })
+    }
+}
+
+/// Returns the language tags of an `Accept-Language` header, most preferred first.
+fn parse_accept_language(header: &str) -> Vec<String> {
+    let mut tags: Vec<(String, f32)> = header
+        .split(',')
+        .filter_map(|part| {
+            let mut pieces = part.split(';');
+            let tag = pieces.next()?.trim().to_lowercase();
+            let quality = pieces
+                .find_map(|p| p.trim().strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.trim().parse().ok())?;
+            (!tag.is_empty() && tag != "*" && quality > 0.0).then_some((tag, quality))
+        })
+        .collect();
+    tags.sort_by(|a, b| b.1.total_cmp(&a.1));
+    tags.into_iter().map(|(tag, _)| tag).collect()
+}
+
+/// Re-renders `UserError` responses in the language the client asked for.
+pub async fn localize_errors(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let catalog = req.app_data::<web::Data<Catalog>>().cloned();
+    let accept_language = req
+        .headers()
+        .get(ACCEPT_LANGUAGE)
+        .and_then(|value| value.to_str().ok())
+        .map(str::to_string);
+    let res = next.call(req).await?;
+
+    let user_error = res
+        .response()
+        .error()
+        .and_then(|e| e.as_error::<UserError>());
+    let (Some(catalog), Some(accept_language), Some(user_error)) =
+        (catalog, accept_language, user_error)
+    else {
+        return Ok(res.map_into_boxed_body());
+    };
+    let Some(locale) = catalog.negotiate(&accept_language) else {
+        return Ok(res.map_into_boxed_body());
+    };
+
+    let mut response = user_error.localized_response(&catalog.translator(locale));
+    let headers = response.headers_mut();
+    // Headers set alongside the error, such as the `Allow` of a 405, are kept
+    let rendered: Vec<_> = headers.keys().cloned().collect();
+    for (name, value) in res.headers() {
+        if !rendered.contains(name) {
+            headers.append(name.clone(), value.clone());
+        }
+    }
+    if let Ok(value) = HeaderValue::from_str(locale) {
+        headers.insert(CONTENT_LANGUAGE, value);
+    }
+    headers.insert(VARY, HeaderValue::from_static("Accept-Language"));
+    Ok(res.into_response(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn catalog() -> Catalog {
+        let uk = HashMap::from([("error.not_found".to_string(), "Не знайдено".to_string())]);
+        Catalog {
+            locales: HashMap::from([("uk".to_string(), uk), ("en".to_string(), HashMap::new())]),
+        }
+    }
+
+    #[test]
+    fn test_parse_accept_language_orders_by_quality() {
+        assert_eq!(
+            parse_accept_language("en;q=0.5, uk-UA, *;q=0.1, fr;q=0"),
+            vec!["uk-ua", "en"]
+        );
+    }
+
+    #[test]
+    fn test_negotiate_falls_back_to_primary_subtag() {
+        let catalog = catalog();
+        assert_eq!(catalog.negotiate("uk-UA,en;q=0.8"), Some("uk"));
+        assert_eq!(catalog.negotiate("fr, en;q=0.5"), Some("en"));
+        assert_eq!(catalog.negotiate("fr"), None);
+    }
+
+    #[test]
+    fn test_translator_uses_fallback_and_args() {
+        let catalog = catalog();
+        assert_eq!(
+            catalog
+                .translator("uk")
+                .text("error.not_found", "Not found", &[]),
+            "Не знайдено"
+        );
+        assert_eq!(
+            Translator::fallback().text("validation.length", "max {max}", &[("max", "5".into())]),
+            "max 5"
+        );
+    }
+}
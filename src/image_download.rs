@@ -0,0 +1,201 @@
+use crate::config::Config;
+use crate::db;
+use crate::errors::UserError;
+use crate::telemetry;
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::http::Uri;
+use log::{error, warn};
+use opentelemetry::KeyValue;
+use std::io::Write;
+use std::net::{IpAddr, ToSocketAddrs};
+use tempfile::NamedTempFile;
+
+/// The extension an image of the given content type is stored under, for
+/// the image types accepted from URLs and `POST /api/uploads`.
+pub fn image_extension(content_type: &str) -> Option<&'static str> {
+    let mime = content_type.split(';').next()?.trim();
+    match mime.to_ascii_lowercase().as_str() {
+        "image/jpeg" => Some("jpg"),
+        "image/png" => Some("png"),
+        "image/gif" => Some("gif"),
+        "image/webp" => Some("webp"),
+        // Converted once received, see `heic`
+        "image/heic" | "image/heif" => Some("heic"),
+        _ => None,
+    }
+}
+
+/// Whether `ip` is reachable on the public internet, as opposed to
+/// loopback, private, link local and other special purpose ranges.
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => {
+            !(ip.is_loopback()
+                || ip.is_private()
+                || ip.is_link_local()
+                || ip.is_unspecified()
+                || ip.is_broadcast()
+                || ip.is_documentation()
+                || ip.is_multicast()
+                // Shared address space for carrier-grade NAT, 100.64.0.0/10
+                || (ip.octets()[0] == 100 && ip.octets()[1] & 0xc0 == 64))
+        }
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public(IpAddr::V4(ip)),
+            None => {
+                let first = ip.segments()[0];
+                !(ip.is_loopback()
+                    || ip.is_unspecified()
+                    || ip.is_multicast()
+                    // Unique local, fc00::/7
+                    || first & 0xfe00 == 0xfc00
+                    // Link local, fe80::/10
+                    || first & 0xffc0 == 0xfe80)
+            }
+        },
+    }
+}
+
+/// Checks that the host of the `http` or `https` URL `url` resolves, and
+/// only to public addresses, answering why not otherwise.
+pub async fn check_public_url(url: &str) -> Result<Result<(), &'static str>, UserError> {
+    let Ok(uri) = url.parse::<Uri>() else {
+        return Ok(Err("not a URL"));
+    };
+    let default_port = match uri.scheme_str() {
+        Some("http") => 80,
+        Some("https") => 443,
+        _ => return Ok(Err("unsupported scheme")),
+    };
+    let Some(host) = uri.host() else {
+        return Ok(Err("no host"));
+    };
+    let host = host.trim_matches(['[', ']']).to_string();
+    let port = uri.port_u16().unwrap_or(default_port);
+    let addrs = db::block(move || {
+        (host.as_str(), port)
+            .to_socket_addrs()
+            .map(|addrs| addrs.collect::<Vec<_>>())
+    })
+    .await?;
+    Ok(match addrs {
+        Err(_) => Err("host does not resolve"),
+        Ok(addrs) if addrs.is_empty() || addrs.iter().any(|addr| !is_public(addr.ip())) => {
+            Err("host is not public")
+        }
+        Ok(_) => Ok(()),
+    })
+}
+
+fn rejected(url: &str, reason: &str) -> UserError {
+    warn!("Rejected image URL {}: {}", url, reason);
+    UserError::ImageDownloadError
+}
+
+/// Downloads the image at `url` into a temporary file, handed back like a
+/// multipart upload so it goes through the same scanning and storage.
+///
+/// Only `http` and `https` URLs of hosts with public addresses are fetched,
+/// unless `IMAGE_DOWNLOAD_ALLOW_PRIVATE` is set, and redirects are not
+/// followed so they cannot lead elsewhere.
+pub async fn download(config: &Config, url: &str) -> Result<awmp::File, UserError> {
+    // The host only, URLs may carry credentials
+    let attributes = url
+        .parse::<Uri>()
+        .ok()
+        .and_then(|uri| Some(KeyValue::new("server.address", uri.host()?.to_string())))
+        .into_iter()
+        .collect();
+    telemetry::in_span_async("image download", attributes, async {
+        fetch(config, url)
+            .await
+            .inspect_err(telemetry::record_error)
+    })
+    .await
+}
+
+async fn fetch(config: &Config, url: &str) -> Result<awmp::File, UserError> {
+    let uri: Uri = url.parse().map_err(|_| rejected(url, "not a URL"))?;
+    if !matches!(uri.scheme_str(), Some("http" | "https")) {
+        return Err(rejected(url, "unsupported scheme"));
+    }
+    if !config.image_download_allow_private {
+        check_public_url(url)
+            .await?
+            .map_err(|reason| rejected(url, reason))?;
+    }
+
+    let client = awc::Client::builder()
+        .timeout(config.image_download_timeout)
+        .disable_redirects()
+        .finish();
+    let mut response = client
+        .get(url)
+        .send()
+        .await
+        .map_err(|e| rejected(url, &e.to_string()))?;
+    if !response.status().is_success() {
+        return Err(rejected(url, response.status().as_str()));
+    }
+    let extension = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .and_then(image_extension)
+        .ok_or_else(|| rejected(url, "not an image"))?;
+    let body = response
+        .body()
+        .limit(config.image_download_max_bytes)
+        .await
+        .map_err(|e| rejected(url, &e.to_string()))?;
+
+    let file = db::block(move || {
+        let mut file = NamedTempFile::new()?;
+        file.write_all(&body)?;
+        Ok::<_, std::io::Error>(file)
+    })
+    .await?
+    .map_err(|e| {
+        error!("Failed to buffer downloaded image: {}", e);
+        UserError::UnexpectedError
+    })?;
+    Ok(awmp::File::new_with_file_name(
+        file,
+        format!("download.{}", extension),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_image_extension() {
+        assert_eq!(image_extension("image/jpeg"), Some("jpg"));
+        assert_eq!(image_extension("IMAGE/PNG; charset=binary"), Some("png"));
+        assert_eq!(image_extension("image/heic"), Some("heic"));
+        assert_eq!(image_extension("image/svg+xml"), None);
+        assert_eq!(image_extension("text/html"), None);
+    }
+
+    #[test]
+    fn test_is_public() {
+        let public = |ip: &str| is_public(ip.parse().unwrap());
+        assert!(public("93.184.216.34"));
+        assert!(public("2606:2800:220:1::1"));
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "192.168.0.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!public(ip), "{} is not public", ip);
+        }
+    }
+}
@@ -0,0 +1,129 @@
+//! Stored files are sharded into a directory per day, as in
+//! `acme/2026/10/15/<uuid>.jpg`, so no directory grows without bound and
+//! backups can pick up a day at a time. Files stored flat before that are
+//! moved by `catdex shard-images`, and their old URLs redirect to where
+//! they went.
+use crate::db::DbConn;
+use crate::errors::UserError;
+use crate::repository;
+use crate::signed_urls;
+use actix_web::http::header::LOCATION;
+use actix_web::{HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use std::path::Path;
+
+/// The key of the file `name` stored in `dir` on `date`.
+pub fn shard_key(dir: &str, name: &str, date: DateTime<Utc>) -> String {
+    format!("{}/{}/{}", dir, date.format("%Y/%m/%d"), name)
+}
+
+fn is_number(value: &str, digits: usize) -> bool {
+    value.len() == digits && value.bytes().all(|byte| byte.is_ascii_digit())
+}
+
+/// The file name at the end of `rest`, the part of a key after its
+/// directory, dropping the date shard in front of it.
+pub fn strip_shard(rest: &str) -> &str {
+    match rest.splitn(4, '/').collect::<Vec<_>>()[..] {
+        [year, month, day, name]
+            if is_number(year, 4) && is_number(month, 2) && is_number(day, 2) =>
+        {
+            name
+        }
+        _ => rest,
+    }
+}
+
+/// The directory in front of the date shard `dir` ends in, if it does.
+pub fn unsharded_dir(dir: &str) -> Option<&str> {
+    match dir.rsplitn(4, '/').collect::<Vec<_>>()[..] {
+        [day, month, year, dir]
+            if is_number(year, 4) && is_number(month, 2) && is_number(day, 2) =>
+        {
+            Some(dir)
+        }
+        _ => None,
+    }
+}
+
+pub fn is_sharded(key: &str) -> bool {
+    key.rsplit_once('/')
+        .and_then(|(dir, _)| unsharded_dir(dir))
+        .is_some()
+}
+
+/// Where the flat `key` moves to when sharded by `date`, none when it is
+/// sharded already or not in a directory.
+pub fn sharded(key: &str, date: DateTime<Utc>) -> Option<String> {
+    if is_sharded(key) {
+        return None;
+    }
+    let (dir, name) = key.rsplit_once('/')?;
+    Some(shard_key(dir, name, date))
+}
+
+/// Redirects a link to a public image that `shard-images` has since moved
+/// to its sharded path. Serves the `/image` mount's missing files.
+pub async fn moved_image_endpoint(
+    req: HttpRequest,
+    connection: DbConn,
+) -> Result<HttpResponse, UserError> {
+    let Some(key) = req.path().strip_prefix("/image/") else {
+        return Err(UserError::NotFoundError);
+    };
+    let Some((dir, name)) = key.rsplit_once('/') else {
+        return Err(UserError::NotFoundError);
+    };
+    if is_sharded(key)
+        || name.is_empty()
+        || name.starts_with('.')
+        || !signed_urls::is_public_image(Path::new(key))
+    {
+        return Err(UserError::NotFoundError);
+    }
+
+    let (dir, name) = (dir.to_string(), name.to_string());
+    let moved = connection
+        .run("look up moved image", move |connection| {
+            repository::find_sharded_image_path(connection, &dir, &name)
+        })
+        .await?
+        .ok_or(UserError::NotFoundError)?;
+
+    let location = match req.query_string() {
+        "" => moved,
+        query => format!("{}?{}", moved, query),
+    };
+    Ok(HttpResponse::MovedPermanently()
+        .insert_header((LOCATION, location))
+        .finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_shard_keys() {
+        let date = Utc.with_ymd_and_hms(2026, 3, 7, 12, 0, 0).unwrap();
+        assert_eq!(
+            shard_key("acme/private", "cat.jpg", date),
+            "acme/private/2026/03/07/cat.jpg"
+        );
+        assert_eq!(
+            sharded("acme/cat.jpg", date).as_deref(),
+            Some("acme/2026/03/07/cat.jpg")
+        );
+        assert_eq!(sharded("acme/2026/03/07/cat.jpg", date), None);
+        assert_eq!(sharded("cat.jpg", date), None);
+
+        assert!(is_sharded("acme/private/records/2026/03/07/x.pdf"));
+        assert!(!is_sharded("acme/private/x.pdf"));
+        assert!(!is_sharded("acme/26/03/07/x.pdf"));
+
+        assert_eq!(strip_shard("2026/03/07/cat.jpg"), "cat.jpg");
+        assert_eq!(strip_shard("cat.jpg"), "cat.jpg");
+        assert_eq!(strip_shard("private/cat.jpg"), "private/cat.jpg");
+    }
+}
@@ -0,0 +1,353 @@
+//! Serves JPEG and PNG images as lossy WebP, or AVIF with the `avif`
+//! feature, to clients whose `Accept` header lists the format. An image is
+//! converted on its first such request and the variant kept in
+//! `IMAGE_VARIANT_DIR`, then served whenever it is smaller than the
+//! original.
+//!
+//! Variants are only served while their original is stored, so deleting an
+//! image takes its variants offline too, and `gc-images` deletes them along
+//! with it. Their files are named after the
+//! image key, which never changes contents, so they are never stale.
+use crate::config::Config;
+use crate::db;
+use crate::file_store::FileStore;
+use crate::signed_urls;
+use actix_files::NamedFile;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderValue, ACCEPT, VARY};
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpRequest, HttpResponse};
+use log::{error, warn};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use tempfile::NamedTempFile;
+
+/// Extensions of the stored images that are converted.
+const CONVERTIBLE_EXTENSIONS: [&str; 3] = ["jpg", "jpeg", "png"];
+/// ravif's speed from 1 to 10, trading compression for encoding time.
+#[cfg(feature = "avif")]
+const AVIF_SPEED: u8 = 8;
+#[cfg(feature = "avif")]
+const AVIF_QUALITY: u8 = 70;
+/// libwebp's lossy quality from 0 to 100.
+#[cfg(feature = "image-processing")]
+const WEBP_QUALITY: f32 = 75.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(not(feature = "image-processing"), allow(dead_code))]
+pub enum Format {
+    #[cfg(feature = "avif")]
+    Avif,
+    WebP,
+}
+
+/// The formats images are converted to, the most compact first. None
+/// without the `image-processing` feature, which decodes them.
+const FORMATS: &[Format] = &[
+    #[cfg(feature = "avif")]
+    Format::Avif,
+    #[cfg(feature = "image-processing")]
+    Format::WebP,
+];
+
+impl Format {
+    pub fn mime(self) -> &'static str {
+        match self {
+            #[cfg(feature = "avif")]
+            Format::Avif => "image/avif",
+            Format::WebP => "image/webp",
+        }
+    }
+
+    fn extension(self) -> &'static str {
+        match self {
+            #[cfg(feature = "avif")]
+            Format::Avif => "avif",
+            Format::WebP => "webp",
+        }
+    }
+}
+
+/// Whether the image `key` may be served converted, so that its responses
+/// vary by `Accept`.
+pub fn is_convertible(config: &Config, key: &str) -> bool {
+    config.image_format_negotiation
+        && !FORMATS.is_empty()
+        && Path::new(key)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .is_some_and(|ext| CONVERTIBLE_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str()))
+}
+
+/// The formats listed in an `Accept` header without `q=0`, in the order of
+/// `FORMATS`. Wildcards do not count, as browsers send `image/*` whether
+/// they decode these formats or not.
+fn accepted_formats(accept: &str) -> Vec<Format> {
+    let accepted: Vec<&str> = accept
+        .split(',')
+        .filter_map(|item| {
+            let mut params = item.split(';');
+            let mime = params.next()?.trim();
+            let refused = params.any(|param| {
+                param
+                    .trim()
+                    .strip_prefix("q=")
+                    .and_then(|quality| quality.trim().parse::<f32>().ok())
+                    .is_some_and(|quality| quality <= 0.0)
+            });
+            (!refused).then_some(mime)
+        })
+        .collect();
+    FORMATS
+        .iter()
+        .copied()
+        .filter(|format| {
+            accepted
+                .iter()
+                .any(|mime| mime.eq_ignore_ascii_case(format.mime()))
+        })
+        .collect()
+}
+
+#[cfg(feature = "image-processing")]
+fn convert(contents: &[u8], format: Format) -> Result<Vec<u8>, String> {
+    use image::DynamicImage;
+
+    let image = image::load_from_memory(contents).map_err(|e| e.to_string())?;
+    // Both encoders take 8 bit pixels
+    let image = match image.color().has_alpha() {
+        true => DynamicImage::ImageRgba8(image.to_rgba8()),
+        false => DynamicImage::ImageRgb8(image.to_rgb8()),
+    };
+    match format {
+        #[cfg(feature = "avif")]
+        Format::Avif => {
+            let mut converted = Vec::new();
+            image
+                .write_with_encoder(image::codecs::avif::AvifEncoder::new_with_speed_quality(
+                    &mut converted,
+                    AVIF_SPEED,
+                    AVIF_QUALITY,
+                ))
+                .map_err(|e| e.to_string())?;
+            Ok(converted)
+        }
+        // Lossy through libwebp, as the image crate only encodes lossless
+        // WebP, which is rarely smaller than a JPEG
+        Format::WebP => {
+            let (width, height) = (image.width(), image.height());
+            let encoder = match &image {
+                DynamicImage::ImageRgba8(pixels) => webp::Encoder::from_rgba(pixels, width, height),
+                _ => webp::Encoder::from_rgb(image.as_bytes(), width, height),
+            };
+            // Fails on images past WebP's 16383 pixels a side
+            let converted = encoder
+                .encode_simple(false, WEBP_QUALITY)
+                .map_err(|e| format!("{:?}", e))?;
+            Ok(converted.to_vec())
+        }
+    }
+}
+
+#[cfg(not(feature = "image-processing"))]
+fn convert(_contents: &[u8], _format: Format) -> Result<Vec<u8>, String> {
+    Err("this build lacks the image-processing feature".to_string())
+}
+
+/// The key of the image the variant cached in the variant directory under
+/// `name` was converted from, see `variant`.
+pub fn original_key(name: &str) -> Option<&str> {
+    name.strip_suffix(".webp")
+        .or_else(|| name.strip_suffix(".avif"))
+}
+
+/// The `format` variant of the image `key` cached in `dir`, converted from
+/// its stored contents when not cached yet. None when the variant is no
+/// smaller than the original, or the original cannot be converted, which
+/// is cached as an empty file so it is not tried again.
+fn variant(
+    store: &dyn FileStore,
+    dir: &Path,
+    key: &str,
+    format: Format,
+) -> io::Result<Option<PathBuf>> {
+    let path = dir.join(format!("{}.{}", key, format.extension()));
+    match fs::metadata(&path) {
+        Ok(metadata) => return Ok((metadata.len() > 0).then_some(path)),
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        Err(_) => {}
+    }
+    let contents = store.get(key)?;
+    let converted = convert(&contents, format).unwrap_or_else(|e| {
+        warn!("Failed to convert {} to {}: {}", key, format.mime(), e);
+        Vec::new()
+    });
+    let smaller = !converted.is_empty() && converted.len() < contents.len();
+    let parent = path.parent().unwrap_or(dir);
+    fs::create_dir_all(parent)?;
+    // Written aside and renamed, so concurrent requests never see half a file
+    let mut file = NamedTempFile::new_in(parent)?;
+    if smaller {
+        file.write_all(&converted)?;
+    }
+    file.persist(&path).map_err(|e| e.error)?;
+    Ok(smaller.then_some(path))
+}
+
+/// The most compact variant of the stored image `key` that `req` accepts,
+/// if any is smaller than the original.
+pub async fn negotiate(
+    req: &HttpRequest,
+    config: &Config,
+    store: web::Data<dyn FileStore>,
+    key: &str,
+) -> Option<(PathBuf, Format)> {
+    if !is_convertible(config, key) {
+        return None;
+    }
+    let formats = req
+        .headers()
+        .get(ACCEPT)
+        .and_then(|value| value.to_str().ok())
+        .map(accepted_formats)
+        .unwrap_or_default();
+    if formats.is_empty() {
+        return None;
+    }
+    let (dir, key) = (config.image_variant_dir.clone(), key.to_string());
+    let found = db::block(move || {
+        // Variants of deleted images are not served
+        store.size(&key)?;
+        for format in formats {
+            if let Some(path) = variant(store.get_ref(), &dir, &key, format)? {
+                return Ok(Some((path, format)));
+            }
+        }
+        Ok::<_, io::Error>(None)
+    })
+    .await
+    .ok()?;
+    found.unwrap_or_else(|e| {
+        if e.kind() != io::ErrorKind::NotFound {
+            error!("Failed to serve a converted image: {}", e);
+        }
+        None
+    })
+}
+
+/// The variant at `path` as a response to `req`, with the validators and
+/// range support of a static file.
+pub fn respond(req: &HttpRequest, path: &Path, format: Format) -> io::Result<HttpResponse> {
+    let file = NamedFile::open(path)?.set_content_type(format.mime().parse().expect("A MIME type"));
+    let mut response = file.into_response(req);
+    response
+        .headers_mut()
+        .insert(VARY, HeaderValue::from_static("accept"));
+    Ok(response)
+}
+
+/// The key of a public image requested under `/image`, if it is one that
+/// may be served. Keys with hidden or empty segments are left to the
+/// static files service to refuse.
+fn public_image_key(path: &str) -> Option<&str> {
+    let key = path.strip_prefix("/image/")?;
+    let valid = key
+        .split('/')
+        .all(|segment| !segment.is_empty() && !segment.starts_with('.'));
+    (valid && signed_urls::is_public_image(Path::new(key))).then_some(key)
+}
+
+/// Middleware of the `/image` mount, serving converted variants to the
+/// clients accepting them and marking the responses of convertible images
+/// as varying by `Accept`.
+pub async fn negotiate_public_images(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse, Error> {
+    let config = req.app_data::<web::Data<Config>>().cloned();
+    let store = req.app_data::<web::Data<dyn FileStore>>().cloned();
+    let key = match (req.method(), &config) {
+        (&Method::GET | &Method::HEAD, Some(config)) => public_image_key(req.path())
+            .filter(|key| is_convertible(config, key))
+            .map(str::to_string),
+        _ => None,
+    };
+    let (Some(key), Some(config), Some(store)) = (key, config, store) else {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_boxed_body);
+    };
+
+    if let Some((path, format)) = negotiate(req.request(), &config, store, &key).await {
+        match respond(req.request(), &path, format) {
+            Ok(response) => return Ok(req.into_response(response)),
+            Err(e) => error!("Failed to open converted image {}: {}", path.display(), e),
+        }
+    }
+    let mut res = next.call(req).await?.map_into_boxed_body();
+    if res.status().is_success() {
+        res.headers_mut()
+            .insert(VARY, HeaderValue::from_static("accept"));
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[cfg(feature = "image-processing")]
+    fn test_accepted_formats() {
+        let webp = |accept: &str| accepted_formats(accept).contains(&Format::WebP);
+        assert!(webp("image/avif,image/webp,image/apng,*/*;q=0.8"));
+        assert!(webp("IMAGE/WEBP;q=0.5"));
+        assert!(!webp("image/webp;q=0, image/png"));
+        assert!(!webp("image/*,*/*;q=0.8"));
+    }
+
+    #[test]
+    #[cfg(feature = "image-processing")]
+    fn test_webp_variant_is_lossy() {
+        use image::codecs::jpeg::JpegEncoder;
+        use image::{ImageFormat, Rgb, RgbImage};
+
+        // A photo-like gradient with some noise
+        let photo = RgbImage::from_fn(256, 256, |x, y| {
+            let noise = ((x * 7919 + y * 104729) % 23) as u8;
+            Rgb([x as u8, y as u8, 128u8.wrapping_add(noise)])
+        });
+        let mut jpeg = Vec::new();
+        photo
+            .write_with_encoder(JpegEncoder::new_with_quality(&mut jpeg, 90))
+            .unwrap();
+
+        let webp = convert(&jpeg, Format::WebP).unwrap();
+        assert!(webp.len() < jpeg.len(), "{} >= {}", webp.len(), jpeg.len());
+        assert_eq!(image::guess_format(&webp).unwrap(), ImageFormat::WebP);
+        let decoded = image::load_from_memory(&webp).unwrap();
+        assert_eq!((decoded.width(), decoded.height()), (256, 256));
+    }
+
+    #[test]
+    fn test_original_key() {
+        assert_eq!(original_key("acme/cat.jpg.webp"), Some("acme/cat.jpg"));
+        assert_eq!(original_key("acme/cat.png.avif"), Some("acme/cat.png"));
+        assert_eq!(original_key("acme/cat.jpg"), None);
+    }
+
+    #[test]
+    fn test_public_image_key() {
+        assert_eq!(
+            public_image_key("/image/acme/cat.jpg"),
+            Some("acme/cat.jpg")
+        );
+        assert_eq!(public_image_key("/image/acme/private/cat.jpg"), None);
+        assert_eq!(public_image_key("/image/acme/../private/cat.jpg"), None);
+        assert_eq!(public_image_key("/image/acme//cat.jpg"), None);
+    }
+}
@@ -0,0 +1,179 @@
+use crate::errors::UserError;
+use crate::history::DEFAULT_HISTORY_LIMIT;
+use crate::medical;
+use crate::models::{Cat, CatChange, CatRelation, MedicalRecord};
+use crate::repository;
+use crate::signed_urls::UrlSigner;
+use chrono::{DateTime, Utc};
+use diesel::{PgConnection, QueryResult};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+/// The `include` query parameter of the cat detail endpoints, a comma
+/// separated list of related resources to embed, e.g.
+/// `?include=records,relations`.
+#[derive(Deserialize)]
+pub struct IncludeQuery {
+    include: Option<String>,
+}
+
+/// Which related resources to embed.
+#[derive(Clone, Copy, Default, Debug, PartialEq)]
+pub struct Includes {
+    /// All the cat's medical records.
+    records: bool,
+    /// The cat's direct relations, with the cats on their other end.
+    relations: bool,
+    /// The cat's oldest changes, as the first page of its history.
+    history: bool,
+}
+
+impl Includes {
+    pub fn any(&self) -> bool {
+        self.records || self.relations || self.history
+    }
+}
+
+impl IncludeQuery {
+    /// The requested resources, rejecting names that are not one of
+    /// `records`, `relations` and `history`.
+    pub fn includes(&self) -> Result<Includes, UserError> {
+        let mut includes = Includes::default();
+        let Some(include) = &self.include else {
+            return Ok(includes);
+        };
+        for name in include.split(',').map(str::trim) {
+            match name {
+                "records" => includes.records = true,
+                "relations" => includes.relations = true,
+                "history" => includes.history = true,
+                _ => {
+                    warn!("Unknown include: {}", name);
+                    return Err(UserError::ValidationError);
+                }
+            }
+        }
+        Ok(includes)
+    }
+}
+
+/// Related resources as loaded, before their links are signed.
+pub struct Related {
+    includes: Includes,
+    relations: Vec<CatRelation>,
+    relatives: Vec<Cat>,
+    history: Vec<CatChange>,
+}
+
+/// Loads the requested resources related to `cat_id`, with one query per
+/// kind of resource. Medical records are left to the caller, which loads
+/// them for the summary anyway.
+pub fn load(
+    connection: &mut PgConnection,
+    tenant_id: i32,
+    cat_id: i32,
+    includes: Includes,
+) -> QueryResult<Related> {
+    let mut related = Related {
+        includes,
+        relations: Vec::new(),
+        relatives: Vec::new(),
+        history: Vec::new(),
+    };
+    if includes.relations {
+        related.relations = repository::list_cat_relations(connection, tenant_id, &[cat_id])?;
+        let mut ids: Vec<i32> = related
+            .relations
+            .iter()
+            .map(|relation| match relation.cat_id == cat_id {
+                true => relation.related_cat_id,
+                false => relation.cat_id,
+            })
+            .collect();
+        ids.sort_unstable();
+        ids.dedup();
+        related.relatives = repository::list_cats_by_ids(connection, tenant_id, &ids)?;
+        related.relatives.sort_by_key(|cat| cat.id);
+    }
+    if includes.history {
+        related.history = repository::list_cat_changes(
+            connection,
+            tenant_id,
+            cat_id,
+            None,
+            DEFAULT_HISTORY_LIMIT,
+        )?;
+    }
+    Ok(related)
+}
+
+/// The `included` member of a cat detail response. Each requested resource
+/// is a list under its own name, with the cats related through `relations`
+/// under `cats`:
+///
+/// ```json
+/// {"id": 1, "name": "Whiskers", ..., "included": {
+///     "records": [...], "relations": [...], "cats": [...], "history": [...]}}
+/// ```
+///
+/// Resources that were not requested are left out.
+#[derive(Serialize)]
+pub struct Included {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    records: Option<Vec<MedicalRecord>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    relations: Option<Vec<CatRelation>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    cats: Option<Vec<Cat>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    history: Option<Vec<CatChange>>,
+}
+
+impl Included {
+    pub fn new(
+        signer: &UrlSigner,
+        related: Related,
+        records: &[MedicalRecord],
+        now: DateTime<Utc>,
+    ) -> Included {
+        let includes = related.includes;
+        Included {
+            records: includes.records.then(|| {
+                records
+                    .iter()
+                    .map(|record| medical::present(signer, record.clone(), now))
+                    .collect()
+            }),
+            relations: includes.relations.then_some(related.relations),
+            cats: includes.relations.then(|| {
+                related
+                    .relatives
+                    .into_iter()
+                    .map(|cat| signer.present(cat, now))
+                    .collect()
+            }),
+            history: includes.history.then_some(related.history),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(include: &str) -> IncludeQuery {
+        IncludeQuery {
+            include: Some(include.to_string()),
+        }
+    }
+
+    #[test]
+    fn test_includes() {
+        let includes = query("records, history").includes().unwrap();
+        assert!(includes.records && includes.history && !includes.relations);
+        let none = IncludeQuery { include: None }.includes().unwrap();
+        assert!(!none.any());
+        assert!(query("tags").includes().is_err());
+        assert!(query("").includes().is_err());
+    }
+}
@@ -0,0 +1,376 @@
+//! Opt-in JSON:API rendering of the API's responses.
+//!
+//! Handlers keep producing their plain JSON; for requests accepting
+//! `application/vnd.api+json` the `json_api` middleware rewrites it into a
+//! JSON:API document. Objects with an id become resource objects whose type
+//! follows from the route, lists become collections, history pages gain
+//! pagination links and error bodies become error objects.
+use actix_web::body::{self, BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderValue, ACCEPT, CONTENT_TYPE, VARY};
+use actix_web::http::StatusCode;
+use actix_web::middleware::Next;
+use actix_web::Error;
+use log::error;
+use serde_json::{json, Map, Value};
+
+pub const MEDIA_TYPE: &str = "application/vnd.api+json";
+
+/// Whether the client asked for JSON:API, listing its media type without
+/// parameters in `Accept` as the specification requires.
+fn wants_json_api(accept: &str) -> bool {
+    accept
+        .split(',')
+        .any(|media_type| media_type.trim().eq_ignore_ascii_case(MEDIA_TYPE))
+}
+
+/// The resource type of the objects a route returns, from its pattern,
+/// e.g. `/api/cat/{id}/records` returns `medical-records`.
+fn resource_type(pattern: &str) -> Option<&'static str> {
+    let segments: Vec<&str> = pattern.trim_start_matches("/api/").split('/').collect();
+    match segments.as_slice() {
+        ["cat", _, "records", ..] => Some("medical-records"),
+        ["cat", _, "relations", ..] => Some("cat-relations"),
+        ["cat", _, "images", _, "primary"] => Some("cats"),
+        ["cat", _, "images", ..] => Some("cat-images"),
+        ["cat", _, "history"] => Some("cat-changes"),
+        ["cat", ..] | ["cats", ..] => Some("cats"),
+        ["uploads", ..] => Some("uploads"),
+        _ => None,
+    }
+}
+
+/// Turns a plain object into a resource object of type `kind`, with its
+/// `id` (or an upload's `token`) moved out of the attributes.
+fn resource(kind: &str, value: Value) -> Value {
+    let Value::Object(mut attributes) = value else {
+        return value;
+    };
+    // JSON:API has its own links
+    attributes.remove("_links");
+    let id = attributes
+        .remove("id")
+        .or_else(|| match kind {
+            "uploads" => attributes.remove("token"),
+            _ => None,
+        })
+        .map(|id| match id {
+            Value::String(id) => id,
+            id => id.to_string(),
+        });
+    let mut object = Map::new();
+    object.insert("type".to_string(), json!(kind));
+    if let Some(id) = id {
+        if kind == "cats" {
+            object.insert(
+                "links".to_string(),
+                json!({"self": format!("/api/cat/{}", id)}),
+            );
+        }
+        object.insert("id".to_string(), json!(id));
+    }
+
+    // Resources embedded with `?include=` become relationships
+    let mut included = Vec::new();
+    if let Some(Value::Object(embedded)) = attributes.remove("included") {
+        let mut relationships = Map::new();
+        for (name, items) in embedded {
+            let kind = match name.as_str() {
+                "records" => "medical-records",
+                "relations" => "cat-relations",
+                "history" => "cat-changes",
+                _ => "cats",
+            };
+            let items: Vec<Value> = match items {
+                Value::Array(items) => items.into_iter().map(|item| resource(kind, item)).collect(),
+                _ => continue,
+            };
+            let linkage: Vec<Value> = items
+                .iter()
+                .map(|item| json!({"type": item["type"], "id": item["id"]}))
+                .collect();
+            relationships.insert(name, json!({ "data": linkage }));
+            included.extend(items);
+        }
+        object.insert("relationships".to_string(), Value::Object(relationships));
+    }
+    object.insert("attributes".to_string(), Value::Object(attributes));
+    if !included.is_empty() {
+        // Carried up to the document by `document`
+        object.insert("included".to_string(), Value::Array(included));
+    }
+    Value::Object(object)
+}
+
+/// Moves resources included by a primary resource up to the document.
+fn hoist_included(data: &mut Value, included: &mut Vec<Value>) {
+    match data {
+        Value::Object(object) => {
+            if let Some(Value::Array(items)) = object.remove("included") {
+                included.extend(items);
+            }
+        }
+        Value::Array(items) => items
+            .iter_mut()
+            .for_each(|item| hoist_included(item, included)),
+        _ => {}
+    }
+}
+
+/// The JSON:API document for a successful response of the route `pattern`,
+/// requested as `uri`.
+fn document(pattern: Option<&str>, uri: &str, value: Value) -> Value {
+    let kind = pattern.and_then(resource_type);
+    let mut document = Map::new();
+    document.insert("links".to_string(), json!({ "self": uri }));
+    let mut data = match (kind, value) {
+        // A page of history
+        (Some(kind), Value::Object(mut page)) if page.contains_key("next_after") => {
+            if let Some(after) = page.get("next_after").and_then(Value::as_i64) {
+                let path = uri.split('?').next().unwrap_or(uri);
+                document["links"]["next"] = json!(format!("{}?after={}", path, after));
+            }
+            let items = match page.remove("changes") {
+                Some(Value::Array(items)) => items,
+                _ => Vec::new(),
+            };
+            Value::Array(items.into_iter().map(|item| resource(kind, item)).collect())
+        }
+        // A family graph
+        (Some(kind), Value::Object(mut family)) if family.contains_key("relations") => {
+            let relations = match family.remove("relations") {
+                Some(Value::Array(relations)) => relations,
+                _ => Vec::new(),
+            };
+            document.insert(
+                "included".to_string(),
+                relations
+                    .into_iter()
+                    .map(|relation| resource("cat-relations", relation))
+                    .collect(),
+            );
+            let cats = match family.remove("cats") {
+                Some(Value::Array(cats)) => cats,
+                _ => Vec::new(),
+            };
+            document.insert("meta".to_string(), Value::Object(family));
+            Value::Array(cats.into_iter().map(|cat| resource(kind, cat)).collect())
+        }
+        (Some(kind), Value::Array(items)) => {
+            Value::Array(items.into_iter().map(|item| resource(kind, item)).collect())
+        }
+        (Some(kind), value @ Value::Object(_)) => resource(kind, value),
+        // Anything else, e.g. a stored image's key, is not a resource
+        (_, value) => {
+            document.insert("meta".to_string(), value);
+            return Value::Object(document);
+        }
+    };
+    let mut included = match document.remove("included") {
+        Some(Value::Array(included)) => included,
+        _ => Vec::new(),
+    };
+    hoist_included(&mut data, &mut included);
+    document.insert("data".to_string(), data);
+    if !included.is_empty() {
+        document.insert("included".to_string(), Value::Array(included));
+    }
+    Value::Object(document)
+}
+
+/// The JSON:API error document for an error response, with one error object
+/// per invalid field of a validation error.
+fn error_document(status: StatusCode, value: Value) -> Value {
+    let Value::Object(mut body) = value else {
+        return json!({"errors": [{"status": status.as_str()}]});
+    };
+    let title = body.remove("msg").unwrap_or(Value::Null);
+    let mut errors = Vec::new();
+    if let Some(Value::Object(fields)) = body.remove("errors") {
+        for (field, details) in fields {
+            for detail in details.as_array().into_iter().flatten() {
+                errors.push(json!({
+                    "status": status.as_str(),
+                    "code": detail["code"],
+                    "title": title,
+                    "detail": detail["message"],
+                    "source": {"pointer": format!("/data/attributes/{}", field)},
+                }));
+            }
+        }
+    }
+    if errors.is_empty() {
+        let mut error = json!({"status": status.as_str(), "title": title});
+        if !body.is_empty() {
+            error["meta"] = Value::Object(body);
+        }
+        errors.push(error);
+    }
+    json!({ "errors": errors })
+}
+
+/// Middleware rendering the JSON responses of API routes as JSON:API
+/// documents for clients that ask for them. It sits outside error
+/// localization, so it sees the final error bodies.
+pub async fn json_api(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let wanted = req.path().starts_with("/api/")
+        && req
+            .headers()
+            .get(ACCEPT)
+            .and_then(|value| value.to_str().ok())
+            .is_some_and(wants_json_api);
+    let res = next.call(req).await?.map_into_boxed_body();
+    if !wanted {
+        return Ok(res);
+    }
+    let is_json = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("application/json"));
+    if !is_json {
+        return Ok(res);
+    }
+
+    let (req, res) = res.into_parts();
+    let status = res.status();
+    let (mut res, payload) = res.into_parts();
+    let bytes = body::to_bytes(payload).await.map_err(|e| {
+        error!("Failed to read response for JSON:API: {}", e);
+        actix_web::error::ErrorInternalServerError("Internal server error")
+    })?;
+    let document = match serde_json::from_slice(&bytes) {
+        Ok(value) if status.is_success() => document(
+            req.match_pattern().as_deref(),
+            &req.uri().to_string(),
+            value,
+        ),
+        Ok(value) => error_document(status, value),
+        Err(_) => return Ok(ServiceResponse::new(req, res.set_body(BoxBody::new(bytes)))),
+    };
+    let headers = res.headers_mut();
+    headers.insert(CONTENT_TYPE, HeaderValue::from_static(MEDIA_TYPE));
+    headers.append(VARY, HeaderValue::from_static("Accept"));
+    let res = res.set_body(BoxBody::new(document.to_string()));
+    Ok(ServiceResponse::new(req, res))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_and_read_body_json, init_service, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+
+    #[test]
+    fn test_wants_json_api() {
+        assert!(wants_json_api("application/json, application/vnd.api+json"));
+        assert!(!wants_json_api("application/json"));
+        assert!(!wants_json_api("application/vnd.api+json; ext=bulk"));
+    }
+
+    #[test]
+    fn test_resource_type() {
+        assert_eq!(resource_type("/api/cats"), Some("cats"));
+        assert_eq!(resource_type("/api/cat/uuid/{public_id}"), Some("cats"));
+        assert_eq!(
+            resource_type("/api/cat/{id}/records/{record_id}"),
+            Some("medical-records")
+        );
+        assert_eq!(resource_type("/api/cat/{id}/history"), Some("cat-changes"));
+        assert_eq!(resource_type("/api/cat/{id}/images"), Some("cat-images"));
+        assert_eq!(
+            resource_type("/api/cat/{id}/images/{image_id}/primary"),
+            Some("cats")
+        );
+        assert_eq!(resource_type("/api/images"), None);
+    }
+
+    #[test]
+    fn test_cat_with_included_resources() {
+        let cat = json!({
+            "id": 7,
+            "name": "Whiskers",
+            "included": {"relations": [{"id": 3, "cat_id": 7, "related_cat_id": 8, "kind": "mother"}]},
+        });
+        let document = document(Some("/api/cat/{id}"), "/api/cat/7?include=relations", cat);
+        assert_eq!(
+            document,
+            json!({
+                "links": {"self": "/api/cat/7?include=relations"},
+                "data": {
+                    "type": "cats",
+                    "id": "7",
+                    "links": {"self": "/api/cat/7"},
+                    "attributes": {"name": "Whiskers"},
+                    "relationships": {
+                        "relations": {"data": [{"type": "cat-relations", "id": "3"}]},
+                    },
+                },
+                "included": [{
+                    "type": "cat-relations",
+                    "id": "3",
+                    "attributes": {"cat_id": 7, "related_cat_id": 8, "kind": "mother"},
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn test_history_page_links() {
+        let page = json!({"changes": [{"id": 5, "field": "name"}], "next_after": 5});
+        let document = document(
+            Some("/api/cat/{id}/history"),
+            "/api/cat/1/history?limit=1",
+            page,
+        );
+        assert_eq!(document["links"]["next"], "/api/cat/1/history?after=5");
+        assert_eq!(document["data"][0]["type"], "cat-changes");
+    }
+
+    #[test]
+    fn test_error_document() {
+        let body = json!({
+            "msg": "Validation failed",
+            "errors": {"name": [{"code": "length", "message": "too long"}]},
+        });
+        assert_eq!(
+            error_document(StatusCode::UNPROCESSABLE_ENTITY, body),
+            json!({"errors": [{
+                "status": "422",
+                "code": "length",
+                "title": "Validation failed",
+                "detail": "too long",
+                "source": {"pointer": "/data/attributes/name"},
+            }]})
+        );
+        assert_eq!(
+            error_document(StatusCode::NOT_FOUND, json!({"msg": "Not found"})),
+            json!({"errors": [{"status": "404", "title": "Not found"}]})
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_json_api_middleware() {
+        let app = init_service(App::new().wrap(from_fn(json_api)).route(
+            "/api/cats",
+            web::get().to(|| async { HttpResponse::Ok().json(json!([{"id": 1, "name": "Tom"}])) }),
+        ))
+        .await;
+
+        let req = TestRequest::get().uri("/api/cats").to_request();
+        let plain: Value = call_and_read_body_json(&app, req).await;
+        assert_eq!(plain, json!([{"id": 1, "name": "Tom"}]));
+
+        let req = TestRequest::get()
+            .uri("/api/cats")
+            .insert_header((ACCEPT, MEDIA_TYPE))
+            .to_request();
+        let document: Value = call_and_read_body_json(&app, req).await;
+        assert_eq!(document["data"][0]["id"], "1");
+        assert_eq!(document["data"][0]["attributes"]["name"], "Tom");
+    }
+}
@@ -0,0 +1,2046 @@
+// The json! literal of Config::describe outgrows the default limit
+#![recursion_limit = "256"]
+
+#[cfg(feature = "tls")]
+mod acme;
+mod adoption;
+mod app;
+mod archival;
+mod assets;
+mod auth;
+mod backup;
+mod cache;
+mod captcha;
+mod cat_detection;
+mod catalogue;
+mod cats;
+mod cli;
+#[cfg(feature = "client")]
+pub mod client;
+mod client_ip;
+mod clock;
+mod concurrency;
+pub mod config;
+#[cfg(test)]
+mod contract_tests;
+mod csrf;
+mod db;
+mod deadline;
+mod errors;
+mod fallback;
+mod family;
+mod fields;
+mod file_store;
+mod gallery;
+mod geo;
+mod health;
+mod heic;
+mod history;
+mod i18n;
+mod image_download;
+mod image_shards;
+mod image_variants;
+mod include;
+mod jsonapi;
+mod links;
+mod listen;
+mod logging;
+mod maintenance;
+mod medical;
+mod metrics;
+mod models;
+mod moderation;
+mod notifications;
+mod outbox;
+mod payload_log;
+mod profile;
+mod query_log;
+mod quotas;
+mod reload;
+mod repository;
+mod scanner;
+mod schema;
+mod schema_guard;
+mod secrets;
+mod security_headers;
+mod seed;
+mod sessions;
+mod signed_urls;
+mod similar;
+mod stats;
+mod storage_migration;
+mod submissions;
+mod sync;
+mod telemetry;
+mod tenants;
+#[cfg(test)]
+mod test_support;
+#[cfg(feature = "tls")]
+mod tls;
+mod tus;
+mod uploads;
+mod validated;
+mod version;
+mod webhooks;
+
+pub use self::app::{CatdexApp, CatdexAppBuilder};
+pub use self::cats::{CreateCat, UploadedImage};
+
+// The services `CatdexAppBuilder` takes, for embedders to implement
+pub use self::cache::CacheBackend;
+pub use self::captcha::CaptchaVerifier;
+pub use self::clock::{Clock, SystemClock};
+pub use self::file_store::{FileStore, LocalFileStore};
+pub use self::scanner::{Scanner, Verdict};
+pub use self::signed_urls::UrlSigner;
+
+/// What the benchmarks in `benches/` reach into the crate for, not part of
+/// its API.
+#[doc(hidden)]
+pub mod bench_support {
+    pub use crate::profile::Profile;
+}
+
+use self::cli::{Cli, Command};
+use self::config::{Config, Settings};
+use self::errors::UserError;
+use self::listen::Listen;
+use self::reload::LiveSettings;
+use actix_files::Files;
+use actix_web::http::Method;
+use actix_web::middleware::{from_fn, Logger};
+use actix_web::{web, App, HttpServer};
+use clap::Parser;
+use diesel::r2d2::ConnectionManager;
+use diesel::PgConnection;
+use log::{info, warn};
+use std::path::Path;
+use std::process;
+use std::time::Duration;
+
+pub type DbPool = r2d2::Pool<ConnectionManager<PgConnection>>;
+
+/// The default access log format with the client address behind proxies
+/// in place of the peer address.
+const ACCESS_LOG_FORMAT: &str = r#"%{client_ip}xi "%r" %s %b "%{Referer}i" "%{User-Agent}i" %T"#;
+
+fn setup_database() -> DbPool {
+    let database_url = secrets::get("DATABASE_URL").expect("DATABASE_URL must be set");
+    query_log::install();
+    let manager = ConnectionManager::<PgConnection>::new(database_url);
+    r2d2::Pool::builder()
+        .connection_timeout(Duration::from_secs(5))
+        .build(manager)
+        .expect("Failed to create DB connection pool.")
+}
+
+/// Runs the `catdex` command line, serving the API unless given another
+/// command.
+pub fn run() {
+    logging::init();
+    if let Err(e) = secrets::init() {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+
+    let result = match Cli::parse().command.unwrap_or(Command::Serve) {
+        Command::Serve => actix_web::rt::System::new()
+            .block_on(serve())
+            .map_err(Into::into),
+        command => cli::run(command),
+    };
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
+}
+
+/// Loads the certificate HTTPS listeners serve, obtaining and renewing it
+/// through ACME when `ACME_DOMAIN` is set. Renewed certificates are only
+/// picked up while the returned watcher lives.
+#[cfg(feature = "tls")]
+fn load_certificates(
+    app: &CatdexApp,
+) -> (
+    Option<std::sync::Arc<tls::Certificates>>,
+    Option<notify::RecommendedWatcher>,
+) {
+    let config = app.config();
+    let acme = acme::Acme::from_config(config);
+    // Only HTTPS listeners need a certificate, behind a Unix socket the
+    // proxy in front terminates TLS
+    let https = config
+        .listen
+        .iter()
+        .any(|listen| matches!(listen, Listen::Tcp(_)));
+    if !https {
+        if acme.is_some() {
+            warn!("ACME_DOMAIN is ignored without an HTTPS listener");
+        }
+        return (None, None);
+    }
+    if let Some(acme) = &acme {
+        acme.ensure_certificate(&config.tls_key_file, &config.tls_cert_file)
+            .expect("Failed to write a placeholder TLS certificate");
+    }
+    let certificates =
+        tls::Certificates::load(config.tls_key_file.clone(), config.tls_cert_file.clone())
+            .expect("Failed to load the TLS certificate");
+    let watcher = reload::watch_files(&certificates.files(), {
+        let certificates = certificates.clone();
+        move || certificates.reload()
+    })
+    .inspect_err(|e| {
+        warn!(
+            "Failed to watch the TLS certificate, reload it with SIGHUP: {}",
+            e
+        )
+    })
+    .ok();
+    if let Some(acme) = acme {
+        actix_rt::spawn(acme.run(
+            certificates.clone(),
+            app.challenges().clone(),
+            app.notifier().cloned(),
+        ));
+    }
+    actix_rt::spawn(certificates.clone().reload_on_hangup());
+    (Some(certificates), watcher)
+}
+
+async fn serve() -> std::io::Result<()> {
+    let config = Config::from_env();
+    let tracer_provider = telemetry::init(&config).unwrap_or_else(|e| panic!("{}", e));
+
+    let settings =
+        Settings::load(config.config_file.as_deref()).unwrap_or_else(|e| panic!("{}", e));
+    reload::apply(&settings);
+    let settings = web::Data::new(LiveSettings::new(settings, config.config_file.clone()));
+    // Settings are only reloaded while the watcher lives, i.e. until shutdown
+    let _watcher = reload::watch(settings.clone()).unwrap_or_else(|e| {
+        warn!(
+            "Failed to watch the config file, reload through the API instead: {}",
+            e
+        );
+        None
+    });
+    actix_rt::spawn(reload::reload_on_hangup(settings.clone()));
+
+    let pool = setup_database();
+    let read_only = {
+        let mut connection = pool
+            .get()
+            .map_err(|e| std::io::Error::other(e.to_string()))?;
+        schema_guard::check_at_startup(&mut connection, config.schema_outdated_action)?
+    };
+    let app = CatdexApp::builder()
+        .config(config)
+        .pool(pool.clone())
+        .settings(settings)
+        .read_only(read_only)
+        .build();
+    version::log_banner(&version::runtime_info(
+        pool.get().ok().as_deref_mut(),
+        app.file_store().as_ref(),
+    ));
+    #[cfg(feature = "tls")]
+    let (certificates, _certificate_watcher) = load_certificates(&app);
+    let config = app.config().clone();
+    let notifier = app.notifier().cloned();
+    actix_rt::spawn(outbox::run_dispatcher(
+        pool.clone(),
+        webhooks::DeliveryPolicy::from_config(&config),
+        notifier.clone(),
+    ));
+    if let Some(older_than) = config.archive_after {
+        if cfg!(feature = "image-processing") {
+            actix_rt::spawn(archival::run_archiver(
+                pool.clone(),
+                app.file_store().clone(),
+                archival::ArchiveOptions::from_config(&config, older_than),
+                config.archive_interval,
+                notifier.clone(),
+            ));
+        } else {
+            warn!("ARCHIVE_AFTER_DAYS is set, but this build lacks the image-processing feature");
+        }
+    }
+    actix_rt::spawn(stats::run_refresher(
+        pool,
+        config.stats_refresh_interval,
+        notifier,
+    ));
+    for listen in &config.listen {
+        info!("Listening on {}", listen);
+    }
+
+    // Plain HTTP listeners get a server of their own, which never reaches
+    // the API
+    let mut redirect_server = HttpServer::new({
+        let app = app.clone();
+        move || {
+            App::new()
+                .wrap(Logger::new(ACCESS_LOG_FORMAT))
+                .app_data(app.config().clone())
+                .route("/healthz", web::get().to(health::healthz_endpoint))
+                .configure(|cfg| app.challenge_config(cfg))
+                .default_service(web::to(listen::redirect_endpoint))
+        }
+    })
+    .workers(1);
+
+    let mut server = HttpServer::new(move || app.app());
+    let mut redirects = false;
+    for listen in config.listen.iter().cloned() {
+        match listen {
+            #[cfg(feature = "tls")]
+            Listen::Tcp(addr) => {
+                let tls = certificates
+                    .as_ref()
+                    .expect("The certificate is loaded for HTTPS listeners")
+                    .acceptor()
+                    .expect("Failed to set up the TLS acceptor");
+                server = server.bind_openssl(addr, tls)?;
+            }
+            #[cfg(not(feature = "tls"))]
+            Listen::Tcp(addr) => {
+                return Err(std::io::Error::other(format!(
+                    "Cannot serve HTTPS on {} without the tls feature",
+                    addr
+                )));
+            }
+            Listen::Http(addr) => {
+                server = server.bind(addr)?;
+            }
+            Listen::Unix(path) => {
+                server = server.listen_uds(listen::bind_unix(&path, config.listen_socket_mode)?)?;
+            }
+            Listen::Redirect(addr) => {
+                redirect_server = redirect_server.bind(addr)?;
+                redirects = true;
+            }
+        }
+    }
+    let result = if redirects {
+        futures_util::future::try_join(server.run(), redirect_server.run())
+            .await
+            .map(|_| ())
+    } else {
+        server.run().await
+    };
+    if let Some(provider) = tracer_provider {
+        if let Err(e) = provider.shutdown() {
+            warn!("Failed to export the remaining traces: {}", e);
+        }
+    }
+    result
+}
+
+/// Public images are served straight from `image_dir`, private ones only
+/// through signed links. Links to images stored before they were sharded
+/// by date redirect.
+fn image_config(cfg: &mut web::ServiceConfig, image_dir: &Path) {
+    cfg.service(
+        web::scope("/image")
+            .wrap(from_fn(image_variants::negotiate_public_images))
+            .service(
+                Files::new("", image_dir)
+                    .show_files_listing()
+                    .path_filter(|path, _| signed_urls::is_public_image(path))
+                    .default_handler(web::to(image_shards::moved_image_endpoint)),
+            ),
+    )
+    .route(
+        "/signed-image/{image_key:.*}",
+        web::get().to(signed_urls::signed_image_endpoint),
+    );
+}
+
+/// Routes of each path share a resource, so requests with another method
+/// get a 405 listing the allowed ones rather than a 404.
+fn api_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::scope("/api")
+            .wrap(from_fn(fallback::api_method_not_allowed))
+            .wrap(from_fn(tenants::resolve_tenant))
+            .app_data(
+                web::PathConfig::default().error_handler(|_, _| UserError::ValidationError.into()),
+            )
+            .app_data(
+                web::QueryConfig::default().error_handler(|_, _| UserError::ValidationError.into()),
+            )
+            .app_data(
+                web::JsonConfig::default().error_handler(|_, _| UserError::ValidationError.into()),
+            )
+            .service(
+                web::resource("/cats")
+                    .route(web::get().to(cats::cats_endpoint))
+                    .route(web::post().to(cats::create_cat_endpoint)),
+            )
+            .service(web::resource("/cats/stream").route(web::get().to(cats::cats_stream_endpoint)))
+            .service(
+                web::resource("/cats/export.pdf")
+                    .route(web::get().to(catalogue::export_pdf_endpoint)),
+            )
+            .service(web::resource("/cats/nearby").route(web::get().to(geo::nearby_endpoint)))
+            .service(web::resource("/add_cat").route(web::post().to(cats::add_cat_endpoint)))
+            .service(
+                web::resource("/submit_cat")
+                    .route(web::post().to(submissions::submit_cat_endpoint)),
+            )
+            .service(web::resource("/images").route(web::post().to(cats::upload_image_endpoint)))
+            .service(
+                web::resource("/uploads").route(web::post().to(uploads::create_upload_endpoint)),
+            )
+            .service(
+                web::resource("/uploads/{token}")
+                    .route(web::get().to(uploads::upload_status_endpoint))
+                    .route(web::put().to(uploads::upload_chunk_endpoint)),
+            )
+            .service(
+                web::scope("/tus")
+                    .wrap(from_fn(tus::tus_resumable))
+                    .service(
+                        web::resource("")
+                            .route(web::method(Method::OPTIONS).to(tus::options_endpoint))
+                            .route(web::post().to(tus::create_endpoint)),
+                    )
+                    .service(
+                        web::resource("/{token}")
+                            .route(web::head().to(tus::head_endpoint))
+                            .route(web::patch().to(tus::patch_endpoint)),
+                    ),
+            )
+            .service(web::resource("/cat/{id}").route(web::get().to(cats::cat_endpoint)))
+            .service(
+                web::resource("/cat/uuid/{public_id}")
+                    .route(web::get().to(cats::cat_by_public_id_endpoint)),
+            )
+            .service(
+                web::resource("/cat/{id}/status")
+                    .route(web::post().to(adoption::set_status_endpoint)),
+            )
+            .service(
+                web::resource("/cat/{id}/relations")
+                    .route(web::post().to(family::add_relation_endpoint)),
+            )
+            .service(
+                web::resource("/cat/{id}/relations/{relation_id}")
+                    .route(web::delete().to(family::delete_relation_endpoint)),
+            )
+            .service(
+                web::resource("/cat/{id}/family").route(web::get().to(family::family_endpoint)),
+            )
+            .service(
+                web::resource("/cat/{id}/similar").route(web::get().to(similar::similar_endpoint)),
+            )
+            .service(
+                web::resource("/cat/{id}/history").route(web::get().to(history::history_endpoint)),
+            )
+            .service(
+                web::resource("/cat/{id}/images")
+                    .route(web::get().to(gallery::gallery_endpoint))
+                    .route(web::post().to(gallery::add_image_endpoint))
+                    .route(web::put().to(gallery::reorder_images_endpoint)),
+            )
+            .service(
+                web::resource("/cat/{id}/images/{image_id}")
+                    .route(web::delete().to(gallery::delete_image_endpoint)),
+            )
+            .service(
+                web::resource("/cat/{id}/images/{image_id}/primary")
+                    .route(web::post().to(gallery::set_primary_image_endpoint)),
+            )
+            .service(
+                web::resource("/cat/{id}/records")
+                    .route(web::get().to(medical::records_endpoint))
+                    .route(web::post().to(medical::add_record_endpoint)),
+            )
+            .service(
+                web::resource("/cat/{id}/records/{record_id}")
+                    .route(web::get().to(medical::record_endpoint))
+                    .route(web::put().to(medical::update_record_endpoint))
+                    .route(web::delete().to(medical::delete_record_endpoint)),
+            )
+            .service(
+                web::resource("/cat/{id}/location")
+                    .route(web::put().to(geo::set_location_endpoint))
+                    .route(web::delete().to(geo::clear_location_endpoint)),
+            )
+            .service(
+                web::resource("/health/details")
+                    .route(web::get().to(health::health_details_endpoint)),
+            )
+            .service(web::resource("/admin/backup").route(web::get().to(backup::backup_endpoint)))
+            .service(web::resource("/admin/config").route(web::get().to(reload::config_endpoint)))
+            .service(
+                web::resource("/admin/maintenance")
+                    .route(web::get().to(maintenance::maintenance_endpoint))
+                    .route(web::put().to(maintenance::set_maintenance_endpoint)),
+            )
+            .service(
+                web::resource("/admin/config/reload")
+                    .route(web::post().to(reload::reload_endpoint)),
+            )
+            .service(
+                web::resource("/admin/payload-log")
+                    .route(web::get().to(payload_log::payload_log_endpoint))
+                    .route(web::put().to(payload_log::set_payload_log_endpoint)),
+            )
+            .service(
+                web::resource("/admin/migrate-storage")
+                    .route(web::post().to(storage_migration::migrate_storage_endpoint)),
+            )
+            .service(
+                web::resource("/admin/moderation")
+                    .route(web::get().to(moderation::moderation_endpoint)),
+            )
+            .service(
+                web::resource("/admin/moderation/{id}/approve")
+                    .route(web::post().to(moderation::approve_endpoint)),
+            )
+            .service(
+                web::resource("/admin/moderation/{id}/reject")
+                    .route(web::post().to(moderation::reject_endpoint)),
+            )
+            .service(web::resource("/quota").route(web::get().to(quotas::quota_endpoint)))
+            .service(web::resource("/stats/summary").route(web::get().to(stats::summary_endpoint)))
+            .service(web::resource("/version").route(web::get().to(version::version_endpoint)))
+            .service(
+                web::resource("/webhooks")
+                    .route(web::get().to(webhooks::webhooks_endpoint))
+                    .route(web::post().to(webhooks::register_webhook_endpoint)),
+            )
+            .service(
+                web::resource("/webhooks/{id}")
+                    .route(web::delete().to(webhooks::delete_webhook_endpoint)),
+            )
+            .service(
+                web::resource("/webhooks/{id}/deliveries")
+                    .route(web::get().to(webhooks::webhook_deliveries_endpoint)),
+            )
+            .default_service(web::to(fallback::api_not_found_endpoint)),
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file_store::FileStore;
+    use crate::models::{Cat, CatImage, NewCat, NewCatImage};
+    #[cfg(feature = "image-processing")]
+    use crate::test_support::test_app_with_store;
+    use crate::test_support::{
+        insert_cat, insert_cat_with, insert_test_admin, multipart_body, multipart_form, test_app,
+        test_app_builder, test_app_with, test_cat, test_pool, FakeCaptcha, MemoryFileStore,
+        ADMIN_AUTHORIZATION,
+    };
+    use actix_web::cookie::Cookie;
+    use actix_web::dev::ServiceResponse;
+    use actix_web::http::header::{
+        ACCEPT_LANGUAGE, ALLOW, CONTENT_LANGUAGE, CONTENT_TYPE, LOCATION, RETRY_AFTER,
+    };
+    use actix_web::http::StatusCode;
+    use actix_web::test;
+    use chrono::{DateTime, Utc};
+    use std::sync::Arc;
+    use uuid::Uuid;
+
+    #[actix_web::test]
+    async fn test_cats_sync() {
+        let pool = test_pool();
+        let (cat, deleted_id) = {
+            let mut connection = pool.get().unwrap();
+            let tenant = repository::find_tenant_by_slug(&mut connection, "default").unwrap();
+            let cat_id = insert_cat(&mut connection, tenant.id).id;
+            let deleted_id = insert_cat(&mut connection, tenant.id).id;
+            repository::delete_cat(&mut connection, deleted_id).unwrap();
+            let cat = repository::find_cat(&mut connection, tenant.id, cat_id).unwrap();
+            (cat, deleted_id)
+        };
+        let app = test_app_with(
+            pool,
+            Arc::new(MemoryFileStore::default()),
+            Config::from_env(),
+            None,
+        )
+        .await;
+        let since = |at: DateTime<Utc>| at.to_rfc3339_opts(chrono::SecondsFormat::Micros, true);
+
+        let req = test::TestRequest::get()
+            .uri(&format!(
+                "/api/cats?modified_since={}",
+                since(cat.updated_at)
+            ))
+            .to_request();
+        let changes: sync::CatChanges = test::call_and_read_body_json(&app, req).await;
+        assert!(changes.cats.iter().any(|changed| changed.id == cat.id));
+        assert!(changes
+            .deleted
+            .iter()
+            .any(|deleted| deleted.id == deleted_id));
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/api/cats?sync_token={}", changes.sync_token))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(resp.status().is_success());
+
+        let later = cat.updated_at + chrono::TimeDelta::hours(1);
+        let req = test::TestRequest::get()
+            .uri(&format!("/api/cats?modified_since={}", since(later)))
+            .to_request();
+        let changes: sync::CatChanges = test::call_and_read_body_json(&app, req).await;
+        assert!(changes.cats.is_empty());
+        assert!(changes.deleted.is_empty());
+
+        for uri in [
+            "/api/cats?sync_token=not-a-token",
+            &format!(
+                "/api/cats?sync_token={}&modified_since={}",
+                changes.sync_token,
+                since(later)
+            ),
+            &format!(
+                "/api/cats?status=available&sync_token={}",
+                changes.sync_token
+            ),
+        ] {
+            let req = test::TestRequest::get().uri(uri).to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", uri);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_moved_image_redirects() {
+        let pool = test_pool();
+        let name = format!("{}.jpg", Uuid::new_v4());
+        {
+            let mut connection = pool.get().unwrap();
+            let tenant = repository::find_tenant_by_slug(&mut connection, "default").unwrap();
+            let new_cat = NewCat {
+                image_path: format!("/image/default/2026/01/02/{}", name),
+                ..test_cat()
+            };
+            insert_cat_with(&mut connection, tenant.id, &new_cat);
+        }
+        let app = test_app_with(
+            pool,
+            Arc::new(MemoryFileStore::default()),
+            Config::from_env(),
+            None,
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/image/default/{}?v=1", name))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::MOVED_PERMANENTLY);
+        assert_eq!(
+            resp.headers().get(LOCATION).unwrap(),
+            &format!("/image/default/2026/01/02/{}?v=1", name)
+        );
+
+        for uri in [
+            format!("/image/default/{}.png", Uuid::new_v4()),
+            format!("/image/default/private/{}", name),
+        ] {
+            let req = test::TestRequest::get().uri(&uri).to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_images_served_from_image_dir() {
+        let image_dir = tempfile::tempdir().unwrap();
+        let key = format!("default/{}.jpg", Uuid::new_v4());
+        std::fs::create_dir_all(image_dir.path().join("default")).unwrap();
+        std::fs::write(image_dir.path().join(&key), b"jpeg").unwrap();
+        let mut config = Config::from_env();
+        config.image_dir = image_dir.path().to_path_buf();
+        let app = test_app_with(
+            test_pool(),
+            Arc::new(MemoryFileStore::default()),
+            config,
+            None,
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/image/{}", key))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(test::read_body(resp).await, &b"jpeg"[..]);
+    }
+
+    #[actix_web::test]
+    async fn test_adoption_status_workflow() {
+        let pool = test_pool();
+        let cat_id = {
+            let mut connection = pool.get().unwrap();
+            let tenant = repository::find_tenant_by_slug(&mut connection, "default").unwrap();
+            insert_test_admin(&mut connection, tenant.id);
+            insert_cat(&mut connection, tenant.id).id
+        };
+        let app = test_app_with(
+            pool,
+            Arc::new(MemoryFileStore::default()),
+            Config::from_env(),
+            None,
+        )
+        .await;
+        let set_status = |status: &str| {
+            test::TestRequest::post()
+                .uri(&format!("/api/cat/{}/status", cat_id))
+                .set_json(serde_json::json!({ "status": status }))
+        };
+
+        for status in ["pending", "adopted"] {
+            let cat: serde_json::Value =
+                test::call_and_read_body_json(&app, set_status(status).to_request()).await;
+            assert_eq!(cat["status"], status);
+        }
+
+        let resp = test::call_service(&app, set_status("adopted").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+        let resp = test::call_service(&app, set_status("available").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let req = set_status("available")
+            .insert_header(("Authorization", ADMIN_AUTHORIZATION))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::get()
+            .uri("/api/cats?status=available")
+            .to_request();
+        let cats: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        assert!(cats.iter().any(|cat| cat["id"] == cat_id));
+        assert!(cats.iter().all(|cat| cat["status"] == "available"));
+    }
+
+    #[actix_web::test]
+    async fn test_family_graph() {
+        let pool = test_pool();
+        let [kitten, mother, grandmother] = {
+            let mut connection = pool.get().unwrap();
+            let tenant = repository::find_tenant_by_slug(&mut connection, "default").unwrap();
+            [(); 3].map(|_| insert_cat(&mut connection, tenant.id).id)
+        };
+        let app = test_app_with(
+            pool,
+            Arc::new(MemoryFileStore::default()),
+            Config::from_env(),
+            None,
+        )
+        .await;
+        let link = |cat: i32, related: i32| {
+            test::TestRequest::post()
+                .uri(&format!("/api/cat/{}/relations", cat))
+                .set_json(serde_json::json!({"related_cat_id": related, "kind": "mother"}))
+                .to_request()
+        };
+
+        let resp = test::call_service(&app, link(kitten, mother)).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        let resp = test::call_service(&app, link(mother, grandmother)).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+        // The kitten cannot become its grandmother's mother
+        let resp = test::call_service(&app, link(grandmother, kitten)).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        for (depth, expected) in [
+            (1, vec![kitten, mother]),
+            (2, vec![kitten, mother, grandmother]),
+        ] {
+            let req = test::TestRequest::get()
+                .uri(&format!("/api/cat/{}/family?depth={}", kitten, depth))
+                .to_request();
+            let family: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+            let ids: Vec<i64> = family["cats"]
+                .as_array()
+                .unwrap()
+                .iter()
+                .map(|cat| cat["id"].as_i64().unwrap())
+                .collect();
+            assert_eq!(
+                ids,
+                expected.iter().map(|&id| id as i64).collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_nearby_cats() {
+        let pool = test_pool();
+        let [kyiv, brovary, lviv] = {
+            let mut connection = pool.get().unwrap();
+            let tenant = repository::find_tenant_by_slug(&mut connection, "default").unwrap();
+            [(); 3].map(|_| insert_cat(&mut connection, tenant.id).id)
+        };
+        let app = test_app_with(
+            pool,
+            Arc::new(MemoryFileStore::default()),
+            Config::from_env(),
+            None,
+        )
+        .await;
+        for (cat, latitude, longitude) in [
+            (kyiv, 50.45, 30.52),
+            (brovary, 50.51, 30.79),
+            (lviv, 49.84, 24.03),
+        ] {
+            let req = test::TestRequest::put()
+                .uri(&format!("/api/cat/{}/location", cat))
+                .set_json(serde_json::json!({"latitude": latitude, "longitude": longitude}))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+        }
+        let req = test::TestRequest::put()
+            .uri(&format!("/api/cat/{}/location", kyiv))
+            .set_json(serde_json::json!({"latitude": 91.0, "longitude": 0.0}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let req = test::TestRequest::get()
+            .uri("/api/cats/nearby?lat=50.45&lon=30.52&radius_km=30")
+            .to_request();
+        let nearby: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        // Earlier runs may have left cats at the same spots
+        let ids: Vec<i64> = nearby
+            .iter()
+            .map(|cat| cat["id"].as_i64().unwrap())
+            .filter(|id| [kyiv, brovary, lviv].contains(&(*id as i32)))
+            .collect();
+        assert_eq!(ids, [kyiv as i64, brovary as i64]);
+        let distances: Vec<f64> = nearby
+            .iter()
+            .map(|cat| cat["distance_km"].as_f64().unwrap())
+            .collect();
+        assert!(distances.windows(2).all(|pair| pair[0] <= pair[1]));
+        assert!(distances.iter().all(|distance| *distance <= 30.0));
+
+        let req = test::TestRequest::get()
+            .uri("/api/cats/nearby?lat=95&lon=30.52")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["errors"]["lat"][0]["code"], "range");
+    }
+
+    #[actix_web::test]
+    async fn test_medical_records() {
+        let pool = test_pool();
+        let (cat, public_id) = {
+            let mut connection = pool.get().unwrap();
+            let tenant = repository::find_tenant_by_slug(&mut connection, "default").unwrap();
+            let cat = insert_cat(&mut connection, tenant.id).id;
+            let public_id = repository::find_cat(&mut connection, tenant.id, cat)
+                .unwrap()
+                .public_id;
+            (cat, public_id)
+        };
+        let store = Arc::new(MemoryFileStore::default());
+        let app = test_app_with(pool, store.clone(), Config::from_env(), None).await;
+        let records_uri = format!("/api/cat/{}/records", cat);
+
+        let fields = [
+            ("record_type", "vaccination"),
+            ("date", "2025-01-20"),
+            ("due_date", "2026-01-20"),
+            ("notes", "Rabies"),
+        ];
+        let (content_type, body) =
+            multipart_form(&fields, &[("attachment", "certificate.pdf", b"%PDF")]);
+        let req = test::TestRequest::post()
+            .uri(&records_uri)
+            .insert_header((CONTENT_TYPE, content_type))
+            .set_payload(body)
+            .to_request();
+        let vaccination: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let link = vaccination["attachment_path"].as_str().unwrap();
+        assert!(link.starts_with("/signed-image/default/private/records/"));
+        let req = test::TestRequest::get().uri(link).to_request();
+        assert_eq!(test::call_and_read_body(&app, req).await, &b"%PDF"[..]);
+
+        let fields = [("record_type", "checkup"), ("date", "2025-12-01")];
+        let (content_type, body) = multipart_form(&fields, &[]);
+        let req = test::TestRequest::post()
+            .uri(&records_uri)
+            .insert_header((CONTENT_TYPE, content_type))
+            .set_payload(body)
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let req = test::TestRequest::get().uri(&records_uri).to_request();
+        let records: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(records.len(), 2);
+        let req = test::TestRequest::get()
+            .uri(&format!("/api/cat/uuid/{}", public_id))
+            .to_request();
+        let detail: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(detail["medical"]["last_checkup"], "2025-12-01");
+        assert_eq!(
+            detail["medical"]["vaccinations_due"][0]["record_id"],
+            vaccination["id"]
+        );
+
+        // Replacing the record keeps its attachment
+        let record_uri = format!("{}/{}", records_uri, vaccination["id"]);
+        let fields = [("record_type", "vaccination"), ("date", "2025-01-20")];
+        let (content_type, body) = multipart_form(&fields, &[]);
+        let req = test::TestRequest::put()
+            .uri(&record_uri)
+            .insert_header((CONTENT_TYPE, content_type))
+            .set_payload(body)
+            .to_request();
+        let updated: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(updated["due_date"], serde_json::Value::Null);
+        assert!(updated["attachment_path"].is_string());
+
+        let req = test::TestRequest::delete().uri(&record_uri).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let attachment_key = link.strip_prefix("/signed-image/").unwrap();
+        let attachment_key = attachment_key.split('?').next().unwrap();
+        assert!(store.get(attachment_key).is_err());
+        let req = test::TestRequest::get().uri(&record_uri).to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn test_medical_attachments_count_towards_quota() {
+        use crate::models::Quota;
+
+        let pool = test_pool();
+        let cat = {
+            let mut connection = pool.get().unwrap();
+            let tenant = repository::find_tenant_by_slug(&mut connection, "default").unwrap();
+            let cat = insert_cat(&mut connection, tenant.id).id;
+            // Room for 6 more bytes
+            let (_, used) = repository::quota_usage(&mut connection, tenant.id).unwrap();
+            let quota = Quota {
+                max_cats: None,
+                max_storage_bytes: Some(used + 6),
+            };
+            repository::set_quota(&mut connection, tenant.id, quota).unwrap();
+            cat
+        };
+        let store = Arc::new(MemoryFileStore::default());
+        let app = test_app_with(pool, store.clone(), Config::from_env(), None).await;
+        let records_uri = format!("/api/cat/{}/records", cat);
+        let fields = [("record_type", "checkup"), ("date", "2025-12-01")];
+        let request = |req: test::TestRequest, contents: &'static [u8]| {
+            let (content_type, body) =
+                multipart_form(&fields, &[("attachment", "report.pdf", contents)]);
+            req.insert_header((CONTENT_TYPE, content_type))
+                .set_payload(body)
+                .to_request()
+        };
+
+        let req = request(test::TestRequest::post().uri(&records_uri), b"%PDF");
+        let record: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let req = request(test::TestRequest::post().uri(&records_uri), b"%PDF");
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert_eq!(store.len(), 1);
+
+        // Replacing the attachment frees the bytes of the previous one
+        let record_uri = format!("{}/{}", records_uri, record["id"]);
+        let req = request(test::TestRequest::put().uri(&record_uri), b"%PDF-1");
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let req = request(test::TestRequest::put().uri(&record_uri), b"%PDF-1.7");
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert_eq!(store.len(), 1);
+    }
+
+    #[actix_web::test]
+    async fn test_cat_history() {
+        let pool = test_pool();
+        let cat = {
+            let mut connection = pool.get().unwrap();
+            let tenant = repository::find_tenant_by_slug(&mut connection, "default").unwrap();
+            insert_cat(&mut connection, tenant.id).id
+        };
+        let app = test_app_with(
+            pool,
+            Arc::new(MemoryFileStore::default()),
+            Config::from_env(),
+            None,
+        )
+        .await;
+        let req = test::TestRequest::post()
+            .uri(&format!("/api/cat/{}/status", cat))
+            .set_json(serde_json::json!({"status": "pending"}))
+            .peer_addr("203.0.113.7:40000".parse().unwrap())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let req = test::TestRequest::put()
+            .uri(&format!("/api/cat/{}/location", cat))
+            .set_json(serde_json::json!({"latitude": 50.45, "longitude": 30.52}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/api/cat/{}/history?limit=2", cat))
+            .to_request();
+        let page: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        let changes = page["changes"].as_array().unwrap();
+        assert_eq!(changes.len(), 2);
+        assert_eq!(changes[0]["field"], "status");
+        assert_eq!(changes[0]["old_value"], "available");
+        assert_eq!(changes[0]["new_value"], "pending");
+        assert_eq!(changes[0]["actor"], "ip:203.0.113.7");
+        assert_eq!(changes[1]["field"], "latitude");
+        assert_eq!(changes[1]["actor"], "ip:127.0.0.1");
+
+        let next = page["_links"]["next"]["href"].as_str().unwrap();
+        assert_eq!(
+            next,
+            format!(
+                "/api/cat/{}/history?after={}&limit=2",
+                cat, page["next_after"]
+            )
+        );
+        let req = test::TestRequest::get().uri(next).to_request();
+        let page: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(page["changes"][0]["field"], "longitude");
+        assert_eq!(page["next_after"], serde_json::Value::Null);
+        assert!(page["_links"].get("next").is_none());
+    }
+
+    #[actix_web::test]
+    #[cfg(feature = "image-processing")]
+    async fn test_image_format_negotiation() {
+        use actix_web::http::header::{ACCEPT, VARY};
+
+        let store = Arc::new(MemoryFileStore::default());
+        let variant_dir = tempfile::tempdir().unwrap();
+        let mut config = Config::from_env();
+        config.image_variant_dir = variant_dir.path().to_path_buf();
+        let app = test_app_with(test_pool(), store.clone(), config, None).await;
+        let mut png = Vec::new();
+        image::RgbImage::from_pixel(64, 48, image::Rgb([200, 120, 40]))
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        let image_key = format!("default/{}.png", Uuid::new_v4());
+        store.put(&image_key, &mut png.as_slice()).unwrap();
+        let get = || {
+            test::TestRequest::get()
+                .uri(&format!("/image/{}", image_key))
+                .insert_header((ACCEPT, "image/webp,*/*;q=0.8"))
+                .to_request()
+        };
+
+        // Converted on the first request, served from the cache after
+        for _ in 0..2 {
+            let resp = test::call_service(&app, get()).await;
+            assert_eq!(resp.status(), StatusCode::OK);
+            assert_eq!(resp.headers().get(CONTENT_TYPE).unwrap(), "image/webp");
+            assert_eq!(resp.headers().get(VARY).unwrap(), "accept");
+            let body = test::read_body(resp).await;
+            assert!(body.starts_with(b"RIFF"));
+            assert!(body.len() < png.len());
+        }
+        assert!(variant_dir
+            .path()
+            .join(format!("{}.webp", image_key))
+            .exists());
+
+        store.delete(&image_key).unwrap();
+        let resp = test::call_service(&app, get()).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    #[cfg(feature = "pdf")]
+    async fn test_export_pdf() {
+        use actix_web::http::header::CONTENT_DISPOSITION;
+
+        let store = Arc::new(MemoryFileStore::default());
+        let app = test_app_with_store(store.clone()).await;
+        let mut png = Vec::new();
+        image::RgbImage::from_pixel(64, 48, image::Rgb([200, 120, 40]))
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+        let image_key = format!("default/{}.png", Uuid::new_v4());
+        store.put(&image_key, &mut png.as_slice()).unwrap();
+        let req = test::TestRequest::post()
+            .uri("/api/cats")
+            .set_json(serde_json::json!({
+                "name": format!("Printed {}", Uuid::new_v4()),
+                "image_key": image_key,
+            }))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        let req = test::TestRequest::get()
+            .uri("/api/cats/export.pdf?status=available")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get(CONTENT_TYPE).unwrap(), "application/pdf");
+        assert_eq!(
+            resp.headers().get(CONTENT_DISPOSITION).unwrap(),
+            "attachment; filename=\"catdex-default.pdf\""
+        );
+        let body = test::read_body(resp).await;
+        assert!(body.starts_with(b"%PDF-"));
+        // The photo is embedded as a JPEG
+        assert!(body.windows(10).any(|window| window == b"/DCTDecode"));
+    }
+
+    #[actix_web::test]
+    #[cfg(feature = "image-processing")]
+    async fn test_similar_cats() {
+        let store = Arc::new(MemoryFileStore::default());
+        let app = test_app_with_store(store.clone()).await;
+        let scene =
+            image::RgbImage::from_fn(64, 48, |x, y| image::Rgb([x as u8 * 4, y as u8 * 5, 90]));
+        let mut png = Vec::new();
+        scene
+            .write_to(&mut std::io::Cursor::new(&mut png), image::ImageFormat::Png)
+            .unwrap();
+
+        let mut ids = Vec::new();
+        let mut similar_header = None;
+        for _ in 0..2 {
+            let image_key = format!("default/{}.png", Uuid::new_v4());
+            store.put(&image_key, &mut png.as_slice()).unwrap();
+            let req = test::TestRequest::post()
+                .uri("/api/cats")
+                .set_json(serde_json::json!({
+                    "name": format!("Look alike {}", Uuid::new_v4()),
+                    "image_key": image_key,
+                }))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::CREATED);
+            similar_header = resp
+                .headers()
+                .get(similar::SIMILAR_HEADER)
+                .map(|value| value.to_str().unwrap().to_string());
+            let cat: Cat = test::read_body_json(resp).await;
+            ids.push(cat.id);
+        }
+        let similar_ids: Vec<i32> = similar_header
+            .expect("Look alike is warned of")
+            .split(", ")
+            .map(|id| id.parse().unwrap())
+            .collect();
+        assert!(similar_ids.contains(&ids[0]));
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/api/cat/{}/similar", ids[1]))
+            .to_request();
+        let similar: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        let first = similar
+            .iter()
+            .find(|cat| cat["id"] == ids[0])
+            .expect("Look alike is listed");
+        assert_eq!(first["distance"], 0);
+        assert!(similar.iter().all(|cat| cat["id"] != ids[1]));
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/api/cat/{}/similar?max_distance=65", ids[1]))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+
+    #[actix_web::test]
+    async fn test_original_format_follows_stored_key() {
+        use crate::schema::{cat_images, cats};
+        use diesel::prelude::*;
+
+        let pool = test_pool();
+        let store = Arc::new(MemoryFileStore::default());
+        let app = test_app_with(pool.clone(), store.clone(), Config::from_env(), None).await;
+        // As stored by POST /api/images or an upload after converting HEIC
+        let converted = || {
+            let key = format!("default/{}.heic.jpg", Uuid::new_v4());
+            store.put(&key, &mut &b"jpeg"[..]).unwrap();
+            key
+        };
+
+        let req = test::TestRequest::post()
+            .uri("/api/cats")
+            .set_json(serde_json::json!({
+                "name": format!("Converted cat {}", Uuid::new_v4()),
+                "image_key": converted(),
+            }))
+            .to_request();
+        let cat: Cat = test::call_and_read_body_json(&app, req).await;
+        let req = test::TestRequest::post()
+            .uri(&format!("/api/cat/{}/images", cat.id))
+            .set_json(serde_json::json!({"image_key": converted()}))
+            .to_request();
+        let image: CatImage = test::call_and_read_body_json(&app, req).await;
+
+        let mut connection = pool.get().unwrap();
+        let cat_format: Option<String> = cats::table
+            .find(cat.id)
+            .select(cats::image_original_format)
+            .first(&mut connection)
+            .unwrap();
+        assert_eq!(cat_format.as_deref(), Some("heic"));
+        let image_format: Option<String> = cat_images::table
+            .find(image.id)
+            .select(cat_images::image_original_format)
+            .first(&mut connection)
+            .unwrap();
+        assert_eq!(image_format.as_deref(), Some("heic"));
+    }
+
+    #[actix_web::test]
+    async fn test_cat_gallery() {
+        let app = test_app().await;
+        let upload = |contents: &'static [u8]| {
+            let (content_type, body) = multipart_body(&[], contents);
+            test::TestRequest::post()
+                .uri("/api/images")
+                .insert_header((CONTENT_TYPE, content_type))
+                .set_payload(body)
+                .to_request()
+        };
+        let uploaded: UploadedImage = test::call_and_read_body_json(&app, upload(b"jpeg")).await;
+        let req = test::TestRequest::post()
+            .uri("/api/cats")
+            .set_json(serde_json::json!({
+                "name": format!("Gallery cat {}", Uuid::new_v4()),
+                "image_key": uploaded.image_key,
+            }))
+            .to_request();
+        let cat: Cat = test::call_and_read_body_json(&app, req).await;
+        let images = format!("/api/cat/{}/images", cat.id);
+
+        let req = test::TestRequest::get().uri(&images).to_request();
+        let gallery: Vec<CatImage> = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(gallery.len(), 1);
+        assert!(gallery[0].is_primary);
+        assert_eq!(gallery[0].image_path, cat.image_path);
+        let first = gallery[0].id;
+
+        let mut added = Vec::new();
+        for (contents, primary) in [(&b"side"[..], false), (&b"front"[..], true)] {
+            let uploaded: UploadedImage =
+                test::call_and_read_body_json(&app, upload(contents)).await;
+            let req = test::TestRequest::post()
+                .uri(&images)
+                .set_json(serde_json::json!({"image_key": uploaded.image_key, "primary": primary}))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::CREATED);
+            let image: CatImage = test::read_body_json(resp).await;
+            assert_eq!(image.is_primary, primary);
+            added.push(image);
+        }
+        let (side, front) = (added[0].id, added[1].id);
+
+        let req = test::TestRequest::get()
+            .uri(&format!("/api/cat/uuid/{}", cat.public_id))
+            .to_request();
+        let detail: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(detail["image_path"], added[1].image_path);
+        let ids: Vec<_> = detail["gallery"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|image| image["id"].as_i64().unwrap() as i32)
+            .collect();
+        assert_eq!(ids, [first, side, front]);
+
+        let req = test::TestRequest::put()
+            .uri(&images)
+            .set_json(serde_json::json!({"image_ids": [front, first, side]}))
+            .to_request();
+        let gallery: Vec<CatImage> = test::call_and_read_body_json(&app, req).await;
+        let ids: Vec<_> = gallery.iter().map(|image| image.id).collect();
+        assert_eq!(ids, [front, first, side]);
+        for image_ids in [vec![front, first], vec![front, first, side, side]] {
+            let req = test::TestRequest::put()
+                .uri(&images)
+                .set_json(serde_json::json!({ "image_ids": image_ids }))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        }
+
+        let req = test::TestRequest::post()
+            .uri(&format!("{}/{}/primary", images, first))
+            .to_request();
+        let primary: Cat = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(primary.image_path, cat.image_path);
+
+        let req = test::TestRequest::delete()
+            .uri(&format!("{}/{}", images, first))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+        let req = test::TestRequest::delete()
+            .uri(&format!("{}/{}", images, front))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        let req = test::TestRequest::get().uri(&images).to_request();
+        let gallery: Vec<CatImage> = test::call_and_read_body_json(&app, req).await;
+        let ids: Vec<_> = gallery.iter().map(|image| image.id).collect();
+        assert_eq!(ids, [first, side]);
+    }
+
+    #[actix_web::test]
+    async fn test_unknown_tenant() {
+        let app = test_app().await;
+        let req = test::TestRequest::get()
+            .uri("/api/cats")
+            .insert_header(("X-Tenant", "no-such-tenant"))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn test_register_and_delete_webhook() {
+        let pool = test_pool();
+        {
+            let mut connection = pool.get().unwrap();
+            let tenant = repository::find_tenant_by_slug(&mut connection, "default").unwrap();
+            insert_test_admin(&mut connection, tenant.id);
+        }
+        let app = test_app_with(
+            pool,
+            Arc::new(MemoryFileStore::default()),
+            Config::from_env(),
+            None,
+        )
+        .await;
+        let req = test::TestRequest::post()
+            .uri("/api/webhooks")
+            .insert_header(("Authorization", ADMIN_AUTHORIZATION))
+            .set_json(serde_json::json!({"url": "https://93.184.216.34/hook"}))
+            .to_request();
+        let webhook: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert!(webhook["secret"].is_string());
+        let webhook_uri = format!("/api/webhooks/{}", webhook["id"]);
+
+        let req = test::TestRequest::get()
+            .uri("/api/webhooks")
+            .insert_header(("Authorization", ADMIN_AUTHORIZATION))
+            .to_request();
+        let webhooks: Vec<serde_json::Value> = test::call_and_read_body_json(&app, req).await;
+        assert!(webhooks.iter().any(|w| w["id"] == webhook["id"]));
+        assert!(webhooks.iter().all(|w| w.get("secret").is_none()));
+
+        let req = test::TestRequest::delete()
+            .uri(&webhook_uri)
+            .insert_header(("Authorization", ADMIN_AUTHORIZATION))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+
+        let req = test::TestRequest::delete()
+            .uri(&webhook_uri)
+            .insert_header(("Authorization", ADMIN_AUTHORIZATION))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[actix_web::test]
+    async fn test_webhooks_need_an_admin() {
+        let pool = test_pool();
+        {
+            let mut connection = pool.get().unwrap();
+            let tenant = repository::find_tenant_by_slug(&mut connection, "default").unwrap();
+            insert_test_admin(&mut connection, tenant.id);
+        }
+        let app = test_app_with(
+            pool,
+            Arc::new(MemoryFileStore::default()),
+            Config::from_env(),
+            None,
+        )
+        .await;
+        // "admin:wrong"
+        for authorization in [None, Some("Basic YWRtaW46d3Jvbmc=")] {
+            for req in [
+                test::TestRequest::post()
+                    .uri("/api/webhooks")
+                    .set_json(serde_json::json!({"url": "https://93.184.216.34/hook"})),
+                test::TestRequest::get().uri("/api/webhooks"),
+                test::TestRequest::delete().uri("/api/webhooks/1"),
+                test::TestRequest::get().uri("/api/webhooks/1/deliveries"),
+            ] {
+                let req = match authorization {
+                    Some(authorization) => req.insert_header(("Authorization", authorization)),
+                    None => req,
+                };
+                let resp = test::call_service(&app, req.to_request()).await;
+                assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+            }
+        }
+
+        for url in [
+            "http://127.0.0.1:5433/hook",
+            "http://169.254.169.254/latest/meta-data",
+            "http://[::1]/hook",
+            "http://10.0.0.1/hook",
+        ] {
+            let req = test::TestRequest::post()
+                .uri("/api/webhooks")
+                .insert_header(("Authorization", ADMIN_AUTHORIZATION))
+                .set_json(serde_json::json!({ "url": url }))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::BAD_REQUEST, "{}", url);
+        }
+    }
+
+    #[actix_web::test]
+    async fn test_health_details() {
+        let pool = test_pool();
+        {
+            let mut connection = pool.get().unwrap();
+            let tenant = repository::find_tenant_by_slug(&mut connection, "default").unwrap();
+            insert_test_admin(&mut connection, tenant.id);
+        }
+        let store = Arc::new(MemoryFileStore::default());
+        let app = test_app_with(pool, store.clone(), Config::from_env(), None).await;
+
+        let req = test::TestRequest::get()
+            .uri("/api/health/details")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let req = test::TestRequest::get()
+            .uri("/api/health/details")
+            .insert_header(("Authorization", ADMIN_AUTHORIZATION))
+            .to_request();
+        let details: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(details["ok"], true);
+        assert_eq!(details["build"]["version"], env!("CARGO_PKG_VERSION"));
+        assert!(details["checks"]["database"]["latency_ms"].is_number());
+        assert_eq!(details["checks"]["storage"]["ok"], true);
+        assert!(store.is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_version() {
+        let app = test_app().await;
+        let req = test::TestRequest::get().uri("/api/version").to_request();
+        let info: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(info["version"], env!("CARGO_PKG_VERSION"));
+        assert_eq!(info["git_sha"], env!("CATDEX_GIT_SHA"));
+        assert!(info["built_at"].is_string());
+        let features = info["features"].as_array().unwrap();
+        assert_eq!(
+            features.contains(&"tls".into()),
+            cfg!(feature = "tls"),
+            "{:?}",
+            features
+        );
+        assert_eq!(info["backends"]["storage"], "memory");
+        assert_eq!(info["backends"]["database"], "postgresql");
+        assert!(info["backends"]["database_version"].is_string());
+    }
+
+    #[actix_web::test]
+    async fn test_active_config_redacts_secrets() {
+        let pool = test_pool();
+        {
+            let mut connection = pool.get().unwrap();
+            let tenant = repository::find_tenant_by_slug(&mut connection, "default").unwrap();
+            insert_test_admin(&mut connection, tenant.id);
+        }
+        let mut config = Config::from_env();
+        config.image_signing_key = Some("very secret".to_string());
+        let app = test_app_with(pool, Arc::new(MemoryFileStore::default()), config, None).await;
+
+        let req = test::TestRequest::post()
+            .uri("/api/admin/config/reload")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let req = test::TestRequest::get()
+            .uri("/api/admin/config")
+            .insert_header(("Authorization", ADMIN_AUTHORIZATION))
+            .to_request();
+        let active: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(active["config"]["image_signing_key"], "<redacted>");
+        assert!(active["settings"]["family_max_depth"].is_number());
+    }
+
+    #[actix_web::test]
+    async fn test_set_payload_log() {
+        let pool = test_pool();
+        {
+            let mut connection = pool.get().unwrap();
+            let tenant = repository::find_tenant_by_slug(&mut connection, "default").unwrap();
+            insert_test_admin(&mut connection, tenant.id);
+        }
+        let app = test_app_with(
+            pool,
+            Arc::new(MemoryFileStore::default()),
+            Config::from_env(),
+            None,
+        )
+        .await;
+
+        let req = test::TestRequest::put()
+            .uri("/api/admin/payload-log")
+            .set_json(serde_json::json!({"routes": ["/api/cats"]}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let req = test::TestRequest::put()
+            .uri("/api/admin/payload-log")
+            .insert_header(("Authorization", ADMIN_AUTHORIZATION))
+            .set_json(serde_json::json!({"routes": ["cats"]}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+
+        let req = test::TestRequest::put()
+            .uri("/api/admin/payload-log")
+            .insert_header(("Authorization", ADMIN_AUTHORIZATION))
+            .set_json(serde_json::json!({"routes": ["/api/cat/{id}"], "max_bytes": 512}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::get()
+            .uri("/api/admin/payload-log")
+            .insert_header(("Authorization", ADMIN_AUTHORIZATION))
+            .to_request();
+        let active: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(active["routes"][0], "/api/cat/{id}");
+        assert_eq!(active["max_bytes"], 512);
+    }
+
+    #[actix_web::test]
+    async fn test_maintenance_mode() {
+        let pool = test_pool();
+        {
+            let mut connection = pool.get().unwrap();
+            let tenant = repository::find_tenant_by_slug(&mut connection, "default").unwrap();
+            insert_test_admin(&mut connection, tenant.id);
+        }
+        let app = test_app_with(
+            pool,
+            Arc::new(MemoryFileStore::default()),
+            Config::from_env(),
+            None,
+        )
+        .await;
+
+        let req = test::TestRequest::put()
+            .uri("/api/admin/maintenance")
+            .set_json(serde_json::json!({"enabled": true, "retry_after_secs": 120}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let req = test::TestRequest::put()
+            .uri("/api/admin/maintenance")
+            .insert_header(("Authorization", ADMIN_AUTHORIZATION))
+            .set_json(serde_json::json!({"enabled": true, "retry_after_secs": 120}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        let req = test::TestRequest::get().uri("/api/cats").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(
+            resp.headers().get(maintenance::MAINTENANCE_HEADER).unwrap(),
+            "on"
+        );
+
+        let req = test::TestRequest::post()
+            .uri("/api/webhooks")
+            .insert_header(("Authorization", ADMIN_AUTHORIZATION))
+            .set_json(serde_json::json!({"url": "https://example.com/hook"}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers().get(RETRY_AFTER).unwrap(), "120");
+
+        let req = test::TestRequest::put()
+            .uri("/api/admin/maintenance")
+            .insert_header(("Authorization", ADMIN_AUTHORIZATION))
+            .set_json(serde_json::json!({"enabled": false, "retry_after_secs": 120}))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let req = test::TestRequest::get().uri("/api/cats").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert!(!resp.headers().contains_key(maintenance::MAINTENANCE_HEADER));
+    }
+
+    #[actix_web::test]
+    async fn test_migrate_storage() {
+        let pool = test_pool();
+        let store = Arc::new(MemoryFileStore::default());
+        let target = Arc::new(MemoryFileStore::default());
+        let key = format!("default/{}.jpg", Uuid::new_v4());
+        let (tenant_id, cat_id) = {
+            let mut connection = pool.get().unwrap();
+            let tenant = repository::find_tenant_by_slug(&mut connection, "default").unwrap();
+            insert_test_admin(&mut connection, tenant.id);
+            let new_cat = NewCat {
+                image_path: format!("/image/{}", key),
+                image_size: 4,
+                ..test_cat()
+            };
+            (
+                tenant.id,
+                insert_cat_with(&mut connection, tenant.id, &new_cat).id,
+            )
+        };
+        store.put(&key, &mut &b"jpeg"[..]).unwrap();
+        let migrate = |config: Config| {
+            test::init_service(
+                test_app_builder(pool.clone(), store.clone())
+                    .config(config)
+                    .migration_target(target.clone())
+                    .build()
+                    .app(),
+            )
+        };
+
+        let app = migrate(Config::from_env()).await;
+        let req = test::TestRequest::post()
+            .uri("/api/admin/migrate-storage")
+            .insert_header(("Authorization", ADMIN_AUTHORIZATION))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CONFLICT);
+
+        let mut config = Config::from_env();
+        config.maintenance_mode = true;
+        let app = migrate(config).await;
+        let req = test::TestRequest::post()
+            .uri("/api/admin/migrate-storage?batch_size=10")
+            .insert_header(("Authorization", ADMIN_AUTHORIZATION))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = test::read_body(resp).await;
+        let last: storage_migration::Progress =
+            serde_json::from_slice(body.trim_ascii().rsplit(|b| *b == b'\n').next().unwrap())
+                .unwrap();
+        assert!(last.done);
+        assert!(last.migrated >= 1);
+
+        let mut connection = pool.get().unwrap();
+        let cat = repository::find_cat(&mut connection, tenant_id, cat_id).unwrap();
+        let new_key = cat.image_path.strip_prefix("/image/").unwrap();
+        assert_ne!(new_key, key);
+        assert_eq!(target.get(new_key).unwrap(), b"jpeg");
+        assert!(store.get(&key).is_err());
+    }
+
+    #[actix_web::test]
+    async fn test_moderation_queue() {
+        let pool = test_pool();
+        let store = Arc::new(MemoryFileStore::default());
+        let (approved_id, rejected_id, rejected_key, shared_id, shared_key) = {
+            let mut connection = pool.get().unwrap();
+            let tenant = repository::find_tenant_by_slug(&mut connection, "default").unwrap();
+            insert_test_admin(&mut connection, tenant.id);
+            let mut pending = |key: &str| {
+                store.put(key, &mut &b"jpeg"[..]).unwrap();
+                let new_cat = NewCat {
+                    image_path: format!("/image/{}", key),
+                    image_size: 4,
+                    pending_review: true,
+                    ..test_cat()
+                };
+                insert_cat_with(&mut connection, tenant.id, &new_cat).id
+            };
+            let rejected_key = format!("default/{}.jpg", Uuid::new_v4());
+            let shared_key = format!("default/{}.jpg", Uuid::new_v4());
+            let approved_id = pending(&format!("default/{}.jpg", Uuid::new_v4()));
+            let rejected_id = pending(&rejected_key);
+            let shared_id = pending(&shared_key);
+            // Another cat shows the same file as a photo of its gallery
+            repository::insert_cat_image(
+                &mut connection,
+                &NewCatImage {
+                    tenant_id: tenant.id,
+                    cat_id: approved_id,
+                    image_path: format!("/image/{}", shared_key),
+                    image_hash: None,
+                    image_size: 4,
+                    image_phash: None,
+                    position: 1,
+                    is_primary: false,
+                    created_at: Utc::now(),
+                    image_original_format: None,
+                },
+            )
+            .unwrap();
+            (
+                approved_id,
+                rejected_id,
+                rejected_key,
+                shared_id,
+                shared_key,
+            )
+        };
+        let app = test_app_with(pool.clone(), store.clone(), Config::from_env(), None).await;
+        let listed = |cats: &serde_json::Value, cat_id: i32| {
+            cats.as_array()
+                .unwrap()
+                .iter()
+                .any(|cat| cat["id"] == cat_id)
+        };
+
+        let req = test::TestRequest::get().uri("/api/cats").to_request();
+        let cats: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert!(!listed(&cats, approved_id));
+
+        let req = test::TestRequest::get()
+            .uri("/api/admin/moderation")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let req = test::TestRequest::get()
+            .uri("/api/admin/moderation")
+            .insert_header(("Authorization", ADMIN_AUTHORIZATION))
+            .to_request();
+        let queue: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert!(listed(&queue, approved_id));
+        assert!(listed(&queue, rejected_id));
+        assert_eq!(queue[0]["pending_review"], true);
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/api/admin/moderation/{}/approve", approved_id))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/api/admin/moderation/{}/approve", approved_id))
+            .insert_header(("Authorization", ADMIN_AUTHORIZATION))
+            .to_request();
+        let approved: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(approved["pending_review"], false);
+
+        let req = test::TestRequest::get().uri("/api/cats").to_request();
+        let cats: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert!(listed(&cats, approved_id));
+
+        // Only cats in the queue can be approved or rejected
+        let req = test::TestRequest::post()
+            .uri(&format!("/api/admin/moderation/{}/reject", approved_id))
+            .insert_header(("Authorization", ADMIN_AUTHORIZATION))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/api/admin/moderation/{}/reject", rejected_id))
+            .insert_header(("Authorization", ADMIN_AUTHORIZATION))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert!(store.get(&rejected_key).is_err());
+
+        let req = test::TestRequest::post()
+            .uri(&format!("/api/admin/moderation/{}/reject", shared_id))
+            .insert_header(("Authorization", ADMIN_AUTHORIZATION))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+        assert_eq!(store.get(&shared_key).unwrap(), b"jpeg");
+        let mut connection = pool.get().unwrap();
+        let tenant = repository::find_tenant_by_slug(&mut connection, "default").unwrap();
+        assert!(matches!(
+            repository::find_cat(&mut connection, tenant.id, rejected_id),
+            Err(diesel::result::Error::NotFound)
+        ));
+    }
+
+    #[actix_web::test]
+    async fn test_public_submission() {
+        let pool = test_pool();
+        let store = Arc::new(MemoryFileStore::default());
+        let submit = |fields: &[(&str, &str)]| {
+            let (content_type, body) = multipart_body(fields, b"kiosk jpeg");
+            test::TestRequest::post()
+                .uri("/api/submit_cat")
+                .peer_addr("192.0.2.10:40000".parse().unwrap())
+                .insert_header((CONTENT_TYPE, content_type))
+                .set_payload(body)
+                .to_request()
+        };
+
+        let app = test_app_with(pool.clone(), store.clone(), Config::from_env(), None).await;
+        let resp = test::call_service(&app, submit(&[("captcha_token", "solved")])).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let mut settings = Settings::load(None).unwrap();
+        settings.public_submission_limit = 3;
+        let app = test_app_builder(pool.clone(), store)
+            .config(Config::from_env())
+            .settings(web::Data::new(LiveSettings::new(settings, None)))
+            .captcha_verifier(Arc::new(FakeCaptcha))
+            .build();
+        let app = test::init_service(app.app()).await;
+        let name = format!("Kiosk cat {}", Uuid::new_v4());
+        // Responses hold the route's permits until dropped
+        let status = |resp: ServiceResponse<_>| resp.status();
+        let resp = test::call_service(&app, submit(&[("name", &name)])).await;
+        assert_eq!(status(resp), StatusCode::FORBIDDEN);
+        let fields = [("name", name.as_str()), ("h-captcha-response", "guessed")];
+        let resp = test::call_service(&app, submit(&fields)).await;
+        assert_eq!(status(resp), StatusCode::FORBIDDEN);
+        let fields = [("name", name.as_str()), ("h-captcha-response", "solved")];
+        let resp = test::call_service(&app, submit(&fields)).await;
+        assert_eq!(status(resp), StatusCode::ACCEPTED);
+        {
+            let mut connection = pool.get().unwrap();
+            let tenant = repository::find_tenant_by_slug(&mut connection, "default").unwrap();
+            let pending = repository::list_pending_cats(&mut connection, tenant.id, 100).unwrap();
+            assert!(pending.iter().any(|cat| cat.name == name));
+        }
+
+        // Failed CAPTCHAs count towards the limit too
+        let resp = test::call_service(&app, submit(&fields)).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers().get(RETRY_AFTER).unwrap(), "3600");
+        // Refused before the form is read
+        let req = test::TestRequest::post()
+            .uri("/api/submit_cat")
+            .peer_addr("192.0.2.10:40000".parse().unwrap())
+            .insert_header((CONTENT_TYPE, "multipart/form-data; boundary=x"))
+            .set_payload("not a form")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[actix_web::test]
+    async fn test_session_login() {
+        let pool = test_pool();
+        {
+            let mut connection = pool.get().unwrap();
+            let tenant = repository::find_tenant_by_slug(&mut connection, "default").unwrap();
+            insert_test_admin(&mut connection, tenant.id);
+        }
+        let app = test_app_with(
+            pool,
+            Arc::new(MemoryFileStore::default()),
+            Config::from_env(),
+            None,
+        )
+        .await;
+        fn session_cookie<B>(resp: &ServiceResponse<B>) -> Cookie<'static> {
+            resp.response()
+                .cookies()
+                .find(|cookie| cookie.name() == sessions::SESSION_COOKIE)
+                .expect("Session cookie is set")
+                .into_owned()
+        }
+        let csrf_token = |body: &[u8]| {
+            let body = std::str::from_utf8(body).unwrap();
+            let (_, rest) = body.split_once(r#"name="csrf_token" value=""#).unwrap();
+            rest.split('"').next().unwrap().to_string()
+        };
+
+        let req = test::TestRequest::get().uri("/login").to_request();
+        let resp = test::call_service(&app, req).await;
+        let cookie = session_cookie(&resp);
+        let token = csrf_token(&test::read_body(resp).await);
+
+        let login = |password: &str, token: &str| {
+            test::TestRequest::post()
+                .uri("/login")
+                .cookie(cookie.clone())
+                .set_form([
+                    ("username", "admin"),
+                    ("password", password),
+                    ("csrf_token", token),
+                ])
+                .to_request()
+        };
+        let resp = test::call_service(&app, login("open sesame", "forged")).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let resp = test::call_service(&app, login("wrong", &token)).await;
+        assert_eq!(resp.status(), StatusCode::UNAUTHORIZED);
+        let resp = test::call_service(&app, login("open sesame", &token)).await;
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        assert_eq!(resp.headers().get(LOCATION).unwrap(), "/upload");
+        let cookie = session_cookie(&resp);
+
+        let req = test::TestRequest::get()
+            .uri("/upload")
+            .cookie(cookie.clone())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        let cookie = resp
+            .response()
+            .cookies()
+            .find(|cookie| cookie.name() == sessions::SESSION_COOKIE)
+            .map(|cookie| cookie.into_owned())
+            .unwrap_or(cookie);
+        let body = test::read_body(resp).await;
+        assert!(std::str::from_utf8(&body)
+            .unwrap()
+            .contains("Logged in as admin"));
+        let new_token = csrf_token(&body);
+        // Logging in issues a new token
+        assert_ne!(new_token, token);
+        let token = new_token;
+
+        // The session only counts along with its CSRF token
+        let req = test::TestRequest::get()
+            .uri("/api/admin/moderation")
+            .cookie(cookie.clone())
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let req = test::TestRequest::get()
+            .uri("/api/admin/moderation")
+            .cookie(cookie.clone())
+            .insert_header((sessions::CSRF_HEADER, token.clone()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+
+        // State-changing requests with the session cookie need the token,
+        // unless they carry credentials of their own
+        let approve = || {
+            test::TestRequest::post()
+                .uri("/api/admin/moderation/999999/approve")
+                .cookie(cookie.clone())
+        };
+        let resp = test::call_service(&app, approve().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["msg"], "Missing or invalid CSRF token");
+        let req = approve()
+            .insert_header(("Authorization", ADMIN_AUTHORIZATION))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let req = approve()
+            .insert_header((sessions::CSRF_HEADER, token.clone()))
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+
+        let req = test::TestRequest::post()
+            .uri("/logout")
+            .cookie(cookie.clone())
+            .set_form([("csrf_token", token.as_str())])
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::SEE_OTHER);
+        assert_eq!(resp.headers().get(LOCATION).unwrap(), "/");
+    }
+
+    #[actix_web::test]
+    async fn test_fallbacks() {
+        let app = test_app_with(
+            test_pool(),
+            Arc::new(MemoryFileStore::default()),
+            Config::from_env(),
+            None,
+        )
+        .await;
+
+        let req = test::TestRequest::get().uri("/api/dogs").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["msg"], "Not found");
+
+        let req = test::TestRequest::delete().uri("/api/cats").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(resp.headers().get(ALLOW).unwrap(), "GET, POST");
+        let body: serde_json::Value = test::read_body_json(resp).await;
+        assert_eq!(body["msg"], "Method not allowed");
+        for language in ["en", "uk"] {
+            let req = test::TestRequest::delete()
+                .uri("/api/cats")
+                .insert_header((ACCEPT_LANGUAGE, language))
+                .to_request();
+            let resp = test::call_service(&app, req).await;
+            assert_eq!(resp.status(), StatusCode::METHOD_NOT_ALLOWED);
+            assert_eq!(resp.headers().get(CONTENT_LANGUAGE).unwrap(), language);
+            assert_eq!(resp.headers().get(ALLOW).unwrap(), "GET, POST");
+        }
+
+        let req = test::TestRequest::get().uri("/dogs").to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        assert_eq!(
+            resp.headers().get(CONTENT_TYPE).unwrap(),
+            "text/html; charset=utf-8"
+        );
+        let body = test::read_body(resp).await;
+        assert!(std::str::from_utf8(&body)
+            .unwrap()
+            .contains("Page not found"));
+    }
+
+    #[actix_web::test]
+    async fn test_stats_summary() {
+        let pool = test_pool();
+        {
+            let mut connection = pool.get().unwrap();
+            let tenant = repository::find_tenant_by_slug(&mut connection, "default").unwrap();
+            insert_cat(&mut connection, tenant.id);
+            insert_cat(&mut connection, tenant.id);
+            repository::refresh_cat_stats(&mut connection).unwrap();
+        }
+        let app = test_app_with(
+            pool,
+            Arc::new(MemoryFileStore::default()),
+            Config::from_env(),
+            None,
+        )
+        .await;
+
+        let req = test::TestRequest::get()
+            .uri("/api/stats/summary?days=7")
+            .to_request();
+        let summary: serde_json::Value = test::call_and_read_body_json(&app, req).await;
+        assert_eq!(summary["since"], "2025-12-26");
+        let today = summary["by_day"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .find(|day| day["day"] == "2026-01-01")
+            .expect("Today is summarized");
+        assert!(today["cats"].as_i64().unwrap() >= 2);
+        assert!(summary["by_status"]["available"].as_i64().unwrap() >= 2);
+
+        let req = test::TestRequest::get()
+            .uri("/api/stats/summary?days=0")
+            .to_request();
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+    }
+}
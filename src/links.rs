@@ -0,0 +1,169 @@
+use crate::config::Config;
+use crate::models::{Cat, MedicalRecord};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+/// A link to a related URL, with the method to use when it is not `GET`.
+#[derive(Serialize, Debug, PartialEq)]
+pub struct Link {
+    href: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    method: Option<&'static str>,
+}
+
+/// The `_links` member of a response, by relation name.
+pub type Links = BTreeMap<&'static str, Link>;
+
+/// An item with its `_links`.
+#[derive(Serialize)]
+pub struct Linked<T> {
+    #[serde(flatten)]
+    pub item: T,
+    pub _links: Links,
+}
+
+/// Builds links against `PUBLIC_BASE_URL`, or as absolute paths when it is
+/// not configured.
+pub struct LinkBuilder<'a> {
+    base_url: &'a str,
+}
+
+impl<'a> LinkBuilder<'a> {
+    pub fn new(config: &'a Config) -> LinkBuilder<'a> {
+        LinkBuilder {
+            base_url: config.public_base_url.as_deref().unwrap_or(""),
+        }
+    }
+
+    /// A link to `path`, which starts with a slash, or to an absolute URL
+    /// as is.
+    pub fn link(&self, path: &str) -> Link {
+        if !path.starts_with('/') {
+            return Link {
+                href: path.to_string(),
+                method: None,
+            };
+        }
+        Link {
+            href: format!("{}{}", self.base_url, path),
+            method: None,
+        }
+    }
+
+    fn action(&self, method: &'static str, path: &str) -> Link {
+        Link {
+            method: Some(method),
+            ..self.link(path)
+        }
+    }
+
+    /// Links of a cat whose image path has already been presented.
+    pub fn cat(&self, cat: &Cat) -> Links {
+        let path = format!("/api/cat/{}", cat.id);
+        Links::from([
+            ("self", self.link(&path)),
+            ("image", self.link(&cat.image_path)),
+            (
+                "update_status",
+                self.action("POST", &format!("{}/status", path)),
+            ),
+            (
+                "update_location",
+                self.action("PUT", &format!("{}/location", path)),
+            ),
+            (
+                "delete_location",
+                self.action("DELETE", &format!("{}/location", path)),
+            ),
+            ("images", self.link(&format!("{}/images", path))),
+            ("records", self.link(&format!("{}/records", path))),
+            ("history", self.link(&format!("{}/history", path))),
+            ("family", self.link(&format!("{}/family", path))),
+        ])
+    }
+
+    pub fn linked_cat(&self, cat: Cat) -> Linked<Cat> {
+        Linked {
+            _links: self.cat(&cat),
+            item: cat,
+        }
+    }
+
+    pub fn record(&self, record: &MedicalRecord) -> Links {
+        let path = format!("/api/cat/{}/records/{}", record.cat_id, record.id);
+        let mut links = Links::from([
+            ("self", self.link(&path)),
+            ("cat", self.link(&format!("/api/cat/{}", record.cat_id))),
+            ("update", self.action("PUT", &path)),
+            ("delete", self.action("DELETE", &path)),
+        ]);
+        if let Some(attachment) = &record.attachment_path {
+            links.insert("attachment", self.link(attachment));
+        }
+        links
+    }
+
+    pub fn linked_record(&self, record: MedicalRecord) -> Linked<MedicalRecord> {
+        Linked {
+            _links: self.record(&record),
+            item: record,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::CatStatus;
+    use chrono::Utc;
+    use uuid::Uuid;
+
+    #[test]
+    fn test_cat_links() {
+        let mut config = Config::from_env();
+        config.public_base_url = Some("https://catdex.example".to_string());
+        let cat = Cat {
+            id: 3,
+            public_id: Uuid::nil(),
+            name: "Whiskers".to_string(),
+            image_path: "/image/default/cat.jpg".to_string(),
+            created_at: Utc::now(),
+            private: false,
+            status: CatStatus::Available,
+            latitude: None,
+            longitude: None,
+            image_hash: None,
+            pending_review: false,
+            updated_at: Utc::now(),
+        };
+        let links = LinkBuilder::new(&config).cat(&cat);
+        assert_eq!(
+            links["self"],
+            Link {
+                href: "https://catdex.example/api/cat/3".to_string(),
+                method: None,
+            }
+        );
+        assert_eq!(
+            links["image"].href,
+            "https://catdex.example/image/default/cat.jpg"
+        );
+        assert_eq!(links["update_status"].method, Some("POST"));
+
+        config.public_base_url = None;
+        assert_eq!(
+            LinkBuilder::new(&config).cat(&cat)["self"].href,
+            "/api/cat/3"
+        );
+
+        config.public_base_url = Some("https://catdex.example".to_string());
+        let on_cdn = Cat {
+            image_path: "https://cdn.catdex.example/image/default/cat.jpg".to_string(),
+            ..cat
+        };
+        assert_eq!(
+            LinkBuilder::new(&config).cat(&on_cdn)["image"].href,
+            "https://cdn.catdex.example/image/default/cat.jpg"
+        );
+    }
+}
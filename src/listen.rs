@@ -0,0 +1,233 @@
+use crate::config::Config;
+use actix_web::http::header::LOCATION;
+use actix_web::{web, HttpRequest, HttpResponse};
+use std::fs;
+use std::io;
+use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
+
+/// One place the server accepts connections, see `parse_all`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Listen {
+    /// HTTPS on a TCP address such as `127.0.0.1:8080`.
+    Tcp(String),
+    /// Plain HTTP on a TCP address, written `http:10.0.0.5:8080`, for a
+    /// reverse proxy on another host that terminates TLS.
+    Http(String),
+    /// Plain HTTP on a Unix socket, written `unix:/run/catdex/catdex.sock`,
+    /// for a reverse proxy on the same host that terminates TLS.
+    Unix(PathBuf),
+    /// Plain HTTP on a TCP address, written `redirect:0.0.0.0:80`, that
+    /// only serves `/healthz` and ACME challenges and redirects everything
+    /// else to HTTPS.
+    Redirect(String),
+}
+
+impl Listen {
+    pub fn parse(value: &str) -> Result<Listen, String> {
+        let (kind, rest) = match value.split_once(':') {
+            Some((kind @ ("http" | "unix" | "redirect"), rest)) => (kind, rest),
+            _ => ("tcp", value),
+        };
+        if rest.is_empty() {
+            return Err(format!("LISTEN has an empty {} address", kind));
+        }
+        Ok(match kind {
+            "http" => Listen::Http(rest.to_string()),
+            "unix" => Listen::Unix(PathBuf::from(rest)),
+            "redirect" => Listen::Redirect(rest.to_string()),
+            _ => Listen::Tcp(rest.to_string()),
+        })
+    }
+
+    /// Parses a comma separated list of listeners, e.g.
+    /// `0.0.0.0:443,redirect:0.0.0.0:80`. HTTPS listeners are refused in
+    /// builds without the `tls` feature, which can only serve plain HTTP.
+    pub fn parse_all(value: &str) -> Result<Vec<Listen>, String> {
+        let listeners = value
+            .split(',')
+            .map(str::trim)
+            .filter(|listener| !listener.is_empty())
+            .map(Listen::parse)
+            .collect::<Result<Vec<_>, _>>()?;
+        if listeners.is_empty() {
+            return Err("LISTEN must name at least one listener".to_string());
+        }
+        #[cfg(not(feature = "tls"))]
+        if let Some(Listen::Tcp(addr)) = listeners
+            .iter()
+            .find(|listener| matches!(listener, Listen::Tcp(_)))
+        {
+            return Err(format!(
+                "LISTEN address {} needs HTTPS, which this build lacks the tls feature for; \
+                 write http:{} to serve plain HTTP",
+                addr, addr
+            ));
+        }
+        let redirects = listeners
+            .iter()
+            .any(|listener| matches!(listener, Listen::Redirect(_)));
+        if redirects && https_port(&listeners).is_none() {
+            return Err("LISTEN needs an HTTPS address with a port to redirect to".to_string());
+        }
+        Ok(listeners)
+    }
+}
+
+impl std::fmt::Display for Listen {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Listen::Tcp(addr) => write!(f, "{}", addr),
+            Listen::Http(addr) => write!(f, "http:{}", addr),
+            Listen::Unix(path) => write!(f, "unix:{}", path.display()),
+            Listen::Redirect(addr) => write!(f, "redirect:{}", addr),
+        }
+    }
+}
+
+/// The port of the first HTTPS listener.
+fn https_port(listeners: &[Listen]) -> Option<u16> {
+    listeners.iter().find_map(|listener| match listener {
+        Listen::Tcp(addr) => addr.rsplit_once(':')?.1.parse().ok(),
+        _ => None,
+    })
+}
+
+/// Binds a Unix socket at `path`, replacing the one a previous run left
+/// behind, and sets its permission bits to `mode` before any connection
+/// is accepted. Other files at `path` are left alone.
+pub fn bind_unix(path: &Path, mode: Option<u32>) -> io::Result<UnixListener> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_socket() => fs::remove_file(path)?,
+        Ok(_) => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} exists and is not a socket", path.display()),
+            ))
+        }
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => return Err(e),
+    }
+    let listener = UnixListener::bind(path)?;
+    if let Some(mode) = mode {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+    Ok(listener)
+}
+
+/// Where a request to `host` for `path_and_query` is redirected to: below
+/// `PUBLIC_BASE_URL` when it is set, otherwise on the same host at the
+/// HTTPS port.
+fn https_location(config: &Config, host: &str, path_and_query: &str) -> String {
+    if let Some(base_url) = &config.public_base_url {
+        return format!("{}{}", base_url, path_and_query);
+    }
+    // Strip the port, keeping the brackets of an IPv6 address
+    let host = match host.rsplit_once(':') {
+        Some((name, port)) if !name.ends_with(':') && port.parse::<u16>().is_ok() => name,
+        _ => host,
+    };
+    match https_port(&config.listen) {
+        Some(443) | None => format!("https://{}{}", host, path_and_query),
+        Some(port) => format!("https://{}:{}{}", host, port, path_and_query),
+    }
+}
+
+/// Permanently redirects a request that came in over plain HTTP to HTTPS.
+pub async fn redirect_endpoint(req: HttpRequest, config: web::Data<Config>) -> HttpResponse {
+    let path_and_query = req.uri().path_and_query().map_or("/", |pq| pq.as_str());
+    let location = https_location(&config, req.connection_info().host(), path_and_query);
+    HttpResponse::MovedPermanently()
+        .insert_header((LOCATION, location))
+        .finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_listen() {
+        assert_eq!(
+            Listen::parse("0.0.0.0:443"),
+            Ok(Listen::Tcp("0.0.0.0:443".to_string()))
+        );
+        assert_eq!(
+            Listen::parse("http:10.0.0.5:8080"),
+            Ok(Listen::Http("10.0.0.5:8080".to_string()))
+        );
+        assert_eq!(
+            Listen::parse("unix:/run/catdex.sock"),
+            Ok(Listen::Unix(PathBuf::from("/run/catdex.sock")))
+        );
+        assert_eq!(
+            Listen::parse("redirect:[::]:80"),
+            Ok(Listen::Redirect("[::]:80".to_string()))
+        );
+        assert!(Listen::parse("unix:").is_err());
+        assert!(Listen::parse("").is_err());
+    }
+
+    #[test]
+    fn test_parse_all_listeners() {
+        #[cfg(feature = "tls")]
+        assert_eq!(
+            Listen::parse_all("[::]:8443, redirect:[::]:80").unwrap(),
+            vec![
+                Listen::Tcp("[::]:8443".to_string()),
+                Listen::Redirect("[::]:80".to_string())
+            ]
+        );
+        #[cfg(not(feature = "tls"))]
+        assert!(Listen::parse_all("[::]:8443").is_err());
+        assert_eq!(
+            Listen::parse_all("http:[::]:8080").unwrap(),
+            vec![Listen::Http("[::]:8080".to_string())]
+        );
+        assert!(Listen::parse_all(" , ").is_err());
+        assert!(Listen::parse_all("unix:/run/catdex.sock,redirect:0.0.0.0:80").is_err());
+        // Redirecting to a plain HTTP port would not reach HTTPS
+        assert!(Listen::parse_all("http:0.0.0.0:8080,redirect:0.0.0.0:80").is_err());
+    }
+
+    #[test]
+    fn test_https_location() {
+        let mut config = Config::from_env();
+        config.public_base_url = None;
+        config.listen = vec![Listen::Tcp("0.0.0.0:443".to_string())];
+        assert_eq!(
+            https_location(&config, "catdex.example:80", "/cats?page=2"),
+            "https://catdex.example/cats?page=2"
+        );
+
+        config.listen = vec![Listen::Tcp("[::]:8443".to_string())];
+        assert_eq!(
+            https_location(&config, "[::1]:8080", "/"),
+            "https://[::1]:8443/"
+        );
+        assert_eq!(https_location(&config, "[::1]", "/"), "https://[::1]:8443/");
+
+        config.public_base_url = Some("https://catdex.example".to_string());
+        assert_eq!(
+            https_location(&config, "10.0.0.1", "/cats"),
+            "https://catdex.example/cats"
+        );
+    }
+
+    #[test]
+    fn test_bind_unix_replaces_stale_socket() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("catdex.sock");
+        drop(bind_unix(&path, None).unwrap());
+
+        bind_unix(&path, Some(0o660)).unwrap();
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o660);
+
+        let file = dir.path().join("catdex.txt");
+        fs::write(&file, "keep").unwrap();
+        assert!(bind_unix(&file, None).is_err());
+        assert_eq!(fs::read_to_string(&file).unwrap(), "keep");
+    }
+}
@@ -0,0 +1,46 @@
+use arc_swap::ArcSwap;
+use env_logger::Logger;
+use log::{Log, Metadata, Record};
+use std::sync::{Arc, LazyLock};
+
+/// An `env_logger` whose filter can be replaced while the server runs.
+struct ReloadableLogger {
+    inner: ArcSwap<Logger>,
+}
+
+static LOGGER: LazyLock<ReloadableLogger> = LazyLock::new(|| ReloadableLogger {
+    inner: ArcSwap::from_pointee(build("error")),
+});
+
+fn build(filter: &str) -> Logger {
+    env_logger::Builder::new().parse_filters(filter).build()
+}
+
+impl Log for ReloadableLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.load().enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.inner.load().log(record)
+    }
+
+    fn flush(&self) {
+        self.inner.load().flush()
+    }
+}
+
+/// Installs the logger with the filter of `RUST_LOG`, like
+/// `env_logger::init`.
+pub fn init() {
+    let filter = std::env::var("RUST_LOG").unwrap_or_else(|_| "error".to_string());
+    set_filter(&filter);
+    log::set_logger(&*LOGGER).expect("The logger is only installed once");
+}
+
+/// Replaces the filter, in `RUST_LOG` syntax, of the installed logger.
+pub fn set_filter(filter: &str) {
+    let logger = build(filter);
+    log::set_max_level(logger.filter());
+    LOGGER.inner.store(Arc::new(logger));
+}
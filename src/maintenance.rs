@@ -0,0 +1,179 @@
+//! Maintenance mode, for migrations and storage moves: requests that may
+//! write are answered with a 503 and a `Retry-After`, while reads keep
+//! working. Responses carry `X-Catdex-Maintenance: on` meanwhile, so
+//! clients can show a banner; list responses are bare arrays with no room
+//! for the flag.
+//!
+//! It starts on with `MAINTENANCE_MODE=true`, and operators turn it on and
+//! off through `PUT /api/admin/maintenance`, which stays writable, as does
+//! `POST /api/admin/migrate-storage`, which needs it on.
+use crate::auth;
+use crate::config::Config;
+use crate::errors::UserError;
+use crate::history::Requester;
+use crate::models::Tenant;
+use crate::validated::Validated;
+use crate::DbPool;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpResponse};
+use arc_swap::ArcSwap;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use validator::Validate;
+
+pub const MAINTENANCE_HEADER: HeaderName = HeaderName::from_static("x-catdex-maintenance");
+/// Path of the admin endpoint, which is let through to turn maintenance off.
+pub const ADMIN_PATH: &str = "/api/admin/maintenance";
+/// Paths of the endpoints let through to write during maintenance.
+const WRITABLE_PATHS: &[&str] = &[ADMIN_PATH, "/api/admin/migrate-storage"];
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Validate)]
+pub struct MaintenanceSettings {
+    pub enabled: bool,
+    /// Seconds clients are told to wait before retrying a write.
+    #[validate(range(min = 1, max = 86400))]
+    pub retry_after_secs: u64,
+}
+
+/// The current `MaintenanceSettings`, shared by every worker.
+pub struct Maintenance {
+    current: ArcSwap<MaintenanceSettings>,
+}
+
+impl Maintenance {
+    pub fn from_config(config: &Config) -> Maintenance {
+        if config.maintenance_mode {
+            warn!("Starting in maintenance mode, writes are rejected");
+        }
+        Maintenance {
+            current: ArcSwap::from_pointee(MaintenanceSettings {
+                enabled: config.maintenance_mode,
+                retry_after_secs: config.maintenance_retry_after.as_secs(),
+            }),
+        }
+    }
+
+    pub fn load(&self) -> Arc<MaintenanceSettings> {
+        self.current.load_full()
+    }
+
+    pub fn store(&self, settings: MaintenanceSettings) {
+        self.current.store(Arc::new(settings));
+    }
+}
+
+/// Middleware rejecting requests that may write while in maintenance, and
+/// flagging the responses to the others.
+pub async fn guard(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse, Error> {
+    let settings = match req.app_data::<web::Data<Maintenance>>() {
+        Some(maintenance) => maintenance.load(),
+        None => {
+            return next
+                .call(req)
+                .await
+                .map(ServiceResponse::map_into_boxed_body)
+        }
+    };
+    if !settings.enabled {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_boxed_body);
+    }
+    if !req.method().is_safe() && !WRITABLE_PATHS.contains(&req.path()) {
+        warn!(
+            "Rejected {} {} during maintenance",
+            req.method(),
+            req.path()
+        );
+        return Ok(req
+            .error_response(UserError::MaintenanceError(settings.retry_after_secs))
+            .map_into_boxed_body());
+    }
+    let mut res = next.call(req).await?.map_into_boxed_body();
+    res.headers_mut()
+        .insert(MAINTENANCE_HEADER, HeaderValue::from_static("on"));
+    Ok(res)
+}
+
+/// Whether the API is in maintenance.
+pub async fn maintenance_endpoint(
+    pool: web::Data<DbPool>,
+    maintenance: web::Data<Maintenance>,
+    tenant: Tenant,
+    requester: Requester,
+) -> Result<HttpResponse, UserError> {
+    auth::require_operator(&pool, &tenant, requester).await?;
+    Ok(HttpResponse::Ok().json(&*maintenance.load()))
+}
+
+/// Turns maintenance on or off for every tenant.
+pub async fn set_maintenance_endpoint(
+    pool: web::Data<DbPool>,
+    maintenance: web::Data<Maintenance>,
+    tenant: Tenant,
+    requester: Requester,
+    settings: Validated<web::Json<MaintenanceSettings>>,
+) -> Result<HttpResponse, UserError> {
+    auth::require_operator(&pool, &tenant, requester).await?;
+    let settings = settings.into_inner().into_inner();
+    info!(
+        "Maintenance mode {}",
+        if settings.enabled { "on" } else { "off" }
+    );
+    maintenance.store(settings.clone());
+    Ok(HttpResponse::Ok().json(settings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::RETRY_AFTER;
+    use actix_web::http::StatusCode;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::App;
+
+    #[actix_web::test]
+    async fn test_guard() {
+        let mut config = Config::from_env();
+        config.maintenance_mode = true;
+        let maintenance = web::Data::new(Maintenance::from_config(&config));
+        let app = init_service(
+            App::new()
+                .app_data(maintenance.clone())
+                .wrap(from_fn(guard))
+                .default_service(web::to(HttpResponse::Ok)),
+        )
+        .await;
+
+        let resp = call_service(&app, TestRequest::get().uri("/api/cats").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert_eq!(resp.headers().get(MAINTENANCE_HEADER).unwrap(), "on");
+
+        let req = TestRequest::post().uri("/api/cats").to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let retry_after = config.maintenance_retry_after.as_secs().to_string();
+        assert_eq!(resp.headers().get(RETRY_AFTER).unwrap(), &retry_after);
+
+        let req = TestRequest::put().uri(ADMIN_PATH).to_request();
+        assert_eq!(call_service(&app, req).await.status(), StatusCode::OK);
+
+        maintenance.store(MaintenanceSettings {
+            enabled: false,
+            retry_after_secs: 60,
+        });
+        let req = TestRequest::post().uri("/api/cats").to_request();
+        let resp = call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        assert!(!resp.headers().contains_key(MAINTENANCE_HEADER));
+    }
+}
@@ -0,0 +1,418 @@
+use crate::cats::{discard_upload, persist_upload, screen_upload, upload_dir, upload_size};
+use crate::clock::Clock;
+use crate::config::Config;
+use crate::db::{self, DbConn};
+use crate::errors::UserError;
+use crate::file_store::FileStore;
+use crate::links::LinkBuilder;
+use crate::models::{MedicalRecord, MedicalRecordForm, MedicalRecordType, Tenant};
+use crate::quotas;
+use crate::repository;
+use crate::scanner::Scanner;
+use crate::signed_urls::UrlSigner;
+use crate::DbPool;
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Days, NaiveDate, Utc};
+use diesel::{Connection, PgConnection, QueryResult};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use validator::Validate;
+
+/// How far ahead a vaccination counts as due.
+const VACCINATION_DUE_WINDOW_DAYS: u64 = 30;
+
+#[derive(Serialize, Debug, PartialEq)]
+pub struct VaccinationDue {
+    record_id: i32,
+    due_date: NaiveDate,
+    notes: String,
+}
+
+/// The part of a cat's medical history shown with the cat itself.
+#[derive(Serialize, Debug, Default, PartialEq)]
+pub struct MedicalSummary {
+    last_checkup: Option<NaiveDate>,
+    /// Vaccinations due within the window or overdue, soonest first.
+    vaccinations_due: Vec<VaccinationDue>,
+}
+
+/// Summarizes a cat's records as of `today`. A vaccination stops being due
+/// once any later vaccination is recorded, which is taken to renew it.
+pub fn summarize(records: &[MedicalRecord], today: NaiveDate) -> MedicalSummary {
+    let last_checkup = records
+        .iter()
+        .filter(|record| record.record_type == MedicalRecordType::Checkup)
+        .map(|record| record.date)
+        .max();
+    let horizon = today + Days::new(VACCINATION_DUE_WINDOW_DAYS);
+    let vaccinations: Vec<&MedicalRecord> = records
+        .iter()
+        .filter(|record| record.record_type == MedicalRecordType::Vaccination)
+        .collect();
+    let mut vaccinations_due: Vec<VaccinationDue> = vaccinations
+        .iter()
+        .filter(|record| !vaccinations.iter().any(|later| later.date > record.date))
+        .filter_map(|record| {
+            let due_date = record.due_date.filter(|due_date| *due_date <= horizon)?;
+            Some(VaccinationDue {
+                record_id: record.id,
+                due_date,
+                notes: record.notes.clone(),
+            })
+        })
+        .collect();
+    vaccinations_due.sort_by_key(|due| due.due_date);
+    MedicalSummary {
+        last_checkup,
+        vaccinations_due,
+    }
+}
+
+/// Replaces the attachment path with a signed link, as attachments are
+/// stored privately.
+pub fn present(signer: &UrlSigner, mut record: MedicalRecord, now: DateTime<Utc>) -> MedicalRecord {
+    if let Some(path) = &record.attachment_path {
+        if let Some(image_key) = path.strip_prefix("/image/") {
+            record.attachment_path = Some(signer.sign(image_key, now));
+        }
+    }
+    record
+}
+
+/// Reads a medical record form: a `record_type` and `date`, with optional
+/// `due_date` and `notes`. Dates are `YYYY-MM-DD`.
+fn parse_form(fields: &HashMap<&str, &str>) -> Result<MedicalRecordForm, UserError> {
+    let invalid = |field: &str| {
+        warn!("Invalid or missing {} field", field);
+        UserError::ValidationError
+    };
+    let field = |name: &str| {
+        fields
+            .get(name)
+            .map(|value| value.trim())
+            .filter(|value| !value.is_empty())
+    };
+    let parse_date = |name: &str| {
+        field(name)
+            .map(|value| value.parse::<NaiveDate>().map_err(|_| invalid(name)))
+            .transpose()
+    };
+    Ok(MedicalRecordForm {
+        record_type: field("record_type")
+            .and_then(MedicalRecordType::from_name)
+            .ok_or_else(|| invalid("record_type"))?,
+        date: parse_date("date")?.ok_or_else(|| invalid("date"))?,
+        due_date: parse_date("due_date")?,
+        notes: field("notes").unwrap_or_default().to_string(),
+    })
+}
+
+/// A stored attachment: its path and size in bytes.
+type Attachment = (String, i64);
+
+/// Validates the form and stores its `attachment` file, if any, under the
+/// tenant's private directory. Returns the form and the attachment.
+async fn read_form(
+    config: &Config,
+    store: &web::Data<dyn FileStore>,
+    scanner: Option<web::Data<dyn Scanner>>,
+    tenant: &Tenant,
+    mut parts: awmp::Parts,
+) -> Result<(MedicalRecordForm, Option<Attachment>), UserError> {
+    let fields: HashMap<_, _> = parts.texts.as_pairs().into_iter().collect();
+    let form = parse_form(&fields)?;
+    form.validate().map_err(|errors| {
+        warn!("Medical record validation failed");
+        UserError::FieldValidationError(errors)
+    })?;
+
+    let Some(file) = parts.files.take("attachment").pop() else {
+        return Ok((form, None));
+    };
+    let file = screen_upload(scanner, config, file).await?;
+    let size = upload_size(&file)?;
+    let dir = format!("{}/records", upload_dir(&tenant.slug, true));
+    let key = persist_upload(store.get_ref(), file, &dir, None).ok_or_else(|| {
+        error!("Failed to store medical record attachment");
+        UserError::UnexpectedError
+    })?;
+    Ok((form, Some((format!("/image/{}", key), size))))
+}
+
+/// Fails when `size` more bytes would take the tenant over its storage
+/// quota. Locks the quota until the transaction ends.
+fn check_quota(
+    connection: &mut PgConnection,
+    tenant_id: i32,
+    size: i64,
+) -> QueryResult<Result<(), UserError>> {
+    let Some(quota) = repository::lock_quota(connection, tenant_id)? else {
+        return Ok(Ok(()));
+    };
+    let status = quotas::status(connection, tenant_id, quota)?;
+    Ok(match status.allows_bytes(size) {
+        true => Ok(()),
+        false => Err(UserError::QuotaExceededError(status)),
+    })
+}
+
+/// Removes a stored attachment given its path.
+fn discard_attachment(store: &dyn FileStore, path: &str) {
+    if let Some(key) = path.strip_prefix("/image/") {
+        discard_upload(store, key);
+    }
+}
+
+#[derive(Deserialize)]
+pub struct CatPath {
+    id: i32,
+}
+
+#[derive(Deserialize)]
+pub struct RecordPath {
+    id: i32,
+    record_id: i32,
+}
+
+pub async fn records_endpoint(
+    connection: DbConn,
+    config: web::Data<Config>,
+    clock: web::Data<dyn Clock>,
+    signer: web::Data<UrlSigner>,
+    tenant: Tenant,
+    path: web::Path<CatPath>,
+) -> Result<HttpResponse, UserError> {
+    let cat_id = path.id;
+    let records = connection
+        .run("list medical records", move |connection| {
+            repository::find_cat(connection, tenant.id, cat_id)?;
+            repository::list_medical_records(connection, tenant.id, cat_id)
+        })
+        .await?;
+
+    let now = clock.now();
+    let links = LinkBuilder::new(&config);
+    let records: Vec<_> = records
+        .into_iter()
+        .map(|record| links.linked_record(present(&signer, record, now)))
+        .collect();
+    Ok(HttpResponse::Ok().json(records))
+}
+
+pub async fn record_endpoint(
+    connection: DbConn,
+    config: web::Data<Config>,
+    clock: web::Data<dyn Clock>,
+    signer: web::Data<UrlSigner>,
+    tenant: Tenant,
+    path: web::Path<RecordPath>,
+) -> Result<HttpResponse, UserError> {
+    let (cat_id, record_id) = (path.id, path.record_id);
+    let record = connection
+        .run("find medical record", move |connection| {
+            repository::find_medical_record(connection, tenant.id, cat_id, record_id)
+        })
+        .await?;
+    let record = present(&signer, record, clock.now());
+    Ok(HttpResponse::Ok().json(LinkBuilder::new(&config).linked_record(record)))
+}
+
+/// Adds a medical record to the cat from a multipart form, with an optional
+/// `attachment` file.
+#[allow(clippy::too_many_arguments)]
+pub async fn add_record_endpoint(
+    pool: web::Data<DbPool>,
+    config: web::Data<Config>,
+    clock: web::Data<dyn Clock>,
+    store: web::Data<dyn FileStore>,
+    scanner: Option<web::Data<dyn Scanner>>,
+    signer: web::Data<UrlSigner>,
+    tenant: Tenant,
+    path: web::Path<CatPath>,
+    parts: awmp::Parts,
+) -> Result<HttpResponse, UserError> {
+    let (form, attachment) = read_form(&config, &store, scanner, &tenant, parts).await?;
+    let mut connection = DbConn::get(&pool)?;
+    let cat_id = path.id;
+
+    let stored = attachment.clone();
+    let record = db::block(move || {
+        connection.transaction(|connection| {
+            repository::find_cat(connection, tenant.id, cat_id)?;
+            if let Some((_, size)) = &stored {
+                if let Err(e) = check_quota(connection, tenant.id, *size)? {
+                    return Ok(Err(e));
+                }
+            }
+            let stored = stored.as_ref().map(|(path, size)| (path.as_str(), *size));
+            repository::insert_medical_record(connection, tenant.id, cat_id, &form, stored).map(Ok)
+        })
+    })
+    .await?
+    .map_err(|e| db::query_error(e, "add medical record"))
+    .and_then(|record| record)
+    .inspect_err(|_| {
+        if let Some((path, _)) = &attachment {
+            discard_attachment(store.get_ref(), path);
+        }
+    })?;
+    Ok(HttpResponse::Created().json(present(&signer, record, clock.now())))
+}
+
+/// Replaces a medical record's fields from a multipart form. Its attachment
+/// is only replaced when the form carries a new one.
+#[allow(clippy::too_many_arguments)]
+pub async fn update_record_endpoint(
+    pool: web::Data<DbPool>,
+    config: web::Data<Config>,
+    clock: web::Data<dyn Clock>,
+    store: web::Data<dyn FileStore>,
+    scanner: Option<web::Data<dyn Scanner>>,
+    signer: web::Data<UrlSigner>,
+    tenant: Tenant,
+    path: web::Path<RecordPath>,
+    parts: awmp::Parts,
+) -> Result<HttpResponse, UserError> {
+    let (form, attachment) = read_form(&config, &store, scanner, &tenant, parts).await?;
+    let mut connection = DbConn::get(&pool)?;
+    let (cat_id, record_id) = (path.id, path.record_id);
+
+    let stored = attachment.clone();
+    let result = db::block(move || {
+        connection.transaction(|connection| {
+            let previous =
+                repository::find_medical_record(connection, tenant.id, cat_id, record_id)?;
+            if let Some((_, size)) = &stored {
+                // The attachment replaced frees its bytes
+                let added = size - previous.attachment_size;
+                if let Err(e) = check_quota(connection, tenant.id, added)? {
+                    return Ok(Err(e));
+                }
+            }
+            let stored = stored.as_ref().map(|(path, size)| (path.as_str(), *size));
+            let record = repository::update_medical_record(
+                connection, tenant.id, cat_id, record_id, &form, stored,
+            )?;
+            Ok(Ok((previous, record)))
+        })
+    })
+    .await?
+    .map_err(|e| db::query_error(e, "update medical record"))
+    .and_then(|updated| updated);
+
+    let (previous, record) = match result {
+        Ok(updated) => updated,
+        Err(e) => {
+            if let Some((path, _)) = &attachment {
+                discard_attachment(store.get_ref(), path);
+            }
+            return Err(e);
+        }
+    };
+    if attachment.is_some() {
+        if let Some(path) = &previous.attachment_path {
+            discard_attachment(store.get_ref(), path);
+        }
+    }
+    Ok(HttpResponse::Ok().json(present(&signer, record, clock.now())))
+}
+
+pub async fn delete_record_endpoint(
+    connection: DbConn,
+    store: web::Data<dyn FileStore>,
+    tenant: Tenant,
+    path: web::Path<RecordPath>,
+) -> Result<HttpResponse, UserError> {
+    let (cat_id, record_id) = (path.id, path.record_id);
+    let deleted = connection
+        .run("delete medical record", move |connection| {
+            repository::delete_medical_record(connection, tenant.id, cat_id, record_id)
+        })
+        .await?
+        .ok_or(UserError::NotFoundError)?;
+    if let Some(path) = &deleted.attachment_path {
+        discard_attachment(store.get_ref(), path);
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn date(s: &str) -> NaiveDate {
+        s.parse().unwrap()
+    }
+
+    fn record(
+        id: i32,
+        record_type: MedicalRecordType,
+        on: &str,
+        due: Option<&str>,
+    ) -> MedicalRecord {
+        MedicalRecord {
+            id,
+            cat_id: 1,
+            record_type,
+            date: date(on),
+            due_date: due.map(date),
+            notes: String::new(),
+            attachment_path: None,
+            attachment_size: 0,
+        }
+    }
+
+    #[test]
+    fn test_summarize() {
+        use MedicalRecordType::*;
+        let today = date("2026-10-15");
+        assert_eq!(summarize(&[], today), MedicalSummary::default());
+
+        let records = [
+            record(1, Checkup, "2026-03-01", None),
+            record(2, Checkup, "2026-09-01", None),
+            record(3, Vaccination, "2025-11-01", Some("2026-11-01")),
+            record(4, Treatment, "2026-10-01", Some("2026-10-20")),
+        ];
+        let summary = summarize(&records, today);
+        assert_eq!(summary.last_checkup, Some(date("2026-09-01")));
+        assert_eq!(
+            summary.vaccinations_due,
+            [VaccinationDue {
+                record_id: 3,
+                due_date: date("2026-11-01"),
+                notes: String::new(),
+            }]
+        );
+
+        // Renewed by a later vaccination, and the renewal is not due yet
+        let records = [
+            record(3, Vaccination, "2025-11-01", Some("2026-11-01")),
+            record(5, Vaccination, "2026-10-10", Some("2027-10-10")),
+        ];
+        assert!(summarize(&records, today).vaccinations_due.is_empty());
+    }
+
+    #[test]
+    fn test_parse_form() {
+        let fields = HashMap::from([
+            ("record_type", "vaccination"),
+            ("date", "2026-10-15"),
+            ("due_date", ""),
+            ("notes", " Rabies "),
+        ]);
+        let form = parse_form(&fields).unwrap();
+        assert_eq!(form.record_type, MedicalRecordType::Vaccination);
+        assert_eq!(form.date, date("2026-10-15"));
+        assert_eq!(form.due_date, None);
+        assert_eq!(form.notes, "Rabies");
+
+        let fields = HashMap::from([("record_type", "surgery"), ("date", "2026-10-15")]);
+        assert!(parse_form(&fields).is_err());
+        let fields = HashMap::from([("record_type", "checkup"), ("date", "15.10.2026")]);
+        assert!(parse_form(&fields).is_err());
+        let fields = HashMap::from([("record_type", "checkup")]);
+        assert!(parse_form(&fields).is_err());
+    }
+}
@@ -0,0 +1,49 @@
+use crate::errors::UserError;
+use actix_web::HttpResponse;
+use log::error;
+use prometheus::{
+    register_histogram_vec, register_int_counter_vec, Encoder, HistogramVec, IntCounterVec,
+    TextEncoder,
+};
+use std::sync::LazyLock;
+
+pub static QUERY_DURATION: LazyLock<HistogramVec> = LazyLock::new(|| {
+    register_histogram_vec!(
+        "catdex_db_query_duration_seconds",
+        "Duration of database queries issued by the repository layer",
+        &["query"]
+    )
+    .expect("Failed to register query duration histogram")
+});
+
+pub static CAT_DETECTIONS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "catdex_cat_detections_total",
+        "New cat images checked by the cat detector, by outcome",
+        &["outcome"]
+    )
+    .expect("Failed to register cat detection counter")
+});
+
+pub static CANCELLED_DB_TASKS: LazyLock<IntCounterVec> = LazyLock::new(|| {
+    register_int_counter_vec!(
+        "catdex_cancelled_db_tasks_total",
+        "Blocking tasks and queries abandoned as their request was gone, by reason",
+        &["reason"]
+    )
+    .expect("Failed to register cancelled task counter")
+});
+
+pub async fn metrics_endpoint() -> Result<HttpResponse, UserError> {
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    encoder
+        .encode(&prometheus::gather(), &mut buffer)
+        .map_err(|_| {
+            error!("Failed to encode metrics");
+            UserError::UnexpectedError
+        })?;
+    Ok(HttpResponse::Ok()
+        .content_type(encoder.format_type())
+        .body(buffer))
+}
@@ -1,18 +1,503 @@
-use crate::schema::cats;
-use diesel::{Insertable, Queryable};
-use serde::Serialize;
+use crate::schema::sql_types::{
+    CatRelationKind as SqlCatRelationKind, CatStatus as SqlCatStatus,
+    MedicalRecordType as SqlMedicalRecordType,
+};
+use crate::schema::{
+    admins, cat_history, cat_images, cat_relations, cat_stats, cat_tombstones, cats,
+    medical_records, outbox, quotas, tenants, uploads, webhook_deliveries, webhooks,
+};
+use chrono::{DateTime, NaiveDate, Utc};
+use diesel::deserialize::{self, FromSql, FromSqlRow};
+use diesel::expression::AsExpression;
+use diesel::pg::{Pg, PgValue};
+use diesel::serialize::{self, IsNull, Output, ToSql};
+use diesel::{AsChangeset, Insertable, Queryable, Selectable};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::io::Write;
+use uuid::Uuid;
+use validator::{Validate, ValidationError};
 
-#[derive(Queryable, Serialize)]
+pub const CAT_NAME_MAX_LENGTH: u64 = 100;
+
+pub const MEDICAL_NOTES_MAX_LENGTH: u64 = 2000;
+
+#[derive(Queryable, Selectable, Serialize, Deserialize, Debug)]
+#[diesel(table_name = cats)]
 pub struct Cat {
     pub id: i32,
+    pub public_id: Uuid,
     pub name: String,
     pub image_path: String,
+    pub created_at: DateTime<Utc>,
+    pub private: bool,
+    pub status: CatStatus,
+    pub latitude: Option<f64>,
+    pub longitude: Option<f64>,
+    /// Version of the image contents, which goes into its URL rather than
+    /// the response body.
+    #[serde(skip_serializing, default)]
+    pub image_hash: Option<String>,
+    /// Awaiting moderation, and left out of listings until approved.
+    pub pending_review: bool,
+    /// When any of the cat's fields last changed, kept by the database.
+    pub updated_at: DateTime<Utc>,
+}
+
+/// A photo in a cat's gallery. The primary photo is the cat's own
+/// `image_path`.
+#[derive(Queryable, Selectable, Serialize, Deserialize, Debug)]
+#[diesel(table_name = cat_images)]
+pub struct CatImage {
+    pub id: i32,
+    pub image_path: String,
+    /// Version of the image contents, as for `Cat`.
+    #[serde(skip_serializing, default)]
+    pub image_hash: Option<String>,
+    pub position: i32,
+    pub is_primary: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = cat_images)]
+pub struct NewCatImage {
+    pub tenant_id: i32,
+    pub cat_id: i32,
+    pub image_path: String,
+    pub image_hash: Option<String>,
+    pub image_size: i64,
+    pub image_phash: Option<i64>,
+    pub position: i32,
+    pub is_primary: bool,
+    pub created_at: DateTime<Utc>,
+    pub image_original_format: Option<String>,
+}
+
+/// Where a cat is in the adoption workflow.
+#[derive(AsExpression, FromSqlRow, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[diesel(sql_type = SqlCatStatus)]
+#[serde(rename_all = "lowercase")]
+pub enum CatStatus {
+    Available,
+    Pending,
+    Adopted,
+}
+
+impl CatStatus {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CatStatus::Available => "available",
+            CatStatus::Pending => "pending",
+            CatStatus::Adopted => "adopted",
+        }
+    }
 }
 
-#[derive(Insertable, Serialize)]
+impl ToSql<SqlCatStatus, Pg> for CatStatus {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<SqlCatStatus, Pg> for CatStatus {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        match bytes.as_bytes() {
+            b"available" => Ok(CatStatus::Available),
+            b"pending" => Ok(CatStatus::Pending),
+            b"adopted" => Ok(CatStatus::Adopted),
+            other => Err(format!("Unknown cat status {}", String::from_utf8_lossy(other)).into()),
+        }
+    }
+}
+
+#[derive(Insertable, Serialize, Deserialize, Validate)]
 #[diesel(table_name = cats)]
 pub struct NewCat {
     // id will be added by the database
+    #[validate(
+        length(
+            min = 1,
+            max = "CAT_NAME_MAX_LENGTH",
+            message = "must be between 1 and 100 characters"
+        ),
+        custom = "validate_cat_name"
+    )]
     pub name: String,
     pub image_path: String,
+    pub created_at: DateTime<Utc>,
+    pub image_size: i64,
+    /// Private images are only served through signed links.
+    pub private: bool,
+    /// Set together with `longitude`, or not at all.
+    #[validate(range(min = -90.0, max = 90.0))]
+    pub latitude: Option<f64>,
+    #[validate(range(min = -180.0, max = 180.0))]
+    pub longitude: Option<f64>,
+    pub image_hash: Option<String>,
+    /// Perceptual hash of the image, see `similar::phash`.
+    pub image_phash: Option<i64>,
+    pub pending_review: bool,
+    /// The format the image was uploaded in, when it was converted before
+    /// it was stored, see `heic`.
+    #[serde(default)]
+    pub image_original_format: Option<String>,
+}
+
+/// How a related cat relates to a cat.
+#[derive(AsExpression, FromSqlRow, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[diesel(sql_type = SqlCatRelationKind)]
+#[serde(rename_all = "lowercase")]
+pub enum CatRelationKind {
+    Mother,
+    Father,
+    Littermate,
+}
+
+impl CatRelationKind {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            CatRelationKind::Mother => "mother",
+            CatRelationKind::Father => "father",
+            CatRelationKind::Littermate => "littermate",
+        }
+    }
+
+    pub fn is_parent(self) -> bool {
+        self != CatRelationKind::Littermate
+    }
+}
+
+impl ToSql<SqlCatRelationKind, Pg> for CatRelationKind {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<SqlCatRelationKind, Pg> for CatRelationKind {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        match bytes.as_bytes() {
+            b"mother" => Ok(CatRelationKind::Mother),
+            b"father" => Ok(CatRelationKind::Father),
+            b"littermate" => Ok(CatRelationKind::Littermate),
+            other => {
+                Err(format!("Unknown relation kind {}", String::from_utf8_lossy(other)).into())
+            }
+        }
+    }
+}
+
+#[derive(Queryable, Selectable, Serialize, Deserialize, Clone, Debug)]
+#[diesel(table_name = cat_relations)]
+pub struct CatRelation {
+    pub id: i32,
+    pub cat_id: i32,
+    pub related_cat_id: i32,
+    pub kind: CatRelationKind,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = cat_relations)]
+pub struct NewCatRelation {
+    pub tenant_id: i32,
+    pub cat_id: i32,
+    pub related_cat_id: i32,
+    pub kind: CatRelationKind,
+}
+
+/// A change to one field of a cat. A `None` old value marks the cat's
+/// creation.
+#[derive(Queryable, Selectable, Serialize, Deserialize, Debug)]
+#[diesel(table_name = cat_history)]
+pub struct CatChange {
+    pub id: i64,
+    pub field: String,
+    pub old_value: Option<Value>,
+    pub new_value: Value,
+    pub actor: String,
+    pub changed_at: DateTime<Utc>,
+}
+
+/// What is left of a deleted cat, so clients syncing the cats can drop it.
+#[derive(Queryable, Selectable, Serialize, Deserialize, Debug)]
+#[diesel(table_name = cat_tombstones)]
+pub struct CatTombstone {
+    #[diesel(column_name = cat_id)]
+    pub id: i32,
+    pub public_id: Uuid,
+    pub deleted_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = cat_history)]
+pub struct NewCatChange {
+    pub tenant_id: i32,
+    pub cat_id: i32,
+    pub field: String,
+    pub old_value: Option<Value>,
+    pub new_value: Value,
+    pub actor: String,
+    pub changed_at: DateTime<Utc>,
+}
+
+#[derive(AsExpression, FromSqlRow, Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[diesel(sql_type = SqlMedicalRecordType)]
+#[serde(rename_all = "lowercase")]
+pub enum MedicalRecordType {
+    Vaccination,
+    Checkup,
+    Treatment,
+    Other,
+}
+
+impl MedicalRecordType {
+    pub const ALL: &'static [MedicalRecordType] = &[
+        MedicalRecordType::Vaccination,
+        MedicalRecordType::Checkup,
+        MedicalRecordType::Treatment,
+        MedicalRecordType::Other,
+    ];
+
+    pub fn as_str(self) -> &'static str {
+        match self {
+            MedicalRecordType::Vaccination => "vaccination",
+            MedicalRecordType::Checkup => "checkup",
+            MedicalRecordType::Treatment => "treatment",
+            MedicalRecordType::Other => "other",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<MedicalRecordType> {
+        MedicalRecordType::ALL
+            .iter()
+            .copied()
+            .find(|record_type| record_type.as_str() == name)
+    }
+}
+
+impl ToSql<SqlMedicalRecordType, Pg> for MedicalRecordType {
+    fn to_sql<'b>(&'b self, out: &mut Output<'b, '_, Pg>) -> serialize::Result {
+        out.write_all(self.as_str().as_bytes())?;
+        Ok(IsNull::No)
+    }
+}
+
+impl FromSql<SqlMedicalRecordType, Pg> for MedicalRecordType {
+    fn from_sql(bytes: PgValue<'_>) -> deserialize::Result<Self> {
+        std::str::from_utf8(bytes.as_bytes())
+            .ok()
+            .and_then(MedicalRecordType::from_name)
+            .ok_or_else(|| {
+                format!(
+                    "Unknown medical record type {}",
+                    String::from_utf8_lossy(bytes.as_bytes())
+                )
+                .into()
+            })
+    }
+}
+
+#[derive(Queryable, Selectable, Serialize, Deserialize, Clone, Debug)]
+#[diesel(table_name = medical_records)]
+pub struct MedicalRecord {
+    pub id: i32,
+    pub cat_id: i32,
+    pub record_type: MedicalRecordType,
+    pub date: NaiveDate,
+    pub due_date: Option<NaiveDate>,
+    pub notes: String,
+    /// File store key of the attachment, or a signed link once presented.
+    pub attachment_path: Option<String>,
+    /// Bytes of the attachment, counted towards the tenant's quota.
+    #[serde(skip_serializing, default)]
+    pub attachment_size: i64,
+}
+
+/// The editable fields of a medical record.
+#[derive(Insertable, AsChangeset, Validate)]
+#[diesel(table_name = medical_records)]
+pub struct MedicalRecordForm {
+    pub record_type: MedicalRecordType,
+    pub date: NaiveDate,
+    /// Clears the due date when `None`, as the form replaces the record.
+    #[diesel(treat_none_as_null = true)]
+    pub due_date: Option<NaiveDate>,
+    #[validate(length(max = "MEDICAL_NOTES_MAX_LENGTH"))]
+    pub notes: String,
+}
+
+/// An image uploaded ahead of creating its cat, possibly in several parts.
+#[derive(Queryable, Selectable, Clone, Debug)]
+#[diesel(table_name = uploads)]
+pub struct Upload {
+    pub token: Uuid,
+    pub private: bool,
+    pub extension: String,
+    pub size: i64,
+    pub received: i64,
+    pub image_key: Option<String>,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = uploads)]
+pub struct NewUpload {
+    pub token: Uuid,
+    pub tenant_id: i32,
+    pub private: bool,
+    pub extension: String,
+    pub size: i64,
+    pub created_at: DateTime<Utc>,
+    pub expires_at: DateTime<Utc>,
+}
+
+#[derive(Queryable, Selectable, Clone, Debug)]
+#[diesel(table_name = tenants)]
+pub struct Tenant {
+    pub id: i32,
+    pub slug: String,
+}
+
+/// Upload limits of a tenant, `None` meaning unlimited.
+#[derive(Queryable, Selectable, Clone, Copy, Debug, Default)]
+#[diesel(table_name = quotas)]
+pub struct Quota {
+    pub max_cats: Option<i32>,
+    pub max_storage_bytes: Option<i64>,
+}
+
+/// Cats added to a tenant on a day with a status, from the `cat_stats`
+/// materialized view.
+#[derive(Queryable, Selectable, Clone, Copy, Debug, PartialEq)]
+#[diesel(table_name = cat_stats)]
+pub struct CatStats {
+    pub day: NaiveDate,
+    pub status: CatStatus,
+    pub cats: i64,
+    pub image_bytes: i64,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = admins)]
+pub struct NewAdmin {
+    pub tenant_id: i32,
+    pub username: String,
+    pub password_hash: String,
+}
+
+#[derive(Queryable, Selectable, Serialize, Deserialize, Clone, Debug)]
+#[diesel(table_name = webhooks)]
+pub struct Webhook {
+    pub id: i32,
+    pub url: String,
+    // Only handed out once, when the webhook is registered
+    #[serde(skip_serializing, default)]
+    pub secret: String,
+    pub events: Vec<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = webhooks)]
+pub struct NewWebhook {
+    pub tenant_id: i32,
+    pub url: String,
+    pub secret: String,
+    pub events: Vec<String>,
+}
+
+#[derive(Queryable, Selectable, Serialize, Deserialize, Debug)]
+#[diesel(table_name = webhook_deliveries)]
+pub struct WebhookDelivery {
+    pub id: i32,
+    pub webhook_id: i32,
+    pub event: String,
+    pub payload: Value,
+    pub attempt: i32,
+    pub status_code: Option<i32>,
+    pub error: Option<String>,
+    pub delivered: bool,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = webhook_deliveries)]
+pub struct NewWebhookDelivery {
+    pub webhook_id: i32,
+    pub event: String,
+    pub payload: Value,
+    pub attempt: i32,
+    pub status_code: Option<i32>,
+    pub error: Option<String>,
+    pub delivered: bool,
+}
+
+#[derive(Queryable, Selectable, Debug)]
+#[diesel(table_name = outbox)]
+pub struct OutboxEvent {
+    pub id: i64,
+    pub tenant_id: i32,
+    pub event: String,
+    pub payload: Value,
+}
+
+#[derive(Insertable)]
+#[diesel(table_name = outbox)]
+pub struct NewOutboxEvent {
+    pub tenant_id: i32,
+    pub event: String,
+    pub payload: Value,
+}
+
+fn validate_cat_name(name: &str) -> Result<(), ValidationError> {
+    if name.chars().any(char::is_control) {
+        let mut error = ValidationError::new("charset");
+        error.message = Some("must not contain control characters".into());
+        return Err(error);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn new_cat(name: &str) -> NewCat {
+        NewCat {
+            name: name.to_string(),
+            image_path: "/image/cat.jpg".to_string(),
+            created_at: Utc::now(),
+            image_size: 0,
+            private: false,
+            latitude: None,
+            longitude: None,
+            image_hash: None,
+            image_phash: None,
+            pending_review: false,
+            image_original_format: None,
+        }
+    }
+
+    #[test]
+    fn test_new_cat_name_validation() {
+        assert!(new_cat("Whiskers").validate().is_ok());
+        assert!(new_cat("").validate().is_err());
+        assert!(new_cat(&"a".repeat(101)).validate().is_err());
+        assert!(new_cat("Whis\u{7}kers").validate().is_err());
+    }
+
+    #[test]
+    fn test_new_cat_location_validation() {
+        let mut cat = new_cat("Whiskers");
+        cat.latitude = Some(50.45);
+        cat.longitude = Some(30.52);
+        assert!(cat.validate().is_ok());
+        cat.latitude = Some(-90.5);
+        assert!(cat.validate().is_err());
+        cat.latitude = Some(0.0);
+        cat.longitude = Some(180.5);
+        assert!(cat.validate().is_err());
+    }
 }
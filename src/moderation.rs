@@ -0,0 +1,133 @@
+//! The moderation queue. With `MODERATE_SUBMISSIONS` set, new cats
+//! submitted without admin credentials wait in it, left out of listings,
+//! until an admin approves or rejects them. Cats whose image the cat
+//! detector flags wait in it either way.
+//!
+//! Approving a cat publishes its `cat.created` event. Rejecting one deletes
+//! it along with its stored image.
+use crate::auth;
+use crate::cats::discard_upload;
+use crate::clock::Clock;
+use crate::config::Config;
+use crate::db::DbConn;
+use crate::errors::UserError;
+use crate::file_store::FileStore;
+use crate::history::{self, Requester};
+use crate::links::LinkBuilder;
+use crate::models::Tenant;
+use crate::outbox;
+use crate::repository;
+use crate::signed_urls::UrlSigner;
+use crate::webhooks::CatEvent;
+use crate::DbPool;
+use actix_web::{web, HttpResponse};
+use diesel::Connection;
+use log::{info, warn};
+use serde::Deserialize;
+
+/// Most cats listed from the queue at once.
+const QUEUE_LIMIT: i64 = 100;
+
+/// The tenant's cats awaiting moderation, oldest first.
+pub async fn moderation_endpoint(
+    pool: web::Data<DbPool>,
+    config: web::Data<Config>,
+    clock: web::Data<dyn Clock>,
+    signer: web::Data<UrlSigner>,
+    tenant: Tenant,
+    requester: Requester,
+) -> Result<HttpResponse, UserError> {
+    auth::require_admin(&pool, tenant.id, requester).await?;
+    let connection = DbConn::get(&pool)?;
+    let cats = connection
+        .run("list cats awaiting moderation", move |connection| {
+            repository::list_pending_cats(connection, tenant.id, QUEUE_LIMIT)
+        })
+        .await?;
+
+    let now = clock.now();
+    let links = LinkBuilder::new(&config);
+    let cats: Vec<_> = cats
+        .into_iter()
+        .map(|cat| links.linked_cat(signer.present(cat, now)))
+        .collect();
+    Ok(HttpResponse::Ok().json(cats))
+}
+
+#[derive(Deserialize)]
+pub struct ModerationPath {
+    id: i32,
+}
+
+/// Lets a cat awaiting moderation into listings.
+pub async fn approve_endpoint(
+    connection: DbConn,
+    clock: web::Data<dyn Clock>,
+    signer: web::Data<UrlSigner>,
+    tenant: Tenant,
+    requester: Requester,
+    path: web::Path<ModerationPath>,
+) -> Result<HttpResponse, UserError> {
+    let cat_id = path.id;
+    let now = clock.now();
+
+    let cat = connection
+        .run(&format!("moderate cat ID {}", cat_id), move |connection| {
+            connection.transaction(|connection| {
+                if !auth::is_admin(connection, tenant.id, &requester)? {
+                    return Ok(Err(UserError::AdminRequiredError));
+                }
+                let before = repository::lock_cat(connection, tenant.id, cat_id)?;
+                if !before.pending_review {
+                    return Ok(Err(UserError::NotFoundError));
+                }
+                let cat = repository::approve_cat(connection, before.id)?;
+                let actor = requester.actor(connection, tenant.id)?;
+                history::record(connection, tenant.id, &actor, Some(&before), &cat, now)?;
+                outbox::record(connection, tenant.id, CatEvent::Created, &cat, now)?;
+                Ok(Ok(cat))
+            })
+        })
+        .await?
+        .inspect_err(|e| warn!("Refused to approve cat ID {}: {}", cat_id, e))?;
+    info!("Cat ID: {} approved", cat.id);
+    Ok(HttpResponse::Ok().json(signer.present(cat, now)))
+}
+
+/// Deletes a cat awaiting moderation, and its image unless another cat
+/// shows it too.
+pub async fn reject_endpoint(
+    connection: DbConn,
+    store: web::Data<dyn FileStore>,
+    tenant: Tenant,
+    requester: Requester,
+    path: web::Path<ModerationPath>,
+) -> Result<HttpResponse, UserError> {
+    let cat_id = path.id;
+
+    let (cat, image_shared) = connection
+        .run(&format!("moderate cat ID {}", cat_id), move |connection| {
+            connection.transaction(|connection| {
+                if !auth::is_admin(connection, tenant.id, &requester)? {
+                    return Ok(Err(UserError::AdminRequiredError));
+                }
+                let cat = repository::lock_cat(connection, tenant.id, cat_id)?;
+                if !cat.pending_review {
+                    return Ok(Err(UserError::NotFoundError));
+                }
+                repository::delete_cat(connection, cat.id)?;
+                let shown = repository::count_image_path_references(connection, &cat.image_path)?;
+                Ok(Ok((cat, shown > 0)))
+            })
+        })
+        .await?
+        .inspect_err(|e| warn!("Refused to reject cat ID {}: {}", cat_id, e))?;
+
+    info!("Cat ID: {} rejected", cat.id);
+    if let Some(key) = cat.image_path.strip_prefix("/image/") {
+        if !image_shared {
+            discard_upload(store.get_ref(), key);
+        }
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
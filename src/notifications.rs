@@ -0,0 +1,222 @@
+//! Email notifications to the addresses in `NOTIFY_RECIPIENTS`, sent
+//! through `SMTP_URL`: of submissions awaiting moderation, tenants nearing
+//! their quota and failing background jobs. With `NOTIFY_DRY_RUN` set they
+//! are logged instead.
+//!
+//! Emails are sent in the background, so a slow or failing mail server
+//! never holds up a request.
+use crate::config::Config;
+use crate::models::{Cat, Tenant};
+use crate::quotas::QuotaStatus;
+use lettre::message::Mailbox;
+use lettre::{AsyncSmtpTransport, AsyncTransport, Message, Tokio1Executor};
+use log::{error, info};
+use std::sync::Arc;
+
+/// Subject and body of an email, with `{name}` placeholders.
+struct Template {
+    subject: &'static str,
+    body: &'static str,
+}
+
+const PENDING_MODERATION: Template = Template {
+    subject: "[catdex] {tenant}: {name} awaits moderation",
+    body: "A new cat, {name} (ID {id}), was submitted to {tenant} and awaits moderation.
+
+Approve or reject it through /api/admin/moderation.
+",
+};
+
+const QUOTA_NEAR_LIMIT: Template = Template {
+    subject: "[catdex] {tenant} is nearing its quota",
+    body: "{tenant} now uses {used_cats} of {max_cats} cats and {used_storage_bytes} of {max_storage_bytes} bytes of storage.
+
+New cats are refused once either limit is reached.
+",
+};
+
+const JOB_FAILED: Template = Template {
+    subject: "[catdex] {job} failed",
+    body: "The background job {job} failed:
+
+{error}
+
+It keeps retrying; you will not be notified again until it has recovered.
+",
+};
+
+/// Something admins are told about.
+pub enum Notification<'a> {
+    PendingModeration {
+        tenant: &'a Tenant,
+        cat: &'a Cat,
+    },
+    QuotaNearLimit {
+        tenant: &'a Tenant,
+        status: &'a QuotaStatus,
+    },
+    JobFailed {
+        job: &'a str,
+        error: &'a str,
+    },
+}
+
+impl Notification<'_> {
+    /// The subject and body of its email.
+    fn render(&self) -> (String, String) {
+        let limit = |max: Option<i64>| max.map_or("unlimited".to_string(), |max| max.to_string());
+        let (template, values) = match self {
+            Notification::PendingModeration { tenant, cat } => (
+                PENDING_MODERATION,
+                vec![
+                    ("tenant", tenant.slug.clone()),
+                    ("name", cat.name.clone()),
+                    ("id", cat.id.to_string()),
+                ],
+            ),
+            Notification::QuotaNearLimit { tenant, status } => (
+                QUOTA_NEAR_LIMIT,
+                vec![
+                    ("tenant", tenant.slug.clone()),
+                    ("used_cats", status.used_cats.to_string()),
+                    ("max_cats", limit(status.max_cats)),
+                    ("used_storage_bytes", status.used_storage_bytes.to_string()),
+                    ("max_storage_bytes", limit(status.max_storage_bytes)),
+                ],
+            ),
+            Notification::JobFailed { job, error } => (
+                JOB_FAILED,
+                vec![("job", job.to_string()), ("error", error.to_string())],
+            ),
+        };
+        (
+            render(template.subject, &values),
+            render(template.body, &values),
+        )
+    }
+}
+
+/// Fills the `{name}` placeholders of `template` in one pass, so values
+/// that look like placeholders are left alone.
+fn render(template: &str, values: &[(&str, String)]) -> String {
+    let mut rendered = String::with_capacity(template.len());
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        let placeholder = &rest[start..];
+        let value = placeholder.find('}').and_then(|end| {
+            let name = &placeholder[1..end];
+            values
+                .iter()
+                .find(|(key, _)| *key == name)
+                .map(|(_, value)| (value, end))
+        });
+        match value {
+            Some((value, end)) => {
+                rendered.push_str(value);
+                rest = &placeholder[end + 1..];
+            }
+            None => {
+                rendered.push('{');
+                rest = &placeholder[1..];
+            }
+        }
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+pub struct Notifier {
+    /// None in dry-run mode.
+    transport: Option<AsyncSmtpTransport<Tokio1Executor>>,
+    from: Mailbox,
+    recipients: Vec<Mailbox>,
+}
+
+impl Notifier {
+    /// Emails `notification` to the recipients in the background. Must be
+    /// called on the server's runtime.
+    pub fn notify(&self, notification: Notification) {
+        let (subject, body) = notification.render();
+        let Some(transport) = &self.transport else {
+            info!("Not sending notification in dry run: {}\n{}", subject, body);
+            return;
+        };
+        let mut builder = Message::builder().from(self.from.clone());
+        for recipient in &self.recipients {
+            builder = builder.to(recipient.clone());
+        }
+        let message = match builder.subject(subject.clone()).body(body) {
+            Ok(message) => message,
+            Err(e) => {
+                error!("Failed to build notification {}: {}", subject, e);
+                return;
+            }
+        };
+        let transport = transport.clone();
+        actix_rt::spawn(async move {
+            if let Err(e) = transport.send(message).await {
+                error!("Failed to send notification {}: {}", subject, e);
+            }
+        });
+    }
+}
+
+/// The notifier configured through `NOTIFY_RECIPIENTS`, if any.
+///
+/// # Panics
+///
+/// When `SMTP_URL` is invalid, or missing without `NOTIFY_DRY_RUN`.
+pub fn from_config(config: &Config) -> Option<Arc<Notifier>> {
+    if config.notify_recipients.is_empty() {
+        return None;
+    }
+    let transport = match (&config.smtp_url, config.notify_dry_run) {
+        (_, true) => None,
+        (Some(url), false) => Some(
+            AsyncSmtpTransport::<Tokio1Executor>::from_url(url)
+                .unwrap_or_else(|e| panic!("SMTP_URL is invalid: {}", e))
+                .build(),
+        ),
+        (None, false) => panic!("NOTIFY_RECIPIENTS needs SMTP_URL, or NOTIFY_DRY_RUN to log them"),
+    };
+    info!(
+        "Notifying {} recipient(s){}",
+        config.notify_recipients.len(),
+        if transport.is_none() {
+            " in dry run"
+        } else {
+            ""
+        }
+    );
+    Some(Arc::new(Notifier {
+        transport,
+        from: config.notify_from.clone(),
+        recipients: config.notify_recipients.clone(),
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        let values = [
+            ("name", "{tenant}".to_string()),
+            ("tenant", "acme".to_string()),
+        ];
+        assert_eq!(
+            render("{name} in {tenant}, {unknown} {", &values),
+            "{tenant} in acme, {unknown} {"
+        );
+
+        let (subject, body) = Notification::JobFailed {
+            job: "outbox_dispatcher",
+            error: "connection refused",
+        }
+        .render();
+        assert_eq!(subject, "[catdex] outbox_dispatcher failed");
+        assert!(body.contains("\n\nconnection refused\n\n"));
+    }
+}
@@ -0,0 +1,188 @@
+use crate::health;
+use crate::models::{Cat, NewOutboxEvent, OutboxEvent};
+use crate::notifications::{Notification, Notifier};
+use crate::repository;
+use crate::telemetry;
+use crate::webhooks::{self, CatEvent, DeliveryPolicy};
+use crate::DbPool;
+use chrono::{DateTime, Utc};
+use diesel::{PgConnection, QueryResult};
+use futures_util::future::join_all;
+use log::{error, warn};
+use std::sync::Arc;
+use std::time::Duration;
+
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+const BATCH_SIZE: i64 = 100;
+/// Time allowed for publishing one event on top of delivering it, for
+/// looking up its subscribers and recording the deliveries.
+const DISPATCH_SLACK: Duration = Duration::from_secs(30);
+/// How much longer than publishing may take a claimed event is left to its
+/// dispatcher, after which another one may claim it, so an event is only
+/// claimed again once its dispatcher has given up on it.
+const CLAIM_MARGIN: Duration = Duration::from_secs(60);
+
+/// How long publishing one event may take before the dispatcher moves on
+/// without it: enough for every subscriber to run out of attempts.
+fn dispatch_timeout(policy: DeliveryPolicy) -> Duration {
+    policy.max_delivery_time().saturating_add(DISPATCH_SLACK)
+}
+
+/// Stores `event` for `cat` in the outbox.
+///
+/// Call this inside the transaction that changes the cat, so the event is
+/// kept if and only if the change is committed.
+pub fn record(
+    connection: &mut PgConnection,
+    tenant_id: i32,
+    event: CatEvent,
+    cat: &Cat,
+    occurred_at: DateTime<Utc>,
+) -> QueryResult<()> {
+    let new_event = NewOutboxEvent {
+        tenant_id,
+        event: event.name().to_string(),
+        payload: event.payload(cat, occurred_at),
+    };
+    repository::insert_outbox_event(connection, &new_event).map(|_| ())
+}
+
+/// Publishes pending outbox events for as long as the server runs.
+///
+/// Events are claimed before they are published, so dispatchers running
+/// on several instances publish each event once. An event is only marked
+/// dispatched after every subscriber has been handled, so events whose
+/// publishing failed, timed out or was interrupted by a restart are
+/// published again once their claim runs out.
+pub async fn run_dispatcher(pool: DbPool, policy: DeliveryPolicy, notifier: Option<Arc<Notifier>>) {
+    let dispatch_timeout = dispatch_timeout(policy);
+    let claim_lease = dispatch_timeout.saturating_add(CLAIM_MARGIN);
+    let mut interval = actix_rt::time::interval(POLL_INTERVAL);
+    let mut failing = false;
+    loop {
+        interval.tick().await;
+
+        let lookup_pool = pool.clone();
+        let pending = telemetry::block(move || {
+            let mut connection = lookup_pool.get().map_err(|e| e.to_string())?;
+            repository::claim_pending_outbox_events(&mut connection, BATCH_SIZE, claim_lease)
+                .map_err(|e| e.to_string())
+        })
+        .await;
+        let events = match pending {
+            Ok(Ok(events)) => events,
+            Ok(Err(e)) => {
+                error!("Failed to claim pending outbox events: {}", e);
+                if let Some(notifier) = notifier.as_ref().filter(|_| !failing) {
+                    notifier.notify(Notification::JobFailed {
+                        job: "outbox_dispatcher",
+                        error: &e,
+                    });
+                }
+                failing = true;
+                continue;
+            }
+            Err(_) => {
+                error!("Blocking Thread Pool Error");
+                continue;
+            }
+        };
+        failing = false;
+
+        join_all(events.into_iter().map(|event| {
+            let event_id = event.id;
+            let dispatched = dispatch(pool.clone(), event, policy);
+            async move {
+                if actix_rt::time::timeout(dispatch_timeout, dispatched)
+                    .await
+                    .is_err()
+                {
+                    warn!(
+                        "Publishing outbox event ID: {} timed out after {:?}",
+                        event_id, dispatch_timeout
+                    );
+                }
+            }
+        }))
+        .await;
+        health::record_job_run("outbox_dispatcher", Utc::now());
+    }
+}
+
+async fn dispatch(pool: DbPool, event: OutboxEvent, policy: DeliveryPolicy) {
+    match CatEvent::from_name(&event.event) {
+        Some(cat_event) => {
+            if let Err(e) =
+                webhooks::publish(&pool, event.tenant_id, cat_event, &event.payload, policy).await
+            {
+                error!("Failed to publish outbox event ID: {}: {}", event.id, e);
+                return;
+            }
+        }
+        None => warn!(
+            "Dropping outbox event ID: {} with unknown event {}",
+            event.id, event.event
+        ),
+    }
+
+    let event_id = event.id;
+    let result = telemetry::block(move || {
+        let mut connection = pool.get().map_err(|e| e.to_string())?;
+        repository::mark_outbox_event_dispatched(&mut connection, event_id)
+            .map_err(|e| e.to_string())
+    })
+    .await;
+    if !matches!(result, Ok(Ok(_))) {
+        error!("Failed to mark outbox event ID: {} as dispatched", event_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::outbox;
+    use crate::tenants::DEFAULT_TENANT;
+    use crate::test_support::test_pool;
+    use chrono::TimeDelta;
+    use diesel::{ExpressionMethods, RunQueryDsl};
+    use serde_json::json;
+
+    #[test]
+    fn test_claim_pending_outbox_events() {
+        let pool = test_pool();
+        let mut connection = pool.get().unwrap();
+        let tenant = repository::find_tenant_by_slug(&mut connection, DEFAULT_TENANT).unwrap();
+        for n in 0..2 {
+            let event = NewOutboxEvent {
+                tenant_id: tenant.id,
+                event: CatEvent::Created.name().to_string(),
+                payload: json!({ "n": n }),
+            };
+            repository::insert_outbox_event(&mut connection, &event).unwrap();
+        }
+        let claim = |connection: &mut PgConnection| -> Vec<i64> {
+            repository::claim_pending_outbox_events(
+                connection,
+                BATCH_SIZE,
+                Duration::from_secs(300),
+            )
+            .unwrap()
+            .iter()
+            .map(|event| event.id)
+            .collect()
+        };
+
+        let claimed = claim(&mut connection);
+        assert_eq!(claimed.len(), 2);
+        assert!(claimed[0] < claimed[1]);
+        assert!(claim(&mut connection).is_empty());
+
+        // Claimed again once the lease ran out, unless dispatched
+        repository::mark_outbox_event_dispatched(&mut connection, claimed[0]).unwrap();
+        diesel::update(outbox::table)
+            .set(outbox::claimed_until.eq(Utc::now() - TimeDelta::days(1)))
+            .execute(&mut connection)
+            .unwrap();
+        assert_eq!(claim(&mut connection), vec![claimed[1]]);
+    }
+}
@@ -0,0 +1,305 @@
+//! Opt-in logging of request and response bodies, for diagnosing client
+//! integrations. Admins pick the routes through `PUT /api/admin/payload-log`
+//! and the payloads are logged at info level while the route stays listed.
+//!
+//! Credentials and image bytes never reach the log: authentication headers
+//! are redacted, binary bodies are reduced to their type and size, and
+//! other bodies are cut off after `max_bytes`.
+use crate::auth;
+use crate::errors::UserError;
+use crate::history::Requester;
+use crate::models::Tenant;
+use crate::validated::Validated;
+use crate::DbPool;
+use actix_web::body::{self, BodySize, BoxBody, MessageBody};
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{
+    HeaderMap, HeaderName, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, COOKIE,
+    PROXY_AUTHORIZATION, SET_COOKIE, TRANSFER_ENCODING,
+};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpResponse};
+use arc_swap::ArcSwap;
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use validator::{Validate, ValidationError};
+
+pub const DEFAULT_MAX_BYTES: usize = 4096;
+/// Larger bodies are not held in memory to be logged.
+const MAX_BUFFERED_BYTES: usize = 256 * 1024;
+
+const REDACTED_HEADERS: [HeaderName; 4] = [AUTHORIZATION, PROXY_AUTHORIZATION, COOKIE, SET_COOKIE];
+
+/// Which routes have their payloads logged.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize, Validate)]
+pub struct PayloadLogSettings {
+    /// Route patterns like `/api/cat/{id}`, or literal paths.
+    #[validate(custom = "validate_routes")]
+    pub routes: Vec<String>,
+    /// Bytes of each body logged before it is cut off.
+    #[serde(default = "default_max_bytes")]
+    #[validate(range(min = 1, max = 262144))]
+    pub max_bytes: usize,
+}
+
+fn default_max_bytes() -> usize {
+    DEFAULT_MAX_BYTES
+}
+
+fn validate_routes(routes: &[String]) -> Result<(), ValidationError> {
+    if routes.iter().any(|route| !route.starts_with('/')) {
+        let mut error = ValidationError::new("routes");
+        error.message = Some("must be paths starting with /".into());
+        return Err(error);
+    }
+    Ok(())
+}
+
+impl Default for PayloadLogSettings {
+    fn default() -> PayloadLogSettings {
+        PayloadLogSettings {
+            routes: Vec::new(),
+            max_bytes: DEFAULT_MAX_BYTES,
+        }
+    }
+}
+
+/// The current `PayloadLogSettings`, shared by every worker. Nothing is
+/// logged until an admin lists a route.
+#[derive(Default)]
+pub struct PayloadLog {
+    current: ArcSwap<PayloadLogSettings>,
+}
+
+impl PayloadLog {
+    pub fn load(&self) -> Arc<PayloadLogSettings> {
+        self.current.load_full()
+    }
+
+    pub fn store(&self, settings: PayloadLogSettings) {
+        self.current.store(Arc::new(settings));
+    }
+}
+
+/// Whether `req` is to one of the listed routes.
+fn is_logged(settings: &PayloadLogSettings, req: &ServiceRequest) -> bool {
+    if settings.routes.is_empty() {
+        return false;
+    }
+    let pattern = req.match_pattern();
+    settings
+        .routes
+        .iter()
+        .any(|route| Some(route) == pattern.as_ref() || route == req.path())
+}
+
+/// Images, multipart forms and other binary bodies, which are left out of
+/// the log.
+fn is_binary(content_type: &str) -> bool {
+    let content_type = content_type.to_ascii_lowercase();
+    content_type.starts_with("image/")
+        || content_type.starts_with("multipart/")
+        || content_type.starts_with("application/octet-stream")
+        || content_type.starts_with("application/offset+octet-stream")
+}
+
+fn content_type_of(headers: &HeaderMap) -> &str {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("")
+}
+
+/// The headers as logged, with credentials redacted.
+fn render_headers(headers: &HeaderMap) -> String {
+    let mut rendered: Vec<String> = headers
+        .iter()
+        .map(|(name, value)| {
+            let value = match REDACTED_HEADERS.contains(name) {
+                true => "<redacted>",
+                false => value.to_str().unwrap_or("<binary>"),
+            };
+            format!("{}: {}", name, value)
+        })
+        .collect();
+    rendered.sort();
+    format!("{{{}}}", rendered.join(", "))
+}
+
+/// The body as logged, at most `max_bytes` of it.
+fn render_body(bytes: &[u8], max_bytes: usize) -> String {
+    if bytes.len() <= max_bytes {
+        return String::from_utf8_lossy(bytes).into_owned();
+    }
+    format!(
+        "{}... ({} bytes)",
+        String::from_utf8_lossy(&bytes[..max_bytes]),
+        bytes.len()
+    )
+}
+
+fn unlogged_body(content_type: &str, size: Option<u64>) -> String {
+    let size = size.map_or("unknown size".to_string(), |size| format!("{} bytes", size));
+    match is_binary(content_type) {
+        true => format!("<redacted {}, {}>", content_type, size),
+        false => format!("<not logged, {}>", size),
+    }
+}
+
+/// Middleware logging the payloads of the routes listed in the
+/// `PayloadLog`, passing them on unchanged.
+pub async fn log_payloads(
+    mut req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<BoxBody>, Error> {
+    let settings = match req.app_data::<web::Data<PayloadLog>>() {
+        Some(log) => log.load(),
+        None => return Ok(next.call(req).await?.map_into_boxed_body()),
+    };
+    if !is_logged(&settings, &req) {
+        return Ok(next.call(req).await?.map_into_boxed_body());
+    }
+
+    let label = format!("{} {}", req.method(), req.uri());
+    let content_type = content_type_of(req.headers()).to_string();
+    let length: Option<u64> = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok()?.parse().ok());
+    let body = match length {
+        Some(length) if !is_binary(&content_type) && length <= MAX_BUFFERED_BYTES as u64 => {
+            let bytes = req.extract::<web::Bytes>().await?;
+            let body = render_body(&bytes, settings.max_bytes);
+            req.set_payload(bytes.into());
+            body
+        }
+        // Bodies without a length are streamed, unless there is none
+        None if !req.headers().contains_key(TRANSFER_ENCODING) => String::new(),
+        _ => unlogged_body(&content_type, length),
+    };
+    info!(
+        "Request {} headers {} body {:?}",
+        label,
+        render_headers(req.headers()),
+        body
+    );
+
+    let res = next.call(req).await?.map_into_boxed_body();
+    let content_type = content_type_of(res.headers()).to_string();
+    let size = match res.response().body().size() {
+        BodySize::Sized(size) => Some(size),
+        BodySize::None => Some(0),
+        BodySize::Stream => None,
+    };
+    let (res, body) = match size {
+        Some(size) if !is_binary(&content_type) && size <= MAX_BUFFERED_BYTES as u64 => {
+            let (req, res) = res.into_parts();
+            let (res, payload) = res.into_parts();
+            let bytes = body::to_bytes(payload).await.map_err(|e| {
+                error!("Failed to read response for the payload log: {}", e);
+                actix_web::error::ErrorInternalServerError("Internal server error")
+            })?;
+            let body = render_body(&bytes, settings.max_bytes);
+            let res = ServiceResponse::new(req, res.set_body(BoxBody::new(bytes)));
+            (res, body)
+        }
+        _ => (res, unlogged_body(&content_type, size)),
+    };
+    info!(
+        "Response {} to {} headers {} body {:?}",
+        res.status(),
+        label,
+        render_headers(res.headers()),
+        body
+    );
+    Ok(res)
+}
+
+/// The routes whose payloads are logged.
+pub async fn payload_log_endpoint(
+    pool: web::Data<DbPool>,
+    log: web::Data<PayloadLog>,
+    tenant: Tenant,
+    requester: Requester,
+) -> Result<HttpResponse, UserError> {
+    auth::require_admin(&pool, tenant.id, requester).await?;
+    Ok(HttpResponse::Ok().json(&*log.load()))
+}
+
+/// Replaces the routes whose payloads are logged, an empty list turns the
+/// logging off.
+pub async fn set_payload_log_endpoint(
+    pool: web::Data<DbPool>,
+    log: web::Data<PayloadLog>,
+    tenant: Tenant,
+    requester: Requester,
+    settings: Validated<web::Json<PayloadLogSettings>>,
+) -> Result<HttpResponse, UserError> {
+    auth::require_admin(&pool, tenant.id, requester).await?;
+    let settings = settings.into_inner().into_inner();
+    info!("Logging payloads of {:?}", settings.routes);
+    log.store(settings.clone());
+    Ok(HttpResponse::Ok().json(settings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::HeaderValue;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_and_read_body, init_service, TestRequest};
+    use actix_web::App;
+
+    #[test]
+    fn test_render_headers_redacts_credentials() {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, HeaderValue::from_static("admin:open sesame"));
+        headers.insert(CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        assert_eq!(
+            render_headers(&headers),
+            "{authorization: <redacted>, content-type: application/json}"
+        );
+    }
+
+    #[test]
+    fn test_render_body() {
+        assert_eq!(
+            render_body(b"{\"name\":\"Tom\"}", 100),
+            "{\"name\":\"Tom\"}"
+        );
+        assert_eq!(render_body(b"0123456789", 4), "0123... (10 bytes)");
+        assert_eq!(
+            unlogged_body("image/jpeg", Some(2048)),
+            "<redacted image/jpeg, 2048 bytes>"
+        );
+        assert!(is_binary("multipart/form-data; boundary=x"));
+        assert!(!is_binary("application/json"));
+    }
+
+    #[actix_web::test]
+    async fn test_logged_payloads_pass_through() {
+        let log = web::Data::new(PayloadLog::default());
+        log.store(PayloadLogSettings {
+            routes: vec!["/echo/{id}".to_string()],
+            max_bytes: 4,
+        });
+        let app = init_service(
+            App::new()
+                .wrap(from_fn(log_payloads))
+                .app_data(log.clone())
+                .route(
+                    "/echo/{id}",
+                    web::post().to(|body: web::Bytes| async move { HttpResponse::Ok().body(body) }),
+                ),
+        )
+        .await;
+
+        let req = TestRequest::post()
+            .uri("/echo/1")
+            .set_payload("a body longer than four bytes")
+            .to_request();
+        let body = call_and_read_body(&app, req).await;
+        assert_eq!(body, "a body longer than four bytes");
+    }
+}
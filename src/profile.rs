@@ -0,0 +1,251 @@
+//! A load profile of the queries behind the cat list and detail pages, run
+//! with `catdex profile-load` against a real database, so changes that slow
+//! down query building show up before they reach production. `Profile`
+//! runs the same queries for the benchmarks in `benches/repository.rs`.
+use crate::clock::SystemClock;
+use crate::file_store::FileStore;
+use crate::models::Tenant;
+use crate::repository;
+use crate::seed;
+use crate::DbPool;
+use diesel::{PgConnection, QueryResult};
+use std::error::Error;
+use std::thread;
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+/// How many cats the list page loads.
+const LIST_LIMIT: i64 = 100;
+
+/// Latencies of one query, measured under load.
+struct Latencies {
+    query: &'static str,
+    sorted: Vec<Duration>,
+    elapsed: Duration,
+}
+
+impl Latencies {
+    fn new(query: &'static str, mut samples: Vec<Duration>, elapsed: Duration) -> Latencies {
+        samples.sort();
+        Latencies {
+            query,
+            sorted: samples,
+            elapsed,
+        }
+    }
+
+    /// The latency `percent` of the samples stay within, by the nearest
+    /// rank method.
+    fn percentile(&self, percent: f64) -> Duration {
+        if self.sorted.is_empty() {
+            return Duration::ZERO;
+        }
+        let rank = (percent / 100.0 * self.sorted.len() as f64).ceil() as usize;
+        self.sorted[rank.clamp(1, self.sorted.len()) - 1]
+    }
+
+    fn report(&self) -> String {
+        let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
+        format!(
+            "{:<24} {:>8} {:>10.1} {:>9.2} {:>9.2} {:>9.2} {:>9.2}",
+            self.query,
+            self.sorted.len(),
+            self.sorted.len() as f64 / self.elapsed.as_secs_f64(),
+            ms(self.percentile(50.0)),
+            ms(self.percentile(95.0)),
+            ms(self.percentile(99.0)),
+            ms(self.sorted.last().copied().unwrap_or_default()),
+        )
+    }
+}
+
+/// Runs `query` `requests` times in total from `concurrency` threads, each
+/// with a connection of its own. `query` gets the index of the request.
+fn measure(
+    pool: &DbPool,
+    name: &'static str,
+    concurrency: usize,
+    requests: usize,
+    query: impl Fn(&mut PgConnection, usize) -> QueryResult<()> + Sync,
+) -> Result<Latencies, Box<dyn Error + Send + Sync>> {
+    let started = Instant::now();
+    let samples = thread::scope(|scope| {
+        let workers: Vec<_> = (0..concurrency)
+            .map(|worker| {
+                let query = &query;
+                scope.spawn(
+                    move || -> Result<Vec<Duration>, Box<dyn Error + Send + Sync>> {
+                        let mut connection = pool.get()?;
+                        let mut samples = Vec::new();
+                        for index in (worker..requests).step_by(concurrency) {
+                            let start = Instant::now();
+                            query(&mut connection, index)?;
+                            samples.push(start.elapsed());
+                        }
+                        Ok(samples)
+                    },
+                )
+            })
+            .collect();
+        workers
+            .into_iter()
+            .map(|worker| worker.join().expect("Profile worker panicked"))
+            .collect::<Result<Vec<_>, _>>()
+    })?;
+    Ok(Latencies::new(
+        name,
+        samples.into_iter().flatten().collect(),
+        started.elapsed(),
+    ))
+}
+
+/// A tenant seeded for profiling, and the public ids of its cats.
+pub struct Profile {
+    tenant: Tenant,
+    public_ids: Vec<Uuid>,
+}
+
+impl Profile {
+    /// Seeds the tenant `slug` up to `cats` cats, with their images in
+    /// `store`. Cats already there count towards `cats`.
+    pub fn prepare(
+        pool: &DbPool,
+        store: &dyn FileStore,
+        slug: &str,
+        cats: usize,
+    ) -> Result<Profile, Box<dyn Error + Send + Sync>> {
+        let tenant = repository::find_tenant_by_slug(&mut *pool.get()?, slug)
+            .map_err(|e| format!("Tenant {}: {}", slug, e))?;
+        Profile::seed(pool, store, tenant, cats)
+    }
+
+    fn seed(
+        pool: &DbPool,
+        store: &dyn FileStore,
+        tenant: Tenant,
+        cats: usize,
+    ) -> Result<Profile, Box<dyn Error + Send + Sync>> {
+        let mut connection = pool.get()?;
+        let existing = repository::list_cats(&mut connection, tenant.id, None, i64::MAX)?.len();
+        if existing < cats {
+            println!("Seeding {} cat(s) into {}", cats - existing, tenant.slug);
+            seed::seed(
+                &mut connection,
+                store,
+                &SystemClock,
+                &tenant,
+                cats - existing,
+            )?;
+        }
+        let public_ids = repository::list_cats(&mut connection, tenant.id, None, i64::MAX)?
+            .iter()
+            .map(|cat| cat.public_id)
+            .collect::<Vec<_>>();
+        if public_ids.is_empty() {
+            return Err("The profile needs at least one cat".into());
+        }
+        Ok(Profile { tenant, public_ids })
+    }
+
+    /// The cats of the tenant.
+    pub fn cats(&self) -> usize {
+        self.public_ids.len()
+    }
+
+    /// The query behind the cat list page.
+    pub fn list_cats(&self, connection: &mut PgConnection) -> QueryResult<()> {
+        repository::list_cats(connection, self.tenant.id, None, LIST_LIMIT).map(|_| ())
+    }
+
+    /// The query behind a cat's detail page, for the cat `index`, wrapping
+    /// around the tenant's cats.
+    pub fn find_cat(&self, connection: &mut PgConnection, index: usize) -> QueryResult<()> {
+        let public_id = self.public_ids[index % self.public_ids.len()];
+        repository::find_cat_by_public_id(connection, self.tenant.id, public_id).map(|_| ())
+    }
+}
+
+/// Seeds the tenant up to `cats` cats, then measures the list and detail
+/// queries and prints their latencies. Fails when a p95 latency exceeds
+/// `max_p95`, for use in CI.
+pub fn run(
+    pool: &DbPool,
+    store: &dyn FileStore,
+    tenant: Tenant,
+    cats: usize,
+    concurrency: usize,
+    requests: usize,
+    max_p95: Option<Duration>,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if concurrency == 0 || concurrency as u32 > pool.max_size() {
+        return Err(format!(
+            "Concurrency must be between 1 and the pool size of {}",
+            pool.max_size()
+        )
+        .into());
+    }
+    let profile = Profile::seed(pool, store, tenant, cats)?;
+
+    let results = [
+        measure(pool, "list_cats", concurrency, requests, |connection, _| {
+            profile.list_cats(connection)
+        })?,
+        measure(
+            pool,
+            "find_cat_by_public_id",
+            concurrency,
+            requests,
+            |connection, index| profile.find_cat(connection, index),
+        )?,
+    ];
+
+    println!(
+        "{} cat(s), {} request(s) per query from {} connection(s)",
+        profile.cats(),
+        requests,
+        concurrency
+    );
+    println!(
+        "{:<24} {:>8} {:>10} {:>9} {:>9} {:>9} {:>9}",
+        "query", "requests", "req/s", "p50 ms", "p95 ms", "p99 ms", "max ms"
+    );
+    for latencies in &results {
+        println!("{}", latencies.report());
+    }
+
+    let Some(max_p95) = max_p95 else {
+        return Ok(());
+    };
+    let slow: Vec<&str> = results
+        .iter()
+        .filter(|latencies| latencies.percentile(95.0) > max_p95)
+        .map(|latencies| latencies.query)
+        .collect();
+    if !slow.is_empty() {
+        return Err(format!(
+            "p95 latency of {} exceeds {} ms",
+            slow.join(", "),
+            max_p95.as_millis()
+        )
+        .into());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percentile() {
+        let samples = (1..=100).map(Duration::from_millis).rev().collect();
+        let latencies = Latencies::new("list_cats", samples, Duration::from_secs(1));
+        assert_eq!(latencies.percentile(50.0), Duration::from_millis(50));
+        assert_eq!(latencies.percentile(95.0), Duration::from_millis(95));
+        assert_eq!(latencies.percentile(99.0), Duration::from_millis(99));
+        assert_eq!(latencies.percentile(0.0), Duration::from_millis(1));
+
+        let empty = Latencies::new("find_cat", Vec::new(), Duration::from_secs(1));
+        assert_eq!(empty.percentile(95.0), Duration::ZERO);
+    }
+}
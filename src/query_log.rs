@@ -0,0 +1,85 @@
+//! Logs every SQL statement with its bind parameters and how long it took,
+//! when `LOG_QUERIES` is set, so the queries a new endpoint makes (and the
+//! N+1s among them) can be seen while developing it. Statements are logged
+//! at `info` under this module, e.g. with `RUST_LOG=catdex_api::query_log=info`.
+//!
+//! Bind parameters are logged as they are, passwords and all, so this is
+//! only meant for development databases. Both the setting and the log
+//! filter can be changed while the server runs, see `reload`.
+use diesel::connection::{set_default_instrumentation, Instrumentation, InstrumentationEvent};
+use log::{error, info};
+use std::fmt::Display;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+/// Times each statement of one connection.
+#[derive(Default)]
+struct QueryLogger {
+    started: Option<Instant>,
+}
+
+impl Instrumentation for QueryLogger {
+    fn on_connection_event(&mut self, event: InstrumentationEvent<'_>) {
+        if !ENABLED.load(Ordering::Relaxed) {
+            self.started = None;
+            return;
+        }
+        match event {
+            InstrumentationEvent::StartQuery { .. } => self.started = Some(Instant::now()),
+            InstrumentationEvent::FinishQuery { query, error, .. } => {
+                let elapsed = self.started.take().map(|started| started.elapsed());
+                info!("{}", entry(query, elapsed, error));
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A statement as logged, e.g. `4.21 ms: SELECT ... -- binds: [1]`.
+fn entry(
+    query: &dyn Display,
+    elapsed: Option<Duration>,
+    error: Option<&diesel::result::Error>,
+) -> String {
+    let elapsed = match elapsed {
+        Some(elapsed) => format!("{:.2} ms", elapsed.as_secs_f64() * 1000.0),
+        None => "? ms".to_string(),
+    };
+    match error {
+        Some(error) => format!("{}: {} failed: {}", elapsed, query, error),
+        None => format!("{}: {}", elapsed, query),
+    }
+}
+
+/// Gives the connections established from now on the query logger, which
+/// stays silent until `set_enabled` turns it on.
+pub fn install() {
+    let installed = set_default_instrumentation(|| Some(Box::new(QueryLogger::default())));
+    if let Err(e) = installed {
+        error!("Failed to install the query logger: {}", e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_entry() {
+        let query = "SELECT 1 -- binds: []";
+        assert_eq!(
+            entry(&query, Some(Duration::from_micros(4210)), None),
+            "4.21 ms: SELECT 1 -- binds: []"
+        );
+        assert_eq!(
+            entry(&query, None, Some(&diesel::result::Error::NotFound)),
+            "? ms: SELECT 1 -- binds: [] failed: Record not found"
+        );
+    }
+}
@@ -0,0 +1,119 @@
+use crate::db::DbConn;
+use crate::errors::UserError;
+use crate::models::{Quota, Tenant};
+use crate::repository;
+use actix_web::HttpResponse;
+use diesel::{PgConnection, QueryResult};
+use serde::{Deserialize, Serialize};
+
+/// Share of a limit in use from which a tenant is warned it is near it.
+const NEAR_LIMIT_RATIO: f64 = 0.9;
+
+/// A tenant's limits next to what it has used. Limits of `None` are
+/// unlimited.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct QuotaStatus {
+    pub max_cats: Option<i64>,
+    pub max_storage_bytes: Option<i64>,
+    pub used_cats: i64,
+    pub used_storage_bytes: i64,
+    pub remaining_cats: Option<i64>,
+    pub remaining_storage_bytes: Option<i64>,
+}
+
+impl QuotaStatus {
+    pub fn new(quota: Quota, used_cats: i64, used_storage_bytes: i64) -> QuotaStatus {
+        let max_cats = quota.max_cats.map(i64::from);
+        QuotaStatus {
+            max_cats,
+            max_storage_bytes: quota.max_storage_bytes,
+            used_cats,
+            used_storage_bytes,
+            remaining_cats: max_cats.map(|max| (max - used_cats).max(0)),
+            remaining_storage_bytes: quota
+                .max_storage_bytes
+                .map(|max| (max - used_storage_bytes).max(0)),
+        }
+    }
+
+    /// Whether one more cat with an image of `image_size` bytes fits.
+    pub fn allows(&self, image_size: i64) -> bool {
+        self.remaining_cats.is_none_or(|remaining| remaining >= 1)
+            && self
+                .remaining_storage_bytes
+                .is_none_or(|remaining| remaining >= image_size)
+    }
+
+    /// Whether one more photo of `image_size` bytes for a cat it has fits.
+    pub fn allows_photo(&self, image_size: i64) -> bool {
+        self.allows_bytes(image_size)
+    }
+
+    /// Whether `size` more bytes of storage fit, as for a medical record's
+    /// attachment.
+    pub fn allows_bytes(&self, size: i64) -> bool {
+        self.remaining_storage_bytes
+            .is_none_or(|remaining| remaining >= size)
+    }
+
+    /// Whether the tenant uses most of its cats or storage.
+    pub fn near_limit(&self) -> bool {
+        let near = |used: i64, max: Option<i64>| {
+            max.is_some_and(|max| used as f64 >= max as f64 * NEAR_LIMIT_RATIO)
+        };
+        near(self.used_cats, self.max_cats) || near(self.used_storage_bytes, self.max_storage_bytes)
+    }
+}
+
+/// Checks `quota` against the tenant's current usage.
+pub fn status(
+    connection: &mut PgConnection,
+    tenant_id: i32,
+    quota: Quota,
+) -> QueryResult<QuotaStatus> {
+    let (used_cats, used_storage_bytes) = repository::quota_usage(connection, tenant_id)?;
+    Ok(QuotaStatus::new(quota, used_cats, used_storage_bytes))
+}
+
+pub async fn quota_endpoint(connection: DbConn, tenant: Tenant) -> Result<HttpResponse, UserError> {
+    let status = connection
+        .run("load quota", move |connection| {
+            let quota = repository::find_quota(connection, tenant.id)?.unwrap_or_default();
+            status(connection, tenant.id, quota)
+        })
+        .await?;
+    Ok(HttpResponse::Ok().json(status))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allows() {
+        let quota = Quota {
+            max_cats: Some(2),
+            max_storage_bytes: Some(100),
+        };
+        let status = QuotaStatus::new(quota, 1, 60);
+        assert_eq!(status.remaining_cats, Some(1));
+        assert!(status.allows(40));
+        assert!(!status.allows(41));
+        assert!(!QuotaStatus::new(quota, 2, 0).allows(0));
+        assert!(QuotaStatus::new(quota, 2, 0).allows_photo(100));
+        assert!(!QuotaStatus::new(quota, 2, 60).allows_photo(41));
+        assert!(QuotaStatus::new(Quota::default(), 1000, 1 << 40).allows(1 << 40));
+    }
+
+    #[test]
+    fn test_near_limit() {
+        let quota = Quota {
+            max_cats: Some(10),
+            max_storage_bytes: Some(1000),
+        };
+        assert!(!QuotaStatus::new(quota, 8, 899).near_limit());
+        assert!(QuotaStatus::new(quota, 9, 0).near_limit());
+        assert!(QuotaStatus::new(quota, 0, 900).near_limit());
+        assert!(!QuotaStatus::new(Quota::default(), 1000, 1 << 40).near_limit());
+    }
+}
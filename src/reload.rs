@@ -0,0 +1,210 @@
+//! Reloading of the `Settings` while the server runs, when `CONFIG_FILE`
+//! changes, on SIGHUP or when an admin asks for it. Everything else in
+//! `Config` is read once at startup and needs a restart to change, except
+//! for the TLS certificate, which is reloaded when its files change and on
+//! SIGHUP too.
+use crate::auth;
+use crate::config::{Config, Settings};
+use crate::errors::UserError;
+use crate::history::Requester;
+use crate::logging;
+use crate::models::Tenant;
+use crate::query_log;
+use crate::repository;
+use crate::DbPool;
+use actix_rt::signal::unix::{signal, SignalKind};
+use actix_web::{web, HttpResponse};
+use arc_swap::ArcSwap;
+use log::{error, info, warn};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use serde_json::json;
+use std::collections::HashSet;
+use std::ffi::OsString;
+use std::path::PathBuf;
+use std::sync::Arc;
+
+/// The current `Settings`, shared by every worker.
+pub struct LiveSettings {
+    current: ArcSwap<Settings>,
+    file: Option<PathBuf>,
+}
+
+impl LiveSettings {
+    /// Settings loaded from `file`, which later reloads read again.
+    pub fn new(settings: Settings, file: Option<PathBuf>) -> LiveSettings {
+        LiveSettings {
+            current: ArcSwap::from_pointee(settings),
+            file,
+        }
+    }
+
+    pub fn load(&self) -> Arc<Settings> {
+        self.current.load_full()
+    }
+
+    /// Reads the settings again and applies them. The current ones are kept
+    /// when the file cannot be read or has invalid values.
+    pub fn reload(&self) -> Result<Arc<Settings>, String> {
+        let settings = Arc::new(Settings::load(self.file.as_deref())?);
+        apply(&settings);
+        let previous = self.current.swap(settings.clone());
+        if *previous != *settings {
+            info!("Reloaded settings: {}", settings.describe());
+        }
+        Ok(settings)
+    }
+}
+
+/// Applies the settings kept outside `LiveSettings`, i.e. the log filter,
+/// the slow query threshold and query logging.
+pub fn apply(settings: &Settings) {
+    logging::set_filter(&settings.log_filter);
+    repository::set_slow_query_threshold(settings.slow_query_threshold);
+    query_log::set_enabled(settings.log_queries);
+}
+
+/// Reloads the settings whenever their file changes, for as long as the
+/// returned watcher is kept. Returns `None` when there is no file to watch.
+pub fn watch(live: web::Data<LiveSettings>) -> notify::Result<Option<RecommendedWatcher>> {
+    let Some(file) = live.file.clone() else {
+        return Ok(None);
+    };
+    watch_files(&[file], move || {
+        if let Err(e) = live.reload() {
+            error!("Kept the current settings: {}", e);
+        }
+    })
+    .map(Some)
+}
+
+/// Calls `on_change` whenever one of `files` is written or replaced, for as
+/// long as the returned watcher is kept.
+///
+/// The files' directories are watched rather than the files themselves, so
+/// edits that replace a file, as most editors and certbot make, are seen
+/// too.
+pub fn watch_files(
+    files: &[PathBuf],
+    on_change: impl Fn() + Send + 'static,
+) -> notify::Result<RecommendedWatcher> {
+    let dirs: HashSet<PathBuf> = files
+        .iter()
+        .map(|file| match file.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        })
+        .collect();
+    let names: HashSet<OsString> = files
+        .iter()
+        .filter_map(|file| file.file_name().map(ToOwned::to_owned))
+        .collect();
+    let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| {
+        let event = match event {
+            Ok(event) => event,
+            Err(e) => {
+                warn!("Failed to watch for file changes: {}", e);
+                return;
+            }
+        };
+        let touches_file = event
+            .paths
+            .iter()
+            .any(|path| path.file_name().is_some_and(|name| names.contains(name)));
+        if touches_file && matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+            on_change();
+        }
+    })?;
+    for dir in dirs {
+        watcher.watch(&dir, RecursiveMode::NonRecursive)?;
+    }
+    Ok(watcher)
+}
+
+/// Reloads the settings on every SIGHUP.
+pub async fn reload_on_hangup(live: web::Data<LiveSettings>) {
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            error!("Failed to listen for SIGHUP: {}", e);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        info!("Reloading on SIGHUP");
+        if let Err(e) = live.reload() {
+            error!("Kept the current settings: {}", e);
+        }
+    }
+}
+
+fn active_config(config: &Config, settings: &Settings) -> HttpResponse {
+    HttpResponse::Ok().json(json!({
+        "config": config.describe(),
+        "settings": settings.describe(),
+    }))
+}
+
+/// The active configuration, with secrets left out.
+pub async fn config_endpoint(
+    pool: web::Data<DbPool>,
+    config: web::Data<Config>,
+    live: web::Data<LiveSettings>,
+    tenant: Tenant,
+    requester: Requester,
+) -> Result<HttpResponse, UserError> {
+    auth::require_admin(&pool, tenant.id, requester).await?;
+    Ok(active_config(&config, &live.load()))
+}
+
+/// Reloads the settings now, responding with the active configuration.
+pub async fn reload_endpoint(
+    pool: web::Data<DbPool>,
+    config: web::Data<Config>,
+    live: web::Data<LiveSettings>,
+    tenant: Tenant,
+    requester: Requester,
+) -> Result<HttpResponse, UserError> {
+    auth::require_admin(&pool, tenant.id, requester).await?;
+    let settings = live.reload().map_err(|e| {
+        error!("Kept the current settings: {}", e);
+        UserError::ConfigReloadError(e)
+    })?;
+    Ok(active_config(&config, &settings))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_reload_keeps_settings_on_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("catdex.env");
+        fs::write(&file, "FAMILY_MAX_DEPTH=2\n").unwrap();
+        let live = LiveSettings::new(Settings::load(None).unwrap(), Some(file.clone()));
+
+        assert_eq!(live.reload().unwrap().family_max_depth, 2);
+        fs::write(&file, "FAMILY_MAX_DEPTH=deep\n").unwrap();
+        assert!(live.reload().is_err());
+        assert_eq!(live.load().family_max_depth, 2);
+    }
+
+    #[test]
+    fn test_watch_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let file = dir.path().join("cert.pem");
+        fs::write(&file, "old").unwrap();
+        let (changed, changes) = std::sync::mpsc::channel();
+        let _watcher = watch_files(std::slice::from_ref(&file), move || {
+            let _ = changed.send(());
+        })
+        .unwrap();
+
+        fs::write(dir.path().join("other.pem"), "other").unwrap();
+        fs::write(dir.path().join("cert.pem.tmp"), "new").unwrap();
+        fs::rename(dir.path().join("cert.pem.tmp"), &file).unwrap();
+        let timeout = std::time::Duration::from_secs(5);
+        assert!(changes.recv_timeout(timeout).is_ok());
+    }
+}
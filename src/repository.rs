@@ -0,0 +1,1966 @@
+use crate::config::DEFAULT_SLOW_QUERY_THRESHOLD_MS;
+use crate::metrics::QUERY_DURATION;
+use crate::models::{
+    Cat, CatChange, CatImage, CatRelation, CatStats, CatStatus, CatTombstone, MedicalRecord,
+    MedicalRecordForm, NewAdmin, NewCat, NewCatChange, NewCatImage, NewCatRelation, NewOutboxEvent,
+    NewUpload, NewWebhook, NewWebhookDelivery, OutboxEvent, Quota, Tenant, Upload, Webhook,
+    WebhookDelivery,
+};
+use crate::schema::cats::dsl::*;
+use crate::schema::{
+    admins, cat_history, cat_images, cat_relations, cat_stats, cat_tombstones, medical_records,
+    outbox, quotas, tenants, uploads, webhook_deliveries, webhooks,
+};
+use crate::telemetry;
+use chrono::{DateTime, NaiveDate, Utc};
+use diesel::dsl::{count_star, now, sql};
+use diesel::pg::PgRowByRowLoadingMode;
+use diesel::sql_types::{BigInt, Bool, Double, Integer, Nullable, Text, Timestamptz};
+use diesel::{
+    define_sql_function, BoolExpressionMethods, Connection, ExpressionMethods, OptionalExtension,
+    PgArrayExpressionMethods, PgConnection, PgExpressionMethods, QueryDsl, QueryResult,
+    RunQueryDsl, SelectableHelper, TextExpressionMethods,
+};
+use log::warn;
+use opentelemetry::KeyValue;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+use uuid::Uuid;
+
+define_sql_function!(fn lower(x: Text) -> Text);
+
+static SLOW_QUERY_THRESHOLD_MS: AtomicU64 = AtomicU64::new(DEFAULT_SLOW_QUERY_THRESHOLD_MS);
+
+pub fn set_slow_query_threshold(threshold: Duration) {
+    SLOW_QUERY_THRESHOLD_MS.store(threshold.as_millis() as u64, Ordering::Relaxed);
+}
+
+/// A query parameter as it appears in the slow query log.
+enum Param {
+    /// Safe to log verbatim, e.g. ids and limits.
+    Plain(String),
+    /// User supplied content, only its length is logged.
+    Redacted(usize),
+}
+
+impl fmt::Display for Param {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Param::Plain(value) => write!(f, "{}", value),
+            Param::Redacted(len) => write!(f, "<redacted, {} bytes>", len),
+        }
+    }
+}
+
+fn format_params(params: &[(&str, Param)]) -> String {
+    params
+        .iter()
+        .map(|(key, value)| format!("{}={}", key, value))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn instrumented<T>(
+    query: &'static str,
+    params: &[(&str, Param)],
+    run: impl FnOnce() -> QueryResult<T>,
+) -> QueryResult<T> {
+    let attributes = vec![
+        KeyValue::new("db.system.name", "postgresql"),
+        KeyValue::new("db.operation.name", query),
+    ];
+    let start = Instant::now();
+    let result = telemetry::in_span(query, attributes, || {
+        run().inspect_err(telemetry::record_error)
+    });
+    let elapsed = start.elapsed();
+
+    QUERY_DURATION
+        .with_label_values(&[query])
+        .observe(elapsed.as_secs_f64());
+    let threshold = Duration::from_millis(SLOW_QUERY_THRESHOLD_MS.load(Ordering::Relaxed));
+    if elapsed >= threshold {
+        warn!(
+            "Slow query {} took {} ms ({})",
+            query,
+            elapsed.as_millis(),
+            format_params(params)
+        );
+    }
+    result
+}
+
+/// A round trip to the database, for health checks.
+pub fn ping(connection: &mut PgConnection) -> QueryResult<()> {
+    instrumented("ping", &[], || {
+        diesel::sql_query("SELECT 1")
+            .execute(connection)
+            .map(|_| ())
+    })
+}
+
+/// The version of the database server, e.g. `16.2`.
+pub fn database_version(connection: &mut PgConnection) -> QueryResult<String> {
+    instrumented("database_version", &[], || {
+        diesel::select(sql::<Text>("current_setting('server_version')")).get_result(connection)
+    })
+}
+
+pub fn find_tenant_by_slug(connection: &mut PgConnection, slug: &str) -> QueryResult<Tenant> {
+    instrumented(
+        "find_tenant_by_slug",
+        &[("slug", Param::Plain(slug.to_string()))],
+        || {
+            tenants::table
+                .select(Tenant::as_select())
+                .filter(tenants::slug.eq(slug))
+                .first(connection)
+        },
+    )
+}
+
+/// Lists the tenant's approved cats, only those in `cat_status` when given.
+pub fn list_cats(
+    connection: &mut PgConnection,
+    cat_tenant_id: i32,
+    cat_status: Option<CatStatus>,
+    limit: i64,
+) -> QueryResult<Vec<Cat>> {
+    instrumented(
+        "list_cats",
+        &[
+            ("tenant_id", Param::Plain(cat_tenant_id.to_string())),
+            (
+                "status",
+                Param::Plain(cat_status.map_or("any", CatStatus::as_str).to_string()),
+            ),
+            ("limit", Param::Plain(limit.to_string())),
+        ],
+        || {
+            let mut query = cats
+                .select(Cat::as_select())
+                .filter(tenant_id.eq(cat_tenant_id))
+                .filter(pending_review.eq(false))
+                .into_boxed();
+            if let Some(cat_status) = cat_status {
+                query = query.filter(status.eq(cat_status));
+            }
+            query.limit(limit).load(connection)
+        },
+    )
+}
+
+pub fn find_cat(
+    connection: &mut PgConnection,
+    cat_tenant_id: i32,
+    cat_id: i32,
+) -> QueryResult<Cat> {
+    instrumented(
+        "find_cat",
+        &[
+            ("tenant_id", Param::Plain(cat_tenant_id.to_string())),
+            ("id", Param::Plain(cat_id.to_string())),
+        ],
+        || {
+            cats.select(Cat::as_select())
+                .filter(tenant_id.eq(cat_tenant_id))
+                .filter(id.eq(cat_id))
+                .first(connection)
+        },
+    )
+}
+
+pub fn find_cat_by_public_id(
+    connection: &mut PgConnection,
+    cat_tenant_id: i32,
+    cat_public_id: Uuid,
+) -> QueryResult<Cat> {
+    instrumented(
+        "find_cat_by_public_id",
+        &[
+            ("tenant_id", Param::Plain(cat_tenant_id.to_string())),
+            ("public_id", Param::Plain(cat_public_id.to_string())),
+        ],
+        || {
+            cats.select(Cat::as_select())
+                .filter(tenant_id.eq(cat_tenant_id))
+                .filter(public_id.eq(cat_public_id))
+                .first(connection)
+        },
+    )
+}
+
+/// Lists up to `limit` of the tenant's approved cats with ids after
+/// `after_id`, by id, for reading the whole catalog in batches.
+pub fn list_cats_after(
+    connection: &mut PgConnection,
+    cat_tenant_id: i32,
+    cat_status: Option<CatStatus>,
+    after_id: i32,
+    limit: i64,
+) -> QueryResult<Vec<Cat>> {
+    instrumented(
+        "list_cats_after",
+        &[
+            ("tenant_id", Param::Plain(cat_tenant_id.to_string())),
+            (
+                "status",
+                Param::Plain(cat_status.map_or("any", CatStatus::as_str).to_string()),
+            ),
+            ("after_id", Param::Plain(after_id.to_string())),
+            ("limit", Param::Plain(limit.to_string())),
+        ],
+        || {
+            let mut query = cats
+                .select(Cat::as_select())
+                .filter(tenant_id.eq(cat_tenant_id))
+                .filter(pending_review.eq(false))
+                .filter(id.gt(after_id))
+                .into_boxed();
+            if let Some(cat_status) = cat_status {
+                query = query.filter(status.eq(cat_status));
+            }
+            query.order(id.asc()).limit(limit).load(connection)
+        },
+    )
+}
+
+/// Lists the tenant's cats with the given ids, in no particular order.
+/// Lists the tenant's approved cats changed at or after `since`, least
+/// recently changed first.
+pub fn list_cats_changed_since(
+    connection: &mut PgConnection,
+    cat_tenant_id: i32,
+    since: DateTime<Utc>,
+) -> QueryResult<Vec<Cat>> {
+    instrumented(
+        "list_cats_changed_since",
+        &[
+            ("tenant_id", Param::Plain(cat_tenant_id.to_string())),
+            ("since", Param::Plain(since.to_rfc3339())),
+        ],
+        || {
+            cats.select(Cat::as_select())
+                .filter(tenant_id.eq(cat_tenant_id))
+                .filter(pending_review.eq(false))
+                .filter(updated_at.ge(since))
+                .order((updated_at.asc(), id.asc()))
+                .load(connection)
+        },
+    )
+}
+
+/// Lists the tombstones of the tenant's cats deleted at or after `since`.
+pub fn list_cat_tombstones_since(
+    connection: &mut PgConnection,
+    tombstone_tenant_id: i32,
+    since: DateTime<Utc>,
+) -> QueryResult<Vec<CatTombstone>> {
+    instrumented(
+        "list_cat_tombstones_since",
+        &[
+            ("tenant_id", Param::Plain(tombstone_tenant_id.to_string())),
+            ("since", Param::Plain(since.to_rfc3339())),
+        ],
+        || {
+            cat_tombstones::table
+                .select(CatTombstone::as_select())
+                .filter(cat_tombstones::tenant_id.eq(tombstone_tenant_id))
+                .filter(cat_tombstones::deleted_at.ge(since))
+                .order(cat_tombstones::id.asc())
+                .load(connection)
+        },
+    )
+}
+
+/// The database's time, which stamps the changes to cats. Inside a
+/// transaction, the time it started.
+pub fn database_now(connection: &mut PgConnection) -> QueryResult<DateTime<Utc>> {
+    instrumented("database_now", &[], || {
+        diesel::select(sql::<Timestamptz>("now()")).get_result(connection)
+    })
+}
+
+pub fn list_cats_by_ids(
+    connection: &mut PgConnection,
+    cat_tenant_id: i32,
+    cat_ids: &[i32],
+) -> QueryResult<Vec<Cat>> {
+    instrumented(
+        "list_cats_by_ids",
+        &[
+            ("tenant_id", Param::Plain(cat_tenant_id.to_string())),
+            ("ids", Param::Plain(cat_ids.len().to_string())),
+        ],
+        || {
+            cats.select(Cat::as_select())
+                .filter(tenant_id.eq(cat_tenant_id))
+                .filter(id.eq_any(cat_ids))
+                .load(connection)
+        },
+    )
+}
+
+/// Lists the tenant's approved, located cats within `radius_m` metres of the given
+/// point, nearest first, along with their distance in metres.
+///
+/// The bounding box test lets the `cats_location_idx` index narrow the
+/// candidates before exact distances are computed.
+pub fn list_cats_nearby(
+    connection: &mut PgConnection,
+    cat_tenant_id: i32,
+    origin_latitude: f64,
+    origin_longitude: f64,
+    radius_m: f64,
+    limit: i64,
+) -> QueryResult<Vec<(Cat, f64)>> {
+    instrumented(
+        "list_cats_nearby",
+        &[
+            ("tenant_id", Param::Plain(cat_tenant_id.to_string())),
+            ("radius_m", Param::Plain(radius_m.to_string())),
+            ("limit", Param::Plain(limit.to_string())),
+        ],
+        || {
+            let distance = || {
+                sql::<Double>("earth_distance(ll_to_earth(")
+                    .bind::<Double, _>(origin_latitude)
+                    .sql(", ")
+                    .bind::<Double, _>(origin_longitude)
+                    .sql("), ll_to_earth(latitude, longitude))")
+            };
+            let within_box = sql::<Bool>("earth_box(ll_to_earth(")
+                .bind::<Double, _>(origin_latitude)
+                .sql(", ")
+                .bind::<Double, _>(origin_longitude)
+                .sql("), ")
+                .bind::<Double, _>(radius_m)
+                .sql(") @> ll_to_earth(latitude, longitude)");
+            cats.select((Cat::as_select(), distance()))
+                .filter(tenant_id.eq(cat_tenant_id))
+                .filter(pending_review.eq(false))
+                .filter(latitude.is_not_null())
+                .filter(within_box)
+                .filter(distance().le(radius_m))
+                .order_by(distance())
+                .limit(limit)
+                .load(connection)
+        },
+    )
+}
+
+/// The perceptual hash of the cat's image, none when it has not got one.
+pub fn find_cat_phash(
+    connection: &mut PgConnection,
+    cat_tenant_id: i32,
+    cat_id: i32,
+) -> QueryResult<Option<i64>> {
+    instrumented(
+        "find_cat_phash",
+        &[
+            ("tenant_id", Param::Plain(cat_tenant_id.to_string())),
+            ("id", Param::Plain(cat_id.to_string())),
+        ],
+        || {
+            cats.select(image_phash)
+                .filter(tenant_id.eq(cat_tenant_id))
+                .filter(id.eq(cat_id))
+                .first(connection)
+        },
+    )
+}
+
+/// The tenant's approved cats other than `cat_id` whose perceptual hashes differ
+/// from `phash` in at most `max_distance` bits, with that number, most
+/// alike first.
+pub fn list_similar_cats(
+    connection: &mut PgConnection,
+    cat_tenant_id: i32,
+    cat_id: i32,
+    phash: i64,
+    max_distance: i32,
+    limit: i64,
+) -> QueryResult<Vec<(Cat, i32)>> {
+    instrumented(
+        "list_similar_cats",
+        &[
+            ("tenant_id", Param::Plain(cat_tenant_id.to_string())),
+            ("id", Param::Plain(cat_id.to_string())),
+            ("max_distance", Param::Plain(max_distance.to_string())),
+            ("limit", Param::Plain(limit.to_string())),
+        ],
+        || {
+            let distance = || {
+                sql::<Integer>("bit_count((image_phash # ")
+                    .bind::<BigInt, _>(phash)
+                    .sql(")::bit(64))::integer")
+            };
+            cats.select((Cat::as_select(), distance()))
+                .filter(tenant_id.eq(cat_tenant_id))
+                .filter(id.ne(cat_id))
+                .filter(pending_review.eq(false))
+                .filter(image_phash.is_not_null())
+                .filter(distance().le(max_distance))
+                .order_by((distance(), id))
+                .limit(limit)
+                .load(connection)
+        },
+    )
+}
+
+/// Like `find_cat`, but keeps the row locked until the transaction ends.
+pub fn lock_cat(
+    connection: &mut PgConnection,
+    cat_tenant_id: i32,
+    cat_id: i32,
+) -> QueryResult<Cat> {
+    instrumented(
+        "lock_cat",
+        &[
+            ("tenant_id", Param::Plain(cat_tenant_id.to_string())),
+            ("id", Param::Plain(cat_id.to_string())),
+        ],
+        || {
+            cats.select(Cat::as_select())
+                .filter(tenant_id.eq(cat_tenant_id))
+                .filter(id.eq(cat_id))
+                .for_update()
+                .first(connection)
+        },
+    )
+}
+
+pub fn set_cat_status(
+    connection: &mut PgConnection,
+    cat_id: i32,
+    cat_status: CatStatus,
+) -> QueryResult<Cat> {
+    instrumented(
+        "set_cat_status",
+        &[
+            ("id", Param::Plain(cat_id.to_string())),
+            ("status", Param::Plain(cat_status.as_str().to_string())),
+        ],
+        || {
+            diesel::update(cats.find(cat_id))
+                .set(status.eq(cat_status))
+                .returning(Cat::as_returning())
+                .get_result(connection)
+        },
+    )
+}
+
+/// Sets or, with `None`, clears the location of the tenant's cat.
+pub fn set_cat_location(
+    connection: &mut PgConnection,
+    cat_tenant_id: i32,
+    cat_id: i32,
+    location: Option<(f64, f64)>,
+) -> QueryResult<Cat> {
+    instrumented(
+        "set_cat_location",
+        &[
+            ("tenant_id", Param::Plain(cat_tenant_id.to_string())),
+            ("id", Param::Plain(cat_id.to_string())),
+            ("located", Param::Plain(location.is_some().to_string())),
+        ],
+        || {
+            diesel::update(
+                cats.filter(tenant_id.eq(cat_tenant_id))
+                    .filter(id.eq(cat_id)),
+            )
+            .set((
+                latitude.eq(location.map(|(lat, _)| lat)),
+                longitude.eq(location.map(|(_, lon)| lon)),
+            ))
+            .returning(Cat::as_returning())
+            .get_result(connection)
+        },
+    )
+}
+
+/// Lists up to `limit` of the tenant's cats awaiting moderation, oldest
+/// first.
+pub fn list_pending_cats(
+    connection: &mut PgConnection,
+    cat_tenant_id: i32,
+    limit: i64,
+) -> QueryResult<Vec<Cat>> {
+    instrumented(
+        "list_pending_cats",
+        &[
+            ("tenant_id", Param::Plain(cat_tenant_id.to_string())),
+            ("limit", Param::Plain(limit.to_string())),
+        ],
+        || {
+            cats.select(Cat::as_select())
+                .filter(tenant_id.eq(cat_tenant_id))
+                .filter(pending_review.eq(true))
+                .order((created_at.asc(), id.asc()))
+                .limit(limit)
+                .load(connection)
+        },
+    )
+}
+
+/// Takes a cat out of the moderation queue, into listings.
+pub fn approve_cat(connection: &mut PgConnection, cat_id: i32) -> QueryResult<Cat> {
+    instrumented(
+        "approve_cat",
+        &[("id", Param::Plain(cat_id.to_string()))],
+        || {
+            diesel::update(cats.find(cat_id))
+                .set(pending_review.eq(false))
+                .returning(Cat::as_returning())
+                .get_result(connection)
+        },
+    )
+}
+
+/// Deletes a cat along with its relations, medical records and history.
+/// Deletes the cat, leaving a tombstone for clients syncing the cats.
+pub fn delete_cat(connection: &mut PgConnection, cat_id: i32) -> QueryResult<usize> {
+    instrumented(
+        "delete_cat",
+        &[("id", Param::Plain(cat_id.to_string()))],
+        || {
+            let deleted: Option<(i32, Uuid)> = diesel::delete(cats.find(cat_id))
+                .returning((tenant_id, public_id))
+                .get_result(connection)
+                .optional()?;
+            let Some((cat_tenant_id, cat_public_id)) = deleted else {
+                return Ok(0);
+            };
+            diesel::insert_into(cat_tombstones::table)
+                .values((
+                    cat_tombstones::tenant_id.eq(cat_tenant_id),
+                    cat_tombstones::cat_id.eq(cat_id),
+                    cat_tombstones::public_id.eq(cat_public_id),
+                ))
+                .execute(connection)?;
+            Ok(1)
+        },
+    )
+}
+
+/// How many cats and gallery photos, across all tenants, show the stored
+/// file at `path` or keep it as their original.
+pub fn count_image_path_references(connection: &mut PgConnection, path: &str) -> QueryResult<i64> {
+    instrumented(
+        "count_image_path_references",
+        &[("image_path", Param::Redacted(path.len()))],
+        || {
+            let shown: i64 = cats
+                .select(count_star())
+                .filter(image_path.eq(path))
+                .first(connection)?;
+            let in_galleries: i64 = cat_images::table
+                .select(count_star())
+                .filter(
+                    cat_images::image_path
+                        .eq(path)
+                        .or(cat_images::original_path.eq(path)),
+                )
+                .first(connection)?;
+            Ok(shown + in_galleries)
+        },
+    )
+}
+
+/// Finds the cat currently holding `cat_name` under the uniqueness constraint.
+pub fn find_cat_by_unique_name(
+    connection: &mut PgConnection,
+    cat_tenant_id: i32,
+    cat_name: &str,
+) -> QueryResult<Cat> {
+    instrumented(
+        "find_cat_by_unique_name",
+        &[
+            ("tenant_id", Param::Plain(cat_tenant_id.to_string())),
+            ("name", Param::Redacted(cat_name.len())),
+        ],
+        || {
+            cats.select(Cat::as_select())
+                .filter(tenant_id.eq(cat_tenant_id))
+                .filter(lower(name).eq(cat_name.to_lowercase()))
+                .filter(allow_duplicate_name.eq(false))
+                .first(connection)
+        },
+    )
+}
+
+/// Inserts a new cat, returning `None` when its name conflicts with an existing cat.
+///
+/// With `allow_duplicate` set the cat is exempt from the unique name index.
+/// Its image becomes the primary photo of its gallery.
+pub fn insert_cat(
+    connection: &mut PgConnection,
+    cat_tenant_id: i32,
+    new_cat: &NewCat,
+    allow_duplicate: bool,
+) -> QueryResult<Option<Cat>> {
+    instrumented(
+        "insert_cat",
+        &[
+            ("tenant_id", Param::Plain(cat_tenant_id.to_string())),
+            ("name", Param::Redacted(new_cat.name.len())),
+            ("image_path", Param::Redacted(new_cat.image_path.len())),
+            ("allow_duplicate", Param::Plain(allow_duplicate.to_string())),
+        ],
+        || {
+            let cat = diesel::insert_into(cats)
+                .values((
+                    new_cat,
+                    tenant_id.eq(cat_tenant_id),
+                    allow_duplicate_name.eq(allow_duplicate),
+                ))
+                .on_conflict_do_nothing()
+                .returning(Cat::as_returning())
+                .get_result(connection)
+                .optional()?;
+            if let Some(cat) = &cat {
+                let primary = NewCatImage {
+                    tenant_id: cat_tenant_id,
+                    cat_id: cat.id,
+                    image_path: cat.image_path.clone(),
+                    image_hash: cat.image_hash.clone(),
+                    image_size: new_cat.image_size,
+                    image_phash: new_cat.image_phash,
+                    position: 0,
+                    is_primary: true,
+                    created_at: cat.created_at,
+                    image_original_format: new_cat.image_original_format.clone(),
+                };
+                diesel::insert_into(cat_images::table)
+                    .values(&primary)
+                    .execute(connection)?;
+            }
+            Ok(cat)
+        },
+    )
+}
+
+/// The photos of the cat's gallery, in order.
+pub fn list_cat_gallery(
+    connection: &mut PgConnection,
+    image_tenant_id: i32,
+    image_cat_id: i32,
+) -> QueryResult<Vec<CatImage>> {
+    instrumented(
+        "list_cat_gallery",
+        &[
+            ("tenant_id", Param::Plain(image_tenant_id.to_string())),
+            ("cat_id", Param::Plain(image_cat_id.to_string())),
+        ],
+        || {
+            cat_images::table
+                .select(CatImage::as_select())
+                .filter(cat_images::tenant_id.eq(image_tenant_id))
+                .filter(cat_images::cat_id.eq(image_cat_id))
+                .order_by((cat_images::position, cat_images::id))
+                .load(connection)
+        },
+    )
+}
+
+pub fn find_cat_image(
+    connection: &mut PgConnection,
+    image_tenant_id: i32,
+    image_cat_id: i32,
+    image_id: i32,
+) -> QueryResult<CatImage> {
+    instrumented(
+        "find_cat_image",
+        &[
+            ("tenant_id", Param::Plain(image_tenant_id.to_string())),
+            ("cat_id", Param::Plain(image_cat_id.to_string())),
+            ("id", Param::Plain(image_id.to_string())),
+        ],
+        || {
+            cat_images::table
+                .select(CatImage::as_select())
+                .filter(cat_images::tenant_id.eq(image_tenant_id))
+                .filter(cat_images::cat_id.eq(image_cat_id))
+                .filter(cat_images::id.eq(image_id))
+                .first(connection)
+        },
+    )
+}
+
+/// Adds a photo to the end of the cat's gallery.
+pub fn insert_cat_image(
+    connection: &mut PgConnection,
+    new_image: &NewCatImage,
+) -> QueryResult<CatImage> {
+    instrumented(
+        "insert_cat_image",
+        &[
+            ("tenant_id", Param::Plain(new_image.tenant_id.to_string())),
+            ("cat_id", Param::Plain(new_image.cat_id.to_string())),
+            ("image_path", Param::Redacted(new_image.image_path.len())),
+        ],
+        || {
+            diesel::insert_into(cat_images::table)
+                .values(new_image)
+                .returning(CatImage::as_returning())
+                .get_result(connection)
+        },
+    )
+}
+
+/// The position after the last photo of the cat's gallery.
+pub fn next_gallery_position(connection: &mut PgConnection, image_cat_id: i32) -> QueryResult<i32> {
+    instrumented(
+        "next_gallery_position",
+        &[("cat_id", Param::Plain(image_cat_id.to_string()))],
+        || {
+            cat_images::table
+                .filter(cat_images::cat_id.eq(image_cat_id))
+                .select(sql::<Integer>("COALESCE(MAX(position) + 1, 0)"))
+                .first(connection)
+        },
+    )
+}
+
+pub fn delete_cat_image(connection: &mut PgConnection, image_id: i32) -> QueryResult<usize> {
+    instrumented(
+        "delete_cat_image",
+        &[("id", Param::Plain(image_id.to_string()))],
+        || diesel::delete(cat_images::table.find(image_id)).execute(connection),
+    )
+}
+
+/// Puts the photos of the cat's gallery in the order of `image_ids`.
+pub fn set_gallery_order(
+    connection: &mut PgConnection,
+    image_cat_id: i32,
+    image_ids: &[i32],
+) -> QueryResult<()> {
+    instrumented(
+        "set_gallery_order",
+        &[
+            ("cat_id", Param::Plain(image_cat_id.to_string())),
+            ("ids", Param::Plain(image_ids.len().to_string())),
+        ],
+        || {
+            for (image_position, image_id) in (0..).zip(image_ids) {
+                diesel::update(
+                    cat_images::table
+                        .filter(cat_images::cat_id.eq(image_cat_id))
+                        .filter(cat_images::id.eq(image_id)),
+                )
+                .set(cat_images::position.eq(image_position))
+                .execute(connection)?;
+            }
+            Ok(())
+        },
+    )
+}
+
+/// Makes the photo the primary one of its cat's gallery, showing it as the
+/// cat's image.
+pub fn set_primary_cat_image(
+    connection: &mut PgConnection,
+    cat_id: i32,
+    image_id: i32,
+) -> QueryResult<Cat> {
+    instrumented(
+        "set_primary_cat_image",
+        &[
+            ("cat_id", Param::Plain(cat_id.to_string())),
+            ("id", Param::Plain(image_id.to_string())),
+        ],
+        || {
+            diesel::update(cat_images::table.filter(cat_images::cat_id.eq(cat_id)))
+                .filter(cat_images::is_primary)
+                .set(cat_images::is_primary.eq(false))
+                .execute(connection)?;
+            let (primary_path, primary_hash, primary_size, primary_phash) =
+                diesel::update(cat_images::table.find(image_id))
+                    .set(cat_images::is_primary.eq(true))
+                    .returning((
+                        cat_images::image_path,
+                        cat_images::image_hash,
+                        cat_images::image_size,
+                        cat_images::image_phash,
+                    ))
+                    .get_result::<(String, Option<String>, i64, Option<i64>)>(connection)?;
+            diesel::update(cats.find(cat_id))
+                .set((
+                    image_path.eq(primary_path),
+                    image_hash.eq(primary_hash),
+                    image_size.eq(primary_size),
+                    image_phash.eq(primary_phash),
+                ))
+                .returning(Cat::as_returning())
+                .get_result(connection)
+        },
+    )
+}
+
+/// Inserts a relation, returning `None` when it already exists or the cat
+/// already has a parent of that kind.
+pub fn insert_cat_relation(
+    connection: &mut PgConnection,
+    relation: &NewCatRelation,
+) -> QueryResult<Option<CatRelation>> {
+    instrumented(
+        "insert_cat_relation",
+        &[
+            ("tenant_id", Param::Plain(relation.tenant_id.to_string())),
+            ("cat_id", Param::Plain(relation.cat_id.to_string())),
+            (
+                "related_cat_id",
+                Param::Plain(relation.related_cat_id.to_string()),
+            ),
+        ],
+        || {
+            diesel::insert_into(cat_relations::table)
+                .values(relation)
+                .on_conflict_do_nothing()
+                .returning(CatRelation::as_returning())
+                .get_result(connection)
+                .optional()
+        },
+    )
+}
+
+/// Lists the relations with any of `cat_ids` on either end.
+pub fn list_cat_relations(
+    connection: &mut PgConnection,
+    relation_tenant_id: i32,
+    cat_ids: &[i32],
+) -> QueryResult<Vec<CatRelation>> {
+    instrumented(
+        "list_cat_relations",
+        &[
+            ("tenant_id", Param::Plain(relation_tenant_id.to_string())),
+            ("ids", Param::Plain(cat_ids.len().to_string())),
+        ],
+        || {
+            cat_relations::table
+                .select(CatRelation::as_select())
+                .filter(cat_relations::tenant_id.eq(relation_tenant_id))
+                .filter(
+                    cat_relations::cat_id
+                        .eq_any(cat_ids)
+                        .or(cat_relations::related_cat_id.eq_any(cat_ids)),
+                )
+                .order(cat_relations::id)
+                .load(connection)
+        },
+    )
+}
+
+/// Deletes one of the cat's relations, returning the number of rows removed.
+pub fn delete_cat_relation(
+    connection: &mut PgConnection,
+    relation_tenant_id: i32,
+    relation_cat_id: i32,
+    relation_id: i32,
+) -> QueryResult<usize> {
+    instrumented(
+        "delete_cat_relation",
+        &[
+            ("tenant_id", Param::Plain(relation_tenant_id.to_string())),
+            ("cat_id", Param::Plain(relation_cat_id.to_string())),
+            ("id", Param::Plain(relation_id.to_string())),
+        ],
+        || {
+            diesel::delete(
+                cat_relations::table
+                    .filter(cat_relations::tenant_id.eq(relation_tenant_id))
+                    .filter(
+                        cat_relations::cat_id
+                            .eq(relation_cat_id)
+                            .or(cat_relations::related_cat_id.eq(relation_cat_id)),
+                    )
+                    .filter(cat_relations::id.eq(relation_id)),
+            )
+            .execute(connection)
+        },
+    )
+}
+
+pub fn insert_cat_changes(
+    connection: &mut PgConnection,
+    changes: &[NewCatChange],
+) -> QueryResult<usize> {
+    instrumented(
+        "insert_cat_changes",
+        &[("changes", Param::Plain(changes.len().to_string()))],
+        || {
+            diesel::insert_into(cat_history::table)
+                .values(changes)
+                .execute(connection)
+        },
+    )
+}
+
+/// Lists up to `limit` changes to the cat, oldest first, starting after the
+/// change with id `after_id` when given.
+pub fn list_cat_changes(
+    connection: &mut PgConnection,
+    change_tenant_id: i32,
+    change_cat_id: i32,
+    after_id: Option<i64>,
+    limit: i64,
+) -> QueryResult<Vec<CatChange>> {
+    instrumented(
+        "list_cat_changes",
+        &[
+            ("tenant_id", Param::Plain(change_tenant_id.to_string())),
+            ("cat_id", Param::Plain(change_cat_id.to_string())),
+            ("limit", Param::Plain(limit.to_string())),
+        ],
+        || {
+            cat_history::table
+                .select(CatChange::as_select())
+                .filter(cat_history::tenant_id.eq(change_tenant_id))
+                .filter(cat_history::cat_id.eq(change_cat_id))
+                .filter(cat_history::id.gt(after_id.unwrap_or(0)))
+                .order(cat_history::id)
+                .limit(limit)
+                .load(connection)
+        },
+    )
+}
+
+/// Lists the cat's medical records, most recent first.
+pub fn list_medical_records(
+    connection: &mut PgConnection,
+    record_tenant_id: i32,
+    record_cat_id: i32,
+) -> QueryResult<Vec<MedicalRecord>> {
+    instrumented(
+        "list_medical_records",
+        &[
+            ("tenant_id", Param::Plain(record_tenant_id.to_string())),
+            ("cat_id", Param::Plain(record_cat_id.to_string())),
+        ],
+        || {
+            medical_records::table
+                .select(MedicalRecord::as_select())
+                .filter(medical_records::tenant_id.eq(record_tenant_id))
+                .filter(medical_records::cat_id.eq(record_cat_id))
+                .order((medical_records::date.desc(), medical_records::id.desc()))
+                .load(connection)
+        },
+    )
+}
+
+pub fn find_medical_record(
+    connection: &mut PgConnection,
+    record_tenant_id: i32,
+    record_cat_id: i32,
+    record_id: i32,
+) -> QueryResult<MedicalRecord> {
+    instrumented(
+        "find_medical_record",
+        &[
+            ("tenant_id", Param::Plain(record_tenant_id.to_string())),
+            ("cat_id", Param::Plain(record_cat_id.to_string())),
+            ("id", Param::Plain(record_id.to_string())),
+        ],
+        || {
+            medical_records::table
+                .select(MedicalRecord::as_select())
+                .filter(medical_records::tenant_id.eq(record_tenant_id))
+                .filter(medical_records::cat_id.eq(record_cat_id))
+                .filter(medical_records::id.eq(record_id))
+                .first(connection)
+        },
+    )
+}
+
+pub fn insert_medical_record(
+    connection: &mut PgConnection,
+    record_tenant_id: i32,
+    record_cat_id: i32,
+    form: &MedicalRecordForm,
+    attachment: Option<(&str, i64)>,
+) -> QueryResult<MedicalRecord> {
+    instrumented(
+        "insert_medical_record",
+        &[
+            ("tenant_id", Param::Plain(record_tenant_id.to_string())),
+            ("cat_id", Param::Plain(record_cat_id.to_string())),
+            ("notes", Param::Redacted(form.notes.len())),
+        ],
+        || {
+            diesel::insert_into(medical_records::table)
+                .values((
+                    form,
+                    medical_records::tenant_id.eq(record_tenant_id),
+                    medical_records::cat_id.eq(record_cat_id),
+                    medical_records::attachment_path.eq(attachment.map(|(path, _)| path)),
+                    medical_records::attachment_size.eq(attachment.map_or(0, |(_, size)| size)),
+                ))
+                .returning(MedicalRecord::as_returning())
+                .get_result(connection)
+        },
+    )
+}
+
+/// Replaces the fields of a medical record, and its attachment when
+/// `attachment`, its path and size, is given.
+pub fn update_medical_record(
+    connection: &mut PgConnection,
+    record_tenant_id: i32,
+    record_cat_id: i32,
+    record_id: i32,
+    form: &MedicalRecordForm,
+    attachment: Option<(&str, i64)>,
+) -> QueryResult<MedicalRecord> {
+    instrumented(
+        "update_medical_record",
+        &[
+            ("tenant_id", Param::Plain(record_tenant_id.to_string())),
+            ("cat_id", Param::Plain(record_cat_id.to_string())),
+            ("id", Param::Plain(record_id.to_string())),
+            ("notes", Param::Redacted(form.notes.len())),
+        ],
+        || {
+            let record = medical_records::table
+                .filter(medical_records::tenant_id.eq(record_tenant_id))
+                .filter(medical_records::cat_id.eq(record_cat_id))
+                .filter(medical_records::id.eq(record_id));
+            match attachment {
+                Some((path, size)) => diesel::update(record)
+                    .set((
+                        form,
+                        medical_records::attachment_path.eq(path),
+                        medical_records::attachment_size.eq(size),
+                    ))
+                    .returning(MedicalRecord::as_returning())
+                    .get_result(connection),
+                None => diesel::update(record)
+                    .set(form)
+                    .returning(MedicalRecord::as_returning())
+                    .get_result(connection),
+            }
+        },
+    )
+}
+
+/// Deletes a medical record, returning it so its attachment can be removed.
+pub fn delete_medical_record(
+    connection: &mut PgConnection,
+    record_tenant_id: i32,
+    record_cat_id: i32,
+    record_id: i32,
+) -> QueryResult<Option<MedicalRecord>> {
+    instrumented(
+        "delete_medical_record",
+        &[
+            ("tenant_id", Param::Plain(record_tenant_id.to_string())),
+            ("cat_id", Param::Plain(record_cat_id.to_string())),
+            ("id", Param::Plain(record_id.to_string())),
+        ],
+        || {
+            diesel::delete(
+                medical_records::table
+                    .filter(medical_records::tenant_id.eq(record_tenant_id))
+                    .filter(medical_records::cat_id.eq(record_cat_id))
+                    .filter(medical_records::id.eq(record_id)),
+            )
+            .returning(MedicalRecord::as_returning())
+            .get_result(connection)
+            .optional()
+        },
+    )
+}
+
+pub fn insert_upload(connection: &mut PgConnection, new_upload: &NewUpload) -> QueryResult<Upload> {
+    instrumented(
+        "insert_upload",
+        &[
+            ("tenant_id", Param::Plain(new_upload.tenant_id.to_string())),
+            ("size", Param::Plain(new_upload.size.to_string())),
+        ],
+        || {
+            diesel::insert_into(uploads::table)
+                .values(new_upload)
+                .returning(Upload::as_returning())
+                .get_result(connection)
+        },
+    )
+}
+
+/// Finds the tenant's upload by token, unless it has expired by `at`.
+pub fn find_upload(
+    connection: &mut PgConnection,
+    upload_tenant_id: i32,
+    token: Uuid,
+    at: DateTime<Utc>,
+) -> QueryResult<Upload> {
+    instrumented(
+        "find_upload",
+        &[("tenant_id", Param::Plain(upload_tenant_id.to_string()))],
+        || {
+            uploads::table
+                .select(Upload::as_select())
+                .filter(uploads::tenant_id.eq(upload_tenant_id))
+                .filter(uploads::token.eq(token))
+                .filter(uploads::expires_at.gt(at))
+                .first(connection)
+        },
+    )
+}
+
+pub fn set_upload_received(
+    connection: &mut PgConnection,
+    token: Uuid,
+    received: i64,
+) -> QueryResult<Upload> {
+    instrumented(
+        "set_upload_received",
+        &[("received", Param::Plain(received.to_string()))],
+        || {
+            diesel::update(uploads::table.find(token))
+                .set(uploads::received.eq(received))
+                .returning(Upload::as_returning())
+                .get_result(connection)
+        },
+    )
+}
+
+pub fn complete_upload(
+    connection: &mut PgConnection,
+    token: Uuid,
+    key: &str,
+) -> QueryResult<Upload> {
+    instrumented("complete_upload", &[], || {
+        diesel::update(uploads::table.find(token))
+            .set(uploads::image_key.eq(key))
+            .returning(Upload::as_returning())
+            .get_result(connection)
+    })
+}
+
+pub fn delete_upload(connection: &mut PgConnection, token: Uuid) -> QueryResult<usize> {
+    instrumented("delete_upload", &[], || {
+        diesel::delete(uploads::table.find(token)).execute(connection)
+    })
+}
+
+/// Deletes the expired uploads, returning them so their staged files can be
+/// removed too.
+pub fn delete_expired_uploads(
+    connection: &mut PgConnection,
+    at: DateTime<Utc>,
+) -> QueryResult<Vec<Upload>> {
+    instrumented("delete_expired_uploads", &[], || {
+        diesel::delete(uploads::table.filter(uploads::expires_at.le(at)))
+            .returning(Upload::as_returning())
+            .get_results(connection)
+    })
+}
+
+/// Lists the paths of every stored file still referenced, i.e. cat images
+/// and the other photos of their galleries, the originals kept of archived
+/// ones, medical record attachments and completed uploads, across all
+/// tenants.
+pub fn list_image_paths(connection: &mut PgConnection) -> QueryResult<Vec<String>> {
+    instrumented("list_image_paths", &[], || {
+        let mut paths: Vec<String> = cats.select(image_path).load(connection)?;
+        paths.extend(
+            cat_images::table
+                .select(cat_images::image_path)
+                .filter(cat_images::is_primary.eq(false))
+                .load::<String>(connection)?,
+        );
+        paths.extend(
+            cat_images::table
+                .select(cat_images::original_path)
+                .filter(cat_images::original_path.is_not_null())
+                .load::<Option<String>>(connection)?
+                .into_iter()
+                .flatten(),
+        );
+        paths.extend(
+            medical_records::table
+                .select(medical_records::attachment_path)
+                .filter(medical_records::attachment_path.is_not_null())
+                .load::<Option<String>>(connection)?
+                .into_iter()
+                .flatten(),
+        );
+        paths.extend(
+            uploads::table
+                .select(uploads::image_key)
+                .filter(uploads::image_key.is_not_null())
+                .load::<Option<String>>(connection)?
+                .into_iter()
+                .flatten()
+                .map(|key| format!("/image/{}", key)),
+        );
+        Ok(paths)
+    })
+}
+
+/// Points every reference to the stored file at `old_path` to `new_path`,
+/// as listed by `list_image_paths`.
+pub fn rename_image_path(
+    connection: &mut PgConnection,
+    old_path: &str,
+    new_path: &str,
+) -> QueryResult<usize> {
+    instrumented("rename_image_path", &[], || {
+        let mut renamed = diesel::update(cats.filter(image_path.eq(old_path)))
+            .set(image_path.eq(new_path))
+            .execute(connection)?;
+        renamed += diesel::update(cat_images::table.filter(cat_images::image_path.eq(old_path)))
+            .set(cat_images::image_path.eq(new_path))
+            .execute(connection)?;
+        renamed += diesel::update(cat_images::table.filter(cat_images::original_path.eq(old_path)))
+            .set(cat_images::original_path.eq(new_path))
+            .execute(connection)?;
+        renamed += diesel::update(
+            medical_records::table.filter(medical_records::attachment_path.eq(old_path)),
+        )
+        .set(medical_records::attachment_path.eq(new_path))
+        .execute(connection)?;
+        if let (Some(old_key), Some(new_key)) = (
+            old_path.strip_prefix("/image/"),
+            new_path.strip_prefix("/image/"),
+        ) {
+            renamed += diesel::update(uploads::table.filter(uploads::image_key.eq(old_key)))
+                .set(uploads::image_key.eq(new_key))
+                .execute(connection)?;
+        }
+        Ok(renamed)
+    })
+}
+
+/// The ids and image paths of up to `limit` cats of any tenant after the
+/// cat with `after_id`, in id order, for walking every cat in batches.
+pub fn list_cat_paths_after(
+    connection: &mut PgConnection,
+    after_id: i32,
+    limit: i64,
+) -> QueryResult<Vec<(i32, String)>> {
+    instrumented(
+        "list_cat_paths_after",
+        &[
+            ("after_id", Param::Plain(after_id.to_string())),
+            ("limit", Param::Plain(limit.to_string())),
+        ],
+        || {
+            cats.select((id, image_path))
+                .filter(id.gt(after_id))
+                .order_by(id)
+                .limit(limit)
+                .load(connection)
+        },
+    )
+}
+
+/// The ids and paths of up to `limit` gallery photos of any tenant, other
+/// than the primary ones mirrored on their cats, after the photo with
+/// `after_id`, in id order.
+pub fn list_gallery_photos_after(
+    connection: &mut PgConnection,
+    after_id: i32,
+    limit: i64,
+) -> QueryResult<Vec<(i32, String)>> {
+    instrumented(
+        "list_gallery_photos_after",
+        &[
+            ("after_id", Param::Plain(after_id.to_string())),
+            ("limit", Param::Plain(limit.to_string())),
+        ],
+        || {
+            cat_images::table
+                .select((cat_images::id, cat_images::image_path))
+                .filter(cat_images::is_primary.eq(false))
+                .filter(cat_images::id.gt(after_id))
+                .order_by(cat_images::id)
+                .limit(limit)
+                .load(connection)
+        },
+    )
+}
+
+/// The ids and paths of up to `limit` gallery images added before
+/// `created_before` that were never archived, after the image with
+/// `after_id`, in id order.
+pub fn list_unarchived_images(
+    connection: &mut PgConnection,
+    created_before: DateTime<Utc>,
+    after_id: i32,
+    limit: i64,
+) -> QueryResult<Vec<(i32, String)>> {
+    instrumented(
+        "list_unarchived_images",
+        &[
+            ("created_before", Param::Plain(created_before.to_rfc3339())),
+            ("after_id", Param::Plain(after_id.to_string())),
+            ("limit", Param::Plain(limit.to_string())),
+        ],
+        || {
+            cat_images::table
+                .select((cat_images::id, cat_images::image_path))
+                .filter(cat_images::archived_at.is_null())
+                .filter(cat_images::created_at.lt(created_before))
+                .filter(cat_images::id.gt(after_id))
+                .order_by(cat_images::id)
+                .limit(limit)
+                .load(connection)
+        },
+    )
+}
+
+/// Marks the image `image_id` as handled by the archival job, leaving it
+/// as it is.
+pub fn set_image_archived(
+    connection: &mut PgConnection,
+    image_id: i32,
+    archived_at: DateTime<Utc>,
+) -> QueryResult<()> {
+    instrumented(
+        "set_image_archived",
+        &[("id", Param::Plain(image_id.to_string()))],
+        || {
+            diesel::update(cat_images::table.filter(cat_images::id.eq(image_id)))
+                .set(cat_images::archived_at.eq(archived_at))
+                .execute(connection)
+                .map(|_| ())
+        },
+    )
+}
+
+/// Points every reference to the image at `old_path` to its archived
+/// version at `new_path`, `new_size` bytes with the version `new_hash`,
+/// recording the original kept at `original_path`. Returns false, changing
+/// nothing, when nothing refers to `old_path` any more.
+///
+/// Call this inside a transaction, so all references move at once.
+#[allow(clippy::too_many_arguments)]
+pub fn archive_image_path(
+    connection: &mut PgConnection,
+    old_path: &str,
+    new_path: &str,
+    new_hash: &str,
+    new_size: i64,
+    original_path: Option<&str>,
+    archived_at: DateTime<Utc>,
+) -> QueryResult<bool> {
+    if rename_image_path(connection, old_path, new_path)? == 0 {
+        return Ok(false);
+    }
+    instrumented("archive_image_path", &[], || {
+        diesel::update(cat_images::table.filter(cat_images::image_path.eq(new_path)))
+            .set((
+                cat_images::image_hash.eq(new_hash),
+                cat_images::image_size.eq(new_size),
+                cat_images::archived_at.eq(archived_at),
+                cat_images::original_path.eq(original_path),
+            ))
+            .execute(connection)?;
+        diesel::update(cats.filter(image_path.eq(new_path)))
+            .set((image_hash.eq(new_hash), image_size.eq(new_size)))
+            .execute(connection)?;
+        Ok(true)
+    })
+}
+
+/// Replaces the cat's perceptual hash and that of the primary photo of its
+/// gallery it mirrors, returning whether either changed.
+pub fn set_cat_phash(
+    connection: &mut PgConnection,
+    cat_id: i32,
+    phash: Option<i64>,
+) -> QueryResult<bool> {
+    instrumented(
+        "set_cat_phash",
+        &[("id", Param::Plain(cat_id.to_string()))],
+        || {
+            let updated = diesel::update(cats.filter(id.eq(cat_id)))
+                .filter(image_phash.is_distinct_from(phash))
+                .set(image_phash.eq(phash))
+                .execute(connection)?;
+            let primary_updated =
+                diesel::update(cat_images::table.filter(cat_images::cat_id.eq(cat_id)))
+                    .filter(cat_images::is_primary)
+                    .filter(cat_images::image_phash.is_distinct_from(phash))
+                    .set(cat_images::image_phash.eq(phash))
+                    .execute(connection)?;
+            Ok(updated + primary_updated > 0)
+        },
+    )
+}
+
+/// Replaces the perceptual hash of a photo of a gallery, returning whether
+/// it changed.
+pub fn set_cat_image_phash(
+    connection: &mut PgConnection,
+    image_id: i32,
+    phash: Option<i64>,
+) -> QueryResult<bool> {
+    instrumented(
+        "set_cat_image_phash",
+        &[("id", Param::Plain(image_id.to_string()))],
+        || {
+            diesel::update(cat_images::table.find(image_id))
+                .filter(cat_images::image_phash.is_distinct_from(phash))
+                .set(cat_images::image_phash.eq(phash))
+                .execute(connection)
+                .map(|updated| updated > 0)
+        },
+    )
+}
+
+/// Rebuilds the indexes of the cats table without locking out writes.
+pub fn reindex_cats(connection: &mut PgConnection) -> QueryResult<()> {
+    instrumented("reindex_cats", &[], || {
+        diesel::sql_query("REINDEX TABLE CONCURRENTLY cats")
+            .execute(connection)
+            .map(|_| ())
+    })
+}
+
+/// The path a cat's photo was moved to by `shard-images`, found by its
+/// directory and file name, which stay the same.
+pub fn find_sharded_image_path(
+    connection: &mut PgConnection,
+    dir: &str,
+    file_name: &str,
+) -> QueryResult<Option<String>> {
+    let escape = |value: &str| {
+        value
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_")
+    };
+    let pattern = format!("/image/{}/____/__/__/{}", escape(dir), escape(file_name));
+    instrumented(
+        "find_sharded_image_path",
+        &[("name", Param::Plain(file_name.to_string()))],
+        || {
+            cat_images::table
+                .select(cat_images::image_path)
+                .filter(cat_images::image_path.like(pattern))
+                .first(connection)
+                .optional()
+        },
+    )
+}
+
+pub fn find_quota(
+    connection: &mut PgConnection,
+    quota_tenant_id: i32,
+) -> QueryResult<Option<Quota>> {
+    instrumented(
+        "find_quota",
+        &[("tenant_id", Param::Plain(quota_tenant_id.to_string()))],
+        || {
+            quotas::table
+                .select(Quota::as_select())
+                .find(quota_tenant_id)
+                .first(connection)
+                .optional()
+        },
+    )
+}
+
+/// Like `find_quota`, but keeps the row locked until the transaction ends.
+pub fn lock_quota(
+    connection: &mut PgConnection,
+    quota_tenant_id: i32,
+) -> QueryResult<Option<Quota>> {
+    instrumented(
+        "lock_quota",
+        &[("tenant_id", Param::Plain(quota_tenant_id.to_string()))],
+        || {
+            quotas::table
+                .select(Quota::as_select())
+                .find(quota_tenant_id)
+                .for_update()
+                .first(connection)
+                .optional()
+        },
+    )
+}
+
+/// Sets the tenant's limits, replacing any previous ones.
+pub fn set_quota(
+    connection: &mut PgConnection,
+    quota_tenant_id: i32,
+    quota: Quota,
+) -> QueryResult<usize> {
+    instrumented(
+        "set_quota",
+        &[("tenant_id", Param::Plain(quota_tenant_id.to_string()))],
+        || {
+            let values = (
+                quotas::max_cats.eq(quota.max_cats),
+                quotas::max_storage_bytes.eq(quota.max_storage_bytes),
+            );
+            diesel::insert_into(quotas::table)
+                .values((quotas::tenant_id.eq(quota_tenant_id), values))
+                .on_conflict(quotas::tenant_id)
+                .do_update()
+                .set(values)
+                .execute(connection)
+        },
+    )
+}
+
+/// Counts the tenant's cats and the bytes taken by their images.
+/// Brings the `cat_stats` view up to date, without blocking reads of it.
+pub fn refresh_cat_stats(connection: &mut PgConnection) -> QueryResult<()> {
+    instrumented("refresh_cat_stats", &[], || {
+        diesel::sql_query("REFRESH MATERIALIZED VIEW CONCURRENTLY cat_stats")
+            .execute(connection)
+            .map(|_| ())
+    })
+}
+
+/// The tenant's statistics of the days from `since`, oldest first.
+pub fn list_cat_stats(
+    connection: &mut PgConnection,
+    stats_tenant_id: i32,
+    since: NaiveDate,
+) -> QueryResult<Vec<CatStats>> {
+    instrumented(
+        "list_cat_stats",
+        &[
+            ("tenant_id", Param::Plain(stats_tenant_id.to_string())),
+            ("since", Param::Plain(since.to_string())),
+        ],
+        || {
+            cat_stats::table
+                .select(CatStats::as_select())
+                .filter(cat_stats::tenant_id.eq(stats_tenant_id))
+                .filter(cat_stats::day.ge(since))
+                .order_by((cat_stats::day, cat_stats::status))
+                .load(connection)
+        },
+    )
+}
+
+/// How many cats the tenant has, and the bytes of all their photos and
+/// medical record attachments.
+pub fn quota_usage(connection: &mut PgConnection, cat_tenant_id: i32) -> QueryResult<(i64, i64)> {
+    instrumented(
+        "quota_usage",
+        &[("tenant_id", Param::Plain(cat_tenant_id.to_string()))],
+        || {
+            let cat_count = cats
+                .filter(tenant_id.eq(cat_tenant_id))
+                .select(count_star())
+                .first(connection)?;
+            let image_bytes: i64 = cat_images::table
+                .filter(cat_images::tenant_id.eq(cat_tenant_id))
+                .select(sql::<BigInt>("COALESCE(SUM(image_size), 0)::BIGINT"))
+                .first(connection)?;
+            let attachment_bytes: i64 = medical_records::table
+                .filter(medical_records::tenant_id.eq(cat_tenant_id))
+                .select(sql::<BigInt>("COALESCE(SUM(attachment_size), 0)::BIGINT"))
+                .first(connection)?;
+            Ok((cat_count, image_bytes + attachment_bytes))
+        },
+    )
+}
+
+/// Inserts an admin, returning `None` when the username is already taken in the tenant.
+pub fn insert_admin(
+    connection: &mut PgConnection,
+    new_admin: &NewAdmin,
+) -> QueryResult<Option<i32>> {
+    instrumented(
+        "insert_admin",
+        &[
+            ("tenant_id", Param::Plain(new_admin.tenant_id.to_string())),
+            ("username", Param::Redacted(new_admin.username.len())),
+        ],
+        || {
+            diesel::insert_into(admins::table)
+                .values(new_admin)
+                .on_conflict_do_nothing()
+                .returning(admins::id)
+                .get_result(connection)
+                .optional()
+        },
+    )
+}
+
+pub fn find_admin_password_hash(
+    connection: &mut PgConnection,
+    admin_tenant_id: i32,
+    admin_username: &str,
+) -> QueryResult<Option<String>> {
+    instrumented(
+        "find_admin_password_hash",
+        &[
+            ("tenant_id", Param::Plain(admin_tenant_id.to_string())),
+            ("username", Param::Redacted(admin_username.len())),
+        ],
+        || {
+            admins::table
+                .select(admins::password_hash)
+                .filter(admins::tenant_id.eq(admin_tenant_id))
+                .filter(admins::username.eq(admin_username))
+                .first(connection)
+                .optional()
+        },
+    )
+}
+
+pub fn insert_webhook(
+    connection: &mut PgConnection,
+    new_webhook: &NewWebhook,
+) -> QueryResult<Webhook> {
+    instrumented(
+        "insert_webhook",
+        &[
+            ("tenant_id", Param::Plain(new_webhook.tenant_id.to_string())),
+            ("url", Param::Redacted(new_webhook.url.len())),
+        ],
+        || {
+            diesel::insert_into(webhooks::table)
+                .values(new_webhook)
+                .returning(Webhook::as_returning())
+                .get_result(connection)
+        },
+    )
+}
+
+pub fn list_webhooks(
+    connection: &mut PgConnection,
+    webhook_tenant_id: i32,
+) -> QueryResult<Vec<Webhook>> {
+    instrumented(
+        "list_webhooks",
+        &[("tenant_id", Param::Plain(webhook_tenant_id.to_string()))],
+        || {
+            webhooks::table
+                .select(Webhook::as_select())
+                .filter(webhooks::tenant_id.eq(webhook_tenant_id))
+                .order(webhooks::id)
+                .load(connection)
+        },
+    )
+}
+
+/// Lists the tenant's webhooks subscribed to `event`.
+pub fn list_webhooks_for_event(
+    connection: &mut PgConnection,
+    webhook_tenant_id: i32,
+    event: &str,
+) -> QueryResult<Vec<Webhook>> {
+    instrumented(
+        "list_webhooks_for_event",
+        &[
+            ("tenant_id", Param::Plain(webhook_tenant_id.to_string())),
+            ("event", Param::Plain(event.to_string())),
+        ],
+        || {
+            webhooks::table
+                .select(Webhook::as_select())
+                .filter(webhooks::tenant_id.eq(webhook_tenant_id))
+                .filter(webhooks::events.contains(vec![event]))
+                .load(connection)
+        },
+    )
+}
+
+/// Deletes a webhook along with its delivery log, returning the number of rows removed.
+pub fn delete_webhook(
+    connection: &mut PgConnection,
+    webhook_tenant_id: i32,
+    webhook_id: i32,
+) -> QueryResult<usize> {
+    instrumented(
+        "delete_webhook",
+        &[
+            ("tenant_id", Param::Plain(webhook_tenant_id.to_string())),
+            ("id", Param::Plain(webhook_id.to_string())),
+        ],
+        || {
+            diesel::delete(
+                webhooks::table
+                    .filter(webhooks::tenant_id.eq(webhook_tenant_id))
+                    .filter(webhooks::id.eq(webhook_id)),
+            )
+            .execute(connection)
+        },
+    )
+}
+
+pub fn insert_webhook_delivery(
+    connection: &mut PgConnection,
+    delivery: &NewWebhookDelivery,
+) -> QueryResult<usize> {
+    instrumented(
+        "insert_webhook_delivery",
+        &[
+            ("webhook_id", Param::Plain(delivery.webhook_id.to_string())),
+            ("attempt", Param::Plain(delivery.attempt.to_string())),
+        ],
+        || {
+            diesel::insert_into(webhook_deliveries::table)
+                .values(delivery)
+                .execute(connection)
+        },
+    )
+}
+
+/// Lists the most recent delivery attempts for a webhook, newest first.
+pub fn list_webhook_deliveries(
+    connection: &mut PgConnection,
+    webhook_tenant_id: i32,
+    webhook_id: i32,
+    limit: i64,
+) -> QueryResult<Vec<WebhookDelivery>> {
+    instrumented(
+        "list_webhook_deliveries",
+        &[
+            ("tenant_id", Param::Plain(webhook_tenant_id.to_string())),
+            ("webhook_id", Param::Plain(webhook_id.to_string())),
+            ("limit", Param::Plain(limit.to_string())),
+        ],
+        || {
+            webhook_deliveries::table
+                .inner_join(webhooks::table)
+                .select(WebhookDelivery::as_select())
+                .filter(webhooks::tenant_id.eq(webhook_tenant_id))
+                .filter(webhook_deliveries::webhook_id.eq(webhook_id))
+                .order(webhook_deliveries::id.desc())
+                .limit(limit)
+                .load(connection)
+        },
+    )
+}
+
+pub fn insert_outbox_event(
+    connection: &mut PgConnection,
+    event: &NewOutboxEvent,
+) -> QueryResult<usize> {
+    instrumented(
+        "insert_outbox_event",
+        &[
+            ("tenant_id", Param::Plain(event.tenant_id.to_string())),
+            ("event", Param::Plain(event.event.clone())),
+        ],
+        || {
+            diesel::insert_into(outbox::table)
+                .values(event)
+                .execute(connection)
+        },
+    )
+}
+
+/// Claims the oldest outbox events that have not been dispatched yet, nor
+/// claimed by a dispatcher whose lease has not run out, for `lease`. Rows
+/// other dispatchers are claiming are skipped rather than waited for.
+pub fn claim_pending_outbox_events(
+    connection: &mut PgConnection,
+    limit: i64,
+    lease: Duration,
+) -> QueryResult<Vec<OutboxEvent>> {
+    instrumented(
+        "claim_pending_outbox_events",
+        &[
+            ("limit", Param::Plain(limit.to_string())),
+            ("lease", Param::Plain(format!("{:?}", lease))),
+        ],
+        || {
+            let mut events = connection.transaction(|connection| {
+                let claimable: Vec<i64> = outbox::table
+                    .select(outbox::id)
+                    .filter(outbox::dispatched_at.is_null())
+                    .filter(
+                        outbox::claimed_until
+                            .is_null()
+                            .or(outbox::claimed_until.lt(now)),
+                    )
+                    .order(outbox::id)
+                    .limit(limit)
+                    .for_update()
+                    .skip_locked()
+                    .load(connection)?;
+                let claimed_until = sql::<Nullable<Timestamptz>>("now() + make_interval(secs => ")
+                    .bind::<Double, _>(lease.as_secs_f64())
+                    .sql(")");
+                diesel::update(outbox::table.filter(outbox::id.eq_any(claimable)))
+                    .set(outbox::claimed_until.eq(claimed_until))
+                    .returning(OutboxEvent::as_returning())
+                    .get_results::<OutboxEvent>(connection)
+            })?;
+            events.sort_by_key(|event| event.id);
+            Ok(events)
+        },
+    )
+}
+
+pub fn mark_outbox_event_dispatched(
+    connection: &mut PgConnection,
+    event_id: i64,
+) -> QueryResult<usize> {
+    instrumented(
+        "mark_outbox_event_dispatched",
+        &[("id", Param::Plain(event_id.to_string()))],
+        || {
+            diesel::update(outbox::table.find(event_id))
+                .set(outbox::dispatched_at.eq(now))
+                .execute(connection)
+        },
+    )
+}
+
+/// The tables holding data, each after the tables it refers to, so they
+/// can be restored in this order.
+pub const DATA_TABLES: &[&str] = &[
+    "tenants",
+    "admins",
+    "quotas",
+    "cats",
+    "cat_images",
+    "cat_tombstones",
+    "cat_relations",
+    "medical_records",
+    "cat_history",
+    "uploads",
+    "webhooks",
+    "webhook_deliveries",
+    "outbox",
+];
+
+/// The data tables with a serial `id`.
+const SERIAL_TABLES: &[&str] = &[
+    "tenants",
+    "admins",
+    "cats",
+    "cat_images",
+    "cat_tombstones",
+    "cat_relations",
+    "medical_records",
+    "cat_history",
+    "webhooks",
+    "webhook_deliveries",
+    "outbox",
+];
+
+fn check_data_table(table: &str) -> QueryResult<()> {
+    if DATA_TABLES.contains(&table) {
+        Ok(())
+    } else {
+        Err(diesel::result::Error::QueryBuilderError(
+            format!("{} is not a data table", table).into(),
+        ))
+    }
+}
+
+#[derive(diesel::QueryableByName)]
+struct JsonRow {
+    #[diesel(sql_type = Text)]
+    row: String,
+}
+
+/// Rows loaded into a data table per statement.
+const LOAD_BATCH: usize = 500;
+
+/// Writes every row of the data table `table` to `out` as JSON, one row
+/// per line, returning how many were written. Rows are streamed rather
+/// than loaded at once.
+pub fn dump_table(
+    connection: &mut PgConnection,
+    table: &str,
+    out: &mut dyn Write,
+) -> QueryResult<usize> {
+    instrumented(
+        "dump_table",
+        &[("table", Param::Plain(table.to_string()))],
+        || {
+            check_data_table(table)?;
+            let rows = diesel::sql_query(format!(
+                "SELECT row_to_json(t)::text AS row FROM {} t",
+                table
+            ))
+            .load_iter::<JsonRow, PgRowByRowLoadingMode>(connection)?;
+            let mut written = 0;
+            for row in rows {
+                writeln!(out, "{}", row?.row)
+                    .map_err(|e| diesel::result::Error::SerializationError(Box::new(e)))?;
+                written += 1;
+            }
+            Ok(written)
+        },
+    )
+}
+
+/// Loads rows written by `dump_table` into the data table `table`,
+/// returning how many were loaded.
+pub fn load_table(
+    connection: &mut PgConnection,
+    table: &str,
+    input: &mut dyn BufRead,
+) -> QueryResult<usize> {
+    instrumented(
+        "load_table",
+        &[("table", Param::Plain(table.to_string()))],
+        || {
+            check_data_table(table)?;
+            let insert = format!(
+                "INSERT INTO {table} SELECT * FROM json_populate_recordset(NULL::{table}, $1::json)"
+            );
+            let mut loaded = 0;
+            let mut lines = input.lines();
+            loop {
+                let batch = lines
+                    .by_ref()
+                    .take(LOAD_BATCH)
+                    .collect::<io::Result<Vec<String>>>()
+                    .map_err(|e| diesel::result::Error::DeserializationError(Box::new(e)))?;
+                if batch.is_empty() {
+                    return Ok(loaded);
+                }
+                loaded += diesel::sql_query(&insert)
+                    .bind::<Text, _>(format!("[{}]", batch.join(",")))
+                    .execute(connection)?;
+            }
+        },
+    )
+}
+
+/// Deletes every row of every data table.
+pub fn truncate_data_tables(connection: &mut PgConnection) -> QueryResult<()> {
+    instrumented("truncate_data_tables", &[], || {
+        diesel::sql_query(format!("TRUNCATE {} CASCADE", DATA_TABLES.join(", ")))
+            .execute(connection)
+            .map(|_| ())
+    })
+}
+
+/// Points the id sequences of the data tables past their highest ids, as
+/// after rows are loaded with their ids.
+pub fn reset_id_sequences(connection: &mut PgConnection) -> QueryResult<()> {
+    instrumented("reset_id_sequences", &[], || {
+        for table in SERIAL_TABLES {
+            diesel::sql_query(format!(
+                "SELECT setval(pg_get_serial_sequence('{table}', 'id'), \
+                 COALESCE(MAX(id), 0) + 1, false) FROM {table}"
+            ))
+            .execute(connection)?;
+        }
+        Ok(())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_params_redacts_user_content() {
+        let params = [
+            ("id", Param::Plain("7".to_string())),
+            ("name", Param::Redacted("Whiskers".len())),
+        ];
+        assert_eq!(format_params(&params), "id=7, name=<redacted, 8 bytes>");
+    }
+}
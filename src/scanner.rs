@@ -0,0 +1,173 @@
+use crate::config::Config;
+use crate::telemetry;
+use log::warn;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// Chunk size for streaming files to clamd, well below its default
+/// `StreamMaxLength`.
+const CLAMD_CHUNK_SIZE: usize = 64 * 1024;
+
+#[derive(Debug, PartialEq)]
+pub enum Verdict {
+    Clean,
+    /// Carries the name of the detected signature.
+    Infected(String),
+}
+
+/// Checks uploads for malware before they reach the image store.
+pub trait Scanner: Send + Sync {
+    fn scan(&self, path: &Path) -> io::Result<Verdict>;
+}
+
+/// Streams files to a clamd daemon over its Unix socket.
+pub struct ClamdScanner {
+    socket: PathBuf,
+}
+
+impl ClamdScanner {
+    pub fn new(socket: impl Into<PathBuf>) -> ClamdScanner {
+        ClamdScanner {
+            socket: socket.into(),
+        }
+    }
+}
+
+/// Interprets a clamd `INSTREAM` reply such as `stream: OK` or
+/// `stream: Eicar-Signature FOUND`.
+fn parse_clamd_reply(reply: &str) -> io::Result<Verdict> {
+    let result = reply.trim_end_matches(['\0', '\n']);
+    let result = result.strip_prefix("stream: ").unwrap_or(result);
+    if result == "OK" {
+        Ok(Verdict::Clean)
+    } else if let Some(signature) = result.strip_suffix(" FOUND") {
+        Ok(Verdict::Infected(signature.to_string()))
+    } else {
+        Err(io::Error::other(format!("clamd: {}", result)))
+    }
+}
+
+impl Scanner for ClamdScanner {
+    fn scan(&self, path: &Path) -> io::Result<Verdict> {
+        let mut file = File::open(path)?;
+        let mut stream = UnixStream::connect(&self.socket)?;
+        stream.write_all(b"zINSTREAM\0")?;
+        let mut chunk = vec![0; CLAMD_CHUNK_SIZE];
+        loop {
+            let read = file.read(&mut chunk)?;
+            stream.write_all(&(read as u32).to_be_bytes())?;
+            if read == 0 {
+                break;
+            }
+            stream.write_all(&chunk[..read])?;
+        }
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply)?;
+        parse_clamd_reply(&reply)
+    }
+}
+
+/// Runs an external command with the file path appended to its arguments,
+/// following the `clamscan` convention: exit status 0 means clean, 1 means
+/// infected and anything else is an error.
+pub struct CommandScanner {
+    program: String,
+    args: Vec<String>,
+}
+
+impl CommandScanner {
+    /// Splits `command` on whitespace into the program and its arguments.
+    pub fn new(command: &str) -> Option<CommandScanner> {
+        let mut words = command.split_whitespace().map(str::to_string);
+        Some(CommandScanner {
+            program: words.next()?,
+            args: words.collect(),
+        })
+    }
+}
+
+impl Scanner for CommandScanner {
+    fn scan(&self, path: &Path) -> io::Result<Verdict> {
+        let output = process::Command::new(&self.program)
+            .args(&self.args)
+            .arg(path)
+            .output()?;
+        match output.status.code() {
+            Some(0) => Ok(Verdict::Clean),
+            Some(1) => Ok(Verdict::Infected(
+                String::from_utf8_lossy(&output.stdout).trim().to_string(),
+            )),
+            _ => Err(io::Error::other(format!(
+                "{} failed with {}: {}",
+                self.program,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            ))),
+        }
+    }
+}
+
+/// The scanner configured through `CLAMD_SOCKET` or `SCAN_COMMAND`, if any,
+/// preferring clamd.
+pub fn from_config(config: &Config) -> Option<Arc<dyn Scanner>> {
+    if let Some(socket) = &config.clamd_socket {
+        return Some(Arc::new(ClamdScanner::new(socket)));
+    }
+    let scanner = CommandScanner::new(config.scan_command.as_deref()?)?;
+    Some(Arc::new(scanner))
+}
+
+/// Scans an upload, moving it into `quarantine_dir` when it is infected.
+/// Clean uploads are handed back untouched.
+pub fn screen_upload(
+    scanner: &dyn Scanner,
+    file: awmp::File,
+    quarantine_dir: &Path,
+) -> io::Result<Result<awmp::File, String>> {
+    let path = file.as_ref().path();
+    let verdict = telemetry::in_span("image scan", Vec::new(), || {
+        scanner.scan(path).inspect_err(telemetry::record_error)
+    })?;
+    let signature = match verdict {
+        Verdict::Clean => return Ok(Ok(file)),
+        Verdict::Infected(signature) => signature,
+    };
+    fs::create_dir_all(quarantine_dir)?;
+    let path = quarantine_dir.join(format!("{}-{}", Uuid::new_v4(), file.sanitized_file_name()));
+    file.persist_at(&path).map_err(io::Error::other)?;
+    warn!("Quarantined upload {} ({})", path.display(), signature);
+    Ok(Err(signature))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_clamd_reply() {
+        assert_eq!(parse_clamd_reply("stream: OK\0").unwrap(), Verdict::Clean);
+        assert_eq!(
+            parse_clamd_reply("stream: Eicar-Signature FOUND\0").unwrap(),
+            Verdict::Infected("Eicar-Signature".to_string())
+        );
+        assert!(parse_clamd_reply("INSTREAM size limit exceeded. ERROR\0").is_err());
+    }
+
+    #[test]
+    fn test_command_scanner_exit_status() {
+        let path = Path::new("Cargo.toml");
+        let clean = CommandScanner::new("true").unwrap();
+        assert_eq!(clean.scan(path).unwrap(), Verdict::Clean);
+        let infected = CommandScanner::new("false").unwrap();
+        assert_eq!(
+            infected.scan(path).unwrap(),
+            Verdict::Infected(String::new())
+        );
+        assert!(CommandScanner::new("  ").is_none());
+    }
+}
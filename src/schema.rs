@@ -1,9 +1,239 @@
 // @generated automatically by Diesel CLI.
 
+pub mod sql_types {
+    #[derive(diesel::query_builder::QueryId, Clone, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "cat_relation_kind"))]
+    pub struct CatRelationKind;
+
+    #[derive(diesel::query_builder::QueryId, Clone, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "cat_status"))]
+    pub struct CatStatus;
+
+    #[derive(diesel::query_builder::QueryId, Clone, diesel::sql_types::SqlType)]
+    #[diesel(postgres_type(name = "medical_record_type"))]
+    pub struct MedicalRecordType;
+}
+
+diesel::table! {
+    admins (id) {
+        id -> Int4,
+        tenant_id -> Int4,
+        username -> Varchar,
+        password_hash -> Varchar,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    cat_history (id) {
+        id -> Int8,
+        tenant_id -> Int4,
+        cat_id -> Int4,
+        field -> Varchar,
+        old_value -> Nullable<Jsonb>,
+        new_value -> Jsonb,
+        actor -> Varchar,
+        changed_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    cat_images (id) {
+        id -> Int4,
+        tenant_id -> Int4,
+        cat_id -> Int4,
+        image_path -> Varchar,
+        image_hash -> Nullable<Varchar>,
+        image_size -> Int8,
+        image_phash -> Nullable<Int8>,
+        position -> Int4,
+        is_primary -> Bool,
+        created_at -> Timestamptz,
+        archived_at -> Nullable<Timestamptz>,
+        original_path -> Nullable<Varchar>,
+        image_original_format -> Nullable<Varchar>,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::CatRelationKind;
+
+    cat_relations (id) {
+        id -> Int4,
+        tenant_id -> Int4,
+        cat_id -> Int4,
+        related_cat_id -> Int4,
+        kind -> CatRelationKind,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    cat_tombstones (id) {
+        id -> Int8,
+        tenant_id -> Int4,
+        cat_id -> Int4,
+        public_id -> Uuid,
+        deleted_at -> Timestamptz,
+    }
+}
+
 diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::CatStatus;
+
     cats (id) {
         id -> Int4,
         name -> Varchar,
         image_path -> Varchar,
+        allow_duplicate_name -> Bool,
+        public_id -> Uuid,
+        tenant_id -> Int4,
+        created_at -> Timestamptz,
+        image_size -> Int8,
+        private -> Bool,
+        status -> CatStatus,
+        latitude -> Nullable<Float8>,
+        longitude -> Nullable<Float8>,
+        image_hash -> Nullable<Varchar>,
+        image_phash -> Nullable<Int8>,
+        pending_review -> Bool,
+        updated_at -> Timestamptz,
+        image_original_format -> Nullable<Varchar>,
+    }
+}
+
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::MedicalRecordType;
+
+    medical_records (id) {
+        id -> Int4,
+        tenant_id -> Int4,
+        cat_id -> Int4,
+        record_type -> MedicalRecordType,
+        date -> Date,
+        due_date -> Nullable<Date>,
+        notes -> Text,
+        attachment_path -> Nullable<Varchar>,
+        created_at -> Timestamptz,
+        attachment_size -> Int8,
     }
 }
+
+diesel::table! {
+    outbox (id) {
+        id -> Int8,
+        event -> Varchar,
+        payload -> Jsonb,
+        created_at -> Timestamptz,
+        dispatched_at -> Nullable<Timestamptz>,
+        tenant_id -> Int4,
+        claimed_until -> Nullable<Timestamptz>,
+    }
+}
+
+diesel::table! {
+    quotas (tenant_id) {
+        tenant_id -> Int4,
+        max_cats -> Nullable<Int4>,
+        max_storage_bytes -> Nullable<Int8>,
+    }
+}
+
+diesel::table! {
+    tenants (id) {
+        id -> Int4,
+        slug -> Varchar,
+        name -> Varchar,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    uploads (token) {
+        token -> Uuid,
+        tenant_id -> Int4,
+        private -> Bool,
+        extension -> Varchar,
+        size -> Int8,
+        received -> Int8,
+        image_key -> Nullable<Varchar>,
+        created_at -> Timestamptz,
+        expires_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    webhook_deliveries (id) {
+        id -> Int4,
+        webhook_id -> Int4,
+        event -> Varchar,
+        payload -> Jsonb,
+        attempt -> Int4,
+        status_code -> Nullable<Int4>,
+        error -> Nullable<Varchar>,
+        delivered -> Bool,
+        created_at -> Timestamptz,
+    }
+}
+
+diesel::table! {
+    webhooks (id) {
+        id -> Int4,
+        url -> Varchar,
+        secret -> Varchar,
+        events -> Array<Text>,
+        created_at -> Timestamptz,
+        tenant_id -> Int4,
+    }
+}
+
+diesel::joinable!(admins -> tenants (tenant_id));
+// Materialized views, which print-schema leaves out; keep this when
+// regenerating the schema
+diesel::table! {
+    use diesel::sql_types::*;
+    use super::sql_types::CatStatus;
+
+    cat_stats (tenant_id, day, status) {
+        tenant_id -> Int4,
+        day -> Date,
+        status -> CatStatus,
+        cats -> Int8,
+        image_bytes -> Int8,
+    }
+}
+
+diesel::joinable!(cat_history -> cats (cat_id));
+diesel::joinable!(cat_history -> tenants (tenant_id));
+diesel::joinable!(cat_images -> cats (cat_id));
+diesel::joinable!(cat_images -> tenants (tenant_id));
+diesel::joinable!(cat_relations -> tenants (tenant_id));
+diesel::joinable!(cat_tombstones -> tenants (tenant_id));
+diesel::joinable!(cats -> tenants (tenant_id));
+diesel::joinable!(medical_records -> cats (cat_id));
+diesel::joinable!(medical_records -> tenants (tenant_id));
+diesel::joinable!(outbox -> tenants (tenant_id));
+diesel::joinable!(quotas -> tenants (tenant_id));
+diesel::joinable!(uploads -> tenants (tenant_id));
+diesel::joinable!(webhook_deliveries -> webhooks (webhook_id));
+diesel::joinable!(webhooks -> tenants (tenant_id));
+
+diesel::allow_tables_to_appear_in_same_query!(
+    admins,
+    cat_history,
+    cat_images,
+    cat_relations,
+    cat_stats,
+    cat_tombstones,
+    cats,
+    medical_records,
+    outbox,
+    quotas,
+    tenants,
+    uploads,
+    webhook_deliveries,
+    webhooks,
+);
@@ -0,0 +1,179 @@
+//! Guards against serving from a binary older than the database schema, as
+//! happens during a blue/green or rolling deploy once the new release has
+//! migrated the database while the old one still runs. Its queries may
+//! then refer to renamed or dropped columns and fail, or worse, write rows
+//! the new release does not expect.
+//!
+//! At startup the migrations applied to the database are compared with
+//! those embedded in the binary. When the database has migrations the
+//! binary does not know, the server refuses to start, or with
+//! `SCHEMA_OUTDATED_ACTION=read-only` serves reads only.
+use crate::cli::MIGRATIONS;
+use crate::errors::UserError;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::Error;
+use diesel::migration::MigrationSource;
+use diesel::pg::Pg;
+use diesel::PgConnection;
+use diesel_migrations::MigrationHarness;
+use log::{error, warn};
+use std::collections::BTreeSet;
+use std::error::Error as StdError;
+use std::io;
+
+/// What the server does when the database schema is newer than the binary.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum OutdatedAction {
+    Refuse,
+    ReadOnly,
+}
+
+impl OutdatedAction {
+    pub fn parse(value: &str) -> Option<OutdatedAction> {
+        match value {
+            "refuse" => Some(OutdatedAction::Refuse),
+            "read-only" => Some(OutdatedAction::ReadOnly),
+            _ => None,
+        }
+    }
+}
+
+/// How the database schema compares to the binary's migrations.
+#[derive(Debug, Default, PartialEq)]
+pub struct SchemaStatus {
+    /// Applied to the database, but unknown to the binary.
+    pub unknown: Vec<String>,
+    /// Known to the binary, but not applied to the database yet.
+    pub pending: Vec<String>,
+}
+
+impl SchemaStatus {
+    /// Whether the binary is older than the schema.
+    pub fn outdated(&self) -> bool {
+        !self.unknown.is_empty()
+    }
+}
+
+fn compare(embedded: &BTreeSet<String>, applied: &BTreeSet<String>) -> SchemaStatus {
+    SchemaStatus {
+        unknown: applied.difference(embedded).cloned().collect(),
+        pending: embedded.difference(applied).cloned().collect(),
+    }
+}
+
+/// Compares the migrations applied to the database with the embedded ones.
+pub fn check(
+    connection: &mut PgConnection,
+) -> Result<SchemaStatus, Box<dyn StdError + Send + Sync>> {
+    let embedded = MigrationSource::<Pg>::migrations(&MIGRATIONS)?
+        .iter()
+        .map(|migration| migration.name().version().to_string())
+        .collect();
+    let applied = connection
+        .applied_migrations()?
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+    Ok(compare(&embedded, &applied))
+}
+
+/// Checks the schema before the server starts, logging what differs.
+/// Returns whether to serve read-only, or an error when the server must
+/// not start at all.
+pub fn check_at_startup(connection: &mut PgConnection, action: OutdatedAction) -> io::Result<bool> {
+    let status = check(connection)
+        .map_err(|e| io::Error::other(format!("Failed to check the schema version: {}", e)))?;
+    if !status.pending.is_empty() {
+        warn!(
+            "The database lacks migrations {}, run `catdex migrate`",
+            status.pending.join(", ")
+        );
+    }
+    if !status.outdated() {
+        return Ok(false);
+    }
+    let unknown = status.unknown.join(", ");
+    match action {
+        OutdatedAction::Refuse => Err(io::Error::other(format!(
+            "The database has migrations {} this build does not know, upgrade it or set \
+             SCHEMA_OUTDATED_ACTION=read-only",
+            unknown
+        ))),
+        OutdatedAction::ReadOnly => {
+            error!(
+                "The database has migrations {} this build does not know, serving read-only",
+                unknown
+            );
+            Ok(true)
+        }
+    }
+}
+
+/// Middleware answering requests that may write with a 503, for serving
+/// read-only from an outdated binary.
+pub async fn reject_writes(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse, Error> {
+    if req.method().is_safe() {
+        return next
+            .call(req)
+            .await
+            .map(ServiceResponse::map_into_boxed_body);
+    }
+    warn!(
+        "Rejected {} {} while serving read-only",
+        req.method(),
+        req.path()
+    );
+    Ok(req
+        .error_response(UserError::ReadOnlyError)
+        .map_into_boxed_body())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::test_pool;
+    use actix_web::http::StatusCode;
+    use actix_web::middleware::from_fn;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{web, App, HttpResponse};
+
+    fn versions(versions: &[&str]) -> BTreeSet<String> {
+        versions.iter().map(|version| version.to_string()).collect()
+    }
+
+    #[test]
+    fn test_compare() {
+        let status = compare(&versions(&["1", "2", "3"]), &versions(&["1", "2", "4"]));
+        assert_eq!(status.unknown, vec!["4"]);
+        assert_eq!(status.pending, vec!["3"]);
+        assert!(status.outdated());
+        assert!(!compare(&versions(&["1", "2"]), &versions(&["1"])).outdated());
+    }
+
+    #[test]
+    fn test_check() {
+        let pool = test_pool();
+        let mut connection = pool.get().unwrap();
+        assert_eq!(check(&mut connection).unwrap(), SchemaStatus::default());
+    }
+
+    #[actix_web::test]
+    async fn test_reject_writes() {
+        let app = init_service(
+            App::new()
+                .wrap(from_fn(reject_writes))
+                .route("/", web::to(HttpResponse::Ok)),
+        )
+        .await;
+        let resp = call_service(&app, TestRequest::get().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = call_service(&app, TestRequest::post().to_request()).await;
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
@@ -0,0 +1,136 @@
+//! Credentials such as `DATABASE_URL`, which can be kept out of the
+//! environment and the process list. Each is read from, in order:
+//!
+//! 1. the environment variable itself,
+//! 2. the file named by `<NAME>_FILE`, as Docker and Kubernetes mount
+//!    secrets,
+//! 3. the HashiCorp Vault secret at `VAULT_SECRET_PATH`, fetched once at
+//!    startup when `VAULT_ADDR` is set.
+use log::info;
+use serde_json::Value;
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+const VAULT_TIMEOUT: Duration = Duration::from_secs(10);
+
+static VAULT_SECRETS: OnceLock<HashMap<String, String>> = OnceLock::new();
+
+/// The secret `name`, `None` when it is set nowhere.
+///
+/// Panics when `<NAME>_FILE` is set but cannot be read, like invalid
+/// configuration values do.
+pub fn get(name: &str) -> Option<String> {
+    if let Ok(value) = env::var(name) {
+        return Some(value);
+    }
+    if let Ok(path) = env::var(format!("{}_FILE", name)) {
+        let value = fs::read_to_string(&path)
+            .unwrap_or_else(|e| panic!("{}_FILE {} cannot be read: {}", name, path, e));
+        // Secret files usually end with a newline that is not part of the value
+        return Some(value.trim_end_matches(['\r', '\n']).to_string());
+    }
+    VAULT_SECRETS.get()?.get(name).cloned()
+}
+
+/// Fetches the Vault secret when `VAULT_ADDR` is set, before anything reads
+/// a secret.
+///
+/// `VAULT_SECRET_PATH` is the API path of the secret below `/v1`, e.g.
+/// `secret/data/catdex` for a KV version 2 engine mounted at `secret`. The
+/// token is the secret `VAULT_TOKEN`, so it can come from a file too, and
+/// `VAULT_NAMESPACE` selects an enterprise namespace.
+pub fn init() -> Result<(), String> {
+    let Ok(addr) = env::var("VAULT_ADDR") else {
+        return Ok(());
+    };
+    let path = env::var("VAULT_SECRET_PATH")
+        .map_err(|_| "VAULT_SECRET_PATH must be set with VAULT_ADDR".to_string())?;
+    let token = get("VAULT_TOKEN").ok_or("VAULT_TOKEN must be set with VAULT_ADDR")?;
+    let url = format!(
+        "{}/v1/{}",
+        addr.trim_end_matches('/'),
+        path.trim_start_matches('/')
+    );
+    let secrets = actix_web::rt::System::new().block_on(fetch(&url, &token))?;
+    info!("Read {} secret(s) from Vault at {}", secrets.len(), url);
+    VAULT_SECRETS
+        .set(secrets)
+        .map_err(|_| "Vault secrets are only fetched once".to_string())
+}
+
+async fn fetch(url: &str, token: &str) -> Result<HashMap<String, String>, String> {
+    let client = awc::Client::builder().timeout(VAULT_TIMEOUT).finish();
+    let mut request = client.get(url).insert_header(("X-Vault-Token", token));
+    if let Ok(namespace) = env::var("VAULT_NAMESPACE") {
+        request = request.insert_header(("X-Vault-Namespace", namespace));
+    }
+    let mut response = request
+        .send()
+        .await
+        .map_err(|e| format!("Failed to reach Vault at {}: {}", url, e))?;
+    if !response.status().is_success() {
+        return Err(format!("Vault answered {} for {}", response.status(), url));
+    }
+    let body = response
+        .body()
+        .await
+        .map_err(|e| format!("Failed to read Vault response for {}: {}", url, e))?;
+    let body: Value = serde_json::from_slice(&body)
+        .map_err(|e| format!("Invalid Vault response for {}: {}", url, e))?;
+    secret_values(&body).ok_or_else(|| format!("Vault response for {} has no secret data", url))
+}
+
+/// The values of a KV secret, under `data.data` for version 2 of the engine
+/// and under `data` for version 1.
+fn secret_values(body: &Value) -> Option<HashMap<String, String>> {
+    let data = body.get("data")?;
+    let values = match data.get("metadata") {
+        Some(_) => data.get("data")?,
+        None => data,
+    };
+    let values = values
+        .as_object()?
+        .iter()
+        .map(|(name, value)| {
+            let value = match value {
+                Value::String(s) => s.clone(),
+                other => other.to_string(),
+            };
+            (name.clone(), value)
+        })
+        .collect();
+    Some(values)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_secret_from_file() {
+        let file = tempfile::NamedTempFile::new().unwrap();
+        fs::write(file.path(), "s3cret\n").unwrap();
+        env::set_var("CATDEX_TEST_SECRET_FILE", file.path());
+        assert_eq!(get("CATDEX_TEST_SECRET"), Some("s3cret".to_string()));
+        assert_eq!(get("CATDEX_TEST_UNSET_SECRET"), None);
+    }
+
+    #[test]
+    fn test_secret_values() {
+        let v2 = json!({"data": {
+            "data": {"DATABASE_URL": "postgres://db/catdex", "PORT": 5432},
+            "metadata": {"version": 3},
+        }});
+        let values = secret_values(&v2).unwrap();
+        assert_eq!(values["DATABASE_URL"], "postgres://db/catdex");
+        assert_eq!(values["PORT"], "5432");
+
+        let v1 = json!({"data": {"IMAGE_SIGNING_KEY": "key"}});
+        assert_eq!(secret_values(&v1).unwrap()["IMAGE_SIGNING_KEY"], "key");
+        assert_eq!(secret_values(&json!({"errors": []})), None);
+    }
+}
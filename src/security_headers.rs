@@ -0,0 +1,105 @@
+use crate::config::Settings;
+use crate::reload::LiveSettings;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{
+    HeaderName, HeaderValue, CONTENT_SECURITY_POLICY, REFERRER_POLICY, STRICT_TRANSPORT_SECURITY,
+    X_CONTENT_TYPE_OPTIONS, X_FRAME_OPTIONS,
+};
+use actix_web::middleware::Next;
+use actix_web::{web, Error};
+
+/// The headers added to every response under `settings`.
+fn security_headers(settings: &Settings) -> Vec<(HeaderName, HeaderValue)> {
+    if !settings.security_headers {
+        return Vec::new();
+    }
+    let mut headers = vec![
+        (X_CONTENT_TYPE_OPTIONS, HeaderValue::from_static("nosniff")),
+        (X_FRAME_OPTIONS, HeaderValue::from_static("DENY")),
+        (REFERRER_POLICY, HeaderValue::from_static("no-referrer")),
+    ];
+    if let Some(max_age) = settings.hsts_max_age {
+        let value = format!("max-age={}; includeSubDomains", max_age.as_secs());
+        headers.push((
+            STRICT_TRANSPORT_SECURITY,
+            HeaderValue::from_str(&value).expect("HSTS header value is ASCII"),
+        ));
+    }
+    if let Some(policy) = &settings.content_security_policy {
+        headers.push((CONTENT_SECURITY_POLICY, policy.clone()));
+    }
+    headers
+}
+
+/// Middleware adding the security headers to every response, leaving alone
+/// any a handler has set itself.
+pub async fn add_security_headers(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let headers = req
+        .app_data::<web::Data<LiveSettings>>()
+        .map(|settings| security_headers(&settings.load()))
+        .unwrap_or_default();
+    let mut res = next.call(req).await?;
+    for (name, value) in headers {
+        if !res.headers().contains_key(&name) {
+            res.headers_mut().insert(name, value);
+        }
+    }
+    Ok(res)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::{call_service, init_service, TestRequest};
+    use actix_web::{App, HttpResponse};
+    use std::time::Duration;
+
+    fn settings() -> Settings {
+        let mut settings = Settings::load(None).unwrap();
+        settings.security_headers = true;
+        settings.hsts_max_age = Some(Duration::from_secs(60));
+        settings.content_security_policy = Some(HeaderValue::from_static("default-src 'self'"));
+        settings
+    }
+
+    #[test]
+    fn test_disabled() {
+        let mut settings = settings();
+        settings.security_headers = false;
+        assert!(security_headers(&settings).is_empty());
+    }
+
+    #[actix_web::test]
+    async fn test_headers_are_added_unless_set() {
+        let app = init_service(
+            App::new()
+                .wrap(actix_web::middleware::from_fn(add_security_headers))
+                .app_data(web::Data::new(LiveSettings::new(settings(), None)))
+                .route(
+                    "/",
+                    web::get().to(|| async {
+                        HttpResponse::Ok()
+                            .insert_header((X_FRAME_OPTIONS, "SAMEORIGIN"))
+                            .finish()
+                    }),
+                ),
+        )
+        .await;
+        let resp = call_service(&app, TestRequest::get().to_request()).await;
+        let headers = resp.headers();
+        assert_eq!(headers.get(X_CONTENT_TYPE_OPTIONS).unwrap(), "nosniff");
+        assert_eq!(headers.get(X_FRAME_OPTIONS).unwrap(), "SAMEORIGIN");
+        assert_eq!(
+            headers.get(STRICT_TRANSPORT_SECURITY).unwrap(),
+            "max-age=60; includeSubDomains"
+        );
+        assert_eq!(
+            headers.get(CONTENT_SECURITY_POLICY).unwrap(),
+            "default-src 'self'"
+        );
+    }
+}
@@ -0,0 +1,106 @@
+use crate::clock::Clock;
+use crate::file_store::FileStore;
+use crate::history;
+use crate::models::{NewCat, Tenant};
+use crate::repository;
+use crate::signed_urls;
+use diesel::PgConnection;
+use std::error::Error;
+
+const ADJECTIVES: &[&str] = &[
+    "Fluffy", "Sleepy", "Grumpy", "Tiny", "Mighty", "Curious", "Lazy", "Sneaky", "Fuzzy", "Brave",
+];
+const NAMES: &[&str] = &[
+    "Whiskers", "Mittens", "Shadow", "Pumpkin", "Biscuit", "Luna", "Oliver", "Pepper", "Ginger",
+    "Tiger",
+];
+const COLORS: &[&str] = &[
+    "#f4a261", "#e9c46a", "#2a9d8f", "#264653", "#e76f51", "#8d99ae", "#b5838d",
+];
+
+/// Name of the `index`th sample cat, numbered once the combinations run out.
+fn sample_name(index: usize) -> String {
+    let combinations = ADJECTIVES.len() * NAMES.len();
+    let name = format!(
+        "{} {}",
+        ADJECTIVES[index % ADJECTIVES.len()],
+        NAMES[(index / ADJECTIVES.len()) % NAMES.len()]
+    );
+    match index / combinations {
+        0 => name,
+        round => format!("{} {}", name, round + 1),
+    }
+}
+
+/// A solid colored SVG with the cat's initial, standing in for a photo.
+fn placeholder_image(name: &str, index: usize) -> String {
+    let initial = name.chars().next().unwrap_or('?');
+    format!(
+        concat!(
+            r#"<svg xmlns="http://www.w3.org/2000/svg" width="400" height="300">"#,
+            r#"<rect width="100%" height="100%" fill="{}"/>"#,
+            r#"<text x="50%" y="50%" font-size="120" text-anchor="middle" "#,
+            r#"dominant-baseline="central" fill="white">{}</text></svg>"#
+        ),
+        COLORS[index % COLORS.len()],
+        initial
+    )
+}
+
+/// Inserts `count` sample cats into the tenant, moving on to the next
+/// generated name whenever one is already taken. No outbox events are
+/// recorded, so webhooks stay quiet.
+pub fn seed(
+    connection: &mut PgConnection,
+    store: &dyn FileStore,
+    clock: &dyn Clock,
+    tenant: &Tenant,
+    count: usize,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut created = 0;
+    // Terminates since only finitely many names can be taken already
+    for index in 0.. {
+        if created == count {
+            break;
+        }
+        let name = sample_name(index);
+        let key = store.new_key(&tenant.slug, Some("svg"));
+        let image = placeholder_image(&name, index);
+        store.put(&key, &mut image.as_bytes())?;
+
+        let new_cat = NewCat {
+            name,
+            image_path: format!("/image/{}", key),
+            created_at: clock.now(),
+            image_size: image.len() as i64,
+            private: false,
+            latitude: None,
+            longitude: None,
+            image_hash: Some(signed_urls::image_version(image.as_bytes())),
+            image_phash: None,
+            pending_review: false,
+            image_original_format: None,
+        };
+        match repository::insert_cat(connection, tenant.id, &new_cat, false)? {
+            Some(cat) => {
+                history::record(connection, tenant.id, "seed", None, &cat, cat.created_at)?;
+                created += 1;
+            }
+            None => store.delete(&key)?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sample_names_are_unique() {
+        let names: std::collections::HashSet<_> = (0..250).map(sample_name).collect();
+        assert_eq!(names.len(), 250);
+        assert_eq!(sample_name(0), "Fluffy Whiskers");
+        assert_eq!(sample_name(100), "Fluffy Whiskers 2");
+    }
+}
@@ -0,0 +1,271 @@
+//! Cookie sessions for the HTML frontend, next to Basic credentials for the
+//! JSON API. Admins log in through the form at `/login`, and stay logged in
+//! for `SESSION_TTL_SECS` in a signed and encrypted cookie.
+//!
+//! Cookies are sent along with requests other sites make, so a session
+//! only counts for requests carrying its CSRF token, which the pages embed
+//! in their forms. Without it a request is as anonymous as any other.
+use crate::auth;
+use crate::config::Config;
+use crate::db::DbConn;
+use crate::errors::UserError;
+use crate::models::Tenant;
+use crate::tenants;
+use actix_session::config::PersistentSession;
+use actix_session::storage::CookieSessionStore;
+use actix_session::{Session, SessionMiddleware};
+use actix_web::cookie::{self, Key};
+use actix_web::http::header::{ContentType, LOCATION};
+use actix_web::http::StatusCode;
+use actix_web::middleware::from_fn;
+use actix_web::{web, HttpResponse};
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use subtle::ConstantTimeEq;
+use uuid::Uuid;
+
+pub const SESSION_COOKIE: &str = "catdex_session";
+/// Form field, or query parameter, carrying the CSRF token.
+pub const CSRF_FIELD: &str = "csrf_token";
+/// Header carrying the CSRF token, for scripts.
+pub const CSRF_HEADER: &str = "X-CSRF-Token";
+
+const ADMIN_KEY: &str = "admin";
+const CSRF_KEY: &str = "csrf_token";
+
+/// The admin a session is logged in as.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct SessionAdmin {
+    pub tenant_id: i32,
+    pub username: String,
+}
+
+/// The key sessions are signed and encrypted with, derived from
+/// `SESSION_KEY`. Sessions do not survive a restart without it.
+pub fn key(config: &Config) -> Key {
+    match &config.session_key {
+        Some(secret) => Key::derive_from(secret.as_bytes()),
+        None => {
+            warn!("SESSION_KEY is not set, logins will not survive a restart");
+            Key::generate()
+        }
+    }
+}
+
+pub fn middleware(key: Key, ttl: Duration) -> SessionMiddleware<CookieSessionStore> {
+    let ttl = cookie::time::Duration::seconds(ttl.as_secs().try_into().unwrap_or(i64::MAX));
+    SessionMiddleware::builder(CookieSessionStore::default(), key)
+        .cookie_name(SESSION_COOKIE.to_string())
+        .session_lifecycle(PersistentSession::default().session_ttl(ttl))
+        .build()
+}
+
+/// The session's CSRF token, issued on first use.
+pub fn csrf_token(session: &Session) -> String {
+    if let Ok(Some(token)) = session.get::<String>(CSRF_KEY) {
+        return token;
+    }
+    let token =
+        URL_SAFE_NO_PAD.encode([Uuid::new_v4().into_bytes(), Uuid::new_v4().into_bytes()].concat());
+    if let Err(e) = session.insert(CSRF_KEY, &token) {
+        error!("Failed to store CSRF token in session: {}", e);
+    }
+    token
+}
+
+/// Whether `token` is the session's CSRF token.
+pub fn verify_csrf(session: &Session, token: Option<&str>) -> bool {
+    match (session.get::<String>(CSRF_KEY), token) {
+        (Ok(Some(expected)), Some(token)) => expected.as_bytes().ct_eq(token.as_bytes()).into(),
+        _ => false,
+    }
+}
+
+/// The admin the session is logged in as, if any.
+pub fn admin(session: &Session) -> Option<SessionAdmin> {
+    session.get(ADMIN_KEY).ok().flatten()
+}
+
+/// Registers the login, logout and upload pages. They resolve the tenant
+/// like the API does.
+pub fn pages_config(cfg: &mut web::ServiceConfig) {
+    cfg.service(
+        web::resource("/login")
+            .wrap(from_fn(tenants::resolve_tenant))
+            .route(web::get().to(login_page_endpoint))
+            .route(web::post().to(login_endpoint)),
+    )
+    .service(
+        web::resource("/logout")
+            .wrap(from_fn(tenants::resolve_tenant))
+            .route(web::post().to(logout_endpoint)),
+    )
+    .service(
+        web::resource("/upload")
+            .wrap(from_fn(tenants::resolve_tenant))
+            .route(web::get().to(upload_page_endpoint)),
+    );
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+/// An HTML page in the frontend's style, with `body` as is.
+pub fn page(status: StatusCode, title: &str, body: &str) -> HttpResponse {
+    HttpResponse::build(status)
+        .content_type(ContentType::html())
+        .body(format!(
+            r#"<!DOCTYPE html>
+<html lang="en">
+    <head>
+        <meta charset="UTF-8" />
+        <title>{title}</title>
+        <link rel="stylesheet" href="/static/css/index.css" type="text/css">
+    </head>
+    <body>
+        <h1>{title}</h1>
+{body}
+    </body>
+</html>
+"#,
+            title = escape(title),
+            body = body,
+        ))
+}
+
+fn login_form(session: &Session, message: Option<&str>) -> String {
+    let message = message
+        .map(|message| format!("        <p class=\"error\">{}</p>\n", escape(message)))
+        .unwrap_or_default();
+    format!(
+        r#"{message}        <form method="post" action="/login">
+            <input type="hidden" name="{CSRF_FIELD}" value="{token}">
+            <label>Username <input name="username" autocomplete="username" required></label>
+            <label>Password <input name="password" type="password" autocomplete="current-password" required></label>
+            <button type="submit">Log in</button>
+        </form>"#,
+        token = escape(&csrf_token(session)),
+    )
+}
+
+fn redirect(location: &str) -> HttpResponse {
+    HttpResponse::SeeOther()
+        .insert_header((LOCATION, location))
+        .finish()
+}
+
+pub async fn login_page_endpoint(session: Session) -> HttpResponse {
+    page(StatusCode::OK, "Log in", &login_form(&session, None))
+}
+
+#[derive(Deserialize)]
+pub struct LoginForm {
+    username: String,
+    password: String,
+    csrf_token: Option<String>,
+}
+
+/// Logs an admin of the tenant in, and sends them on to the upload page.
+pub async fn login_endpoint(
+    connection: DbConn,
+    session: Session,
+    tenant: Tenant,
+    form: web::Form<LoginForm>,
+) -> Result<HttpResponse, UserError> {
+    let form = form.into_inner();
+    // Checked here too, as the CSRF middleware lets requests without a
+    // session cookie through
+    if !verify_csrf(&session, form.csrf_token.as_deref()) {
+        warn!("Rejected login without a valid CSRF token");
+        return Ok(page(
+            StatusCode::FORBIDDEN,
+            "Log in",
+            &login_form(&session, Some("The form expired, please try again")),
+        ));
+    }
+    let username = form.username.clone();
+    let verified = connection
+        .run("check admin credentials", move |connection| {
+            auth::verify_password(connection, tenant.id, &form.username, &form.password)
+        })
+        .await?;
+    if !verified {
+        warn!("Failed login to tenant {}", tenant.slug);
+        return Ok(page(
+            StatusCode::UNAUTHORIZED,
+            "Log in",
+            &login_form(&session, Some("Wrong username or password")),
+        ));
+    }
+
+    // A fresh session and token, so none set before logging in carry over
+    session.renew();
+    session.remove(CSRF_KEY);
+    let admin = SessionAdmin {
+        tenant_id: tenant.id,
+        username,
+    };
+    session.insert(ADMIN_KEY, &admin).map_err(|e| {
+        error!("Failed to store admin in session: {}", e);
+        UserError::UnexpectedError
+    })?;
+    info!(
+        "Admin {} logged in to tenant {}",
+        admin.username, tenant.slug
+    );
+    Ok(redirect("/upload"))
+}
+
+/// Ends the session. The CSRF middleware has checked the form's token.
+pub async fn logout_endpoint(session: Session) -> HttpResponse {
+    session.purge();
+    redirect("/")
+}
+
+/// The form for adding a cat. Cats added while logged in skip moderation.
+pub async fn upload_page_endpoint(session: Session, tenant: Tenant) -> HttpResponse {
+    let token = escape(&csrf_token(&session));
+    let account = match admin(&session).filter(|admin| admin.tenant_id == tenant.id) {
+        Some(admin) => format!(
+            r#"        <form method="post" action="/logout">
+            Logged in as {username}
+            <input type="hidden" name="{CSRF_FIELD}" value="{token}">
+            <button type="submit">Log out</button>
+        </form>"#,
+            username = escape(&admin.username),
+        ),
+        None => r#"        <p><a href="/login">Log in</a> to skip moderation</p>"#.to_string(),
+    };
+    let body = format!(
+        r#"{account}
+        <form method="post" action="/api/add_cat?{CSRF_FIELD}={token}" enctype="multipart/form-data">
+            <label>Name <input name="name" required></label>
+            <label>Image <input name="image" type="file" accept="image/*" required></label>
+            <label><input name="private" type="checkbox" value="true"> Private</label>
+            <button type="submit">Add cat</button>
+        </form>
+        <p><a href="/">Back</a></p>"#,
+    );
+    page(StatusCode::OK, "Add a cat", &body)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_escape() {
+        assert_eq!(
+            escape(r#"<a href="x">Tom & 'Jerry'</a>"#),
+            "&lt;a href=&quot;x&quot;&gt;Tom &amp; &#39;Jerry&#39;&lt;/a&gt;"
+        );
+    }
+}
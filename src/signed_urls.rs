@@ -0,0 +1,314 @@
+use crate::clock::Clock;
+use crate::config::Config;
+use crate::db;
+use crate::errors::UserError;
+use crate::file_store::FileStore;
+use crate::image_variants;
+use crate::models::{Cat, CatImage};
+use actix_files::file_extension_to_mime;
+use actix_web::http::header::{CacheControl, CacheDirective, HeaderValue, CACHE_CONTROL, VARY};
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use log::{error, warn};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+/// Path segment that keeps an image out of the public `/image` mount, as in
+/// `/image/<tenant>/private/<uuid>.jpg`.
+pub const PRIVATE_DIR: &str = "private";
+
+/// Hex digits of the content hash kept as an image's version.
+const IMAGE_VERSION_LENGTH: usize = 16;
+
+/// The version of an image with `contents`, added to its URL as `?v=` so
+/// caches fetch it anew whenever the contents change.
+pub fn image_version(contents: &[u8]) -> String {
+    let mut version = hex::encode(Sha256::digest(contents));
+    version.truncate(IMAGE_VERSION_LENGTH);
+    version
+}
+
+/// Signs links to private images, which are only served through
+/// `/signed-image` with a valid, unexpired signature. Links to public
+/// images carry their version and point at the CDN when one is set.
+pub struct UrlSigner {
+    key: Vec<u8>,
+    ttl: Duration,
+    cdn_base_url: Option<String>,
+}
+
+impl UrlSigner {
+    pub fn new(key: impl Into<Vec<u8>>, ttl: Duration) -> UrlSigner {
+        UrlSigner {
+            key: key.into(),
+            ttl,
+            cdn_base_url: None,
+        }
+    }
+
+    /// Serves public images from `cdn_base_url`, e.g.
+    /// `https://cdn.catdex.example`, instead of this server.
+    pub fn with_cdn_base_url(mut self, cdn_base_url: Option<String>) -> UrlSigner {
+        self.cdn_base_url = cdn_base_url;
+        self
+    }
+
+    fn mac(&self, image_key: &str, expires: i64) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.key).expect("HMAC accepts keys of any size");
+        mac.update(format!("{}\n{}", image_key, expires).as_bytes());
+        mac
+    }
+
+    /// A link to the image stored under `image_key`, valid for the signer's
+    /// TTL from `now`.
+    pub fn sign(&self, image_key: &str, now: DateTime<Utc>) -> String {
+        let expires = now.timestamp() + self.ttl.as_secs() as i64;
+        let signature = hex::encode(self.mac(image_key, expires).finalize().into_bytes());
+        format!(
+            "/signed-image/{}?expires={}&signature={}",
+            image_key, expires, signature
+        )
+    }
+
+    pub fn verify(
+        &self,
+        image_key: &str,
+        expires: i64,
+        signature: &str,
+        now: DateTime<Utc>,
+    ) -> bool {
+        if expires < now.timestamp() {
+            return false;
+        }
+        let Ok(signature) = hex::decode(signature) else {
+            return false;
+        };
+        // Compares in constant time
+        self.mac(image_key, expires)
+            .verify_slice(&signature)
+            .is_ok()
+    }
+
+    /// The URL to show for an image stored at `image_path`: a signed link
+    /// for a private image, since the stored path is not served, or the
+    /// versioned URL of a public one.
+    fn image_url(
+        &self,
+        image_path: &str,
+        image_hash: Option<&str>,
+        private: bool,
+        now: DateTime<Utc>,
+    ) -> String {
+        if private {
+            return match image_path.strip_prefix("/image/") {
+                Some(image_key) => self.sign(image_key, now),
+                None => image_path.to_string(),
+            };
+        }
+        let base_url = self.cdn_base_url.as_deref().unwrap_or("");
+        match image_hash {
+            Some(version) => format!("{}{}?v={}", base_url, image_path, version),
+            None => format!("{}{}", base_url, image_path),
+        }
+    }
+
+    /// Replaces the image path of a cat with the URL to show for it.
+    pub fn present(&self, mut cat: Cat, now: DateTime<Utc>) -> Cat {
+        cat.image_path =
+            self.image_url(&cat.image_path, cat.image_hash.as_deref(), cat.private, now);
+        cat
+    }
+
+    /// Replaces the image path of a photo of a cat, `private` or not, with
+    /// the URL to show for it.
+    pub fn present_image(
+        &self,
+        mut image: CatImage,
+        private: bool,
+        now: DateTime<Utc>,
+    ) -> CatImage {
+        image.image_path =
+            self.image_url(&image.image_path, image.image_hash.as_deref(), private, now);
+        image
+    }
+}
+
+/// Whether a path under the image directory may be served by the public
+/// `/image` mount, i.e. is not a tenant's private image.
+pub fn is_public_image(path: &Path) -> bool {
+    path.components().nth(1).map(|dir| dir.as_os_str()) != Some(PRIVATE_DIR.as_ref())
+}
+
+#[derive(Deserialize)]
+pub struct SignedImageQuery {
+    expires: i64,
+    signature: String,
+}
+
+pub async fn signed_image_endpoint(
+    req: HttpRequest,
+    config: web::Data<Config>,
+    signer: web::Data<UrlSigner>,
+    clock: web::Data<dyn Clock>,
+    store: web::Data<dyn FileStore>,
+    image_key: web::Path<String>,
+    query: web::Query<SignedImageQuery>,
+) -> Result<HttpResponse, UserError> {
+    let image_key = image_key.into_inner();
+    if !signer.verify(&image_key, query.expires, &query.signature, clock.now()) {
+        warn!("Rejected invalid or expired image link");
+        return Err(UserError::InvalidSignatureError);
+    }
+
+    if let Some((path, format)) =
+        image_variants::negotiate(&req, &config, store.clone(), &image_key).await
+    {
+        match image_variants::respond(&req, &path, format) {
+            Ok(mut response) => {
+                response
+                    .headers_mut()
+                    .insert(CACHE_CONTROL, HeaderValue::from_static("private"));
+                return Ok(response);
+            }
+            Err(e) => error!("Failed to open converted image {}: {}", path.display(), e),
+        }
+    }
+    let vary = image_variants::is_convertible(&config, &image_key);
+    let content_type = file_extension_to_mime(
+        Path::new(&image_key)
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .unwrap_or_default(),
+    );
+    let contents = db::block(move || store.get(&image_key))
+        .await?
+        .map_err(|e| match e.kind() {
+            io::ErrorKind::NotFound => UserError::NotFoundError,
+            _ => {
+                error!("Failed to read private image: {}", e);
+                UserError::UnexpectedError
+            }
+        })?;
+    let mut response = HttpResponse::Ok();
+    if vary {
+        response.insert_header((VARY, "accept"));
+    }
+    Ok(response
+        .content_type(content_type)
+        // Shared caches must not keep a copy of a private image
+        .insert_header(CacheControl(vec![CacheDirective::Private]))
+        .body(contents))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Splits a signed link into the image key, expiry and signature.
+    fn parse(url: &str) -> (String, i64, String) {
+        let (path, query) = url.split_once('?').unwrap();
+        let query = web::Query::<SignedImageQuery>::from_query(query)
+            .unwrap()
+            .into_inner();
+        (
+            path.strip_prefix("/signed-image/").unwrap().to_string(),
+            query.expires,
+            query.signature,
+        )
+    }
+
+    #[test]
+    fn test_sign_and_verify() {
+        let signer = UrlSigner::new("secret", Duration::from_secs(60));
+        let now = Utc::now();
+        let (key, expires, signature) = parse(&signer.sign("acme/private/cat.jpg", now));
+        assert_eq!(key, "acme/private/cat.jpg");
+        assert!(signer.verify(&key, expires, &signature, now));
+        assert!(!signer.verify("acme/private/dog.jpg", expires, &signature, now));
+        assert!(!signer.verify(&key, expires + 1, &signature, now));
+        assert!(!signer.verify(
+            &key,
+            expires,
+            &signature,
+            now + chrono::Duration::seconds(61)
+        ));
+        let other = UrlSigner::new("other", Duration::from_secs(60));
+        assert!(!other.verify(&key, expires, &signature, now));
+    }
+
+    fn cat(private: bool) -> Cat {
+        Cat {
+            id: 1,
+            public_id: uuid::Uuid::nil(),
+            name: "Whiskers".to_string(),
+            image_path: "/image/default/cat.jpg".to_string(),
+            created_at: Utc::now(),
+            private,
+            status: crate::models::CatStatus::Available,
+            latitude: None,
+            longitude: None,
+            image_hash: Some(image_version(b"cat")),
+            pending_review: false,
+            updated_at: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn test_present_versioned_image() {
+        let version = image_version(b"cat");
+        assert_eq!(version.len(), IMAGE_VERSION_LENGTH);
+        assert_ne!(version, image_version(b"other cat"));
+
+        let signer = UrlSigner::new("secret", Duration::from_secs(60));
+        let now = Utc::now();
+        assert_eq!(
+            signer.present(cat(false), now).image_path,
+            format!("/image/default/cat.jpg?v={}", version)
+        );
+        let mut unversioned = cat(false);
+        unversioned.image_hash = None;
+        assert_eq!(
+            signer.present(unversioned, now).image_path,
+            "/image/default/cat.jpg"
+        );
+
+        let signer = signer.with_cdn_base_url(Some("https://cdn.catdex.example".to_string()));
+        assert_eq!(
+            signer.present(cat(false), now).image_path,
+            format!(
+                "https://cdn.catdex.example/image/default/cat.jpg?v={}",
+                version
+            )
+        );
+        // Private images are never handed to the CDN
+        assert!(signer
+            .present(cat(true), now)
+            .image_path
+            .starts_with("/signed-image/default/cat.jpg?"));
+
+        let photo = CatImage {
+            id: 2,
+            image_path: "/image/default/photo.jpg".to_string(),
+            image_hash: Some(version.clone()),
+            position: 1,
+            is_primary: false,
+            created_at: now,
+        };
+        assert!(signer
+            .present_image(photo, true, now)
+            .image_path
+            .starts_with("/signed-image/default/photo.jpg?"));
+    }
+
+    #[test]
+    fn test_is_public_image() {
+        assert!(is_public_image(Path::new("acme/cat.jpg")));
+        assert!(is_public_image(Path::new("private/cat.jpg")));
+        assert!(!is_public_image(Path::new("acme/private/cat.jpg")));
+    }
+}
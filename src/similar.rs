@@ -0,0 +1,239 @@
+//! Finds cats whose images look alike, even when re-encoded, resized or
+//! slightly edited, by comparing perceptual hashes: the signs of the lowest
+//! frequencies of the image's discrete cosine transform, which survive
+//! such changes.
+use crate::clock::Clock;
+use crate::config::Config;
+use crate::db::DbConn;
+use crate::errors::UserError;
+use crate::fields::{self, FieldsQuery};
+use crate::links::{LinkBuilder, Links};
+use crate::models::{Cat, Tenant};
+use crate::repository;
+use crate::signed_urls::UrlSigner;
+use crate::validated::Validated;
+use crate::DbPool;
+use actix_web::web;
+use actix_web::HttpResponse;
+#[cfg(feature = "image-processing")]
+use image::imageops::FilterType;
+use log::warn;
+use serde::{Deserialize, Serialize};
+#[cfg(feature = "image-processing")]
+use std::f64::consts::PI;
+use validator::Validate;
+
+/// Response header of a new cat listing the ids of cats with a near
+/// identical image, e.g. `X-Catdex-Similar-Cats: 12, 40`.
+pub const SIMILAR_HEADER: &str = "X-Catdex-Similar-Cats";
+
+/// Differing bits up to which two images count as near identical.
+pub const DEFAULT_MAX_DISTANCE: i32 = 10;
+const MAX_SIMILAR: i64 = 20;
+
+/// Side of the downscaled image the transform is taken of.
+#[cfg(feature = "image-processing")]
+const SAMPLE_SIZE: usize = 32;
+/// Side of the block of lowest frequencies kept in the hash.
+#[cfg(feature = "image-processing")]
+const HASH_SIZE: usize = 8;
+
+/// The perceptual hash of an image, none when it is in a format that
+/// cannot be decoded.
+#[cfg(feature = "image-processing")]
+pub fn phash(contents: &[u8]) -> Option<i64> {
+    let image = image::load_from_memory(contents).ok()?;
+    let pixels = image
+        .grayscale()
+        .resize_exact(SAMPLE_SIZE as u32, SAMPLE_SIZE as u32, FilterType::Triangle)
+        .to_luma8();
+    let cosines: Vec<[f64; SAMPLE_SIZE]> = (0..HASH_SIZE)
+        .map(|frequency| {
+            std::array::from_fn(|x| {
+                ((2 * x + 1) as f64 * frequency as f64 * PI / (2 * SAMPLE_SIZE) as f64).cos()
+            })
+        })
+        .collect();
+
+    // Transforms the rows, then the columns, keeping only low frequencies
+    let rows: Vec<[f64; HASH_SIZE]> = (0..SAMPLE_SIZE)
+        .map(|y| {
+            std::array::from_fn(|u| {
+                (0..SAMPLE_SIZE)
+                    .map(|x| f64::from(pixels.get_pixel(x as u32, y as u32)[0]) * cosines[u][x])
+                    .sum()
+            })
+        })
+        .collect();
+    let coefficients: Vec<f64> = (0..HASH_SIZE)
+        .flat_map(|v| {
+            let rows = &rows;
+            let cosines = &cosines;
+            (0..HASH_SIZE).map(move |u| (0..SAMPLE_SIZE).map(|y| rows[y][u] * cosines[v][y]).sum())
+        })
+        .collect();
+
+    // The average brightness would dominate the median, so it is left out
+    let mut sorted = coefficients[1..].to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let median = sorted[sorted.len() / 2];
+    let hash = coefficients
+        .iter()
+        .enumerate()
+        .filter(|(_, coefficient)| **coefficient > median)
+        .fold(0u64, |hash, (bit, _)| hash | 1 << bit);
+    Some(hash as i64)
+}
+
+/// Builds without the `image-processing` feature decode no images, so hash
+/// none.
+#[cfg(not(feature = "image-processing"))]
+pub fn phash(_contents: &[u8]) -> Option<i64> {
+    None
+}
+
+/// The ids of the tenant's other cats with images near identical to the
+/// perceptual hash `phash`, for warning about a new cat that is likely a
+/// duplicate. Failures are logged rather than failing the upload.
+pub async fn similar_cat_ids(
+    pool: &DbPool,
+    tenant_id: i32,
+    cat_id: i32,
+    phash: Option<i64>,
+) -> Vec<i32> {
+    let Some(phash) = phash else {
+        return Vec::new();
+    };
+    let Ok(connection) = DbConn::get(pool) else {
+        return Vec::new();
+    };
+    let similar = connection
+        .run("search similar cats", move |connection| {
+            repository::list_similar_cats(
+                connection,
+                tenant_id,
+                cat_id,
+                phash,
+                DEFAULT_MAX_DISTANCE,
+                MAX_SIMILAR,
+            )
+        })
+        .await;
+    let Ok(cats) = similar else {
+        return Vec::new();
+    };
+    let ids: Vec<i32> = cats.iter().map(|(cat, _)| cat.id).collect();
+    if !ids.is_empty() {
+        warn!("New cat ID: {} looks like cat(s) {:?}", cat_id, ids);
+    }
+    ids
+}
+
+#[derive(Deserialize)]
+pub struct CatPath {
+    id: i32,
+}
+
+#[derive(Deserialize, Validate)]
+pub struct SimilarQuery {
+    #[validate(range(min = 0, max = 64))]
+    max_distance: Option<i32>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SimilarCat {
+    #[serde(flatten)]
+    pub cat: Cat,
+    /// Bits in which the perceptual hashes differ, 0 for a look alike.
+    pub distance: i32,
+    #[serde(skip_deserializing)]
+    _links: Links,
+}
+
+/// The tenant's cats whose images look like the cat's, most alike first.
+#[allow(clippy::too_many_arguments)]
+pub async fn similar_endpoint(
+    connection: DbConn,
+    config: web::Data<Config>,
+    clock: web::Data<dyn Clock>,
+    signer: web::Data<UrlSigner>,
+    tenant: Tenant,
+    path: web::Path<CatPath>,
+    query: Validated<web::Query<SimilarQuery>>,
+    fields: web::Query<FieldsQuery>,
+) -> Result<HttpResponse, UserError> {
+    let selection = fields.selection()?;
+    let cat_id = path.id;
+    let max_distance = query.max_distance.unwrap_or(DEFAULT_MAX_DISTANCE);
+
+    let cats = connection
+        .run("search similar cats", move |connection| {
+            let phash = repository::find_cat_phash(connection, tenant.id, cat_id)?;
+            match phash {
+                Some(phash) => repository::list_similar_cats(
+                    connection,
+                    tenant.id,
+                    cat_id,
+                    phash,
+                    max_distance,
+                    MAX_SIMILAR,
+                ),
+                None => Ok(Vec::new()),
+            }
+        })
+        .await?;
+
+    let now = clock.now();
+    let links = LinkBuilder::new(&config);
+    let cats: Vec<SimilarCat> = cats
+        .into_iter()
+        .map(|(cat, distance)| {
+            let cat = signer.present(cat, now);
+            SimilarCat {
+                _links: links.cat(&cat),
+                cat,
+                distance,
+            }
+        })
+        .collect();
+    fields::json(&cats, selection.as_ref())
+}
+
+#[cfg(all(test, feature = "image-processing"))]
+mod tests {
+    use super::*;
+    use image::{ImageFormat, Rgb, RgbImage};
+    use std::io::Cursor;
+
+    fn encode(image: &RgbImage, format: ImageFormat) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        image
+            .write_to(&mut Cursor::new(&mut bytes), format)
+            .unwrap();
+        bytes
+    }
+
+    fn distance(a: i64, b: i64) -> u32 {
+        (a ^ b).count_ones()
+    }
+
+    #[test]
+    fn test_phash() {
+        let scene = RgbImage::from_fn(120, 80, |x, y| {
+            let (x, y) = (x as f64, y as f64);
+            let value = 120.0 + 60.0 * (x / 17.0).sin() * (y / 11.0).cos() + x / 2.0;
+            Rgb([value as u8, (value * 0.8) as u8, (255.0 - value) as u8])
+        });
+        let original = phash(&encode(&scene, ImageFormat::Png)).unwrap();
+
+        let resized = image::imageops::resize(&scene, 240, 160, FilterType::Nearest);
+        let resized = phash(&encode(&resized, ImageFormat::Jpeg)).unwrap();
+        assert!(distance(original, resized) <= DEFAULT_MAX_DISTANCE as u32);
+
+        let gradient = RgbImage::from_fn(120, 80, |x, y| Rgb([(x * 2) as u8, (y * 3) as u8, 90]));
+        let gradient = phash(&encode(&gradient, ImageFormat::Png)).unwrap();
+        assert!(distance(original, gradient) > DEFAULT_MAX_DISTANCE as u32);
+
+        assert_eq!(phash(b"<svg/>"), None);
+    }
+}
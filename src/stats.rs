@@ -0,0 +1,172 @@
+//! Statistics of the cats added to a tenant, for dashboards. They are read
+//! from the `cat_stats` materialized view, which a background job refreshes
+//! every `STATS_REFRESH_SECS`, so they can lag behind by that much.
+use crate::clock::Clock;
+use crate::db::DbConn;
+use crate::errors::UserError;
+use crate::health;
+use crate::models::{CatStats, CatStatus, Tenant};
+use crate::notifications::{Notification, Notifier};
+use crate::repository;
+use crate::telemetry;
+use crate::validated::Validated;
+use crate::DbPool;
+use actix_web::{web, HttpResponse};
+use chrono::{Days, NaiveDate, Utc};
+use log::error;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use std::time::Duration;
+use validator::Validate;
+
+const DEFAULT_DAYS: u32 = 30;
+
+/// Refreshes the statistics every `interval` for as long as the server runs.
+pub async fn run_refresher(pool: DbPool, interval: Duration, notifier: Option<Arc<Notifier>>) {
+    let mut interval = actix_rt::time::interval(interval);
+    let mut failing = false;
+    loop {
+        interval.tick().await;
+
+        let refresh_pool = pool.clone();
+        let refreshed = telemetry::block(move || {
+            let mut connection = refresh_pool.get().map_err(|e| e.to_string())?;
+            repository::refresh_cat_stats(&mut connection).map_err(|e| e.to_string())
+        })
+        .await;
+        match refreshed {
+            Ok(Ok(())) => {
+                failing = false;
+                health::record_job_run("stats_refresh", Utc::now());
+            }
+            Ok(Err(e)) => {
+                error!("Failed to refresh the cat statistics: {}", e);
+                if let Some(notifier) = notifier.as_ref().filter(|_| !failing) {
+                    notifier.notify(Notification::JobFailed {
+                        job: "stats_refresh",
+                        error: &e,
+                    });
+                }
+                failing = true;
+            }
+            Err(_) => error!("Blocking Thread Pool Error"),
+        }
+    }
+}
+
+#[derive(Deserialize, Validate)]
+pub struct SummaryQuery {
+    /// Days back from today the summary covers, today included.
+    #[validate(range(min = 1, max = 366))]
+    days: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct DayCount {
+    pub day: NaiveDate,
+    pub cats: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub struct Summary {
+    pub since: NaiveDate,
+    pub cats: i64,
+    pub image_bytes: i64,
+    pub by_status: BTreeMap<String, i64>,
+    /// Only the days cats were added on.
+    pub by_day: Vec<DayCount>,
+}
+
+fn summarize(since: NaiveDate, stats: &[CatStats]) -> Summary {
+    let mut by_status: BTreeMap<String, i64> =
+        [CatStatus::Available, CatStatus::Pending, CatStatus::Adopted]
+            .into_iter()
+            .map(|status| (status.as_str().to_string(), 0))
+            .collect();
+    let mut by_day: Vec<DayCount> = Vec::new();
+    for row in stats {
+        *by_status
+            .entry(row.status.as_str().to_string())
+            .or_default() += row.cats;
+        match by_day.last_mut() {
+            Some(last) if last.day == row.day => last.cats += row.cats,
+            _ => by_day.push(DayCount {
+                day: row.day,
+                cats: row.cats,
+            }),
+        }
+    }
+    Summary {
+        since,
+        cats: stats.iter().map(|row| row.cats).sum(),
+        image_bytes: stats.iter().map(|row| row.image_bytes).sum(),
+        by_status,
+        by_day,
+    }
+}
+
+/// The cats added to the tenant over the last `days` days, by adoption
+/// status and by day.
+pub async fn summary_endpoint(
+    connection: DbConn,
+    clock: web::Data<dyn Clock>,
+    tenant: Tenant,
+    query: Validated<web::Query<SummaryQuery>>,
+) -> Result<HttpResponse, UserError> {
+    let days = query.days.unwrap_or(DEFAULT_DAYS);
+    let since = clock.now().date_naive() - Days::new(u64::from(days - 1));
+    let stats = connection
+        .run("load cat statistics", move |connection| {
+            repository::list_cat_stats(connection, tenant.id, since)
+        })
+        .await?;
+    Ok(HttpResponse::Ok().json(summarize(since, &stats)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summarize() {
+        let day = |d| NaiveDate::from_ymd_opt(2026, 10, d).unwrap();
+        let row = |d, status, cats| CatStats {
+            day: day(d),
+            status,
+            cats,
+            image_bytes: cats * 100,
+        };
+        let summary = summarize(
+            day(1),
+            &[
+                row(2, CatStatus::Available, 3),
+                row(2, CatStatus::Adopted, 1),
+                row(5, CatStatus::Available, 2),
+            ],
+        );
+        assert_eq!(summary.cats, 6);
+        assert_eq!(summary.image_bytes, 600);
+        assert_eq!(
+            summary.by_status,
+            BTreeMap::from([
+                ("adopted".to_string(), 1),
+                ("available".to_string(), 5),
+                ("pending".to_string(), 0)
+            ])
+        );
+        assert_eq!(
+            summary.by_day,
+            vec![
+                DayCount {
+                    day: day(2),
+                    cats: 4
+                },
+                DayCount {
+                    day: day(5),
+                    cats: 2
+                },
+            ]
+        );
+    }
+}
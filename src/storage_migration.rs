@@ -0,0 +1,310 @@
+//! Moving every stored file to another store, e.g. from the local image
+//! directory to object storage: `POST /api/admin/migrate-storage`. Files
+//! are copied in batches under fresh keys, and each copy is read back and
+//! checked against the checksum of its original. The references to a
+//! batch's files are then rewritten in one transaction, and only once it
+//! commits are the originals deleted. Signed links follow the new keys, so
+//! links handed out before stop working. The response streams the
+//! progress as NDJSON, a line per batch and a last one with `done` set.
+//!
+//! Files are moved to the store the app is built with as its
+//! `migration_target`, or else a directory named by `STORAGE_MIGRATION_DIR`.
+//! As images keep being served from the old store until the server
+//! restarts with the new one, for that directory with `IMAGE_DIR` set to
+//! it, migrating requires maintenance mode. A migration cut short can be
+//! started again, skipping the files it moved.
+use crate::auth;
+use crate::clock::Clock;
+use crate::config::Config;
+use crate::db::{self, DbConn};
+use crate::errors::UserError;
+use crate::file_store::{FileStore, LocalFileStore};
+use crate::history::Requester;
+use crate::image_shards;
+use crate::maintenance::Maintenance;
+use crate::models::Tenant;
+use crate::repository;
+use crate::DbPool;
+use actix_web::{web, Error, HttpResponse};
+use diesel::Connection;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+pub const DEFAULT_BATCH_SIZE: usize = 100;
+const MAX_BATCH_SIZE: usize = 1000;
+
+/// The store files are moved to.
+pub struct MigrationTarget(pub Arc<dyn FileStore>);
+
+/// The directory `STORAGE_MIGRATION_DIR` names as a store, if any, sharded
+/// by `clock`.
+pub fn from_config(config: &Config, clock: Arc<dyn Clock>) -> Option<Arc<dyn FileStore>> {
+    let dir = config.storage_migration_dir.as_ref()?;
+    Some(Arc::new(
+        LocalFileStore::new(dir)
+            .with_date_shards(config.image_date_shards)
+            .with_clock(clock),
+    ))
+}
+
+/// How far a migration got.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Progress {
+    /// Stored files referenced when the migration started.
+    pub total: usize,
+    pub migrated: usize,
+    /// Files moved by an earlier migration, or not in the store at all.
+    pub skipped: usize,
+    /// Files left where they were, as they could not be copied or the copy
+    /// did not match.
+    pub failed: usize,
+    pub done: bool,
+}
+
+/// What became of one batch's files before their references moved.
+#[derive(Default)]
+struct CopiedBatch {
+    /// The keys of the copied files, and of their copies.
+    moves: Vec<(String, String)>,
+    skipped: usize,
+    failed: usize,
+}
+
+/// Copies the file `key` to `target` under a fresh key in the same
+/// directory, returning the new key once the copy reads back with the
+/// checksum of the original. None when the file is in `target` already.
+fn copy_file(
+    source: &dyn FileStore,
+    target: &dyn FileStore,
+    key: &str,
+) -> io::Result<Option<String>> {
+    let contents = match source.get(key) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound && target.size(key).is_ok() => {
+            return Ok(None)
+        }
+        Err(e) => return Err(e),
+    };
+    let (dir, name) = key
+        .rsplit_once('/')
+        .ok_or_else(|| io::Error::other("the key is in no directory"))?;
+    let dir = image_shards::unsharded_dir(dir).unwrap_or(dir);
+    let extension = Path::new(name).extension().and_then(|ext| ext.to_str());
+    let new_key = target.new_key(dir, extension);
+    target.put(&new_key, &mut contents.as_slice())?;
+    let copied = target.get(&new_key).and_then(|copy| {
+        match Sha256::digest(&copy) == Sha256::digest(&contents) {
+            true => Ok(()),
+            false => Err(io::Error::other("the copy does not match the original")),
+        }
+    });
+    if let Err(e) = copied {
+        let _ = target.delete(&new_key);
+        return Err(e);
+    }
+    Ok(Some(new_key))
+}
+
+fn copy_batch(source: &dyn FileStore, target: &dyn FileStore, paths: &[String]) -> CopiedBatch {
+    let mut copied = CopiedBatch::default();
+    for path in paths {
+        let Some(key) = path.strip_prefix("/image/") else {
+            warn!("Not migrating {}, which is not a stored file", path);
+            copied.skipped += 1;
+            continue;
+        };
+        match copy_file(source, target, key) {
+            Ok(Some(new_key)) => copied.moves.push((key.to_string(), new_key)),
+            Ok(None) => copied.skipped += 1,
+            Err(e) => {
+                error!("Failed to migrate {}: {}", key, e);
+                copied.failed += 1;
+            }
+        }
+    }
+    copied
+}
+
+/// Moves the files at `paths`, adding them to `progress`.
+async fn migrate_batch(
+    pool: &DbPool,
+    source: Arc<dyn FileStore>,
+    target: Arc<dyn FileStore>,
+    paths: Vec<String>,
+    progress: &mut Progress,
+) -> Result<(), UserError> {
+    let copied = db::block({
+        let (source, target) = (source.clone(), target.clone());
+        move || copy_batch(source.as_ref(), target.as_ref(), &paths)
+    })
+    .await?;
+    progress.skipped += copied.skipped;
+    progress.failed += copied.failed;
+    let moves = copied.moves;
+    if moves.is_empty() {
+        return Ok(());
+    }
+
+    let connection = DbConn::get(pool)?;
+    let renamed = connection
+        .run("rewrite migrated image paths", {
+            let moves = moves.clone();
+            move |connection| {
+                connection.transaction(|connection| {
+                    for (old_key, new_key) in &moves {
+                        repository::rename_image_path(
+                            connection,
+                            &format!("/image/{}", old_key),
+                            &format!("/image/{}", new_key),
+                        )?;
+                    }
+                    Ok(())
+                })
+            }
+        })
+        .await;
+    if let Err(e) = renamed {
+        // Nothing references the copies, while the originals are in use
+        let moves = moves.clone();
+        db::block(move || {
+            for (_, new_key) in moves {
+                let _ = target.delete(&new_key);
+            }
+        })
+        .await?;
+        return Err(e);
+    }
+    progress.migrated += moves.len();
+    db::block(move || {
+        for (old_key, _) in moves {
+            if let Err(e) = source.delete(&old_key) {
+                warn!("Failed to delete {} after migrating it: {}", old_key, e);
+            }
+        }
+    })
+    .await
+}
+
+#[derive(Deserialize)]
+pub struct MigrateQuery {
+    batch_size: Option<usize>,
+}
+
+fn progress_line(progress: &Progress) -> Result<web::Bytes, Error> {
+    let mut line = serde_json::to_vec(progress).map_err(|e| {
+        error!("Failed to serialize migration progress: {}", e);
+        UserError::UnexpectedError
+    })?;
+    line.push(b'\n');
+    Ok(web::Bytes::from(line))
+}
+
+/// Moves every stored file to the migration target, streaming the
+/// `Progress` as each batch is done. A failure to rewrite a batch's
+/// references ends the migration, with the batch left where it was.
+#[allow(clippy::too_many_arguments)]
+pub async fn migrate_storage_endpoint(
+    pool: web::Data<DbPool>,
+    maintenance: web::Data<Maintenance>,
+    store: web::Data<dyn FileStore>,
+    target: Option<web::Data<MigrationTarget>>,
+    tenant: Tenant,
+    requester: Requester,
+    query: web::Query<MigrateQuery>,
+) -> Result<HttpResponse, UserError> {
+    auth::require_operator(&pool, &tenant, requester).await?;
+    let Some(target) = target else {
+        warn!("Storage migration requested, but no target is set up");
+        return Err(UserError::NotFoundError);
+    };
+    if !maintenance.load().enabled {
+        warn!("Refused to migrate storage outside maintenance mode");
+        return Err(UserError::MaintenanceRequiredError);
+    }
+    let batch_size = query.batch_size.unwrap_or(DEFAULT_BATCH_SIZE);
+    if !(1..=MAX_BATCH_SIZE).contains(&batch_size) {
+        warn!("Invalid migration batch size {}", batch_size);
+        return Err(UserError::ValidationError);
+    }
+
+    let mut paths = DbConn::get(&pool)?
+        .run("list image paths", repository::list_image_paths)
+        .await?;
+    paths.sort();
+    paths.dedup();
+    info!(
+        "Migrating {} stored file(s) from the {} store to the {} store",
+        paths.len(),
+        store.kind(),
+        target.0.kind()
+    );
+    let progress = Progress {
+        total: paths.len(),
+        ..Progress::default()
+    };
+    let batches: Vec<Vec<String>> = paths.chunks(batch_size).map(<[String]>::to_vec).collect();
+    let (source, target) = (store.into_inner(), target.0.clone());
+
+    let lines = futures_util::stream::unfold(Some((batches.into_iter(), progress)), move |state| {
+        let (pool, source, target) = (pool.clone(), source.clone(), target.clone());
+        async move {
+            let (mut batches, mut progress) = state?;
+            let Some(paths) = batches.next() else {
+                progress.done = true;
+                info!(
+                    "Migrated {} stored file(s), skipped {} and failed {}",
+                    progress.migrated, progress.skipped, progress.failed
+                );
+                return Some((progress_line(&progress), None));
+            };
+            match migrate_batch(&pool, source, target, paths, &mut progress).await {
+                Ok(()) => Some((progress_line(&progress), Some((batches, progress)))),
+                Err(e) => {
+                    error!("Storage migration stopped: {}", e);
+                    Some((Err(Error::from(e)), None))
+                }
+            }
+        }
+    });
+    Ok(HttpResponse::Ok()
+        .content_type("application/x-ndjson")
+        .streaming(lines))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::MemoryFileStore;
+
+    #[test]
+    fn test_copy_batch() {
+        let source = MemoryFileStore::default();
+        let target = MemoryFileStore::default();
+        source
+            .put("acme/2026/10/15/cat.jpg", &mut &b"jpeg"[..])
+            .unwrap();
+        target.put("acme/moved.png", &mut &b"png"[..]).unwrap();
+        let paths = [
+            "/image/acme/2026/10/15/cat.jpg",
+            "/image/acme/moved.png",
+            "/image/acme/missing.gif",
+            "/static/placeholder.svg",
+        ]
+        .map(String::from);
+
+        let copied = copy_batch(&source, &target, &paths);
+        assert_eq!((copied.skipped, copied.failed), (2, 1));
+        let [(old_key, new_key)] = &copied.moves[..] else {
+            panic!("Expected one move, got {:?}", copied.moves);
+        };
+        assert_eq!(old_key, "acme/2026/10/15/cat.jpg");
+        assert!(new_key.starts_with("acme/") && new_key.ends_with(".jpg"));
+        assert_eq!(target.get(new_key).unwrap(), b"jpeg");
+        // Deleted only once the references moved
+        assert_eq!(source.get(old_key).unwrap(), b"jpeg");
+    }
+}
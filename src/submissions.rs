@@ -0,0 +1,221 @@
+//! Public submissions of cats, e.g. from kiosks, through
+//! `POST /api/submit_cat`. It takes the same form as `POST /api/add_cat`
+//! plus the token of a solved CAPTCHA, in a `captcha_token` field or the
+//! provider widget's own `h-captcha-response` or `g-recaptcha-response`.
+//!
+//! Each address may submit `PUBLIC_SUBMISSION_LIMIT` cats per
+//! `PUBLIC_SUBMISSION_WINDOW_SECS`, solved or not, counted before the form
+//! is read so addresses over the limit cannot upload, and every submitted cat
+//! goes to the moderation queue whatever `MODERATE_SUBMISSIONS` says. The
+//! route is off until `CAPTCHA_PROVIDER` is set.
+use crate::cache::CacheBackend;
+use crate::captcha::CaptchaVerifier;
+use crate::cat_detection::CatDetector;
+use crate::cats::{
+    created_cat, discard_upload, flag_for_review, insert_new_cat, inspect_stored_image,
+};
+use crate::cats::{persist_upload, screen_upload, upload_dir, upload_size};
+use crate::client_ip::ClientIp;
+use crate::clock::Clock;
+use crate::config::Config;
+use crate::errors::UserError;
+use crate::file_store::FileStore;
+use crate::geo;
+use crate::heic;
+use crate::history::Requester;
+use crate::models::{NewCat, Tenant};
+use crate::notifications::Notifier;
+use crate::reload::LiveSettings;
+use crate::scanner::Scanner;
+use crate::DbPool;
+use actix_web::{web, FromRequest, HttpRequest, HttpResponse};
+use log::{error, info, warn};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use validator::Validate;
+
+/// Form fields the CAPTCHA token is looked for in, in order.
+const CAPTCHA_FIELDS: [&str; 3] = [
+    "captcha_token",
+    "h-captcha-response",
+    "g-recaptcha-response",
+];
+
+/// Counts the submissions of each address in fixed windows, in the
+/// `CacheBackend` so that instances share the counts.
+pub struct SubmissionLimiter {
+    cache: Arc<dyn CacheBackend>,
+}
+
+impl SubmissionLimiter {
+    pub fn new(cache: Arc<dyn CacheBackend>) -> SubmissionLimiter {
+        SubmissionLimiter { cache }
+    }
+
+    /// Counts a submission from `ip`, or refuses it with the seconds until
+    /// the address may submit again, when over `limit` per `window`. Admits
+    /// it when the cache is down, as its CAPTCHA still has to be solved.
+    async fn admit(&self, ip: IpAddr, limit: u32, window: Duration) -> Result<(), u64> {
+        let key = format!("submissions:{}", ip);
+        match self.cache.increment(&key, window).await {
+            Ok((count, left)) if count > u64::from(limit) => Err(left.as_secs().max(1)),
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("Failed to count the submissions from {}: {}", ip, e);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Stores a publicly submitted cat for moderation, once its CAPTCHA is
+/// verified.
+#[allow(clippy::too_many_arguments)]
+pub async fn submit_cat_endpoint(
+    pool: web::Data<DbPool>,
+    config: web::Data<Config>,
+    settings: web::Data<LiveSettings>,
+    clock: web::Data<dyn Clock>,
+    store: web::Data<dyn FileStore>,
+    scanner: Option<web::Data<dyn Scanner>>,
+    detector: Option<web::Data<dyn CatDetector>>,
+    notifier: Option<web::Data<Notifier>>,
+    verifier: Option<web::Data<dyn CaptchaVerifier>>,
+    limiter: web::Data<SubmissionLimiter>,
+    tenant: Tenant,
+    requester: Requester,
+    ClientIp(client_ip): ClientIp,
+    req: HttpRequest,
+    payload: web::Payload,
+) -> Result<HttpResponse, UserError> {
+    let Some(verifier) = verifier else {
+        warn!("Public submission refused, CAPTCHA_PROVIDER is not set");
+        return Err(UserError::NotFoundError);
+    };
+    let current = settings.load();
+    limiter
+        .admit(
+            client_ip,
+            current.public_submission_limit,
+            current.public_submission_window,
+        )
+        .await
+        .map_err(|retry_after| {
+            warn!("Public submissions from {} are over the limit", client_ip);
+            UserError::RateLimitedError(retry_after)
+        })?;
+    let mut parts = awmp::Parts::from_request(&req, &mut payload.into_inner())
+        .await
+        .map_err(|e| {
+            warn!("Failed to read the submission from {}: {}", client_ip, e);
+            UserError::ValidationError
+        })?;
+
+    let text_fields: HashMap<_, _> = parts.texts.as_pairs().into_iter().collect();
+    let token = CAPTCHA_FIELDS
+        .iter()
+        .find_map(|field| text_fields.get(field))
+        .filter(|token| !token.is_empty())
+        .ok_or_else(|| {
+            warn!("Public submission from {} has no CAPTCHA token", client_ip);
+            UserError::CaptchaError
+        })?;
+    let solved = verifier.verify(token, Some(client_ip)).await.map_err(|e| {
+        error!("Failed to verify CAPTCHA: {}", e);
+        UserError::UnexpectedError
+    })?;
+    if !solved {
+        warn!("Public submission from {} failed its CAPTCHA", client_ip);
+        return Err(UserError::CaptchaError);
+    }
+    let name = text_fields
+        .get("name")
+        .ok_or_else(|| {
+            error!("Error in getting name field");
+            UserError::ValidationError
+        })?
+        .trim()
+        .to_string();
+    let location = geo::parse_location(&text_fields)?;
+
+    let file = parts.files.take("image").pop().ok_or_else(|| {
+        error!("Error in getting image file");
+        UserError::ValidationError
+    })?;
+    let file = screen_upload(scanner, &config, file).await?;
+    let (file, original_format) = heic::convert_upload(&config, file).await?;
+    let image_size = upload_size(&file)?;
+    let dir = upload_dir(&tenant.slug, false);
+    let image_key =
+        persist_upload(store.get_ref(), file, &dir, original_format).ok_or_else(|| {
+            error!("Error in getting image path");
+            UserError::UnexpectedError
+        })?;
+    let image = inspect_stored_image(&store, detector, &config, &image_key)
+        .await
+        .inspect_err(|_| discard_upload(store.get_ref(), &image_key))?;
+    let new_cat = NewCat {
+        name,
+        image_path: format!("/image/{}", image_key),
+        created_at: clock.now(),
+        image_size,
+        private: false,
+        latitude: location.map(|(latitude, _)| latitude),
+        longitude: location.map(|(_, longitude)| longitude),
+        image_hash: image.version,
+        image_phash: image.phash,
+        pending_review: true,
+        image_original_format: original_format.map(str::to_string),
+    };
+    if let Err(errors) = new_cat.validate() {
+        warn!("New cat validation failed");
+        discard_upload(store.get_ref(), &image_key);
+        return Err(UserError::FieldValidationError(errors));
+    }
+
+    let allow_duplicate = !current.unique_cat_names;
+    let outcome =
+        insert_new_cat(&pool, tenant.id, requester, new_cat, allow_duplicate, true).await?;
+    let cat = created_cat(
+        outcome,
+        store.get_ref(),
+        notifier.as_ref().map(|notifier| notifier.get_ref()),
+        &tenant,
+        &image_key,
+        true,
+    )?;
+    flag_for_review(&cat, image.flagged);
+    info!(
+        "Cat ID: {} submitted from {} for moderation",
+        cat.id, client_ip
+    );
+    Ok(HttpResponse::Accepted().finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache::MemoryCache;
+    use crate::test_support::ManualClock;
+    use chrono::TimeDelta;
+
+    #[actix_web::test]
+    async fn test_submission_limiter() {
+        let clock = Arc::new(ManualClock::new());
+        let cache = Arc::new(MemoryCache::new(clock.clone()));
+        let limiter = SubmissionLimiter::new(cache);
+        let admit = |ip| limiter.admit(ip, 2, Duration::from_secs(60));
+        let (kiosk, other): (IpAddr, IpAddr) =
+            ("192.0.2.1".parse().unwrap(), "192.0.2.2".parse().unwrap());
+        assert_eq!(admit(kiosk).await, Ok(()));
+        clock.advance(TimeDelta::seconds(10));
+        assert_eq!(admit(kiosk).await, Ok(()));
+        clock.advance(TimeDelta::seconds(10));
+        assert_eq!(admit(kiosk).await, Err(40));
+        assert_eq!(admit(other).await, Ok(()));
+        clock.advance(TimeDelta::seconds(40));
+        assert_eq!(admit(kiosk).await, Ok(()));
+    }
+}
@@ -0,0 +1,121 @@
+//! Delta sync of the cats: `GET /api/cats?modified_since=<RFC 3339 time>`
+//! or `?sync_token=<token>` answers with only the cats changed since then
+//! and the tombstones of the cats deleted since then:
+//!
+//! ```json
+//! {"cats": [...], "deleted": [{"id": 1, "public_id": "...", "deleted_at": "..."}], "sync_token": "..."}
+//! ```
+//!
+//! Clients keep the `sync_token` and send it with their next sync. A cat
+//! may be sent again by the sync after the one it changed in, so clients
+//! should treat the cats as upserts, applied before the deletions.
+use crate::clock::Clock;
+use crate::db::DbConn;
+use crate::errors::UserError;
+use crate::models::{Cat, CatTombstone, Tenant};
+use crate::repository;
+use crate::signed_urls::UrlSigner;
+use base64::engine::general_purpose::URL_SAFE_NO_PAD;
+use base64::Engine;
+use chrono::{DateTime, TimeDelta, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+/// How far before a sync token changes are looked for again. Changes are
+/// stamped when their transaction starts, so a change committed just after
+/// a sync can carry an earlier time than the token of that sync.
+const SYNC_OVERLAP: TimeDelta = TimeDelta::seconds(60);
+
+#[derive(Deserialize)]
+pub struct SyncQuery {
+    modified_since: Option<DateTime<Utc>>,
+    sync_token: Option<String>,
+}
+
+impl SyncQuery {
+    /// Whether a delta sync is asked for rather than the plain listing.
+    pub fn is_sync(&self) -> bool {
+        self.modified_since.is_some() || self.sync_token.is_some()
+    }
+
+    /// The time to look for changes from.
+    fn since(&self) -> Result<DateTime<Utc>, UserError> {
+        match (&self.modified_since, &self.sync_token) {
+            (Some(modified_since), None) => Ok(*modified_since),
+            (None, Some(token)) => parse_token(token).ok_or_else(|| {
+                warn!("Invalid sync token: {}", token);
+                UserError::ValidationError
+            }),
+            _ => {
+                warn!("Both modified_since and sync_token given");
+                Err(UserError::ValidationError)
+            }
+        }
+    }
+}
+
+/// What changed since a client's last sync.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CatChanges {
+    pub cats: Vec<Cat>,
+    pub deleted: Vec<CatTombstone>,
+    /// To be sent as `sync_token` by the next sync.
+    pub sync_token: String,
+}
+
+fn encode_token(at: DateTime<Utc>) -> String {
+    URL_SAFE_NO_PAD.encode(at.to_rfc3339())
+}
+
+fn parse_token(token: &str) -> Option<DateTime<Utc>> {
+    let decoded = URL_SAFE_NO_PAD.decode(token).ok()?;
+    let at = DateTime::parse_from_rfc3339(std::str::from_utf8(&decoded).ok()?).ok()?;
+    Some(at.with_timezone(&Utc) - SYNC_OVERLAP)
+}
+
+/// The tenant's cats and tombstones changed since `query` asks for.
+///
+/// The token's time is read before the changes, so anything changed after
+/// it is sent again by the next sync rather than missed.
+pub async fn changes(
+    connection: DbConn,
+    signer: &UrlSigner,
+    clock: &dyn Clock,
+    tenant: Tenant,
+    query: &SyncQuery,
+) -> Result<CatChanges, UserError> {
+    let since = query.since()?;
+    let (cats, deleted, synced_at) = connection
+        .run("sync cats", move |connection| {
+            let synced_at = repository::database_now(connection)?;
+            let cats = repository::list_cats_changed_since(connection, tenant.id, since)?;
+            let deleted = repository::list_cat_tombstones_since(connection, tenant.id, since)?;
+            Ok((cats, deleted, synced_at))
+        })
+        .await?;
+    let now = clock.now();
+    Ok(CatChanges {
+        cats: cats
+            .into_iter()
+            .map(|cat| signer.present(cat, now))
+            .collect(),
+        deleted,
+        sync_token: encode_token(synced_at),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sync_token() {
+        let at = DateTime::parse_from_rfc3339("2026-10-16T02:00:00.123456Z")
+            .unwrap()
+            .with_timezone(&Utc);
+        let token = encode_token(at);
+        assert_eq!(parse_token(&token), Some(at - SYNC_OVERLAP));
+        assert_eq!(parse_token("not a token!"), None);
+        assert_eq!(parse_token(&URL_SAFE_NO_PAD.encode("yesterday")), None);
+    }
+}
@@ -0,0 +1,198 @@
+//! OpenTelemetry tracing of requests, database queries, storage I/O and
+//! image handling, exported over OTLP/HTTP when
+//! `OTEL_EXPORTER_OTLP_ENDPOINT` is set. Until then every span is a no-op.
+//!
+//! Requests continue the trace of an incoming `traceparent` header. The
+//! current trace context follows a request's future through the
+//! `trace_requests` middleware and into blocking work through `block`.
+use crate::config::Config;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::error::BlockingError;
+use actix_web::http::header::HeaderMap;
+use actix_web::middleware::Next;
+use actix_web::{web, Error};
+use log::info;
+use opentelemetry::context::{FutureExt, WithContext};
+use opentelemetry::propagation::{Extractor, TextMapPropagator};
+use opentelemetry::trace::{SpanKind, Status, TraceContextExt, Tracer};
+use opentelemetry::{global, Context, KeyValue};
+use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+use opentelemetry_sdk::propagation::TraceContextPropagator;
+use opentelemetry_sdk::trace::{Sampler, SdkTracerProvider};
+use opentelemetry_sdk::Resource;
+use std::fmt::Display;
+use std::future::Future;
+
+const TRACER: &str = "catdex-api";
+
+/// Installs the exporting tracer when an OTLP endpoint is configured. The
+/// returned provider is to be shut down on exit, which exports the spans
+/// still queued.
+pub fn init(config: &Config) -> Result<Option<SdkTracerProvider>, String> {
+    let Some(endpoint) = &config.otlp_endpoint else {
+        return Ok(None);
+    };
+    let endpoint = format!("{}/v1/traces", endpoint.trim_end_matches('/'));
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(&endpoint)
+        .build()
+        .map_err(|e| format!("Failed to set up the OTLP exporter for {}: {}", endpoint, e))?;
+    let resource = Resource::builder()
+        .with_service_name(config.otel_service_name.clone())
+        .with_attribute(KeyValue::new("service.version", env!("CARGO_PKG_VERSION")))
+        .build();
+    // Requests that arrive as part of a trace follow its sampling decision
+    let sampler = Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+        config.trace_sample_ratio,
+    )));
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_sampler(sampler)
+        .with_resource(resource)
+        .build();
+    global::set_tracer_provider(provider.clone());
+    info!(
+        "Exporting traces to {}, sampling {} of them",
+        endpoint, config.trace_sample_ratio
+    );
+    Ok(Some(provider))
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+/// The trace context a request carries in its `traceparent` and
+/// `tracestate` headers, empty when it has none.
+fn remote_context(headers: &HeaderMap) -> Context {
+    TraceContextPropagator::new().extract(&HeaderExtractor(headers))
+}
+
+/// Middleware running each request in a server span, named after its
+/// route so requests for different cats share a name.
+pub async fn trace_requests(
+    req: ServiceRequest,
+    next: Next<impl MessageBody>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let parent = remote_context(req.headers());
+    let route = req.match_pattern();
+    let name = match &route {
+        Some(route) => format!("{} {}", req.method(), route),
+        None => req.method().to_string(),
+    };
+    let mut attributes = vec![
+        KeyValue::new("http.request.method", req.method().to_string()),
+        KeyValue::new("url.path", req.path().to_string()),
+    ];
+    if let Some(route) = route {
+        attributes.push(KeyValue::new("http.route", route));
+    }
+    let tracer = global::tracer(TRACER);
+    let span = tracer
+        .span_builder(name)
+        .with_kind(SpanKind::Server)
+        .with_attributes(attributes)
+        .start_with_context(&tracer, &parent);
+    let cx = parent.with_span(span);
+
+    let result = next.call(req).with_context(cx.clone()).await;
+    let status = match &result {
+        Ok(res) => res.status(),
+        Err(e) => e.as_response_error().status_code(),
+    };
+    let span = cx.span();
+    span.set_attribute(KeyValue::new(
+        "http.response.status_code",
+        i64::from(status.as_u16()),
+    ));
+    // Client errors leave the status unset, as they are not the server's
+    if status.is_server_error() {
+        span.set_status(Status::error(
+            status.canonical_reason().unwrap_or("Server error"),
+        ));
+    }
+    span.end();
+    result
+}
+
+/// Runs `run` in a span named `name`, a child of the current span.
+pub fn in_span<T>(name: &'static str, attributes: Vec<KeyValue>, run: impl FnOnce() -> T) -> T {
+    let tracer = global::tracer(TRACER);
+    let span = tracer
+        .span_builder(name)
+        .with_attributes(attributes)
+        .start(&tracer);
+    let _guard = Context::current_with_span(span).attach();
+    run()
+}
+
+/// Runs `future` in a span named `name`, a child of the current span. The
+/// span ends when the future completes or is dropped.
+pub fn in_span_async<F: Future>(
+    name: &'static str,
+    attributes: Vec<KeyValue>,
+    future: F,
+) -> WithContext<F> {
+    let tracer = global::tracer(TRACER);
+    let span = tracer
+        .span_builder(name)
+        .with_attributes(attributes)
+        .start(&tracer);
+    future.with_context(Context::current_with_span(span))
+}
+
+/// Marks the current span as failed with `error`.
+pub fn record_error(error: &impl Display) {
+    Context::map_current(|cx| cx.span().set_status(Status::error(error.to_string())));
+}
+
+/// Like `web::block`, but runs `run` in the current trace context, so the
+/// spans it starts join the request's trace.
+pub fn block<F, R>(run: F) -> impl Future<Output = Result<R, BlockingError>>
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    let cx = Context::current();
+    web::block(move || {
+        let _guard = cx.attach();
+        run()
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::header::HeaderValue;
+
+    #[test]
+    fn test_remote_context() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "traceparent".parse().unwrap(),
+            HeaderValue::from_static("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+        );
+        let cx = remote_context(&headers);
+        let span = cx.span();
+        let context = span.span_context();
+        assert!(context.is_remote());
+        assert!(context.is_sampled());
+        assert_eq!(
+            context.trace_id().to_string(),
+            "4bf92f3577b34da6a3ce929d0e0e4736"
+        );
+
+        let cx = remote_context(&HeaderMap::new());
+        assert!(!cx.span().span_context().is_valid());
+    }
+}
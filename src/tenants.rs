@@ -0,0 +1,140 @@
+use crate::config::Config;
+use crate::db::DbConn;
+use crate::errors::UserError;
+use crate::models::Tenant;
+use crate::repository;
+use crate::DbPool;
+use actix_web::body::MessageBody;
+use actix_web::dev::{Payload, ServiceRequest, ServiceResponse};
+use actix_web::middleware::Next;
+use actix_web::{web, Error, FromRequest, HttpMessage, HttpRequest};
+use log::{error, warn};
+use std::future::{ready, Ready};
+
+pub const TENANT_HEADER: &str = "X-Tenant";
+pub const DEFAULT_TENANT: &str = "default";
+
+/// Picks the requested tenant slug: the `X-Tenant` header wins, then the
+/// subdomain of `base_domain`, then the default tenant.
+fn requested_slug<'a>(
+    header: Option<&'a str>,
+    host: &'a str,
+    base_domain: Option<&str>,
+) -> &'a str {
+    if let Some(slug) = header {
+        return slug.trim();
+    }
+    let host = host.split(':').next().unwrap_or(host);
+    base_domain
+        .and_then(|base| host.strip_suffix(base)?.strip_suffix('.'))
+        .filter(|subdomain| !subdomain.is_empty() && !subdomain.contains('.'))
+        .unwrap_or(DEFAULT_TENANT)
+}
+
+/// Slugs end up in storage paths, so only allow lowercase letters, digits and dashes.
+fn is_valid_slug(slug: &str) -> bool {
+    !slug.is_empty()
+        && slug
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+}
+
+/// Resolves the tenant of an API request and stores it in the request
+/// extensions, where handlers pick it up through the `Tenant` extractor.
+pub async fn resolve_tenant(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    match lookup_tenant(&req).await {
+        Ok(tenant) => {
+            req.extensions_mut().insert(tenant);
+            next.call(req)
+                .await
+                .map(ServiceResponse::map_into_left_body)
+        }
+        // Rendered here rather than returned, so outer middleware such as
+        // error localization still sees the response
+        Err(e) => Ok(req.error_response(e).map_into_right_body()),
+    }
+}
+
+async fn lookup_tenant(req: &ServiceRequest) -> Result<Tenant, UserError> {
+    let header = match req.headers().get(TENANT_HEADER) {
+        Some(value) => Some(value.to_str().map_err(|_| {
+            warn!("Tenant header is not valid UTF-8");
+            UserError::ValidationError
+        })?),
+        None => None,
+    };
+    let base_domain = req
+        .app_data::<web::Data<Config>>()
+        .and_then(|config| config.tenant_base_domain.clone());
+    let slug =
+        requested_slug(header, req.connection_info().host(), base_domain.as_deref()).to_string();
+    if !is_valid_slug(&slug) {
+        warn!("Invalid tenant slug requested");
+        return Err(UserError::ValidationError);
+    }
+
+    let pool = req
+        .app_data::<web::Data<DbPool>>()
+        .ok_or_else(|| {
+            error!("DB pool missing from app data");
+            UserError::UnexpectedError
+        })?
+        .clone();
+    let connection = DbConn::get(&pool)?;
+    let lookup_slug = slug.clone();
+    connection
+        .run(&format!("find tenant {}", slug), move |connection| {
+            repository::find_tenant_by_slug(connection, &lookup_slug)
+        })
+        .await
+}
+
+impl FromRequest for Tenant {
+    type Error = UserError;
+    type Future = Ready<Result<Tenant, UserError>>;
+
+    fn from_request(req: &HttpRequest, _: &mut Payload) -> Self::Future {
+        ready(req.extensions().get::<Tenant>().cloned().ok_or_else(|| {
+            error!("Tenant was not resolved for this route");
+            UserError::UnexpectedError
+        }))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_requested_slug() {
+        let base = Some("catdex.example");
+        assert_eq!(
+            requested_slug(Some("acme"), "other.catdex.example", base),
+            "acme"
+        );
+        assert_eq!(
+            requested_slug(None, "acme.catdex.example:8080", base),
+            "acme"
+        );
+        assert_eq!(requested_slug(None, "catdex.example", base), DEFAULT_TENANT);
+        assert_eq!(
+            requested_slug(None, "a.b.catdex.example", base),
+            DEFAULT_TENANT
+        );
+        assert_eq!(
+            requested_slug(None, "acme.catdex.example", None),
+            DEFAULT_TENANT
+        );
+    }
+
+    #[test]
+    fn test_is_valid_slug() {
+        assert!(is_valid_slug("acme-cats2"));
+        assert!(!is_valid_slug(""));
+        assert!(!is_valid_slug("../etc"));
+        assert!(!is_valid_slug("Acme"));
+    }
+}
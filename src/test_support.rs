@@ -0,0 +1,318 @@
+//! Database fixtures and test doubles for the endpoint tests.
+//!
+//! Postgres is started in a container through testcontainers, once per test
+//! run. Set `TEST_DATABASE_URL` to use an existing server instead, e.g. when
+//! Docker is not available.
+use crate::captcha::CaptchaVerifier;
+use crate::cli::MIGRATIONS;
+use crate::clock::Clock;
+use crate::config::Config;
+use crate::file_store::FileStore;
+use crate::models::{Cat, NewAdmin, NewCat};
+use crate::repository;
+use crate::scanner::Scanner;
+use crate::signed_urls::UrlSigner;
+use crate::{CatdexApp, CatdexAppBuilder, DbPool};
+use actix_http::Request;
+use actix_web::body::MessageBody;
+use actix_web::cookie::Key;
+use actix_web::dev::{Service, ServiceResponse};
+use actix_web::{test, Error};
+use chrono::{DateTime, TimeDelta, TimeZone, Utc};
+use diesel::r2d2::{ConnectionManager, CustomizeConnection};
+use diesel::{Connection, PgConnection};
+use diesel_migrations::MigrationHarness;
+use futures_util::future::LocalBoxFuture;
+use std::collections::HashMap;
+use std::env;
+use std::io::{self, Read};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::Duration;
+use testcontainers_modules::postgres::Postgres;
+use testcontainers_modules::testcontainers::runners::SyncRunner;
+use testcontainers_modules::testcontainers::Container;
+
+static POSTGRES: OnceLock<Container<Postgres>> = OnceLock::new();
+static DATABASE_URL: OnceLock<String> = OnceLock::new();
+
+/// Returns the URL of a migrated test database.
+fn database_url() -> &'static str {
+    DATABASE_URL.get_or_init(|| {
+        let url = env::var("TEST_DATABASE_URL").unwrap_or_else(|_| {
+            let container = POSTGRES.get_or_init(|| {
+                Postgres::default()
+                    .start()
+                    .expect("Failed to start Postgres container")
+            });
+            format!(
+                "postgres://postgres:postgres@{}:{}/postgres",
+                container.get_host().expect("Container host"),
+                container.get_host_port_ipv4(5432).expect("Container port")
+            )
+        });
+        let mut connection =
+            PgConnection::establish(&url).expect("Failed to connect to test database");
+        connection
+            .run_pending_migrations(MIGRATIONS)
+            .expect("Failed to migrate test database");
+        url
+    })
+}
+
+#[derive(Debug)]
+struct TestTransaction;
+
+impl CustomizeConnection<PgConnection, diesel::r2d2::Error> for TestTransaction {
+    fn on_acquire(&self, connection: &mut PgConnection) -> Result<(), diesel::r2d2::Error> {
+        connection
+            .begin_test_transaction()
+            .map_err(diesel::r2d2::Error::QueryError)
+    }
+}
+
+/// A pool with a single connection that stays inside a transaction which is
+/// never committed, so every test starts from the migrated schema and its
+/// writes are invisible to other tests.
+pub fn test_pool() -> DbPool {
+    r2d2::Pool::builder()
+        .max_size(1)
+        .connection_customizer(Box::new(TestTransaction))
+        .build(ConnectionManager::new(database_url()))
+        .expect("Failed to create test DB connection pool")
+}
+
+/// An app on `pool` and `store`, with a `FixedClock` and fixed signing
+/// keys, for the config to be set on.
+pub fn test_app_builder(pool: DbPool, store: Arc<dyn FileStore>) -> CatdexAppBuilder {
+    CatdexApp::builder()
+        .pool(pool)
+        .clock(Arc::new(FixedClock::new()))
+        .file_store(store)
+        .url_signer(UrlSigner::new("test signing key", Duration::from_secs(60)))
+        .session_key(Key::generate())
+}
+
+/// The app `test_app_builder` builds from the environment, on a test pool
+/// and an empty `MemoryFileStore`.
+pub async fn test_app(
+) -> impl Service<Request, Response = ServiceResponse<impl MessageBody>, Error = Error> {
+    test_app_with_store(Arc::new(MemoryFileStore::default())).await
+}
+
+pub async fn test_app_with_store(
+    store: Arc<MemoryFileStore>,
+) -> impl Service<Request, Response = ServiceResponse<impl MessageBody>, Error = Error> {
+    test_app_with(test_pool(), store, Config::from_env(), None).await
+}
+
+/// The app `test_app_builder` builds, with `config` and `scanner`.
+pub async fn test_app_with(
+    pool: DbPool,
+    store: Arc<MemoryFileStore>,
+    config: Config,
+    scanner: Option<Arc<dyn Scanner>>,
+) -> impl Service<Request, Response = ServiceResponse<impl MessageBody>, Error = Error> {
+    let mut builder = test_app_builder(pool, store).config(config);
+    if let Some(scanner) = scanner {
+        builder = builder.scanner(scanner);
+    }
+    test::init_service(builder.build().app()).await
+}
+
+/// The file store key of a public image presented with its version.
+pub fn image_key_of(image_path: &str) -> &str {
+    let (path, _) = image_path
+        .split_once("?v=")
+        .expect("Image path is versioned");
+    path.strip_prefix("/image/").unwrap()
+}
+
+/// "admin:open sesame"
+pub const ADMIN_AUTHORIZATION: &str = "Basic YWRtaW46b3BlbiBzZXNhbWU=";
+
+/// Inserts the admin `admin` with the password `open sesame`, sent as
+/// `ADMIN_AUTHORIZATION`.
+pub fn insert_test_admin(connection: &mut PgConnection, tenant_id: i32) {
+    use argon2::password_hash::SaltString;
+    use argon2::{Argon2, PasswordHasher};
+
+    let salt = SaltString::encode_b64(b"test salt bytes!").unwrap();
+    let new_admin = NewAdmin {
+        tenant_id,
+        username: "admin".to_string(),
+        password_hash: Argon2::default()
+            .hash_password(b"open sesame", &salt)
+            .unwrap()
+            .to_string(),
+    };
+    repository::insert_admin(connection, &new_admin).unwrap();
+}
+
+/// A public cat with a name of its own and the image `/image/cat.jpg`,
+/// created at `FixedClock`'s instant. Tests needing another cat override
+/// its fields with struct update syntax.
+pub fn test_cat() -> NewCat {
+    NewCat {
+        name: format!("Test cat {}", uuid::Uuid::new_v4()),
+        image_path: "/image/cat.jpg".to_string(),
+        created_at: FixedClock::new().now(),
+        image_size: 0,
+        private: false,
+        latitude: None,
+        longitude: None,
+        image_hash: None,
+        image_phash: None,
+        pending_review: false,
+        image_original_format: None,
+    }
+}
+
+/// Inserts `new_cat` straight into the database.
+pub fn insert_cat_with(connection: &mut PgConnection, tenant_id: i32, new_cat: &NewCat) -> Cat {
+    repository::insert_cat(connection, tenant_id, new_cat, true)
+        .unwrap()
+        .unwrap()
+}
+
+/// Inserts a `test_cat()` straight into the database.
+pub fn insert_cat(connection: &mut PgConnection, tenant_id: i32) -> Cat {
+    insert_cat_with(connection, tenant_id, &test_cat())
+}
+
+/// A clock stopped at a fixed instant.
+pub struct FixedClock(pub DateTime<Utc>);
+
+impl FixedClock {
+    /// 2026-01-01T00:00:00Z
+    pub fn new() -> FixedClock {
+        FixedClock(Utc.with_ymd_and_hms(2026, 1, 1, 0, 0, 0).unwrap())
+    }
+}
+
+impl Clock for FixedClock {
+    fn now(&self) -> DateTime<Utc> {
+        self.0
+    }
+}
+
+/// A clock that only moves when told to, starting at 2026-01-01T00:00:00Z.
+pub struct ManualClock(Mutex<DateTime<Utc>>);
+
+impl ManualClock {
+    pub fn new() -> ManualClock {
+        ManualClock(Mutex::new(FixedClock::new().0))
+    }
+
+    pub fn advance(&self, by: TimeDelta) {
+        *self.0.lock().unwrap() += by;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> DateTime<Utc> {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// Takes the token `solved` and no other.
+pub struct FakeCaptcha;
+
+impl CaptchaVerifier for FakeCaptcha {
+    fn verify<'a>(
+        &'a self,
+        token: &'a str,
+        _client_ip: Option<IpAddr>,
+    ) -> LocalBoxFuture<'a, Result<bool, String>> {
+        Box::pin(async move { Ok(token == "solved") })
+    }
+}
+
+/// Keeps stored files in memory so tests can inspect what was written and
+/// what was cleaned up.
+#[derive(Default)]
+pub struct MemoryFileStore {
+    files: Mutex<HashMap<String, Vec<u8>>>,
+}
+
+impl MemoryFileStore {
+    pub fn is_empty(&self) -> bool {
+        self.files.lock().unwrap().is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.files.lock().unwrap().len()
+    }
+}
+
+impl FileStore for MemoryFileStore {
+    fn put(&self, key: &str, contents: &mut dyn Read) -> io::Result<()> {
+        let mut data = Vec::new();
+        contents.read_to_end(&mut data)?;
+        self.files.lock().unwrap().insert(key.to_string(), data);
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> io::Result<Vec<u8>> {
+        self.files
+            .lock()
+            .unwrap()
+            .get(key)
+            .cloned()
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+    }
+
+    fn size(&self, key: &str) -> io::Result<u64> {
+        self.get(key).map(|contents| contents.len() as u64)
+    }
+
+    fn delete(&self, key: &str) -> io::Result<()> {
+        self.files
+            .lock()
+            .unwrap()
+            .remove(key)
+            .map(|_| ())
+            .ok_or_else(|| io::Error::from(io::ErrorKind::NotFound))
+    }
+
+    fn kind(&self) -> &'static str {
+        "memory"
+    }
+}
+
+/// Builds a `multipart/form-data` body with the given text fields and an
+/// `image` file, returning the content type and the body.
+pub fn multipart_body(fields: &[(&str, &str)], image: &[u8]) -> (String, Vec<u8>) {
+    multipart_form(fields, &[("image", "cat.jpg", image)])
+}
+
+/// Builds a `multipart/form-data` body with the given text fields and
+/// `(field, filename, contents)` files, returning the content type and the
+/// body.
+pub fn multipart_form(fields: &[(&str, &str)], files: &[(&str, &str, &[u8])]) -> (String, Vec<u8>) {
+    const BOUNDARY: &str = "catdex-test-boundary";
+    let mut body = Vec::new();
+    for (name, value) in fields {
+        body.extend_from_slice(
+            format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"{}\"\r\n\r\n{}\r\n",
+                BOUNDARY, name, value
+            )
+            .as_bytes(),
+        );
+    }
+    for (name, filename, contents) in files {
+        body.extend_from_slice(
+            format!(
+                "--{}\r\nContent-Disposition: form-data; name=\"{}\"; filename=\"{}\"\r\n\
+                 Content-Type: application/octet-stream\r\n\r\n",
+                BOUNDARY, name, filename
+            )
+            .as_bytes(),
+        );
+        body.extend_from_slice(contents);
+        body.extend_from_slice(b"\r\n");
+    }
+    body.extend_from_slice(format!("--{}--\r\n", BOUNDARY).as_bytes());
+    (format!("multipart/form-data; boundary={}", BOUNDARY), body)
+}
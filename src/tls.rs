@@ -0,0 +1,117 @@
+use actix_rt::signal::unix::{signal, SignalKind};
+use arc_swap::ArcSwap;
+use log::{error, info, warn};
+use openssl::error::ErrorStack;
+use openssl::ssl::{SniError, SslAcceptor, SslAcceptorBuilder, SslContext, SslFiletype, SslMethod};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+/// The server's certificate and key, which can be replaced while it runs,
+/// e.g. after a Let's Encrypt renewal.
+///
+/// Each new connection picks up the current certificate while it
+/// negotiates, so connections already open keep theirs until they close.
+pub struct Certificates {
+    key_file: PathBuf,
+    cert_file: PathBuf,
+    current: ArcSwap<SslContext>,
+}
+
+impl Certificates {
+    pub fn load(key_file: PathBuf, cert_file: PathBuf) -> Result<Arc<Certificates>, ErrorStack> {
+        let context = context(&key_file, &cert_file)?;
+        Ok(Arc::new(Certificates {
+            key_file,
+            cert_file,
+            current: ArcSwap::from_pointee(context),
+        }))
+    }
+
+    pub fn files(&self) -> [PathBuf; 2] {
+        [self.key_file.clone(), self.cert_file.clone()]
+    }
+
+    /// Reads the files again. The current certificate is kept when they
+    /// cannot be read or the key does not match the certificate, as happens
+    /// briefly while a renewal writes one file and then the other.
+    pub fn reload(&self) {
+        match context(&self.key_file, &self.cert_file) {
+            Ok(context) => {
+                self.current.store(Arc::new(context));
+                info!("Reloaded TLS certificate {}", self.cert_file.display());
+            }
+            Err(e) => warn!(
+                "Kept the current TLS certificate, {} could not be loaded: {}",
+                self.cert_file.display(),
+                e
+            ),
+        }
+    }
+
+    /// Reads the files again on every SIGHUP.
+    pub async fn reload_on_hangup(self: Arc<Certificates>) {
+        let mut hangups = match signal(SignalKind::hangup()) {
+            Ok(hangups) => hangups,
+            Err(e) => {
+                error!("Failed to listen for SIGHUP: {}", e);
+                return;
+            }
+        };
+        while hangups.recv().await.is_some() {
+            self.reload();
+        }
+    }
+
+    /// An acceptor serving whichever certificate is current.
+    pub fn acceptor(self: &Arc<Certificates>) -> Result<SslAcceptorBuilder, ErrorStack> {
+        let mut builder = builder(&self.key_file, &self.cert_file)?;
+        let certificates = self.clone();
+        // OpenSSL calls this for every handshake, whether or not the client
+        // sends a server name
+        builder.set_servername_callback(move |ssl, _| {
+            ssl.set_ssl_context(&certificates.current.load())
+                .map_err(|e| {
+                    error!("Failed to switch TLS certificate: {}", e);
+                    SniError::ALERT_FATAL
+                })
+        });
+        Ok(builder)
+    }
+}
+
+fn builder(key_file: &Path, cert_file: &Path) -> Result<SslAcceptorBuilder, ErrorStack> {
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+    builder.set_private_key_file(key_file, SslFiletype::PEM)?;
+    builder.set_certificate_chain_file(cert_file)?;
+    builder.check_private_key()?;
+    Ok(builder)
+}
+
+fn context(key_file: &Path, cert_file: &Path) -> Result<SslContext, ErrorStack> {
+    builder(key_file, cert_file).map(|builder| builder.build().into_context())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_reload_keeps_certificate_on_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let key_file = dir.path().join("key.pem");
+        let cert_file = dir.path().join("cert.pem");
+        fs::copy("key-no-password.pem", &key_file).unwrap();
+        fs::copy("cert.pem", &cert_file).unwrap();
+        let certificates = Certificates::load(key_file, cert_file.clone()).unwrap();
+        let before = Arc::as_ptr(&certificates.current.load_full());
+
+        fs::write(&cert_file, "not a certificate").unwrap();
+        certificates.reload();
+        assert_eq!(Arc::as_ptr(&certificates.current.load_full()), before);
+
+        fs::copy("cert.pem", &cert_file).unwrap();
+        certificates.reload();
+        assert_ne!(Arc::as_ptr(&certificates.current.load_full()), before);
+    }
+}
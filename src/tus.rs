@@ -0,0 +1,229 @@
+//! The tus resumable upload protocol, version 1.0.0 with the `creation` and
+//! `expiration` extensions, on top of the uploads of `POST /api/uploads`.
+//! A finished upload's id is its token, to be passed to `POST /api/cats` as
+//! `upload_token`.
+use crate::clock::Clock;
+use crate::config::Config;
+use crate::errors::UserError;
+use crate::file_store::FileStore;
+use crate::models::{Tenant, Upload};
+use crate::scanner::Scanner;
+use crate::uploads;
+use crate::DbPool;
+use actix_web::body::MessageBody;
+use actix_web::dev::{ServiceRequest, ServiceResponse};
+use actix_web::http::header::{HeaderName, HeaderValue, CACHE_CONTROL, CONTENT_TYPE, LOCATION};
+use actix_web::http::Method;
+use actix_web::middleware::Next;
+use actix_web::{web, Error, HttpRequest, HttpResponse, HttpResponseBuilder};
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use chrono::{DateTime, Utc};
+use log::warn;
+use serde::Deserialize;
+use std::collections::HashMap;
+use uuid::Uuid;
+
+pub const TUS_VERSION: &str = "1.0.0";
+const TUS_EXTENSIONS: &str = "creation,expiration";
+const OFFSET_CONTENT_TYPE: &str = "application/offset+octet-stream";
+
+const TUS_RESUMABLE: HeaderName = HeaderName::from_static("tus-resumable");
+const TUS_VERSION_HEADER: HeaderName = HeaderName::from_static("tus-version");
+const TUS_EXTENSION: HeaderName = HeaderName::from_static("tus-extension");
+const TUS_MAX_SIZE: HeaderName = HeaderName::from_static("tus-max-size");
+const UPLOAD_LENGTH: HeaderName = HeaderName::from_static("upload-length");
+const UPLOAD_OFFSET: HeaderName = HeaderName::from_static("upload-offset");
+const UPLOAD_METADATA: HeaderName = HeaderName::from_static("upload-metadata");
+const UPLOAD_EXPIRES: HeaderName = HeaderName::from_static("upload-expires");
+
+/// Middleware refusing requests for other protocol versions, as the
+/// protocol requires of every request but `OPTIONS`, and adding
+/// `Tus-Resumable` to every response.
+pub async fn tus_resumable(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let version = req.headers().get(TUS_RESUMABLE).map(HeaderValue::as_bytes);
+    let mut res = if req.method() != Method::OPTIONS && version != Some(TUS_VERSION.as_bytes()) {
+        warn!("Rejected tus request without version {}", TUS_VERSION);
+        let res = HttpResponse::PreconditionFailed()
+            .insert_header((TUS_VERSION_HEADER, TUS_VERSION))
+            .finish();
+        req.into_response(res).map_into_right_body()
+    } else {
+        next.call(req).await?.map_into_left_body()
+    };
+    res.headers_mut()
+        .insert(TUS_RESUMABLE, HeaderValue::from_static(TUS_VERSION));
+    Ok(res)
+}
+
+/// Parses `Upload-Metadata`, comma separated pairs of a key and an optional
+/// base64 encoded value.
+fn parse_metadata(value: &str) -> Option<HashMap<String, String>> {
+    value
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| {
+            let (key, value) = match pair.split_once(' ') {
+                Some((key, value)) => (key, STANDARD.decode(value.trim()).ok()?),
+                None => (pair, Vec::new()),
+            };
+            Some((key.to_string(), String::from_utf8(value).ok()?))
+        })
+        .collect()
+}
+
+/// Formats an instant as an HTTP date, e.g. `Thu, 01 Jan 2026 00:00:00 GMT`.
+fn http_date(at: DateTime<Utc>) -> String {
+    at.format("%a, %d %b %Y %H:%M:%S GMT").to_string()
+}
+
+fn header<'a>(req: &'a HttpRequest, name: &HeaderName) -> Option<&'a str> {
+    req.headers()
+        .get(name)
+        .and_then(|value| value.to_str().ok())
+}
+
+/// The protocol version and limits of the server.
+pub async fn options_endpoint(config: web::Data<Config>) -> HttpResponse {
+    HttpResponse::NoContent()
+        .insert_header((TUS_VERSION_HEADER, TUS_VERSION))
+        .insert_header((TUS_EXTENSION, TUS_EXTENSIONS))
+        .insert_header((TUS_MAX_SIZE, config.upload_max_bytes.to_string()))
+        .finish()
+}
+
+/// Creates an upload of `Upload-Length` bytes. Its `Upload-Metadata` must
+/// carry the image's `filetype`, and may set `private` to `true`.
+pub async fn create_endpoint(
+    pool: web::Data<DbPool>,
+    config: web::Data<Config>,
+    clock: web::Data<dyn Clock>,
+    tenant: Tenant,
+    req: HttpRequest,
+) -> Result<HttpResponse, UserError> {
+    let size = header(&req, &UPLOAD_LENGTH)
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| {
+            warn!("Missing or invalid Upload-Length");
+            UserError::ValidationError
+        })?;
+    let metadata = match header(&req, &UPLOAD_METADATA) {
+        Some(value) => parse_metadata(value).ok_or_else(|| {
+            warn!("Invalid Upload-Metadata");
+            UserError::ValidationError
+        })?,
+        None => HashMap::new(),
+    };
+    let content_type = metadata.get("filetype").map(String::as_str).unwrap_or("");
+    let private = metadata.get("private").is_some_and(|value| value == "true");
+
+    let upload = uploads::start_upload(
+        &pool,
+        &config,
+        tenant.id,
+        size,
+        content_type,
+        private,
+        clock.now(),
+    )
+    .await?;
+    Ok(HttpResponse::Created()
+        .insert_header((LOCATION, format!("/api/tus/{}", upload.token)))
+        .insert_header((UPLOAD_EXPIRES, http_date(upload.expires_at)))
+        .finish())
+}
+
+#[derive(Deserialize)]
+pub struct TusPath {
+    token: Uuid,
+}
+
+/// The offset to resume the upload from.
+pub async fn head_endpoint(
+    pool: web::Data<DbPool>,
+    clock: web::Data<dyn Clock>,
+    tenant: Tenant,
+    path: web::Path<TusPath>,
+) -> Result<HttpResponse, UserError> {
+    let upload = uploads::find_upload(&pool, tenant.id, path.token, clock.now()).await?;
+    Ok(progress(HttpResponse::Ok(), &upload)
+        .insert_header((UPLOAD_LENGTH, upload.size.to_string()))
+        .insert_header((CACHE_CONTROL, "no-store"))
+        .finish())
+}
+
+/// Adds the offset and expiry of `upload` to a response.
+fn progress(mut builder: HttpResponseBuilder, upload: &Upload) -> HttpResponseBuilder {
+    builder
+        .insert_header((UPLOAD_OFFSET, upload.received.to_string()))
+        .insert_header((UPLOAD_EXPIRES, http_date(upload.expires_at)));
+    builder
+}
+
+/// Appends the body at `Upload-Offset`, which must be the current offset.
+#[allow(clippy::too_many_arguments)]
+pub async fn patch_endpoint(
+    pool: web::Data<DbPool>,
+    config: web::Data<Config>,
+    clock: web::Data<dyn Clock>,
+    store: web::Data<dyn FileStore>,
+    scanner: Option<web::Data<dyn Scanner>>,
+    tenant: Tenant,
+    path: web::Path<TusPath>,
+    req: HttpRequest,
+    payload: web::Payload,
+) -> Result<HttpResponse, UserError> {
+    if header(&req, &CONTENT_TYPE) != Some(OFFSET_CONTENT_TYPE) {
+        warn!("Rejected tus chunk that is not {}", OFFSET_CONTENT_TYPE);
+        return Err(UserError::UnsupportedMediaTypeError);
+    }
+    let offset = header(&req, &UPLOAD_OFFSET)
+        .and_then(|value| value.parse().ok())
+        .ok_or_else(|| {
+            warn!("Missing or invalid Upload-Offset");
+            UserError::ValidationError
+        })?;
+    let now = clock.now();
+    let upload = uploads::find_upload(&pool, tenant.id, path.token, now).await?;
+    let upload = uploads::append(
+        &pool,
+        &config,
+        store.get_ref(),
+        scanner,
+        &tenant,
+        upload,
+        offset,
+        None,
+        payload,
+        now,
+    )
+    .await?;
+    Ok(progress(HttpResponse::NoContent(), &upload).finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn test_parse_metadata() {
+        let metadata =
+            parse_metadata("filetype aW1hZ2UvcG5n, private dHJ1ZQ==,is_confidential").unwrap();
+        assert_eq!(metadata["filetype"], "image/png");
+        assert_eq!(metadata["private"], "true");
+        assert_eq!(metadata["is_confidential"], "");
+        assert_eq!(parse_metadata("").unwrap(), HashMap::new());
+        assert_eq!(parse_metadata("filetype not-base64!"), None);
+    }
+
+    #[test]
+    fn test_http_date() {
+        let at = Utc.with_ymd_and_hms(2026, 1, 1, 0, 0, 0).unwrap();
+        assert_eq!(http_date(at), "Thu, 01 Jan 2026 00:00:00 GMT");
+    }
+}
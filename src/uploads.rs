@@ -0,0 +1,500 @@
+use crate::cats::{persist_upload, screen_upload, upload_dir};
+use crate::clock::Clock;
+use crate::config::Config;
+use crate::db::{self, DbConn};
+use crate::errors::UserError;
+use crate::file_store::FileStore;
+use crate::heic;
+use crate::image_download::image_extension;
+use crate::models::{NewUpload, Tenant, Upload};
+use crate::repository;
+use crate::scanner::Scanner;
+use crate::DbPool;
+use actix_web::http::header::CONTENT_RANGE;
+use actix_web::{web, HttpRequest, HttpResponse};
+use chrono::{DateTime, Utc};
+use futures_util::StreamExt;
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use std::fs::{self, File, OpenOptions, TryLockError};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use tempfile::{NamedTempFile, TempPath};
+use uuid::Uuid;
+
+/// Bytes written between updates of how much of an upload was received.
+const RECORD_INTERVAL: i64 = 256 * 1024;
+
+/// Parses a `Content-Range: bytes <first>-<last>/<total>` header into the
+/// offset of the first byte, the number of bytes and the total size.
+fn parse_content_range(value: &str) -> Option<(i64, i64, i64)> {
+    let (range, total) = value.strip_prefix("bytes ")?.split_once('/')?;
+    let (first, last) = range.split_once('-')?;
+    let (first, last, total): (i64, i64, i64) = (
+        first.trim().parse().ok()?,
+        last.trim().parse().ok()?,
+        total.trim().parse().ok()?,
+    );
+    if first < 0 || last < first || last >= total {
+        return None;
+    }
+    Some((first, last - first + 1, total))
+}
+
+/// Where the bytes of an upload are collected until it is complete.
+fn staging_path(staging_dir: &Path, token: Uuid) -> PathBuf {
+    staging_dir.join(token.to_string())
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct UploadStatus {
+    pub token: Uuid,
+    /// Where to `PUT` the image, whole or in `Content-Range` chunks.
+    pub upload_url: String,
+    pub size: i64,
+    /// Bytes received so far, i.e. the offset of the next chunk.
+    pub received: i64,
+    /// Whether a cat can be created from the upload.
+    pub complete: bool,
+    pub expires_at: DateTime<Utc>,
+}
+
+impl From<Upload> for UploadStatus {
+    fn from(upload: Upload) -> UploadStatus {
+        UploadStatus {
+            token: upload.token,
+            upload_url: format!("/api/uploads/{}", upload.token),
+            size: upload.size,
+            received: upload.received,
+            complete: upload.image_key.is_some(),
+            expires_at: upload.expires_at,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct CreateUpload {
+    pub size: i64,
+    pub content_type: String,
+    #[serde(default)]
+    pub private: bool,
+}
+
+/// Records a new upload of an image of the given size and content type.
+#[allow(clippy::too_many_arguments)]
+pub async fn start_upload(
+    pool: &DbPool,
+    config: &Config,
+    tenant_id: i32,
+    size: i64,
+    content_type: &str,
+    private: bool,
+    now: DateTime<Utc>,
+) -> Result<Upload, UserError> {
+    if size <= 0 || size > config.upload_max_bytes {
+        warn!("Rejected upload of {} bytes", size);
+        return Err(UserError::ValidationError);
+    }
+    let extension = image_extension(content_type).ok_or_else(|| {
+        warn!("Rejected upload of type {}", content_type);
+        UserError::ValidationError
+    })?;
+    let connection = DbConn::get(pool)?;
+    let new_upload = NewUpload {
+        token: Uuid::new_v4(),
+        tenant_id,
+        private,
+        extension: extension.to_string(),
+        size,
+        created_at: now,
+        expires_at: now + config.upload_ttl,
+    };
+
+    connection
+        .run("create upload", move |connection| {
+            repository::insert_upload(connection, &new_upload)
+        })
+        .await
+}
+
+/// Starts an upload of an image of the given size and type, to be sent to
+/// the returned URL and then referenced by its token in `POST /api/cats`.
+pub async fn create_upload_endpoint(
+    pool: web::Data<DbPool>,
+    config: web::Data<Config>,
+    clock: web::Data<dyn Clock>,
+    tenant: Tenant,
+    body: web::Json<CreateUpload>,
+) -> Result<HttpResponse, UserError> {
+    let upload = start_upload(
+        &pool,
+        &config,
+        tenant.id,
+        body.size,
+        &body.content_type,
+        body.private,
+        clock.now(),
+    )
+    .await?;
+    Ok(HttpResponse::Created().json(UploadStatus::from(upload)))
+}
+
+#[derive(Deserialize)]
+pub struct UploadPath {
+    token: Uuid,
+}
+
+/// How far an upload got, for clients resuming it.
+pub async fn upload_status_endpoint(
+    pool: web::Data<DbPool>,
+    clock: web::Data<dyn Clock>,
+    tenant: Tenant,
+    path: web::Path<UploadPath>,
+) -> Result<HttpResponse, UserError> {
+    let upload = find_upload(&pool, tenant.id, path.token, clock.now()).await?;
+    Ok(HttpResponse::Ok().json(UploadStatus::from(upload)))
+}
+
+/// The tenant's unexpired upload with the given token.
+pub async fn find_upload(
+    pool: &DbPool,
+    tenant_id: i32,
+    token: Uuid,
+    at: DateTime<Utc>,
+) -> Result<Upload, UserError> {
+    let connection = DbConn::get(pool)?;
+    connection
+        .run("load upload", move |connection| {
+            repository::find_upload(connection, tenant_id, token, at)
+        })
+        .await
+}
+
+/// Opens the staged file for writing, creating it if need be, and locks it
+/// for this request alone. None when another request holds the lock.
+fn lock_staged(path: &Path) -> std::io::Result<Option<File>> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(false)
+        .open(path)?;
+    match file.try_lock() {
+        Ok(()) => Ok(Some(file)),
+        Err(TryLockError::WouldBlock) => Ok(None),
+        Err(TryLockError::Error(e)) => Err(e),
+    }
+}
+
+/// Writes `chunk` at `offset` of the staged file.
+fn write_chunk(file: &mut File, offset: i64, chunk: &[u8]) -> std::io::Result<()> {
+    file.seek(SeekFrom::Start(offset as u64))?;
+    file.write_all(chunk)
+}
+
+async fn record_received(pool: &DbPool, token: Uuid, received: i64) -> Result<Upload, UserError> {
+    DbConn::get(pool)?
+        .run("record chunk", move |connection| {
+            repository::set_upload_received(connection, token, received)
+        })
+        .await
+}
+
+/// Receives the image of an upload, whole or one `Content-Range` chunk at a
+/// time. Each chunk must start where the previous one ended; a chunk that
+/// does not is refused with the current offset, so the client can resume
+/// from there.
+///
+/// The last chunk completes the upload: the image is scanned and moved to
+/// the file store, after which a cat can be created from it.
+#[allow(clippy::too_many_arguments)]
+pub async fn upload_chunk_endpoint(
+    pool: web::Data<DbPool>,
+    config: web::Data<Config>,
+    clock: web::Data<dyn Clock>,
+    store: web::Data<dyn FileStore>,
+    scanner: Option<web::Data<dyn Scanner>>,
+    tenant: Tenant,
+    path: web::Path<UploadPath>,
+    req: HttpRequest,
+    payload: web::Payload,
+) -> Result<HttpResponse, UserError> {
+    let token = path.token;
+    let now = clock.now();
+    let upload = find_upload(&pool, tenant.id, token, now).await?;
+    let range = match req.headers().get(CONTENT_RANGE) {
+        Some(value) => {
+            let (start, length, total) = value
+                .to_str()
+                .ok()
+                .and_then(parse_content_range)
+                .ok_or_else(|| {
+                    warn!("Invalid Content-Range header");
+                    UserError::ValidationError
+                })?;
+            if total != upload.size {
+                warn!("Content-Range total does not match the upload size");
+                return Err(UserError::ValidationError);
+            }
+            Some((start, length))
+        }
+        None => None,
+    };
+    // Without a range the body is the whole image
+    let (start, length) = range.unwrap_or((0, upload.size));
+    let upload = append(
+        &pool,
+        &config,
+        store.get_ref(),
+        scanner,
+        &tenant,
+        upload,
+        start,
+        Some(length),
+        payload,
+        now,
+    )
+    .await?;
+    Ok(HttpResponse::Ok().json(UploadStatus::from(upload)))
+}
+
+/// Appends the request body to `upload` at `start`, which must be where the
+/// upload left off, completing the upload with its last byte. With `length`
+/// given the body must be exactly that long. The body is written as it
+/// arrives, so a body cut short keeps the bytes that made it.
+#[allow(clippy::too_many_arguments)]
+pub async fn append(
+    pool: &DbPool,
+    config: &Config,
+    store: &dyn FileStore,
+    scanner: Option<web::Data<dyn Scanner>>,
+    tenant: &Tenant,
+    upload: Upload,
+    start: i64,
+    length: Option<i64>,
+    payload: web::Payload,
+    now: DateTime<Utc>,
+) -> Result<Upload, UserError> {
+    let token = upload.token;
+    if upload.image_key.is_some() || start != upload.received {
+        return Err(UserError::UploadOffsetError(upload.received));
+    }
+    // The staged file is locked until the body is written, so a chunk sent
+    // twice cannot overwrite the bytes that followed it
+    let staged = staging_path(&config.upload_staging_dir, token);
+    let locked = db::block({
+        let staged = staged.clone();
+        move || lock_staged(&staged)
+    })
+    .await?
+    .map_err(|e| {
+        error!("Failed to stage upload {}: {}", token, e);
+        UserError::UnexpectedError
+    })?;
+    let Some(mut file) = locked else {
+        warn!("Upload {} is being written by another request", token);
+        return Err(UserError::UploadOffsetError(upload.received));
+    };
+    let mut upload = find_upload(pool, tenant.id, token, now).await?;
+    if upload.image_key.is_some() || start != upload.received {
+        return Err(UserError::UploadOffsetError(upload.received));
+    }
+
+    // Bytes are recorded as they land, so an interrupted body resumes from
+    // the last of them rather than from where it started
+    let limit = length.unwrap_or(upload.size - start);
+    let (mut received, mut recorded) = (start, start);
+    let mut failure = None;
+    let mut payload = payload;
+    while let Some(chunk) = payload.next().await {
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            Err(e) => {
+                warn!("Body of upload {} was cut short: {}", token, e);
+                failure = Some(UserError::ValidationError);
+                break;
+            }
+        };
+        if received - start + chunk.len() as i64 > limit {
+            warn!("Chunk runs past the end of upload {}", token);
+            failure = Some(UserError::ValidationError);
+            break;
+        }
+        let offset = received;
+        let written;
+        (file, written) = db::block(move || {
+            let written = write_chunk(&mut file, offset, &chunk);
+            (file, written.map(|_| chunk.len() as i64))
+        })
+        .await?;
+        match written {
+            Ok(written) => received += written,
+            Err(e) => {
+                error!("Failed to stage chunk of upload {}: {}", token, e);
+                failure = Some(UserError::UnexpectedError);
+                break;
+            }
+        }
+        if received - recorded >= RECORD_INTERVAL {
+            upload = record_received(pool, token, received).await?;
+            recorded = received;
+        }
+    }
+    if received != recorded {
+        upload = record_received(pool, token, received).await?;
+    }
+    if let Some(length) = length.filter(|length| failure.is_none() && received - start != *length) {
+        warn!("Chunk of {} bytes, expected {}", received - start, length);
+        failure = Some(UserError::ValidationError);
+    }
+    if let Some(e) = failure {
+        return Err(e);
+    }
+
+    if upload.received < upload.size {
+        return Ok(upload);
+    }
+    let completed = complete(pool, config, store, scanner, tenant, upload, &staged).await;
+    drop(file);
+    completed
+}
+
+/// Scans the fully received image, converting it when in HEIC, and moves it
+/// to the file store. The upload is dropped when that fails, as its bytes
+/// cannot be sent again.
+async fn complete(
+    pool: &DbPool,
+    config: &Config,
+    store: &dyn FileStore,
+    scanner: Option<web::Data<dyn Scanner>>,
+    tenant: &Tenant,
+    upload: Upload,
+    staged: &Path,
+) -> Result<Upload, UserError> {
+    let token = upload.token;
+    let image_key = async {
+        let file = stage_file(staged, &upload.extension).map_err(|e| {
+            error!("Failed to open staged upload {}: {}", token, e);
+            UserError::UnexpectedError
+        })?;
+        let file = screen_upload(scanner, config, file).await?;
+        let (file, original_format) = heic::convert_upload(config, file).await?;
+        let dir = upload_dir(&tenant.slug, upload.private);
+        persist_upload(store, file, &dir, original_format).ok_or_else(|| {
+            error!("Failed to store upload {}", token);
+            UserError::UnexpectedError
+        })
+    }
+    .await;
+
+    let connection = DbConn::get(pool)?;
+    connection
+        .run("complete upload", move |connection| match image_key {
+            Ok(image_key) => repository::complete_upload(connection, token, &image_key).map(Ok),
+            Err(e) => repository::delete_upload(connection, token).map(|_| Err(e)),
+        })
+        .await?
+}
+
+/// Hands the staged file over like a multipart upload, to be deleted once
+/// it has been stored.
+fn stage_file(staged: &Path, extension: &str) -> std::io::Result<awmp::File> {
+    let file = File::open(staged)?;
+    let file = NamedTempFile::from_parts(file, TempPath::try_from_path(staged)?);
+    Ok(awmp::File::new_with_file_name(
+        file,
+        format!("upload.{}", extension),
+    ))
+}
+
+/// The key of the completed upload a new cat is created from, which must
+/// have been made for a cat of the same visibility.
+pub async fn completed_upload(
+    pool: &DbPool,
+    tenant_id: i32,
+    token: Uuid,
+    private: bool,
+    at: DateTime<Utc>,
+) -> Result<String, UserError> {
+    let upload = find_upload(pool, tenant_id, token, at)
+        .await
+        .map_err(|e| match e {
+            UserError::NotFoundError => UserError::ValidationError,
+            e => e,
+        })?;
+    match upload.image_key {
+        Some(image_key) if upload.private == private => Ok(image_key),
+        Some(_) => {
+            warn!("Upload {} was made for a cat of other visibility", token);
+            Err(UserError::ValidationError)
+        }
+        None => {
+            warn!("Upload {} is not complete", token);
+            Err(UserError::ValidationError)
+        }
+    }
+}
+
+/// Forgets an upload a cat was created from. Its image stays, now
+/// referenced by the cat.
+pub async fn finish_upload(pool: &DbPool, token: Uuid) {
+    let Ok(connection) = DbConn::get(pool) else {
+        return;
+    };
+    // A failure is logged, and the upload purged once it expires
+    let _ = connection
+        .run("finish upload", move |connection| {
+            repository::delete_upload(connection, token)
+        })
+        .await;
+}
+
+/// Deletes expired uploads along with their staged files, returning how
+/// many there were.
+pub fn purge_expired(
+    connection: &mut diesel::PgConnection,
+    staging_dir: &Path,
+    at: DateTime<Utc>,
+) -> diesel::QueryResult<usize> {
+    let expired = repository::delete_expired_uploads(connection, at)?;
+    for upload in &expired {
+        let staged = staging_path(staging_dir, upload.token);
+        match fs::remove_file(&staged) {
+            Ok(()) => {}
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {}
+            Err(e) => warn!("Failed to remove {}: {}", staged.display(), e),
+        }
+    }
+    Ok(expired.len())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_content_range() {
+        assert_eq!(parse_content_range("bytes 0-99/200"), Some((0, 100, 200)));
+        assert_eq!(
+            parse_content_range("bytes 100-199/200"),
+            Some((100, 100, 200))
+        );
+        assert_eq!(parse_content_range("bytes 100-200/200"), None);
+        assert_eq!(parse_content_range("bytes 99-0/200"), None);
+        assert_eq!(parse_content_range("bytes */200"), None);
+        assert_eq!(parse_content_range("items 0-1/2"), None);
+    }
+
+    #[test]
+    fn test_lock_staged() {
+        let staging = tempfile::tempdir().unwrap();
+        let staged = staging_path(&staging.path().join("nested"), Uuid::new_v4());
+        let locked = lock_staged(&staged).unwrap();
+        assert!(locked.is_some());
+        assert!(lock_staged(&staged).unwrap().is_none());
+        drop(locked);
+        assert!(lock_staged(&staged).unwrap().is_some());
+    }
+}
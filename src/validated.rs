@@ -0,0 +1,88 @@
+//! `Validated<T>` wraps an extractor such as `web::Json`, `web::Query` or
+//! `web::Path`, and runs the validator rules of what it extracts before the
+//! handler sees it. Failures are answered with the structured 422 format of
+//! `UserError::FieldValidationError`, naming the offending fields.
+use crate::errors::UserError;
+use actix_web::dev::Payload;
+use actix_web::{Error, FromRequest, HttpRequest};
+use futures_util::future::LocalBoxFuture;
+use log::warn;
+use std::ops::Deref;
+use validator::Validate;
+
+pub struct Validated<T>(pub T);
+
+impl<T> Validated<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for Validated<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> FromRequest for Validated<T>
+where
+    T: FromRequest + Deref + 'static,
+    T::Target: Validate,
+    T::Error: Into<Error>,
+{
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let extracted = T::from_request(req, payload);
+        let path = req.path().to_string();
+        Box::pin(async move {
+            let value = extracted.await.map_err(Into::into)?;
+            value.validate().map_err(|errors| {
+                warn!("Validation of {} failed: {}", path, errors);
+                UserError::FieldValidationError(errors)
+            })?;
+            Ok(Validated(value))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test::TestRequest;
+    use actix_web::{web, ResponseError};
+    use serde::Deserialize;
+
+    #[derive(Deserialize, Validate)]
+    struct Page {
+        #[validate(range(min = 1, max = 10))]
+        page: u32,
+    }
+
+    #[actix_web::test]
+    async fn test_validated() {
+        let (req, mut payload) = TestRequest::get().uri("/?page=3").to_http_parts();
+        let query = Validated::<web::Query<Page>>::from_request(&req, &mut payload)
+            .await
+            .unwrap();
+        assert_eq!(query.page, 3);
+
+        let (req, mut payload) = TestRequest::get().uri("/?page=11").to_http_parts();
+        let error = Validated::<web::Query<Page>>::from_request(&req, &mut payload)
+            .await
+            .err()
+            .unwrap();
+        let error = error.as_error::<UserError>().unwrap();
+        assert_eq!(error.status_code(), StatusCode::UNPROCESSABLE_ENTITY);
+        match error {
+            UserError::FieldValidationError(errors) => {
+                assert!(errors.field_errors().contains_key("page"))
+            }
+            other => panic!("Unexpected error {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,101 @@
+//! Which build is running and on what, for support to tell installations
+//! apart: served by `GET /api/version` and logged at startup as
+//!
+//! ```text
+//! Starting catdex {"version":"0.1.0","git_sha":"3f5b2f7","built_at":"...","features":["tls"],"backends":{...}}
+//! ```
+use crate::db::DbConn;
+use crate::file_store::FileStore;
+use crate::repository;
+use crate::DbPool;
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use diesel::PgConnection;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+
+#[derive(Serialize, Deserialize)]
+pub struct Build {
+    pub version: String,
+    pub git_sha: String,
+    /// When the build script last ran, or `SOURCE_DATE_EPOCH`.
+    pub built_at: Option<DateTime<Utc>>,
+    /// The Cargo features enabled, e.g. `tls`.
+    pub features: Vec<String>,
+}
+
+pub fn build() -> Build {
+    Build {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        git_sha: env!("CATDEX_GIT_SHA").to_string(),
+        built_at: env!("CATDEX_BUILD_EPOCH")
+            .parse()
+            .ok()
+            .and_then(|secs| DateTime::from_timestamp(secs, 0)),
+        features: env!("CATDEX_FEATURES")
+            .split(',')
+            .filter(|feature| !feature.is_empty())
+            .map(str::to_string)
+            .collect(),
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Backends {
+    /// The `FileStore` images are kept in, e.g. `local`.
+    pub storage: String,
+    pub database: String,
+    /// None when the database could not be reached.
+    pub database_version: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RuntimeInfo {
+    #[serde(flatten)]
+    pub build: Build,
+    pub backends: Backends,
+}
+
+/// The build and the backends it runs on, asking the database for its
+/// version over `connection`.
+pub fn runtime_info(connection: Option<&mut PgConnection>, store: &dyn FileStore) -> RuntimeInfo {
+    let database_version = connection.and_then(|connection| {
+        repository::database_version(connection)
+            .inspect_err(|e| warn!("Failed to read the database version: {}", e))
+            .ok()
+    });
+    RuntimeInfo {
+        build: build(),
+        backends: Backends {
+            storage: store.kind().to_string(),
+            database: "postgresql".to_string(),
+            database_version,
+        },
+    }
+}
+
+pub fn log_banner(info: &RuntimeInfo) {
+    let banner = serde_json::to_string(info).expect("Runtime info serializes to JSON");
+    info!("Starting catdex {}", banner);
+}
+
+/// Answers even when the database is down, leaving its version out.
+pub async fn version_endpoint(
+    pool: web::Data<DbPool>,
+    store: web::Data<dyn FileStore>,
+) -> HttpResponse {
+    let read = match DbConn::get(&pool) {
+        Ok(connection) => {
+            let store = store.clone();
+            connection
+                .run("read runtime info", move |connection| {
+                    Ok(runtime_info(Some(connection), store.get_ref()))
+                })
+                .await
+                .ok()
+        }
+        Err(_) => None,
+    };
+    let info = read.unwrap_or_else(|| runtime_info(None, store.get_ref()));
+    HttpResponse::Ok().json(info)
+}
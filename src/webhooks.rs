@@ -0,0 +1,480 @@
+use crate::auth;
+use crate::config::Config;
+use crate::db::DbConn;
+use crate::errors::UserError;
+use crate::history::Requester;
+use crate::image_download;
+use crate::models::{Cat, NewWebhook, NewWebhookDelivery, Tenant, Webhook};
+use crate::repository;
+use crate::telemetry;
+use crate::validated::Validated;
+use crate::DbPool;
+use actix_web::http::header::CONTENT_TYPE;
+use actix_web::{web, HttpResponse};
+use chrono::{DateTime, Utc};
+use futures_util::future::join_all;
+use hmac::{Hmac, Mac};
+use log::{error, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use sha2::Sha256;
+use std::time::Duration;
+use uuid::Uuid;
+use validator::{Validate, ValidationError};
+
+pub const SIGNATURE_HEADER: &str = "X-Catdex-Signature";
+pub const EVENT_HEADER: &str = "X-Catdex-Event";
+
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+const BACKOFF_BASE: Duration = Duration::from_secs(1);
+const DELIVERY_LOG_LIMIT: i64 = 100;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CatEvent {
+    Created,
+    StatusChanged,
+}
+
+impl CatEvent {
+    pub const ALL: &'static [CatEvent] = &[CatEvent::Created, CatEvent::StatusChanged];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            CatEvent::Created => "cat.created",
+            CatEvent::StatusChanged => "cat.status_changed",
+        }
+    }
+
+    /// The JSON body sent to subscribers.
+    pub fn payload(self, cat: &Cat, occurred_at: DateTime<Utc>) -> Value {
+        json!({
+            "event": self.name(),
+            "occurred_at": occurred_at,
+            "cat": cat,
+        })
+    }
+
+    pub fn from_name(name: &str) -> Option<CatEvent> {
+        CatEvent::ALL
+            .iter()
+            .copied()
+            .find(|event| event.name() == name)
+    }
+}
+
+#[derive(Serialize, Deserialize, Validate)]
+pub struct RegisterWebhook {
+    #[validate(custom = "validate_webhook_url")]
+    pub url: String,
+    /// Defaults to every event when omitted.
+    #[serde(default = "all_event_names")]
+    #[validate(custom = "validate_webhook_events")]
+    pub events: Vec<String>,
+}
+
+fn all_event_names() -> Vec<String> {
+    CatEvent::ALL
+        .iter()
+        .map(|event| event.name().to_string())
+        .collect()
+}
+
+fn validate_webhook_url(url: &str) -> Result<(), ValidationError> {
+    let http = url.starts_with("http://") || url.starts_with("https://");
+    if !http || !validator::validate_url(url) {
+        let mut error = ValidationError::new("url");
+        error.message = Some("must be an http or https URL".into());
+        return Err(error);
+    }
+    Ok(())
+}
+
+fn validate_webhook_events(events: &[String]) -> Result<(), ValidationError> {
+    if events.is_empty() || events.iter().any(|e| CatEvent::from_name(e).is_none()) {
+        let mut error = ValidationError::new("events");
+        error.message = Some("must list at least one known event".into());
+        return Err(error);
+    }
+    Ok(())
+}
+
+#[derive(Deserialize)]
+pub struct WebhookPath {
+    id: i32,
+}
+
+/// Registers a webhook for the tenant's admins. Unless
+/// `WEBHOOK_ALLOW_PRIVATE` is set, its host must resolve to public
+/// addresses only, so deliveries cannot reach internal services.
+pub async fn register_webhook_endpoint(
+    pool: web::Data<DbPool>,
+    config: web::Data<Config>,
+    tenant: Tenant,
+    requester: Requester,
+    body: Validated<web::Json<RegisterWebhook>>,
+) -> Result<HttpResponse, UserError> {
+    auth::require_admin(&pool, tenant.id, requester).await?;
+    let body = body.into_inner().into_inner();
+    if !config.webhook_allow_private {
+        if let Err(reason) = image_download::check_public_url(&body.url).await? {
+            warn!("Rejected webhook URL {}: {}", body.url, reason);
+            return Err(UserError::ValidationError);
+        }
+    }
+    let new_webhook = NewWebhook {
+        tenant_id: tenant.id,
+        url: body.url,
+        secret: Uuid::new_v4().simple().to_string(),
+        events: body.events,
+    };
+
+    let webhook = DbConn::get(&pool)?
+        .run("insert webhook", move |connection| {
+            repository::insert_webhook(connection, &new_webhook)
+        })
+        .await?;
+
+    // The secret is not part of the regular serialization, this is the only
+    // time the integrator gets to see it
+    let mut response = json!(webhook);
+    response["secret"] = Value::String(webhook.secret);
+    Ok(HttpResponse::Created().json(response))
+}
+
+pub async fn webhooks_endpoint(
+    pool: web::Data<DbPool>,
+    tenant: Tenant,
+    requester: Requester,
+) -> Result<HttpResponse, UserError> {
+    auth::require_admin(&pool, tenant.id, requester).await?;
+    let webhooks = DbConn::get(&pool)?
+        .run("list webhooks", move |connection| {
+            repository::list_webhooks(connection, tenant.id)
+        })
+        .await?;
+    Ok(HttpResponse::Ok().json(webhooks))
+}
+
+pub async fn delete_webhook_endpoint(
+    pool: web::Data<DbPool>,
+    tenant: Tenant,
+    requester: Requester,
+    path: web::Path<WebhookPath>,
+) -> Result<HttpResponse, UserError> {
+    auth::require_admin(&pool, tenant.id, requester).await?;
+    let webhook_id = path.id;
+    let deleted = DbConn::get(&pool)?
+        .run("delete webhook", move |connection| {
+            repository::delete_webhook(connection, tenant.id, webhook_id)
+        })
+        .await?;
+    if deleted == 0 {
+        error!("Webhook ID: {} not found in DB", webhook_id);
+        return Err(UserError::NotFoundError);
+    }
+    Ok(HttpResponse::NoContent().finish())
+}
+
+pub async fn webhook_deliveries_endpoint(
+    pool: web::Data<DbPool>,
+    tenant: Tenant,
+    requester: Requester,
+    path: web::Path<WebhookPath>,
+) -> Result<HttpResponse, UserError> {
+    auth::require_admin(&pool, tenant.id, requester).await?;
+    let webhook_id = path.id;
+    let deliveries = DbConn::get(&pool)?
+        .run("list webhook deliveries", move |connection| {
+            repository::list_webhook_deliveries(
+                connection,
+                tenant.id,
+                webhook_id,
+                DELIVERY_LOG_LIMIT,
+            )
+        })
+        .await?;
+    Ok(HttpResponse::Ok().json(deliveries))
+}
+
+/// Signs a payload as `sha256=<hex HMAC-SHA256>` so receivers can verify it came from us.
+fn sign(secret: &str, payload: &[u8]) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any size");
+    mac.update(payload);
+    format!("sha256={}", hex::encode(mac.finalize().into_bytes()))
+}
+
+/// How webhooks are delivered to.
+#[derive(Clone, Copy, Debug)]
+pub struct DeliveryPolicy {
+    pub max_attempts: u32,
+    /// Deliver to hosts resolving to non public addresses as well, see
+    /// `WEBHOOK_ALLOW_PRIVATE`.
+    pub allow_private: bool,
+}
+
+impl DeliveryPolicy {
+    pub fn from_config(config: &Config) -> DeliveryPolicy {
+        DeliveryPolicy {
+            max_attempts: config.webhook_max_attempts,
+            allow_private: config.webhook_allow_private,
+        }
+    }
+
+    /// The longest delivering to one webhook can take, with every host
+    /// check and request of every attempt timing out, and the backoff
+    /// between attempts.
+    pub fn max_delivery_time(&self) -> Duration {
+        let per_attempt = match self.allow_private {
+            true => DELIVERY_TIMEOUT,
+            false => DELIVERY_TIMEOUT * 2,
+        };
+        (1..self.max_attempts)
+            .map(backoff)
+            .fold(per_attempt * self.max_attempts, Duration::saturating_add)
+    }
+}
+
+/// Delay before retrying after the given (1-based) failed attempt.
+fn backoff(attempt: u32) -> Duration {
+    BACKOFF_BASE * 2u32.saturating_pow(attempt - 1)
+}
+
+/// Delivers `payload` to every webhook subscribed to `event`, returning once
+/// each delivery has either succeeded or run out of attempts.
+///
+/// Only fails when the subscribers could not be looked up, in which case
+/// nothing was sent.
+pub async fn publish(
+    pool: &DbPool,
+    tenant_id: i32,
+    event: CatEvent,
+    payload: &Value,
+    policy: DeliveryPolicy,
+) -> Result<(), String> {
+    let lookup_pool = pool.clone();
+    let webhooks = telemetry::block(move || {
+        let mut connection = lookup_pool.get().map_err(|e| e.to_string())?;
+        repository::list_webhooks_for_event(&mut connection, tenant_id, event.name())
+            .map_err(|e| e.to_string())
+    })
+    .await
+    .map_err(|e| e.to_string())??;
+
+    join_all(
+        webhooks
+            .into_iter()
+            .map(|webhook| deliver(pool.clone(), webhook, event, payload.clone(), policy)),
+    )
+    .await;
+    Ok(())
+}
+
+/// Posts `payload` to `webhook` until it answers with a 2xx status or
+/// `policy` runs out of attempts. Redirects are not followed, and unless
+/// private hosts are allowed, the host is checked to still resolve to
+/// public addresses only before each attempt, as it may have been
+/// re-pointed since the webhook was registered.
+async fn deliver(
+    pool: DbPool,
+    webhook: Webhook,
+    event: CatEvent,
+    payload: Value,
+    policy: DeliveryPolicy,
+) {
+    let max_attempts = policy.max_attempts;
+    let client = awc::Client::builder()
+        .timeout(DELIVERY_TIMEOUT)
+        .disable_redirects()
+        .finish();
+    let body = payload.to_string();
+    let signature = sign(&webhook.secret, body.as_bytes());
+
+    for attempt in 1..=max_attempts {
+        let refused = match policy.allow_private {
+            true => None,
+            false => {
+                let checked = image_download::check_public_url(&webhook.url);
+                match actix_rt::time::timeout(DELIVERY_TIMEOUT, checked).await {
+                    Ok(Ok(checked)) => checked.err().map(str::to_string),
+                    Ok(Err(e)) => Some(e.to_string()),
+                    Err(_) => Some("host lookup timed out".to_string()),
+                }
+            }
+        };
+        let (status_code, error) = match refused {
+            Some(reason) => {
+                warn!(
+                    "Refused to deliver to webhook ID: {}: {}",
+                    webhook.id, reason
+                );
+                (None, Some(reason))
+            }
+            None => match client
+                .post(&webhook.url)
+                .insert_header((CONTENT_TYPE, "application/json"))
+                .insert_header((EVENT_HEADER, event.name()))
+                .insert_header((SIGNATURE_HEADER, signature.as_str()))
+                .send_body(body.clone())
+                .await
+            {
+                Ok(response) => (Some(i32::from(response.status().as_u16())), None),
+                Err(e) => (None, Some(e.to_string())),
+            },
+        };
+        let delivered = status_code.is_some_and(|code| (200..300).contains(&code));
+
+        record_delivery(
+            &pool,
+            NewWebhookDelivery {
+                webhook_id: webhook.id,
+                event: event.name().to_string(),
+                payload: payload.clone(),
+                attempt: attempt as i32,
+                status_code,
+                error,
+                delivered,
+            },
+        )
+        .await;
+
+        if delivered {
+            return;
+        }
+        if attempt < max_attempts {
+            actix_rt::time::sleep(backoff(attempt)).await;
+        }
+    }
+    warn!(
+        "Giving up on webhook ID: {} for {} after {} attempts",
+        webhook.id,
+        event.name(),
+        max_attempts
+    );
+}
+
+async fn record_delivery(pool: &DbPool, delivery: NewWebhookDelivery) {
+    let pool = pool.clone();
+    let webhook_id = delivery.webhook_id;
+    let result = telemetry::block(move || {
+        let mut connection = pool.get().map_err(|e| e.to_string())?;
+        repository::insert_webhook_delivery(&mut connection, &delivery).map_err(|e| e.to_string())
+    })
+    .await;
+    if !matches!(result, Ok(Ok(_))) {
+        error!("Failed to record delivery for webhook ID: {}", webhook_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_matches_known_hmac() {
+        // RFC 4231 test case 2
+        assert_eq!(
+            sign("Jefe", b"what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[actix_web::test]
+    async fn test_deliver_refuses_private_hosts_and_redirects() {
+        use crate::tenants::DEFAULT_TENANT;
+        use crate::test_support::test_pool;
+        use actix_web::http::header::LOCATION;
+        use actix_web::{App, HttpServer};
+
+        // Redirects to a path that would count as delivered
+        let server = HttpServer::new(|| {
+            App::new()
+                .route(
+                    "/hook",
+                    web::post().to(|| async {
+                        HttpResponse::Found()
+                            .insert_header((LOCATION, "/ok"))
+                            .finish()
+                    }),
+                )
+                .route("/ok", web::to(HttpResponse::Ok))
+        })
+        .workers(1)
+        .bind(("127.0.0.1", 0))
+        .unwrap();
+        let addr = server.addrs()[0];
+        let server = server.run();
+        let handle = server.handle();
+        actix_rt::spawn(server);
+
+        let pool = test_pool();
+        let tenant_id = {
+            let mut connection = pool.get().unwrap();
+            repository::find_tenant_by_slug(&mut connection, DEFAULT_TENANT)
+                .unwrap()
+                .id
+        };
+        let webhook = {
+            let mut connection = pool.get().unwrap();
+            let new_webhook = NewWebhook {
+                tenant_id,
+                url: format!("http://{}/hook", addr),
+                secret: "secret".to_string(),
+                events: vec![CatEvent::Created.name().to_string()],
+            };
+            repository::insert_webhook(&mut connection, &new_webhook).unwrap()
+        };
+        let webhook_id = webhook.id;
+        for allow_private in [false, true] {
+            let policy = DeliveryPolicy {
+                max_attempts: 1,
+                allow_private,
+            };
+            let webhook = webhook.clone();
+            deliver(pool.clone(), webhook, CatEvent::Created, json!({}), policy).await;
+        }
+        handle.stop(false).await;
+
+        let mut connection = pool.get().unwrap();
+        let mut deliveries =
+            repository::list_webhook_deliveries(&mut connection, tenant_id, webhook_id, 10)
+                .unwrap();
+        deliveries.sort_by_key(|delivery| delivery.id);
+        let [refused, redirected] = &deliveries[..] else {
+            panic!("Expected two deliveries, got {:?}", deliveries);
+        };
+        assert_eq!(refused.status_code, None);
+        assert_eq!(refused.error.as_deref(), Some("host is not public"));
+        assert_eq!(redirected.status_code, Some(302));
+        assert!(!redirected.delivered);
+    }
+
+    #[test]
+    fn test_backoff_doubles_per_attempt() {
+        assert_eq!(backoff(1), Duration::from_secs(1));
+        assert_eq!(backoff(2), Duration::from_secs(2));
+        assert_eq!(backoff(4), Duration::from_secs(8));
+    }
+
+    #[test]
+    fn test_max_delivery_time() {
+        let policy = DeliveryPolicy {
+            max_attempts: 7,
+            allow_private: true,
+        };
+        // 7 timed out requests and 1 + 2 + ... + 32 seconds of backoff
+        assert_eq!(policy.max_delivery_time(), Duration::from_secs(70 + 63));
+        let policy = DeliveryPolicy {
+            allow_private: false,
+            ..policy
+        };
+        assert_eq!(policy.max_delivery_time(), Duration::from_secs(140 + 63));
+    }
+
+    #[test]
+    fn test_webhook_events_validation() {
+        assert!(validate_webhook_events(&all_event_names()).is_ok());
+        assert!(validate_webhook_events(&[]).is_err());
+        assert!(validate_webhook_events(&["cat.adopted".to_string()]).is_err());
+    }
+}